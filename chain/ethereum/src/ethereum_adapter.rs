@@ -12,12 +12,13 @@ use graph::components::ethereum::{EthereumAdapter as EthereumAdapterTrait, *};
 use graph::prelude::{
     debug, err_msg, error, ethabi, format_err,
     futures03::{self, compat::Future01CompatExt, FutureExt, StreamExt, TryStreamExt},
-    hex, retry, stream, tiny_keccak, trace, warn, web3, ChainStore, CheapClone, DynTryFuture,
-    Error, EthereumCallCache, Logger, TimeoutError,
+    hex, retry, serde_json, stream, tiny_keccak, trace, warn, web3, ChainStore, CheapClone,
+    DynTryFuture, Error, EthereumCallCache, Logger, TimeoutError,
 };
 use web3::api::Web3;
 use web3::transports::batch::Batch;
 use web3::types::{Filter, *};
+use web3::Transport;
 
 #[derive(Clone)]
 pub struct EthereumAdapter<T: web3::Transport> {
@@ -727,6 +728,83 @@ where
         )
     }
 
+    fn raw_block_by_hash(
+        &self,
+        logger: &Logger,
+        block_hash: H256,
+    ) -> Box<dyn Future<Item = Option<serde_json::Value>, Error = Error> + Send> {
+        let web3 = self.web3.clone();
+        let logger = logger.clone();
+
+        Box::new(
+            retry("eth_getBlockByHash RPC call", &logger)
+                .limit(*REQUEST_RETRIES)
+                .timeout_secs(*JSON_RPC_TIMEOUT)
+                .run(move || {
+                    web3.transport()
+                        .execute(
+                            "eth_getBlockByHash",
+                            vec![
+                                serde_json::Value::String(format!("{:#x}", block_hash)),
+                                serde_json::Value::Bool(false),
+                            ],
+                        )
+                        .from_err()
+                        .map(|block| match block {
+                            serde_json::Value::Null => None,
+                            block => Some(block),
+                        })
+                })
+                .map_err(move |e| {
+                    e.into_inner().unwrap_or_else(move || {
+                        format_err!("Ethereum node took too long to return block {}", block_hash)
+                    })
+                }),
+        )
+    }
+
+    fn block_number_by_tag(
+        &self,
+        logger: &Logger,
+        tag: &'static str,
+    ) -> Box<dyn Future<Item = Option<u64>, Error = Error> + Send> {
+        let web3 = self.web3.clone();
+        let logger = logger.clone();
+
+        Box::new(
+            // Nodes that do not know the tag fail every attempt, and we
+            // don't want to wait for that for every block
+            retry("eth_getBlockByNumber RPC call", &logger)
+                .limit(1)
+                .no_logging()
+                .timeout_secs(*JSON_RPC_TIMEOUT)
+                .run(move || {
+                    web3.transport()
+                        .execute(
+                            "eth_getBlockByNumber",
+                            vec![
+                                serde_json::Value::String(tag.to_owned()),
+                                serde_json::Value::Bool(false),
+                            ],
+                        )
+                        .from_err()
+                        .and_then(move |block| match block {
+                            serde_json::Value::Null => Ok(None),
+                            block => serde_json::from_value::<U64>(block["number"].clone())
+                                .map(|number| Some(number.as_u64()))
+                                .map_err(|e| {
+                                    format_err!("the `{}` block has no valid number: {}", tag, e)
+                                }),
+                        })
+                })
+                .map_err(move |e| {
+                    e.into_inner().unwrap_or_else(move || {
+                        format_err!("Ethereum node took too long to return the {} block", tag)
+                    })
+                }),
+        )
+    }
+
     fn load_full_block(
         &self,
         logger: &Logger,
@@ -2,6 +2,7 @@ use std::time::Instant;
 
 use graph::prelude::*;
 
+use super::convert::{required_header_fields, TryToEntityKey};
 use super::*;
 
 /// Metrics for analyzing the block writer performance.
@@ -46,6 +47,10 @@ pub struct BlockWriter {
 
     /// Metrics for analyzing the block writer performance.
     metrics: Arc<BlockWriterMetrics>,
+
+    /// Whether to check the total difficulty of each block against the
+    /// total difficulty of its parent before writing it.
+    check_total_difficulty: bool,
 }
 
 impl BlockWriter {
@@ -68,9 +73,18 @@ impl BlockWriter {
             subgraph_id,
             logger,
             metrics,
+            check_total_difficulty: true,
         }
     }
 
+    /// Do not check the total difficulty of blocks before writing them. This
+    /// is needed for chains where the total difficulty no longer changes
+    /// or is not reported, e.g., Ethereum after the merge.
+    pub fn skip_total_difficulty_check(mut self) -> Self {
+        self.check_total_difficulty = false;
+        self
+    }
+
     /// Writes a block to the store and updates the network subgraph block pointer.
     pub fn write(
         &self,
@@ -87,6 +101,7 @@ impl BlockWriter {
             store: self.store.clone(),
             cache: EntityCache::new(),
             metrics: self.metrics.clone(),
+            check_total_difficulty: self.check_total_difficulty,
         };
         context.write(block)
     }
@@ -99,6 +114,7 @@ struct WriteContext {
     store: Arc<dyn Store>,
     cache: EntityCache,
     metrics: Arc<BlockWriterMetrics>,
+    check_total_difficulty: bool,
 }
 
 /// Internal result type used to thread WriteContext through the chain of futures
@@ -107,17 +123,41 @@ type WriteContextResult = Box<dyn Future<Item = WriteContext, Error = Error> + S
 
 impl WriteContext {
     /// Updates an entity to a new value (potentially merging it with existing data).
-    fn set_entity(mut self, value: impl TryIntoEntity + ToEntityKey) -> WriteContextResult {
-        self.cache.set(
-            value.to_entity_key(self.subgraph_id.clone()),
-            match value.try_into_entity() {
-                Ok(entity) => entity,
-                Err(e) => return Box::new(future::err(e.into())),
-            },
-        );
+    /// Values that can not be stored, like blocks without a hash, are
+    /// reported as an error
+    fn set_entity(mut self, value: impl TryIntoEntity + TryToEntityKey) -> WriteContextResult {
+        let key = match value.try_to_entity_key(self.subgraph_id.clone()) {
+            Ok(key) => key,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let entity = match value.try_into_entity() {
+            Ok(entity) => entity,
+            Err(e) => return Box::new(future::err(e.into())),
+        };
+        self.cache.set(key, entity);
         Box::new(future::ok(self))
     }
 
+    /// Checks the total difficulty of the block against its parent, if the
+    /// parent is in the store.
+    fn check_total_difficulty(self, block: &BlockWithOmmers) -> WriteContextResult {
+        if !self.check_total_difficulty {
+            return Box::new(future::ok(self));
+        }
+
+        let parent_key = EntityKey {
+            subgraph_id: self.subgraph_id.clone(),
+            entity_type: "Block".into(),
+            entity_id: block.inner().parent_hash.to_entity_id(),
+        };
+        let result = match self.store.get(parent_key) {
+            Ok(Some(parent)) => check_total_difficulty(block, &parent).map_err(|e| e.into()),
+            Ok(None) => Ok(()),
+            Err(e) => Err(e.into()),
+        };
+        Box::new(future::result(result.map(|_| self)))
+    }
+
     /// Writes a block to the store.
     fn write(
         self,
@@ -130,12 +170,23 @@ impl WriteContext {
         let block_for_store = block.clone();
 
         Box::new(
-            // Add the block entity
-            self.set_entity(block.as_ref())
+            // Make sure the block is consistent with its parent
+            self.check_total_difficulty(block.as_ref())
+                // Add the block entity
+                .and_then(move |context| context.set_entity(block.as_ref()))
                 // Add uncle block entities
                 .and_then(move |context| {
-                    futures::stream::iter_ok::<_, Error>(block_for_ommers.ommers.clone())
-                        .fold(context, move |context, ommer| context.set_entity(ommer))
+                    future::result(required_header_fields(block_for_ommers.inner())).and_then(
+                        move |(_, number)| {
+                            let ommers = block_for_ommers
+                                .ommers
+                                .clone()
+                                .into_iter()
+                                .map(move |ommer| ommer.included_in(number));
+                            futures::stream::iter_ok::<_, Error>(ommers)
+                                .fold(context, move |context, ommer| context.set_entity(ommer))
+                        },
+                    )
                 })
                 // Transact everything into the store
                 .and_then(move |context| {
@@ -1,56 +1,191 @@
+use graph::data::store::scalar::big_decimal_round_half_even;
 use graph::prelude::*;
+use rayon::prelude::*;
+use rlp::RlpStream;
+use secp256k1::recovery::{RecoverableSignature, RecoveryId};
+use secp256k1::{Message, Secp256k1, VerifyOnly};
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use web3::types::{H160, H256, H64, U256, U64};
 
 use super::*;
 
-impl ToEntityId for Ommer {
+/// A value whose entity key can only be derived when some of its fields
+/// are set, like blocks, which are keyed by their hash
+pub(crate) trait TryToEntityKey {
+    fn try_to_entity_key(&self, subgraph_id: SubgraphDeploymentId) -> Result<EntityKey, Error>;
+}
+
+/// The key of the `Block` entity for `block`; blocks that can not be
+/// converted into entities (see `required_header_fields`) have no key
+fn block_entity_key<T>(
+    block: &web3::types::Block<T>,
+    subgraph_id: SubgraphDeploymentId,
+) -> Result<EntityKey, Error> {
+    let (hash, _) = required_header_fields(block)?;
+    Ok(EntityKey {
+        subgraph_id,
+        entity_type: "Block".into(),
+        entity_id: hash.to_entity_id(),
+    })
+}
+
+impl TryToEntityKey for Ommer {
+    fn try_to_entity_key(&self, subgraph_id: SubgraphDeploymentId) -> Result<EntityKey, Error> {
+        block_entity_key(&self.0, subgraph_id)
+    }
+}
+
+impl TryToEntityKey for IncludedOmmer {
+    fn try_to_entity_key(&self, subgraph_id: SubgraphDeploymentId) -> Result<EntityKey, Error> {
+        self.ommer.try_to_entity_key(subgraph_id)
+    }
+}
+
+impl TryToEntityKey for &BlockWithOmmers {
+    fn try_to_entity_key(&self, subgraph_id: SubgraphDeploymentId) -> Result<EntityKey, Error> {
+        block_entity_key(&self.block.block, subgraph_id)
+    }
+}
+
+impl ToEntityId for Transaction {
     fn to_entity_id(&self) -> String {
-        format!("{:x}", self.0.hash.unwrap())
+        format!("{:x}", self.hash)
     }
 }
 
-impl ToEntityKey for Ommer {
+impl ToEntityKey for Transaction {
     fn to_entity_key(&self, subgraph_id: SubgraphDeploymentId) -> EntityKey {
         EntityKey {
             subgraph_id,
-            entity_type: "Block".into(),
-            entity_id: format!("{:x}", self.0.hash.unwrap()),
+            entity_type: "Transaction".into(),
+            entity_id: format!("{:x}", self.hash),
         }
     }
 }
 
-impl ToEntityId for BlockWithOmmers {
+impl ToEntityId for Log {
     fn to_entity_id(&self) -> String {
-        (*self).block.block.hash.unwrap().to_entity_id()
+        format!("{:x}-{}", self.transaction, self.index)
     }
 }
 
-impl ToEntityKey for &BlockWithOmmers {
+impl ToEntityKey for Log {
     fn to_entity_key(&self, subgraph_id: SubgraphDeploymentId) -> EntityKey {
         EntityKey {
             subgraph_id,
-            entity_type: "Block".into(),
-            entity_id: format!("{:x}", (*self).block.block.hash.unwrap()),
+            entity_type: "Log".into(),
+            entity_id: self.to_entity_id(),
         }
     }
 }
 
+/// The block nonce as a number. The 8 bytes of the nonce are interpreted
+/// as a big-endian unsigned integer, which is how clients display it when
+/// they show it as a number
+fn nonce_int(nonce: H64) -> Value {
+    BigInt::from(u64::from_be_bytes(nonce.to_fixed_bytes())).into()
+}
+
+/// The account that mined or sealed a block. That is the `signer` if we
+/// know it, and otherwise the block's author, unless that is zero, as it
+/// is for Clique blocks whose signer we do not know
+fn miner(author: H160, signer: Option<H160>) -> Value {
+    match signer {
+        Some(signer) => signer.into(),
+        None if author.is_zero() => Value::Null,
+        None => author.into(),
+    }
+}
+
+/// The number of digits after the decimal point in `sizeKb`
+const SIZE_KB_SCALE: i64 = 3;
+
+/// The block size in kilobytes of 1024 bytes, rounded half to even to
+/// `SIZE_KB_SCALE` digits, so that dashboards do not have to convert the
+/// size themselves
+fn size_kb(size: Option<U256>) -> Value {
+    size.map_or(Value::Null, |size| {
+        let bytes = BigInt::from_unsigned_u256(&size).to_big_decimal(BigInt::from(0));
+        big_decimal_round_half_even(&(bytes / BigDecimal::from(1024)), SIZE_KB_SCALE).into()
+    })
+}
+
+/// Return the hash and number of `block`. Nodes leave them out for pending
+/// blocks, and we can not store a block without them; rather than fail on
+/// the first one that is missing, the error lists all missing fields
+pub(crate) fn required_header_fields<T>(
+    block: &web3::types::Block<T>,
+) -> Result<(H256, U64), Error> {
+    match (block.hash, block.number) {
+        (Some(hash), Some(number)) => Ok((hash, number)),
+        (hash, number) => {
+            let mut missing = vec![];
+            if hash.is_none() {
+                missing.push("`hash`");
+            }
+            if number.is_none() {
+                missing.push("`number`");
+            }
+            let name = hash.map_or_else(
+                || format!("with parent hash {:x}", block.parent_hash),
+                |hash| format!("{:x}", hash),
+            );
+            Err(format_err!(
+                "block {} can not be converted since it is missing the required \
+                 header fields {}",
+                name,
+                missing.join(", ")
+            ))
+        }
+    }
+}
+
+/// Check that the total difficulty of `block` is the total difficulty of
+/// its `parent` block entity plus the difficulty of `block`. Nodes
+/// occasionally return a total difficulty that does not add up, which
+/// means that the block data is bad. If the parent does not have a total
+/// difficulty, there is nothing to check
+pub fn check_total_difficulty(block: &BlockWithOmmers, parent: &Entity) -> Result<(), StoreError> {
+    let inner = block.inner();
+    let parent_total = match parent.get("totalDifficulty") {
+        Some(Value::BigInt(total)) => total.clone(),
+        _ => return Ok(()),
+    };
+    let difficulty = BigInt::from_unsigned_u256(&inner.difficulty);
+    let total = BigInt::from_unsigned_u256(&inner.total_difficulty);
+    let expected = parent_total.clone() + difficulty.clone();
+    if total != expected {
+        return Err(StoreError::InconsistentBlock(format!(
+            "block {} has total difficulty {}, but the total difficulty of its parent \
+             is {} and its difficulty is {}, which adds up to {}",
+            block, total, parent_total, difficulty, expected
+        )));
+    }
+    Ok(())
+}
+
 impl TryIntoEntity for Ommer {
     fn try_into_entity(self) -> Result<Entity, Error> {
         let inner = &self.0;
+        let (hash, number) = required_header_fields(inner)?;
 
         Ok(Entity::from(vec![
-            ("id", format!("{:x}", inner.hash.unwrap()).into()),
-            ("number", inner.number.unwrap().into()),
-            ("hash", inner.hash.unwrap().into()),
+            ("id", format!("{:x}", hash).into()),
+            ("number", number.into()),
+            ("hash", hash.into()),
             ("parent", inner.parent_hash.to_entity_id().into()),
             (
                 "nonce",
                 inner.nonce.map_or(Value::Null, |nonce| nonce.into()),
             ),
+            ("nonceInt", inner.nonce.map_or(Value::Null, nonce_int)),
             ("transactionsRoot", inner.transactions_root.into()),
             ("transactionCount", (inner.transactions.len() as i32).into()),
             ("stateRoot", inner.state_root.into()),
             ("receiptsRoot", inner.receipts_root.into()),
+            ("miner", miner(inner.author, None)),
             ("extraData", inner.extra_data.clone().into()),
             ("gasLimit", inner.gas_limit.into()),
             ("gasUsed", inner.gas_used.into()),
@@ -71,29 +206,72 @@ impl TryIntoEntity for Ommer {
                     .into(),
             ),
             ("size", inner.size.into()),
+            ("sizeKb", size_kb(inner.size)),
             ("sealFields", inner.seal_fields.clone().into()),
+            ("blobGasUsed", Value::Null),
+            ("excessBlobGas", Value::Null),
             ("isOmmer", true.into()),
+            ("inclusionDistance", Value::Null),
+            ("finality", Value::Null),
         ] as Vec<(_, Value)>))
     }
 }
 
+/// The maximum number of blocks by which an ommer can precede the block
+/// that includes it.
+const MAX_OMMER_INCLUSION_DISTANCE: u64 = 6;
+
+impl TryIntoEntity for IncludedOmmer {
+    fn try_into_entity(self) -> Result<Entity, Error> {
+        let (ommer_hash, ommer_number) = required_header_fields(&self.ommer.0)?;
+        let including_number = self.including_block_number;
+        if ommer_number >= including_number
+            || including_number - ommer_number > U64::from(MAX_OMMER_INCLUSION_DISTANCE)
+        {
+            return Err(format_err!(
+                "ommer {:x} at block {} can not be included in block {}; ommers must be \
+                 between 1 and {} blocks older than the block that includes them",
+                ommer_hash,
+                ommer_number,
+                including_number,
+                MAX_OMMER_INCLUSION_DISTANCE
+            ));
+        }
+        let distance = (including_number - ommer_number).as_u64() as i32;
+
+        let mut entity = self.ommer.try_into_entity()?;
+        entity.set("inclusionDistance", distance);
+        Ok(entity)
+    }
+}
+
 impl TryIntoEntity for &BlockWithOmmers {
     fn try_into_entity(self) -> Result<Entity, Error> {
         let inner = self.inner();
+        let (hash, number) = required_header_fields(inner)?;
+
+        // The genesis block has no parent; its parent hash is all zeros
+        let parent = if number.is_zero() {
+            Value::Null
+        } else {
+            inner.parent_hash.to_entity_id().into()
+        };
 
         Ok(Entity::from(vec![
-            ("id", format!("{:x}", inner.hash.unwrap()).into()),
-            ("number", inner.number.unwrap().into()),
-            ("hash", inner.hash.unwrap().into()),
-            ("parent", inner.parent_hash.to_entity_id().into()),
+            ("id", format!("{:x}", hash).into()),
+            ("number", number.into()),
+            ("hash", hash.into()),
+            ("parent", parent),
             (
                 "nonce",
                 inner.nonce.map_or(Value::Null, |nonce| nonce.into()),
             ),
+            ("nonceInt", inner.nonce.map_or(Value::Null, nonce_int)),
             ("transactionsRoot", inner.transactions_root.into()),
             ("transactionCount", (inner.transactions.len() as i32).into()),
             ("stateRoot", inner.state_root.into()),
             ("receiptsRoot", inner.receipts_root.into()),
+            ("miner", miner(inner.author, self.signer)),
             ("extraData", inner.extra_data.clone().into()),
             ("gasLimit", inner.gas_limit.into()),
             ("gasUsed", inner.gas_used.into()),
@@ -114,8 +292,1284 @@ impl TryIntoEntity for &BlockWithOmmers {
                     .into(),
             ),
             ("size", inner.size.into()),
+            ("sizeKb", size_kb(inner.size)),
             ("sealFields", inner.seal_fields.clone().into()),
+            (
+                "blobGasUsed",
+                self.blob_gas
+                    .as_ref()
+                    .map_or(Value::Null, |blob_gas| blob_gas.blob_gas_used.into()),
+            ),
+            (
+                "excessBlobGas",
+                self.blob_gas
+                    .as_ref()
+                    .map_or(Value::Null, |blob_gas| blob_gas.excess_blob_gas.into()),
+            ),
+            ("baseFeePerGas", self.fork_fields.base_fee_per_gas.into()),
+            ("withdrawalsRoot", self.fork_fields.withdrawals_root.into()),
+            (
+                "parentBeaconBlockRoot",
+                self.fork_fields.parent_beacon_block_root.into(),
+            ),
+            ("requestsHash", self.fork_fields.requests_hash.into()),
             ("isOmmer", false.into()),
+            ("inclusionDistance", Value::Null),
+            (
+                "finality",
+                self.finality.unwrap_or_default().to_string().into(),
+            ),
         ] as Vec<(_, Value)>))
     }
 }
+
+impl TryIntoEntity for Transaction {
+    fn try_into_entity(self) -> Result<Entity, Error> {
+        let inner = &self.transaction;
+
+        // Pending transactions are not part of a block yet; we can only
+        // store transactions that have been included in a block
+        let block_hash = inner.block_hash.ok_or_else(|| {
+            format_err!(
+                "transaction {:x} is pending and does not belong to a block",
+                inner.hash
+            )
+        })?;
+
+        let mut entity = Entity::from(vec![
+            ("id", format!("{:x}", inner.hash).into()),
+            ("hash", inner.hash.into()),
+            ("nonce", inner.nonce.into()),
+            ("block", block_hash.to_entity_id().into()),
+            (
+                "index",
+                inner
+                    .transaction_index
+                    .map_or(Value::Null, |index| index.into()),
+            ),
+            ("from", inner.from.into()),
+            ("to", inner.to.map_or(Value::Null, |to| to.into())),
+            ("value", inner.value.into()),
+            // Precomputed so that value transfers can be found with a
+            // simple filter
+            ("transfersValue", (!inner.value.is_zero()).into()),
+            ("gasPrice", inner.gas_price.into()),
+            ("gas", inner.gas.into()),
+            ("inputData", inner.input.clone().into()),
+            (
+                "logCount",
+                self.receipt
+                    .as_ref()
+                    .map_or(Value::Null, |receipt| (receipt.logs.len() as i32).into()),
+            ),
+            (
+                "maxFeePerBlobGas",
+                self.blob
+                    .as_ref()
+                    .map_or(Value::Null, |blob| blob.max_fee_per_blob_gas.into()),
+            ),
+            (
+                "blobVersionedHashes",
+                self.blob
+                    .as_ref()
+                    .map(|blob| blob.blob_versioned_hashes.clone())
+                    .unwrap_or_default()
+                    .into(),
+            ),
+            (
+                "chainId",
+                self.signature
+                    .as_ref()
+                    .and_then(|signature| signature.chain_id())
+                    .map_or(Value::Null, |chain_id| chain_id.into()),
+            ),
+        ] as Vec<(_, Value)>);
+        if self.input_hex {
+            entity.set("inputHex", format!("0x{}", hex::encode(&inner.input.0)));
+        }
+        Ok(entity)
+    }
+}
+
+lazy_static! {
+    /// The threads on which `transactions_to_entities` converts
+    /// transactions. The pool is shared by all conversions so that
+    /// backfilling several blocks at once does not start more threads than
+    /// there are CPUs
+    static ref CONVERSION_POOL: rayon::ThreadPool = rayon::ThreadPoolBuilder::new()
+        .thread_name(|i| format!("transaction-conversion-{}", i))
+        .build()
+        .expect("failed to start the transaction conversion threads");
+}
+
+/// Convert `transactions` to entities on the shared conversion pool, which
+/// speeds up backfilling blocks with thousands of transactions. The
+/// transactions are split into at most `workers` chunks, and the entities
+/// are returned in the order of the transactions. The result is the same
+/// as converting the transactions one after the other: if conversions
+/// fail, the error for the first transaction that failed is returned, and
+/// transactions that come after one that is known to have failed are not
+/// converted anymore
+pub fn transactions_to_entities(
+    transactions: Vec<Transaction>,
+    workers: usize,
+) -> Result<Vec<Entity>, Error> {
+    if workers <= 1 || transactions.len() <= 1 {
+        return transactions
+            .into_iter()
+            .map(|transaction| transaction.try_into_entity())
+            .collect();
+    }
+
+    let chunk_size = (transactions.len() + workers - 1) / workers;
+    // The position of the first transaction that we know failed
+    let first_failure = AtomicUsize::new(usize::max_value());
+    let results: Vec<Option<Result<Entity, Error>>> = CONVERSION_POOL.install(|| {
+        transactions
+            .into_par_iter()
+            .enumerate()
+            .with_min_len(chunk_size)
+            .map(|(position, transaction)| {
+                if position > first_failure.load(Ordering::SeqCst) {
+                    return None;
+                }
+                let result = transaction.try_into_entity();
+                if result.is_err() {
+                    first_failure.fetch_min(position, Ordering::SeqCst);
+                }
+                Some(result)
+            })
+            .collect()
+    });
+    // Transactions are only skipped when one before them failed, and
+    // leaving them out therefore still returns the first error
+    results.into_iter().flatten().collect()
+}
+
+impl TryIntoEntity for Log {
+    fn try_into_entity(self) -> Result<Entity, Error> {
+        // For events that are not anonymous, the first topic is the hash of
+        // the event signature, and the remaining topics are the indexed
+        // parameters. We can't tell anonymous events apart from others
+        // here, and only treat a log without any topics as anonymous
+        let (event_signature, indexed_topics) = match self.topics.split_first() {
+            Some((signature, rest)) => (Value::from(*signature), rest.to_vec()),
+            None => (Value::Null, vec![]),
+        };
+
+        Ok(Entity::from(vec![
+            ("id", self.to_entity_id().into()),
+            ("transaction", self.transaction.to_entity_id().into()),
+            ("index", (self.index as i32).into()),
+            ("address", self.address.into()),
+            ("topics", self.topics.clone().into()),
+            ("eventSignature", event_signature),
+            ("indexedTopics", indexed_topics.into()),
+            ("data", self.data.clone().into()),
+        ] as Vec<(_, Value)>))
+    }
+}
+
+/// Get the value of a required attribute from `entity`
+fn required<'a>(entity: &'a Entity, attr: &str) -> Result<&'a Value, Error> {
+    match entity.get(attr) {
+        None | Some(Value::Null) => Err(format_err!("entity is missing attribute `{}`", attr)),
+        Some(value) => Ok(value),
+    }
+}
+
+fn bytes_attr<'a>(entity: &'a Entity, attr: &str) -> Result<&'a [u8], Error> {
+    match required(entity, attr)? {
+        Value::Bytes(bytes) => Ok(bytes.as_slice()),
+        value => Err(format_err!(
+            "attribute `{}` should be Bytes but is {:?}",
+            attr,
+            value
+        )),
+    }
+}
+
+fn h256_attr(entity: &Entity, attr: &str) -> Result<H256, Error> {
+    let bytes = bytes_attr(entity, attr)?;
+    if bytes.len() != 32 {
+        return Err(format_err!(
+            "attribute `{}` should be 32 bytes long but is {} bytes long",
+            attr,
+            bytes.len()
+        ));
+    }
+    Ok(H256::from_slice(bytes))
+}
+
+fn h160_attr(entity: &Entity, attr: &str) -> Result<H160, Error> {
+    let bytes = bytes_attr(entity, attr)?;
+    if bytes.len() != 20 {
+        return Err(format_err!(
+            "attribute `{}` should be 20 bytes long but is {} bytes long",
+            attr,
+            bytes.len()
+        ));
+    }
+    Ok(H160::from_slice(bytes))
+}
+
+fn u256_attr(entity: &Entity, attr: &str) -> Result<U256, Error> {
+    match required(entity, attr)? {
+        Value::BigInt(n) if n >= &BigInt::from(0) && n.bits() <= 256 => Ok(n.to_unsigned_u256()),
+        value => Err(format_err!(
+            "attribute `{}` should be an unsigned 256 bit BigInt but is {:?}",
+            attr,
+            value
+        )),
+    }
+}
+
+fn u64_attr(entity: &Entity, attr: &str) -> Result<U64, Error> {
+    match required(entity, attr)? {
+        Value::BigInt(n) => u64::try_from(n)
+            .map(U64::from)
+            .map_err(|e| format_err!("attribute `{}` does not fit into a u64: {}", attr, e)),
+        value => Err(format_err!(
+            "attribute `{}` should be a BigInt but is {:?}",
+            attr,
+            value
+        )),
+    }
+}
+
+/// The value of the optional attribute `attr` of `entity`, or `None` if it
+/// is not set
+fn optional<'a>(entity: &'a Entity, attr: &str) -> Option<&'a Value> {
+    match entity.get(attr) {
+        None | Some(Value::Null) => None,
+        Some(value) => Some(value),
+    }
+}
+
+/// The RLP encoding of an unsigned integer is its big-endian
+/// representation without leading zeros
+fn rlp_uint(value: U256) -> Vec<u8> {
+    let mut bytes = [0u8; 32];
+    value.to_big_endian(&mut bytes);
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    bytes[start..].to_vec()
+}
+
+/// The fields that forks added to the block header, in the order in which
+/// they follow the original 15 fields, and whether they are integers
+const FORK_HEADER_FIELDS: &[(&str, bool)] = &[
+    ("baseFeePerGas", true),
+    ("withdrawalsRoot", false),
+    ("blobGasUsed", true),
+    ("excessBlobGas", true),
+    ("parentBeaconBlockRoot", false),
+    ("requestsHash", false),
+];
+
+/// Return how many of the `FORK_HEADER_FIELDS` the header of the block
+/// stored in `entity` has. Each fork only adds fields after the ones of
+/// earlier forks, and the fields that are set must therefore come first.
+/// Return an error if that is not the case, or if the block has a
+/// difficulty of 0, which means that it is from after the merge and must
+/// have at least a `baseFeePerGas`. Such blocks were stored without the
+/// fields of their fork, and we can not tell which layout their header has
+fn fork_header_field_count(entity: &Entity) -> Result<usize, Error> {
+    let count = FORK_HEADER_FIELDS
+        .iter()
+        .take_while(|(attr, _)| optional(entity, attr).is_some())
+        .count();
+    if let Some((attr, _)) = FORK_HEADER_FIELDS[count..]
+        .iter()
+        .find(|(attr, _)| optional(entity, attr).is_some())
+    {
+        return Err(format_err!(
+            "the header layout of the block can not be determined since it has \
+             `{}` but not `{}`",
+            attr,
+            FORK_HEADER_FIELDS[count].0
+        ));
+    }
+    if count == 0 && u256_attr(entity, "difficulty")?.is_zero() {
+        return Err(format_err!(
+            "the header layout of the block can not be determined since it has \
+             a difficulty of 0 but not `baseFeePerGas`"
+        ));
+    }
+    Ok(count)
+}
+
+/// Recompute the hash of the header of the block stored in `entity` as
+/// the keccak256 hash of the RLP encoding of the header fields. The fields
+/// that forks added to the header, like `baseFeePerGas`, are appended to
+/// the original 15 fields if they are set. That requires the block to be
+/// stored with all the fields of its fork; blocks from chains that use
+/// Clique can't be checked either since `miner` is the signer of the block
+/// rather than the author that went into the hash
+fn block_header_hash(entity: &Entity) -> Result<H256, Error> {
+    let fork_fields = fork_header_field_count(entity)?;
+
+    // The genesis block has no parent, and its parent hash is all zeros
+    let parent_hash = match optional(entity, "parent") {
+        None => H256::zero(),
+        Some(Value::String(id)) => H256::from_str(id)
+            .map_err(|e| format_err!("attribute `parent` is not a block hash: {}", e))?,
+        Some(value) => {
+            return Err(format_err!(
+                "attribute `parent` should be a String but is {:?}",
+                value
+            ))
+        }
+    };
+    // We store a zero author as `null`
+    let miner = match optional(entity, "miner") {
+        None => H160::zero(),
+        Some(_) => h160_attr(entity, "miner")?,
+    };
+
+    let mut fields = vec![
+        parent_hash.as_bytes().to_vec(),
+        bytes_attr(entity, "ommerHash")?.to_vec(),
+        miner.as_bytes().to_vec(),
+        bytes_attr(entity, "stateRoot")?.to_vec(),
+        bytes_attr(entity, "transactionsRoot")?.to_vec(),
+        bytes_attr(entity, "receiptsRoot")?.to_vec(),
+        bytes_attr(entity, "logsBloom")?.to_vec(),
+        rlp_uint(u256_attr(entity, "difficulty")?),
+        rlp_uint(u256_attr(entity, "number")?),
+        rlp_uint(u256_attr(entity, "gasLimit")?),
+        rlp_uint(u256_attr(entity, "gasUsed")?),
+        rlp_uint(u256_attr(entity, "timestamp")?),
+        bytes_attr(entity, "extraData")?.to_vec(),
+        bytes_attr(entity, "mixHash")?.to_vec(),
+        bytes_attr(entity, "nonce")?.to_vec(),
+    ];
+    for (attr, is_uint) in &FORK_HEADER_FIELDS[..fork_fields] {
+        let field = if *is_uint {
+            rlp_uint(u256_attr(entity, attr)?)
+        } else {
+            bytes_attr(entity, attr)?.to_vec()
+        };
+        fields.push(field);
+    }
+
+    let mut stream = RlpStream::new_list(fields.len());
+    for field in &fields {
+        stream.append(field);
+    }
+    Ok(H256::from(tiny_keccak::keccak256(&stream.out())))
+}
+
+/// Check that the `hash` of the block stored in `entity` is the hash of
+/// its header fields, which makes it possible to verify blocks that we
+/// got from a node we do not fully trust. Return an error if the entity
+/// is missing fields that are needed to compute the hash, or if we can
+/// not tell which header layout the block has; see
+/// `fork_header_field_count`. Proof-of-work blocks from after London that
+/// were stored without `baseFeePerGas` can not be told apart from blocks
+/// from before London, and will not match their hash
+pub fn verify_block_hash(entity: &Entity) -> Result<bool, Error> {
+    Ok(block_header_hash(entity)? == h256_attr(entity, "hash")?)
+}
+
+/// Clique keeps 32 bytes of vanity data at the start of `extraData` and
+/// appends the 65 byte signature of the sealer to it
+const CLIQUE_VANITY_LEN: usize = 32;
+const CLIQUE_SEAL_LEN: usize = 65;
+
+lazy_static! {
+    static ref SECP256K1: Secp256k1<VerifyOnly> = Secp256k1::verification_only();
+}
+
+/// Recover the account that sealed `block` on a chain that uses Clique.
+/// Such blocks report a zero author and end their `extraData` with the
+/// signature of the sealer over the hash of the header without that
+/// signature. Return `None` for blocks that do not look like Clique blocks
+pub fn clique_signer(block: &BlockWithOmmers) -> Result<Option<H160>, Error> {
+    let inner = block.inner();
+    let extra_data = &inner.extra_data.0;
+    if !inner.author.is_zero() || extra_data.len() < CLIQUE_VANITY_LEN + CLIQUE_SEAL_LEN {
+        return Ok(None);
+    }
+    let (unsealed, seal) = extra_data.split_at(extra_data.len() - CLIQUE_SEAL_LEN);
+
+    let mut entity = block.try_into_entity()?;
+    entity.set("miner", Value::Null);
+    entity.set("extraData", web3::types::Bytes(unsealed.to_vec()));
+    let seal_hash = block_header_hash(&entity)?;
+
+    let signature =
+        RecoverableSignature::from_compact(&seal[..64], RecoveryId::from_i32(seal[64] as i32)?)?;
+    let public_key = SECP256K1.recover(&Message::from_slice(seal_hash.as_bytes())?, &signature)?;
+    let hash = tiny_keccak::keccak256(&public_key.serialize_uncompressed()[1..]);
+    Ok(Some(H160::from_slice(&hash[12..])))
+}
+
+/// Reconstructs the block header fields that are stored for a block; the
+/// block's transactions, receipts and ommers are not part of the entity
+/// and are left empty
+impl TryFromEntity for BlockWithOmmers {
+    fn try_from_entity(entity: &Entity) -> Result<Self, Error> {
+        let mut block = LightEthereumBlock::default();
+        block.hash = Some(h256_attr(entity, "hash")?);
+        block.number = Some(u64_attr(entity, "number")?);
+        block.gas_limit = u256_attr(entity, "gasLimit")?;
+        block.gas_used = u256_attr(entity, "gasUsed")?;
+        block.timestamp = u256_attr(entity, "timestamp")?;
+        block.difficulty = u256_attr(entity, "difficulty")?;
+
+        Ok(BlockWithOmmers {
+            block: EthereumBlock {
+                block,
+                transaction_receipts: vec![],
+            },
+            ommers: vec![],
+            blob_gas: None,
+            fork_fields: ForkHeaderFields::default(),
+            finality: None,
+            signer: None,
+        })
+    }
+}
+
+impl TryFromEntity for Transaction {
+    fn try_from_entity(entity: &Entity) -> Result<Self, Error> {
+        let mut transaction = web3::types::Transaction::default();
+        transaction.hash = h256_attr(entity, "hash")?;
+        transaction.nonce = u256_attr(entity, "nonce")?;
+        transaction.block_hash = Some(match required(entity, "block")? {
+            Value::String(id) => H256::from_str(id)
+                .map_err(|e| format_err!("attribute `block` is not a block hash: {}", e))?,
+            value => {
+                return Err(format_err!(
+                    "attribute `block` should be a String but is {:?}",
+                    value
+                ))
+            }
+        });
+        transaction.from = h160_attr(entity, "from")?;
+        transaction.to = match entity.get("to") {
+            None | Some(Value::Null) => None,
+            Some(_) => Some(h160_attr(entity, "to")?),
+        };
+        transaction.value = u256_attr(entity, "value")?;
+        transaction.gas_price = u256_attr(entity, "gasPrice")?;
+        transaction.gas = u256_attr(entity, "gas")?;
+        transaction.input = web3::types::Bytes(bytes_attr(entity, "inputData")?.to_vec());
+
+        Ok(Transaction {
+            transaction,
+            input_hex: entity.contains_key("inputHex"),
+            receipt: None,
+            blob: None,
+            signature: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use graph::prelude::*;
+    use std::str::FromStr;
+    use web3::types::{Bytes, TransactionReceipt, H160, H256, H64, U256, U64};
+
+    use super::super::{
+        BlobGas, BlobTransactionFields, BlockFinality, BlockWithOmmers, ForkHeaderFields, Log,
+        Ommer, Transaction, TransactionSignature,
+    };
+    use super::{
+        block_header_hash, check_total_difficulty, clique_signer, transactions_to_entities,
+        verify_block_hash,
+    };
+
+    #[test]
+    fn transaction_input_hex() {
+        let mut tx = web3::types::Transaction::default();
+        tx.block_hash = Some(H256::from_low_u64_be(1));
+        tx.input = Bytes(vec![0xde, 0xad, 0xbe, 0xef]);
+
+        let entity = Transaction::from(tx.clone())
+            .with_input_hex()
+            .try_into_entity()
+            .unwrap();
+        let input_data = match entity.get("inputData") {
+            Some(Value::Bytes(bytes)) => bytes.to_string(),
+            other => panic!("unexpected inputData {:?}", other),
+        };
+        assert_eq!(Some(&Value::from(input_data)), entity.get("inputHex"));
+        assert_eq!(Some(&Value::from("0xdeadbeef")), entity.get("inputHex"));
+
+        // Without the option, there is no inputHex
+        let entity = Transaction::from(tx.clone()).try_into_entity().unwrap();
+        assert_eq!(None, entity.get("inputHex"));
+
+        // Empty input is encoded as "0x"
+        tx.input = Bytes(vec![]);
+        let entity = Transaction::from(tx)
+            .with_input_hex()
+            .try_into_entity()
+            .unwrap();
+        assert_eq!(Some(&Value::from("0x")), entity.get("inputHex"));
+    }
+
+    #[test]
+    fn transaction_transfers_value() {
+        let mut tx = web3::types::Transaction::default();
+        tx.block_hash = Some(H256::from_low_u64_be(1));
+
+        // A contract call that does not send any ether along
+        tx.input = Bytes(vec![0xde, 0xad, 0xbe, 0xef]);
+        let entity = Transaction::from(tx.clone()).try_into_entity().unwrap();
+        assert_eq!(Some(&Value::Bool(false)), entity.get("transfersValue"));
+
+        // A plain transfer of one wei
+        tx.input = Bytes(vec![]);
+        tx.value = U256::from(1);
+        let entity = Transaction::from(tx).try_into_entity().unwrap();
+        assert_eq!(Some(&Value::Bool(true)), entity.get("transfersValue"));
+    }
+
+    #[test]
+    fn pending_transaction_is_an_error() {
+        let tx = web3::types::Transaction::default();
+        assert!(tx.block_hash.is_none());
+
+        let err = Transaction::from(tx)
+            .try_into_entity()
+            .expect_err("pending transactions can not be converted");
+        assert!(err.to_string().contains("is pending"));
+
+        // Once the transaction is in a block, it refers to that block
+        let mut tx = web3::types::Transaction::default();
+        tx.block_hash = Some(H256::from_low_u64_be(1));
+        let entity = Transaction::from(tx).try_into_entity().unwrap();
+        assert_eq!(
+            Some(&Value::from(H256::from_low_u64_be(1).to_entity_id())),
+            entity.get("block")
+        );
+    }
+
+    #[test]
+    fn transaction_logs() {
+        let mut tx = web3::types::Transaction::default();
+        tx.hash = H256::from_low_u64_be(3);
+        tx.block_hash = Some(H256::from_low_u64_be(7));
+
+        // Without a receipt, there is no log count and there are no logs
+        let entity = Transaction::from(tx.clone()).try_into_entity().unwrap();
+        assert_eq!(Some(&Value::Null), entity.get("logCount"));
+        assert!(Transaction::from(tx.clone()).logs().is_empty());
+
+        let log = |data: &str| {
+            serde_json::json!({
+                "address": "0x0000000000000000000000000000000000000001",
+                "topics": [],
+                "data": data,
+                "blockHash": null,
+                "blockNumber": null,
+                "transactionHash": null,
+                "transactionIndex": null,
+                "logIndex": null,
+                "transactionLogIndex": null,
+                "logType": null,
+                "removed": null
+            })
+        };
+        let receipt: TransactionReceipt = serde_json::from_value(serde_json::json!({
+            "transactionHash": format!("{:?}", tx.hash),
+            "transactionIndex": "0x0",
+            "blockHash": format!("{:?}", tx.block_hash.unwrap()),
+            "blockNumber": "0x1",
+            "cumulativeGasUsed": "0x5208",
+            "gasUsed": "0x5208",
+            "contractAddress": null,
+            "logs": [log("0x01"), log("0x02")],
+            "status": "0x1",
+            "root": null,
+            "logsBloom": format!("0x{}", "0".repeat(512))
+        }))
+        .unwrap();
+
+        let tx = Transaction::from(tx).with_receipt(receipt);
+        let entity = tx.clone().try_into_entity().unwrap();
+        assert_eq!(Some(&Value::Int(2)), entity.get("logCount"));
+
+        let logs = tx
+            .logs()
+            .into_iter()
+            .map(|log| log.try_into_entity().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(2, logs.len());
+        for (index, log) in logs.iter().enumerate() {
+            assert_eq!(entity.get("id"), log.get("transaction"));
+            assert_eq!(Some(&Value::from(index as i32)), log.get("index"));
+        }
+        assert_ne!(logs[0].get("id"), logs[1].get("id"));
+        assert_eq!(Some(&Value::from(Bytes(vec![0x02]))), logs[1].get("data"));
+
+        // A log without topics comes from an anonymous event
+        assert_eq!(Some(&Value::Null), logs[0].get("eventSignature"));
+        assert_eq!(Some(&Value::List(vec![])), logs[0].get("indexedTopics"));
+    }
+
+    #[test]
+    fn log_topics() {
+        let topics = vec![
+            H256::from_low_u64_be(1),
+            H256::from_low_u64_be(2),
+            H256::from_low_u64_be(3),
+        ];
+        let mut log: web3::types::Log = serde_json::from_value(serde_json::json!({
+            "address": "0x0000000000000000000000000000000000000001",
+            "topics": [],
+            "data": "0xdeadbeef",
+            "blockHash": null,
+            "blockNumber": null,
+            "transactionHash": null,
+            "transactionIndex": null,
+            "logIndex": null,
+            "transactionLogIndex": null,
+            "logType": null,
+            "removed": null
+        }))
+        .unwrap();
+        log.topics = topics.clone();
+        let log = Log {
+            log,
+            transaction: H256::from_low_u64_be(7),
+            index: 0,
+        };
+
+        let entity = log.try_into_entity().unwrap();
+        assert_eq!(Some(&Value::from(topics[0])), entity.get("eventSignature"));
+        assert_eq!(
+            Some(&Value::from(vec![topics[1], topics[2]])),
+            entity.get("indexedTopics")
+        );
+        assert_eq!(
+            Some(&Value::from(Bytes(vec![0xde, 0xad, 0xbe, 0xef]))),
+            entity.get("data")
+        );
+    }
+
+    #[test]
+    fn block_round_trip() {
+        let mut block = BlockWithOmmers::default();
+        {
+            let inner = &mut block.block.block;
+            inner.hash = Some(H256::from_low_u64_be(7));
+            inner.parent_hash = H256::from_low_u64_be(6);
+            inner.number = Some(U64::from(42));
+            inner.gas_limit = U256::from(8_000_000);
+            inner.gas_used = U256::from(21_000);
+            inner.timestamp = U256::from(1_588_000_000);
+            inner.difficulty = U256::max_value();
+        }
+
+        let entity = (&block).try_into_entity().unwrap();
+        let other = BlockWithOmmers::try_from_entity(&entity).unwrap();
+        assert_eq!(block.inner().hash, other.inner().hash);
+        assert_eq!(block.inner().number, other.inner().number);
+        assert_eq!(block.inner().gas_limit, other.inner().gas_limit);
+        assert_eq!(block.inner().gas_used, other.inner().gas_used);
+        assert_eq!(block.inner().timestamp, other.inner().timestamp);
+        assert_eq!(block.inner().difficulty, other.inner().difficulty);
+
+        // Missing and mistyped attributes are errors
+        let mut broken = entity.clone();
+        broken.remove("gasUsed");
+        assert!(BlockWithOmmers::try_from_entity(&broken).is_err());
+        let mut broken = entity.clone();
+        broken.set("number", "42");
+        assert!(BlockWithOmmers::try_from_entity(&broken).is_err());
+    }
+
+    #[test]
+    fn genesis_block_has_no_parent() {
+        let mut block = BlockWithOmmers::default();
+        block.block.block.hash = Some(H256::from_low_u64_be(1));
+        block.block.block.number = Some(U64::zero());
+
+        let entity = (&block).try_into_entity().unwrap();
+        assert_eq!(Some(&Value::Null), entity.get("parent"));
+        assert_eq!(Some(&Value::from(U64::zero())), entity.get("number"));
+
+        // Other blocks refer to their parent
+        block.block.block.number = Some(U64::from(1));
+        block.block.block.parent_hash = H256::from_low_u64_be(2);
+        let entity = (&block).try_into_entity().unwrap();
+        assert_eq!(
+            Some(&Value::from(H256::from_low_u64_be(2).to_entity_id())),
+            entity.get("parent")
+        );
+    }
+
+    #[test]
+    fn total_difficulty() {
+        let mut block = BlockWithOmmers::default();
+        block.block.block.hash = Some(H256::from_low_u64_be(2));
+        block.block.block.number = Some(U64::from(2));
+        block.block.block.parent_hash = H256::from_low_u64_be(1);
+        block.block.block.difficulty = U256::from(10);
+        block.block.block.total_difficulty = U256::from(1010);
+
+        let parent = Entity::from(vec![("totalDifficulty", Value::from(U256::from(1000)))]);
+        assert!(check_total_difficulty(&block, &parent).is_ok());
+
+        // The total difficulty does not add up
+        block.block.block.total_difficulty = U256::from(1005);
+        match check_total_difficulty(&block, &parent) {
+            Err(StoreError::InconsistentBlock(msg)) => assert!(msg.contains("1005")),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // Without the parent's total difficulty, there is nothing to check
+        let parent = Entity::from(vec![("number", Value::from(U64::from(1)))]);
+        assert!(check_total_difficulty(&block, &parent).is_ok());
+    }
+
+    #[test]
+    fn ommer_inclusion_distance() {
+        let mut block = web3::types::Block::<H256>::default();
+        block.hash = Some(H256::from_low_u64_be(1));
+        block.number = Some(U64::from(8));
+        let ommer = Ommer::from(block);
+
+        let entity = ommer
+            .clone()
+            .included_in(U64::from(10))
+            .try_into_entity()
+            .unwrap();
+        assert_eq!(Some(&Value::Int(2)), entity.get("inclusionDistance"));
+        assert_eq!(Some(&Value::Bool(true)), entity.get("isOmmer"));
+
+        // Without the including block, the distance is unknown
+        let entity = ommer.clone().try_into_entity().unwrap();
+        assert_eq!(Some(&Value::Null), entity.get("inclusionDistance"));
+
+        // Ommers must be older than the including block, but by no more
+        // than 6 blocks
+        assert!(ommer
+            .clone()
+            .included_in(U64::from(8))
+            .try_into_entity()
+            .is_err());
+        assert!(ommer
+            .clone()
+            .included_in(U64::from(7))
+            .try_into_entity()
+            .is_err());
+        assert!(ommer
+            .clone()
+            .included_in(U64::from(14))
+            .try_into_entity()
+            .is_ok());
+        assert!(ommer.included_in(U64::from(15)).try_into_entity().is_err());
+    }
+
+    #[test]
+    fn block_nonce() {
+        let mut block = BlockWithOmmers::default();
+        block.block.block.hash = Some(H256::from_low_u64_be(1));
+        block.block.block.number = Some(U64::from(1));
+        block.block.block.nonce = Some(H64::from_low_u64_be(0x0102_0304_0506_0708));
+
+        let entity = (&block).try_into_entity().unwrap();
+        let nonce = match entity.get("nonce") {
+            Some(Value::Bytes(bytes)) => bytes.clone(),
+            other => panic!("unexpected nonce {:?}", other),
+        };
+        assert_eq!("0x0102030405060708", nonce.to_string());
+        assert_eq!(
+            Some(&Value::BigInt(BigInt::from(0x0102_0304_0506_0708u64))),
+            entity.get("nonceInt")
+        );
+
+        // Without a nonce, both forms are null
+        block.block.block.nonce = None;
+        let entity = (&block).try_into_entity().unwrap();
+        assert_eq!(Some(&Value::Null), entity.get("nonce"));
+        assert_eq!(Some(&Value::Null), entity.get("nonceInt"));
+    }
+
+    #[test]
+    fn schema_declares_converted_attributes() {
+        use graph::data::graphql::ext::DocumentExt;
+
+        let schema = Schema::parse(
+            include_str!("ethereum.graphql"),
+            SubgraphDeploymentId::new("network").unwrap(),
+        )
+        .unwrap();
+        let check = |entity_type: &str, entity: Entity| {
+            let object = schema
+                .document
+                .get_object_type_definition(entity_type)
+                .unwrap_or_else(|| panic!("the schema does not declare `{}`", entity_type));
+            for attr in entity.keys() {
+                assert!(
+                    object.fields.iter().any(|field| &field.name == attr),
+                    "the schema does not declare `{}.{}`",
+                    entity_type,
+                    attr
+                );
+            }
+        };
+
+        let mut block = BlockWithOmmers::default();
+        block.block.block.hash = Some(H256::from_low_u64_be(1));
+        block.block.block.number = Some(U64::from(1));
+        check("Block", (&block).try_into_entity().unwrap());
+
+        let mut tx = web3::types::Transaction::default();
+        tx.block_hash = Some(H256::from_low_u64_be(1));
+        let tx = Transaction::from(tx).with_input_hex();
+        check("Transaction", tx.try_into_entity().unwrap());
+
+        let log: web3::types::Log = serde_json::from_value(serde_json::json!({
+            "address": "0x0000000000000000000000000000000000000001",
+            "topics": [],
+            "data": "0x",
+            "blockHash": null,
+            "blockNumber": null,
+            "transactionHash": null,
+            "transactionIndex": null,
+            "logIndex": null,
+            "transactionLogIndex": null,
+            "logType": null,
+            "removed": null
+        }))
+        .unwrap();
+        let log = Log {
+            log,
+            transaction: H256::from_low_u64_be(7),
+            index: 0,
+        };
+        check("Log", log.try_into_entity().unwrap());
+    }
+
+    #[test]
+    fn transaction_chain_id() {
+        let mut tx = web3::types::Transaction::default();
+        tx.block_hash = Some(H256::from_low_u64_be(7));
+
+        // Without a signature, we do not know the chain id
+        let entity = Transaction::from(tx.clone()).try_into_entity().unwrap();
+        assert_eq!(Some(&Value::Null), entity.get("chainId"));
+
+        // A legacy EIP-155 transaction on mainnet
+        let entity = Transaction::from(tx.clone())
+            .with_signature(TransactionSignature::Legacy { v: U64::from(37) })
+            .try_into_entity()
+            .unwrap();
+        assert_eq!(Some(&Value::from(U256::from(1))), entity.get("chainId"));
+
+        // A legacy transaction from before EIP-155
+        let entity = Transaction::from(tx.clone())
+            .with_signature(TransactionSignature::Legacy { v: U64::from(27) })
+            .try_into_entity()
+            .unwrap();
+        assert_eq!(Some(&Value::Null), entity.get("chainId"));
+
+        // A typed transaction
+        let entity = Transaction::from(tx)
+            .with_signature(TransactionSignature::Typed {
+                chain_id: U256::from(10),
+            })
+            .try_into_entity()
+            .unwrap();
+        assert_eq!(Some(&Value::from(U256::from(10))), entity.get("chainId"));
+    }
+
+    #[test]
+    fn blob_transaction() {
+        let mut tx = web3::types::Transaction::default();
+        tx.block_hash = Some(H256::from_low_u64_be(7));
+
+        // A legacy transaction has no blob fields
+        let entity = Transaction::from(tx.clone()).try_into_entity().unwrap();
+        assert_eq!(Some(&Value::Null), entity.get("maxFeePerBlobGas"));
+        assert_eq!(
+            Some(&Value::List(vec![])),
+            entity.get("blobVersionedHashes")
+        );
+
+        let hashes = vec![H256::from_low_u64_be(1), H256::from_low_u64_be(2)];
+        let entity = Transaction::from(tx)
+            .with_blob(BlobTransactionFields {
+                max_fee_per_blob_gas: U256::from(1_000_000_000),
+                blob_versioned_hashes: hashes.clone(),
+            })
+            .try_into_entity()
+            .unwrap();
+        assert_eq!(
+            Some(&Value::from(U256::from(1_000_000_000))),
+            entity.get("maxFeePerBlobGas")
+        );
+        assert_eq!(
+            Some(&Value::List(hashes.into_iter().map(Value::from).collect())),
+            entity.get("blobVersionedHashes")
+        );
+    }
+
+    #[test]
+    fn block_blob_gas() {
+        let mut block = BlockWithOmmers::default();
+        block.block.block.hash = Some(H256::from_low_u64_be(1));
+        block.block.block.number = Some(U64::from(1));
+
+        let entity = (&block).try_into_entity().unwrap();
+        assert_eq!(Some(&Value::Null), entity.get("blobGasUsed"));
+        assert_eq!(Some(&Value::Null), entity.get("excessBlobGas"));
+
+        let block = block.with_blob_gas(BlobGas {
+            blob_gas_used: U256::from(131_072),
+            excess_blob_gas: U256::from(0),
+        });
+        let entity = (&block).try_into_entity().unwrap();
+        assert_eq!(
+            Some(&Value::from(U256::from(131_072))),
+            entity.get("blobGasUsed")
+        );
+        assert_eq!(
+            Some(&Value::from(U256::from(0))),
+            entity.get("excessBlobGas")
+        );
+    }
+
+    #[test]
+    fn block_fork_fields() {
+        let mut block = BlockWithOmmers::default();
+        block.block.block.hash = Some(H256::from_low_u64_be(1));
+        block.block.block.number = Some(U64::from(1));
+
+        let entity = (&block).try_into_entity().unwrap();
+        assert_eq!(Some(&Value::Null), entity.get("baseFeePerGas"));
+        assert_eq!(Some(&Value::Null), entity.get("withdrawalsRoot"));
+        assert_eq!(Some(&Value::Null), entity.get("parentBeaconBlockRoot"));
+        assert_eq!(Some(&Value::Null), entity.get("requestsHash"));
+
+        let block = block.with_fork_fields(ForkHeaderFields {
+            base_fee_per_gas: Some(U256::from(7)),
+            withdrawals_root: Some(H256::from_low_u64_be(2)),
+            parent_beacon_block_root: Some(H256::from_low_u64_be(3)),
+            requests_hash: Some(H256::from_low_u64_be(4)),
+        });
+        let entity = (&block).try_into_entity().unwrap();
+        assert_eq!(
+            Some(&Value::from(U256::from(7))),
+            entity.get("baseFeePerGas")
+        );
+        assert_eq!(
+            Some(&Value::from(H256::from_low_u64_be(2))),
+            entity.get("withdrawalsRoot")
+        );
+        assert_eq!(
+            Some(&Value::from(H256::from_low_u64_be(3))),
+            entity.get("parentBeaconBlockRoot")
+        );
+        assert_eq!(
+            Some(&Value::from(H256::from_low_u64_be(4))),
+            entity.get("requestsHash")
+        );
+    }
+
+    #[test]
+    fn block_hash_verification() {
+        fn h256(hex: &str) -> H256 {
+            H256::from_str(hex).unwrap()
+        }
+
+        // The mainnet genesis block
+        let mut block = BlockWithOmmers::default();
+        {
+            let inner = &mut block.block.block;
+            inner.hash = Some(h256(
+                "d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3",
+            ));
+            inner.number = Some(U64::from(0));
+            inner.uncles_hash =
+                h256("1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347");
+            inner.state_root =
+                h256("d7f8974fb5ac78d9ac099b9ad5018bedc2ce0a72dad1827a1709da30580f0544");
+            inner.transactions_root =
+                h256("56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421");
+            inner.receipts_root =
+                h256("56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421");
+            inner.difficulty = U256::from(17_179_869_184u64);
+            inner.gas_limit = U256::from(5000);
+            inner.extra_data = Bytes(
+                h256("11bbe8db4e347b4e8c937c1c8370e4b5ed33adb3db69cbdb7a38e1e50b1b82fa")
+                    .as_bytes()
+                    .to_vec(),
+            );
+            inner.mix_hash = Some(H256::zero());
+            inner.nonce = Some(H64::from_low_u64_be(0x42));
+        }
+
+        let mut entity = (&block).try_into_entity().unwrap();
+        assert!(verify_block_hash(&entity).unwrap());
+
+        // Any change to the header changes the hash
+        entity.set("gasUsed", U256::from(1));
+        assert!(!verify_block_hash(&entity).unwrap());
+        entity.set("gasUsed", U256::from(0));
+
+        // Setting a field from a later fork uses the post-London header
+        // layout, which hashes differently
+        entity.set("baseFeePerGas", U256::from(1_000_000_000));
+        assert!(!verify_block_hash(&entity).unwrap());
+        entity.set("baseFeePerGas", Value::Null);
+        assert!(verify_block_hash(&entity).unwrap());
+
+        // Fork fields can not be skipped
+        entity.set("withdrawalsRoot", H256::zero());
+        assert!(verify_block_hash(&entity).is_err());
+        entity.set("withdrawalsRoot", Value::Null);
+
+        // A block from after the merge that was stored without the fields
+        // of its fork has an unknown layout rather than a wrong hash
+        entity.set("difficulty", U256::from(0));
+        assert!(verify_block_hash(&entity).is_err());
+        entity.set("baseFeePerGas", U256::from(1_000_000_000));
+        assert!(!verify_block_hash(&entity).unwrap());
+        entity.set("difficulty", U256::from(17_179_869_184u64));
+        entity.set("baseFeePerGas", Value::Null);
+
+        // Without the header fields, the hash can not be computed
+        entity.remove("stateRoot");
+        assert!(verify_block_hash(&entity).is_err());
+    }
+
+    #[test]
+    fn block_finality() {
+        let mut block = BlockWithOmmers::default();
+        block.block.block.hash = Some(H256::from_low_u64_be(1));
+        block.block.block.number = Some(U64::from(1));
+
+        let entity = (&block).try_into_entity().unwrap();
+        assert_eq!(Some(&Value::from("Unknown")), entity.get("finality"));
+
+        let block = block.with_finality(BlockFinality::Finalized);
+        let entity = (&block).try_into_entity().unwrap();
+        assert_eq!(Some(&Value::from("Finalized")), entity.get("finality"));
+    }
+
+    #[test]
+    fn block_miner() {
+        let mut block = BlockWithOmmers::default();
+        block.block.block.hash = Some(H256::from_low_u64_be(1));
+        block.block.block.number = Some(U64::from(1));
+
+        // A Clique block reports a zero author
+        let entity = (&block).try_into_entity().unwrap();
+        assert_eq!(Some(&Value::Null), entity.get("miner"));
+
+        let signer = H160::from_low_u64_be(7);
+        let entity = (&block.clone().with_signer(signer))
+            .try_into_entity()
+            .unwrap();
+        assert_eq!(Some(&Value::from(signer)), entity.get("miner"));
+
+        let author = H160::from_low_u64_be(42);
+        block.block.block.author = author;
+        let entity = (&block).try_into_entity().unwrap();
+        assert_eq!(Some(&Value::from(author)), entity.get("miner"));
+    }
+
+    #[test]
+    fn block_header_fields_from_json() {
+        let header = serde_json::json!({
+            "hash": format!("{:#x}", H256::from_low_u64_be(1)),
+            "baseFeePerGas": "0x7",
+            "withdrawalsRoot": format!("{:#x}", H256::from_low_u64_be(2)),
+            "blobGasUsed": "0x20000",
+            "excessBlobGas": "0x0",
+            "parentBeaconBlockRoot": format!("{:#x}", H256::from_low_u64_be(3)),
+            "requestsHash": format!("{:#x}", H256::from_low_u64_be(4)),
+        });
+        assert_eq!(
+            ForkHeaderFields {
+                base_fee_per_gas: Some(U256::from(7)),
+                withdrawals_root: Some(H256::from_low_u64_be(2)),
+                parent_beacon_block_root: Some(H256::from_low_u64_be(3)),
+                requests_hash: Some(H256::from_low_u64_be(4)),
+            },
+            ForkHeaderFields::from_json(&header).unwrap()
+        );
+        assert_eq!(
+            Some(BlobGas {
+                blob_gas_used: U256::from(131_072),
+                excess_blob_gas: U256::from(0),
+            }),
+            BlobGas::from_json(&header).unwrap()
+        );
+
+        // Blocks from before London have none of the fields
+        let header = serde_json::json!({ "hash": format!("{:#x}", H256::from_low_u64_be(1)) });
+        assert_eq!(
+            ForkHeaderFields::default(),
+            ForkHeaderFields::from_json(&header).unwrap()
+        );
+        assert_eq!(None, BlobGas::from_json(&header).unwrap());
+
+        let header = serde_json::json!({ "blobGasUsed": "0x0" });
+        assert!(BlobGas::from_json(&header).is_err());
+        let header = serde_json::json!({ "baseFeePerGas": "seven" });
+        assert!(ForkHeaderFields::from_json(&header).is_err());
+    }
+
+    #[test]
+    fn block_clique_signer() {
+        use secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+        let mut block = BlockWithOmmers::default();
+        {
+            let inner = &mut block.block.block;
+            inner.hash = Some(H256::from_low_u64_be(1));
+            inner.number = Some(U64::from(1));
+            inner.difficulty = U256::from(2);
+            inner.mix_hash = Some(H256::zero());
+            inner.nonce = Some(H64::zero());
+            inner.extra_data = Bytes(vec![0x11; 32]);
+        }
+
+        // Without a seal, this is not a Clique block
+        assert_eq!(None, clique_signer(&block).unwrap());
+
+        // Seal the block the way a Clique sealer does
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[0x42; 32]).unwrap();
+        let public_key = PublicKey::from_secret_key(&secp, &secret_key);
+        let sealer = H160::from_slice(
+            &tiny_keccak::keccak256(&public_key.serialize_uncompressed()[1..])[12..],
+        );
+        let seal_hash = block_header_hash(&(&block).try_into_entity().unwrap()).unwrap();
+        let (recovery_id, signature) = secp
+            .sign_recoverable(
+                &secp256k1::Message::from_slice(seal_hash.as_bytes()).unwrap(),
+                &secret_key,
+            )
+            .serialize_compact();
+        block.block.block.extra_data.0.extend_from_slice(&signature);
+        block
+            .block
+            .block
+            .extra_data
+            .0
+            .push(recovery_id.to_i32() as u8);
+
+        assert_eq!(Some(sealer), clique_signer(&block).unwrap());
+
+        // Blocks with an author are not sealed by Clique
+        block.block.block.author = H160::from_low_u64_be(42);
+        assert_eq!(None, clique_signer(&block).unwrap());
+    }
+
+    #[test]
+    fn block_size_kb() {
+        let mut block = BlockWithOmmers::default();
+        block.block.block.hash = Some(H256::from_low_u64_be(1));
+        block.block.block.number = Some(U64::from(1));
+
+        let entity = (&block).try_into_entity().unwrap();
+        assert_eq!(Some(&Value::Null), entity.get("sizeKb"));
+
+        // `sizeKb` is `size / 1024` with three digits after the decimal
+        // point: 1536 bytes are exactly 1.5 KB, and 1000 bytes are
+        // 0.9765625 KB, which is rounded to 0.977
+        for (size, kb) in &[(1536, "1.500"), (1000, "0.977")] {
+            block.block.block.size = Some(U256::from(*size));
+            let entity = (&block).try_into_entity().unwrap();
+            assert_eq!(
+                Some(&Value::BigDecimal(BigDecimal::from_str(kb).unwrap())),
+                entity.get("sizeKb")
+            );
+        }
+    }
+
+    #[test]
+    fn transaction_round_trip() {
+        let mut tx = web3::types::Transaction::default();
+        tx.hash = H256::from_low_u64_be(3);
+        tx.nonce = U256::from(5);
+        tx.block_hash = Some(H256::from_low_u64_be(7));
+        tx.from = H160::from_low_u64_be(1);
+        tx.to = Some(H160::from_low_u64_be(2));
+        tx.value = U256::from(1000);
+        tx.gas_price = U256::from(20);
+        tx.gas = U256::from(21_000);
+        tx.input = Bytes(vec![0xde, 0xad]);
+
+        let tx = Transaction::from(tx).with_input_hex();
+        let entity = tx.clone().try_into_entity().unwrap();
+        let other = Transaction::try_from_entity(&entity).unwrap();
+        assert_eq!(tx, other);
+    }
+
+    #[test]
+    fn block_missing_required_fields() {
+        let mut block = BlockWithOmmers::default();
+        block.block.block.hash = Some(H256::from_low_u64_be(1));
+
+        let err = (&block)
+            .try_into_entity()
+            .expect_err("blocks without a number can not be converted");
+        let msg = err.to_string();
+        assert!(msg.contains("`number`"));
+        assert!(!msg.contains("`hash`"));
+
+        // All missing fields are reported at once
+        block.block.block.hash = None;
+        let msg = (&block).try_into_entity().unwrap_err().to_string();
+        assert!(msg.contains("`hash`, `number`"));
+
+        // Blocks without a hash have no key
+        let subgraph_id = SubgraphDeploymentId::new("network").unwrap();
+        let msg = (&block)
+            .try_to_entity_key(subgraph_id.clone())
+            .unwrap_err()
+            .to_string();
+        assert!(msg.contains("`hash`"));
+
+        // Ommers are checked the same way
+        let ommer = Ommer::from(web3::types::Block::<H256>::default());
+        assert!(ommer.try_to_entity_key(subgraph_id).is_err());
+        assert!(ommer.clone().try_into_entity().is_err());
+        assert!(ommer.included_in(U64::from(10)).try_into_entity().is_err());
+    }
+
+    #[test]
+    fn parallel_transactions_to_entities() {
+        let transactions: Vec<_> = (0..2500)
+            .map(|i| {
+                let mut tx = web3::types::Transaction::default();
+                tx.hash = H256::from_low_u64_be(i);
+                tx.block_hash = Some(H256::from_low_u64_be(1));
+                tx.transaction_index = Some(U64::from(i));
+                Transaction::from(tx)
+            })
+            .collect();
+
+        let sequential = transactions
+            .iter()
+            .cloned()
+            .map(|tx| tx.try_into_entity().unwrap())
+            .collect::<Vec<_>>();
+        for workers in &[1, 3, 8] {
+            let parallel = transactions_to_entities(transactions.clone(), *workers).unwrap();
+            assert_eq!(sequential, parallel);
+        }
+
+        // The first pending transaction is the one whose error we see
+        let mut broken = transactions;
+        for i in &[1800, 700] {
+            let mut tx = broken[*i].transaction.clone();
+            tx.block_hash = None;
+            broken[*i] = Transaction::from(tx);
+        }
+        let err = transactions_to_entities(broken, 4).expect_err("pending transactions fail");
+        assert!(err
+            .to_string()
+            .contains(&format!("{:x}", H256::from_low_u64_be(700))));
+    }
+}
@@ -1,7 +1,7 @@
 use graph::prelude::*;
 use std::fmt;
 use std::ops::Deref;
-use web3::types::{Block, H256};
+use web3::types::{Block, TransactionReceipt, H160, H256, U256, U64};
 
 mod block_writer;
 mod convert;
@@ -16,7 +16,58 @@ pub use self::subgraph::*;
 
 pub use self::network_indexer::NetworkIndexerEvent;
 
-const NETWORK_INDEXER_VERSION: u32 = 0;
+/// Part of the id of the network subgraph; it needs to change whenever
+/// the schema in `ethereum.graphql` changes between releases. Schema
+/// changes that are released together share one bump
+const NETWORK_INDEXER_VERSION: u32 = 1;
+
+/// The fields that the London, Shanghai, Cancun and Prague forks add to
+/// block headers, apart from the blob gas fields in `BlobGas`. Like those,
+/// they are not part of the blocks we get from `web3` and need to be
+/// supplied separately. Each of them is `None` for blocks from before the
+/// fork that introduced it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ForkHeaderFields {
+    /// EIP-1559, London
+    pub base_fee_per_gas: Option<U256>,
+    /// EIP-4895, Shanghai
+    pub withdrawals_root: Option<H256>,
+    /// EIP-4788, Cancun
+    pub parent_beacon_block_root: Option<H256>,
+    /// EIP-7685, Prague
+    pub requests_hash: Option<H256>,
+}
+
+impl ForkHeaderFields {
+    /// Read the fork fields from `header`, the JSON object that the node
+    /// returned for the block
+    pub fn from_json(header: &serde_json::Value) -> Result<Self, Error> {
+        Ok(ForkHeaderFields {
+            base_fee_per_gas: json_u256(header, "baseFeePerGas")?,
+            withdrawals_root: json_h256(header, "withdrawalsRoot")?,
+            parent_beacon_block_root: json_h256(header, "parentBeaconBlockRoot")?,
+            requests_hash: json_h256(header, "requestsHash")?,
+        })
+    }
+}
+
+fn json_u256(header: &serde_json::Value, field: &str) -> Result<Option<U256>, Error> {
+    match header.get(field) {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(value) => serde_json::from_value(value.clone())
+            .map(Some)
+            .map_err(|e| format_err!("block header field `{}` is not a number: {}", field, e)),
+    }
+}
+
+fn json_h256(header: &serde_json::Value, field: &str) -> Result<Option<H256>, Error> {
+    match header.get(field) {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(value) => serde_json::from_value(value.clone())
+            .map(Some)
+            .map_err(|e| format_err!("block header field `{}` is not a hash: {}", field, e)),
+    }
+}
 
 /// Helper type to represent ommer blocks.
 #[derive(Clone, Debug, Default, PartialEq)]
@@ -64,17 +115,243 @@ impl Deref for Ommer {
     }
 }
 
+impl Ommer {
+    /// Pairs the ommer with the number of the block that includes it.
+    pub fn included_in(self, including_block_number: U64) -> IncludedOmmer {
+        IncludedOmmer {
+            ommer: self,
+            including_block_number,
+        }
+    }
+}
+
+/// An ommer together with the number of the block that includes it, which
+/// is needed to know how many blocks back the ommer was mined.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IncludedOmmer {
+    pub ommer: Ommer,
+    pub including_block_number: U64,
+}
+
+/// The fields that EIP-4844 adds to blob transactions (transactions of
+/// type 3). The transactions we get from `web3` do not have them, and
+/// they need to be supplied separately
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BlobTransactionFields {
+    pub max_fee_per_blob_gas: U256,
+    pub blob_versioned_hashes: Vec<H256>,
+}
+
+/// The fields that EIP-4844 adds to block headers; like
+/// `BlobTransactionFields`, they need to be supplied separately
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BlobGas {
+    pub blob_gas_used: U256,
+    pub excess_blob_gas: U256,
+}
+
+impl BlobGas {
+    /// Read the blob gas fields from `header`, the JSON object that the
+    /// node returned for the block. Blocks from before EIP-4844 have none
+    pub fn from_json(header: &serde_json::Value) -> Result<Option<Self>, Error> {
+        let blob_gas_used = json_u256(header, "blobGasUsed")?;
+        let excess_blob_gas = json_u256(header, "excessBlobGas")?;
+        match (blob_gas_used, excess_blob_gas) {
+            (Some(blob_gas_used), Some(excess_blob_gas)) => Ok(Some(BlobGas {
+                blob_gas_used,
+                excess_blob_gas,
+            })),
+            (None, None) => Ok(None),
+            _ => Err(format_err!(
+                "block header has only one of `blobGasUsed` and `excessBlobGas`"
+            )),
+        }
+    }
+}
+
+/// The part of a transaction's signature that determines its chain id.
+/// Legacy transactions encode the chain id in `v` (EIP-155), typed
+/// transactions carry it explicitly. The transactions we get from `web3`
+/// have neither, and they need to be supplied separately
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransactionSignature {
+    Legacy { v: U64 },
+    Typed { chain_id: U256 },
+}
+
+impl TransactionSignature {
+    /// The chain id the transaction was signed for, or `None` for legacy
+    /// transactions from before EIP-155, whose `v` is 27 or 28
+    pub fn chain_id(&self) -> Option<U256> {
+        match self {
+            TransactionSignature::Legacy { v } if v.as_u64() >= 35 => {
+                Some(U256::from((v.as_u64() - 35) / 2))
+            }
+            TransactionSignature::Legacy { .. } => None,
+            TransactionSignature::Typed { chain_id } => Some(*chain_id),
+        }
+    }
+}
+
+/// Helper type to convert transactions into entities.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Transaction {
+    pub transaction: web3::types::Transaction,
+    /// Whether to also store the input data as a `0x`-prefixed hex string
+    /// in the `inputHex` attribute
+    pub input_hex: bool,
+    /// The receipt of the transaction, if we have it. The `logCount` of
+    /// the transaction and its logs come from the receipt
+    pub receipt: Option<TransactionReceipt>,
+    /// The blob fields of the transaction if it is a blob transaction
+    pub blob: Option<BlobTransactionFields>,
+    /// The signature fields that determine the chain id, if we have them
+    pub signature: Option<TransactionSignature>,
+}
+
+impl Transaction {
+    pub fn with_input_hex(mut self) -> Self {
+        self.input_hex = true;
+        self
+    }
+
+    pub fn with_receipt(mut self, receipt: TransactionReceipt) -> Self {
+        self.receipt = Some(receipt);
+        self
+    }
+
+    pub fn with_blob(mut self, blob: BlobTransactionFields) -> Self {
+        self.blob = Some(blob);
+        self
+    }
+
+    pub fn with_signature(mut self, signature: TransactionSignature) -> Self {
+        self.signature = Some(signature);
+        self
+    }
+
+    /// The logs that the transaction emitted, in the order in which they
+    /// appear in its receipt. Without a receipt, there are no logs
+    pub fn logs(&self) -> Vec<Log> {
+        self.receipt
+            .iter()
+            .flat_map(|receipt| receipt.logs.iter())
+            .enumerate()
+            .map(|(index, log)| Log {
+                log: log.clone(),
+                transaction: self.transaction.hash,
+                index,
+            })
+            .collect()
+    }
+}
+
+impl From<web3::types::Transaction> for Transaction {
+    fn from(transaction: web3::types::Transaction) -> Self {
+        Self {
+            transaction,
+            input_hex: false,
+            receipt: None,
+            blob: None,
+            signature: None,
+        }
+    }
+}
+
+impl Deref for Transaction {
+    type Target = web3::types::Transaction;
+
+    fn deref(&self) -> &Self::Target {
+        &self.transaction
+    }
+}
+
+/// Helper type to convert logs into entities. Logs refer to the
+/// transaction that emitted them, and `index` is the position of the log
+/// in the receipt of that transaction
+#[derive(Clone, Debug, PartialEq)]
+pub struct Log {
+    pub log: web3::types::Log,
+    pub transaction: H256,
+    pub index: usize,
+}
+
+impl Deref for Log {
+    type Target = web3::types::Log;
+
+    fn deref(&self) -> &Self::Target {
+        &self.log
+    }
+}
+
+/// How final a block was when it was indexed, as far as the node that we
+/// got it from could tell. Mirrors the `BlockFinality` enum in
+/// `ethereum.graphql`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockFinality {
+    Unknown,
+    Safe,
+    Finalized,
+}
+
+impl Default for BlockFinality {
+    fn default() -> Self {
+        BlockFinality::Unknown
+    }
+}
+
+impl fmt::Display for BlockFinality {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockFinality::Unknown => write!(f, "Unknown"),
+            BlockFinality::Safe => write!(f, "Safe"),
+            BlockFinality::Finalized => write!(f, "Finalized"),
+        }
+    }
+}
+
 /// Helper type to bundle blocks and their ommers together.
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct BlockWithOmmers {
     pub block: EthereumBlock,
     pub ommers: Vec<Ommer>,
+    /// The blob gas fields of the block header, for blocks after EIP-4844
+    pub blob_gas: Option<BlobGas>,
+    /// The other fields that forks added to the block header
+    pub fork_fields: ForkHeaderFields,
+    /// The finality of the block when we fetched it, if the node told us;
+    /// without that hint, the block is stored as `Unknown`
+    pub finality: Option<BlockFinality>,
+    /// The account that sealed the block on chains that use Clique. Their
+    /// blocks report a zero author, and the signer has to be recovered
+    /// from the signature in `extraData` with `clique_signer`
+    pub signer: Option<H160>,
 }
 
 impl BlockWithOmmers {
     pub fn inner(&self) -> &LightEthereumBlock {
         &self.block.block
     }
+
+    pub fn with_blob_gas(mut self, blob_gas: BlobGas) -> Self {
+        self.blob_gas = Some(blob_gas);
+        self
+    }
+
+    pub fn with_fork_fields(mut self, fork_fields: ForkHeaderFields) -> Self {
+        self.fork_fields = fork_fields;
+        self
+    }
+
+    pub fn with_finality(mut self, finality: BlockFinality) -> Self {
+        self.finality = Some(finality);
+        self
+    }
+
+    pub fn with_signer(mut self, signer: H160) -> Self {
+        self.signer = Some(signer);
+        self
+    }
 }
 
 impl fmt::Display for BlockWithOmmers {
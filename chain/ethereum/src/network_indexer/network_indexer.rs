@@ -15,6 +15,18 @@ use super::metrics::NetworkIndexerMetrics;
 use super::subgraph;
 use super::*;
 
+lazy_static! {
+    /// Skip checking that the total difficulty of each block is the total
+    /// difficulty of its parent plus its own difficulty. Needed for chains
+    /// that do not report a meaningful total difficulty, e.g. after the merge.
+    /// The check is only skipped if the variable is set to `true`.
+    static ref SKIP_TOTAL_DIFFICULTY_CHECK: bool =
+        std::env::var("GRAPH_NETWORK_INDEXER_SKIP_TOTAL_DIFFICULTY_CHECK")
+            .ok()
+            .map(|s| s.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+}
+
 /// Terminology used in this component:
 ///
 /// Head / head block:
@@ -50,6 +62,9 @@ type EnsureSubgraphFuture = Box<dyn Future<Item = (), Error = Error> + Send>;
 type LocalHeadFuture = Box<dyn Future<Item = Option<EthereumBlockPointer>, Error = Error> + Send>;
 type ChainHeadFuture = Box<dyn Future<Item = LightEthereumBlock, Error = Error> + Send>;
 type OmmersFuture = Box<dyn Future<Item = Vec<Ommer>, Error = Error> + Send>;
+type HeaderFieldsFuture =
+    Box<dyn Future<Item = (ForkHeaderFields, Option<BlobGas>), Error = Error> + Send>;
+type FinalityFuture = Box<dyn Future<Item = Option<BlockFinality>, Error = Error> + Send>;
 type BlockPointerFuture = Box<dyn Future<Item = EthereumBlockPointer, Error = Error> + Send>;
 type BlockFuture = Box<dyn Future<Item = Option<BlockWithOmmers>, Error = Error> + Send>;
 type BlockStream = Box<dyn Stream<Item = BlockWithOmmers, Error = Error> + Send>;
@@ -166,6 +181,62 @@ fn fetch_ommers(
     ))
 }
 
+/// Fetch the header fields of `block` that `LightEthereumBlock` does not
+/// have, i.e., the ones that forks added after London
+fn fetch_header_fields(
+    logger: Logger,
+    adapter: Arc<dyn EthereumAdapter>,
+    block: &EthereumBlock,
+) -> HeaderFieldsFuture {
+    let block_ptr: EthereumBlockPointer = block.into();
+
+    Box::new(adapter.raw_block_by_hash(&logger, block_ptr.hash).and_then(
+        move |header| -> Result<_, Error> {
+            let header =
+                header.ok_or_else(|| format_err!("Header of block {} missing", block_ptr))?;
+            Ok((
+                ForkHeaderFields::from_json(&header)?,
+                BlobGas::from_json(&header)?,
+            ))
+        },
+    ))
+}
+
+/// Find out how final the block with `block_number` is by comparing it to
+/// the `finalized` and `safe` blocks of the node. Nodes that don't support
+/// these tags leave the finality unknown
+fn fetch_finality(
+    logger: Logger,
+    adapter: Arc<dyn EthereumAdapter>,
+    block_number: u64,
+) -> FinalityFuture {
+    let is_at_or_before = move |tagged: Option<u64>| tagged.map_or(false, |n| block_number <= n);
+
+    Box::new(
+        adapter
+            .block_number_by_tag(&logger, "finalized")
+            .join(adapter.block_number_by_tag(&logger, "safe"))
+            .map(move |(finalized, safe)| {
+                Some(if is_at_or_before(finalized) {
+                    BlockFinality::Finalized
+                } else if is_at_or_before(safe) {
+                    BlockFinality::Safe
+                } else {
+                    BlockFinality::Unknown
+                })
+            })
+            .or_else(move |e| -> Result<_, Error> {
+                trace!(
+                    logger,
+                    "Failed to fetch the finality of block";
+                    "error" => format!("{}", e),
+                    "block" => format!("#{}", block_number),
+                );
+                Ok(None)
+            }),
+    )
+}
+
 fn fetch_block_and_ommers_by_number(
     logger: Logger,
     adapter: Arc<dyn EthereumAdapter>,
@@ -218,19 +289,41 @@ fn fetch_block_and_ommers_by_number(
                 .and_then(move |block| {
                     fetch_ommers(
                         logger_for_ommers.clone(),
-                        adapter_for_ommers,
+                        adapter_for_ommers.clone(),
                         metrics_for_ommers,
                         &block,
                     )
+                    .join3(
+                        fetch_header_fields(
+                            logger_for_ommers.clone(),
+                            adapter_for_ommers.clone(),
+                            &block,
+                        ),
+                        fetch_finality(logger_for_ommers.clone(), adapter_for_ommers, block_number),
+                    )
+                    .and_then(
+                        move |(ommers, (fork_fields, blob_gas), finality)| -> Result<_, Error> {
+                            let mut block = BlockWithOmmers {
+                                block,
+                                ommers,
+                                blob_gas,
+                                fork_fields,
+                                finality,
+                                signer: None,
+                            };
+                            block.signer = clique_signer(&block)?;
+                            Ok(block)
+                        },
+                    )
                     .then(move |result| {
                         future::ok(match result {
-                            Ok(ommers) => Some(BlockWithOmmers { block, ommers }),
+                            Ok(block) => Some(block),
                             Err(e) => {
                                 debug!(
                                     logger_for_ommers,
-                                    "Failed to fetch ommers for block";
+                                    "Failed to fetch ommers and header fields for block";
                                     "error" => format!("{}", e),
-                                    "block" => format!("{}", EthereumBlockPointer::from(block)),
+                                    "block" => format!("#{}", block_number),
                                 );
 
                                 None
@@ -1150,13 +1243,17 @@ impl NetworkIndexer {
             metrics_registry.clone(),
         ));
 
-        let block_writer = Arc::new(BlockWriter::new(
+        let mut block_writer = BlockWriter::new(
             subgraph_id.clone(),
             &logger,
             store.clone(),
             stopwatch,
             metrics_registry.clone(),
-        ));
+        );
+        if *SKIP_TOTAL_DIFFICULTY_CHECK {
+            block_writer = block_writer.skip_total_difficulty_check();
+        }
+        let block_writer = Arc::new(block_writer);
 
         // Create a channel for emitting events
         let (event_sink, output) = channel(100);
@@ -662,6 +662,24 @@ pub trait EthereumAdapter: Send + Sync + 'static {
         block_number: u64,
     ) -> Box<dyn Future<Item = Option<LightEthereumBlock>, Error = Error> + Send>;
 
+    /// Find a block by its hash and return the JSON object that the node
+    /// sent for it, without its transactions. Unlike `LightEthereumBlock`,
+    /// it has the header fields that forks added after London.
+    fn raw_block_by_hash(
+        &self,
+        logger: &Logger,
+        block_hash: H256,
+    ) -> Box<dyn Future<Item = Option<serde_json::Value>, Error = Error> + Send>;
+
+    /// Find the number of the latest block that the node reports for a
+    /// block tag like `safe` or `finalized`. Nodes that do not know the
+    /// tag return an error.
+    fn block_number_by_tag(
+        &self,
+        logger: &Logger,
+        tag: &'static str,
+    ) -> Box<dyn Future<Item = Option<u64>, Error = Error> + Send>;
+
     /// Load full information for the specified `block` (in particular, transaction receipts).
     fn load_full_block(
         &self,
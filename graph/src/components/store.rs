@@ -77,6 +77,59 @@ pub enum EntityFilter {
     NotStartsWith(Attribute, Value),
     EndsWith(Attribute, Value),
     NotEndsWith(Attribute, Value),
+    /// Matches if any of the attributes contains the value; this is a
+    /// shorthand for an `Or` of `Contains` filters
+    AnyFieldContains(Vec<Attribute>, Value),
+    /// Matches if the entity has no child reachable through the attribute
+    /// that matches the filter. The attribute can either be a reference to
+    /// other entities or a field with `@derivedFrom`
+    ChildNotExists(Attribute, Box<EntityFilter>),
+    /// Matches if the reference stored in the attribute points to an
+    /// entity that exists. For list-valued references, all entries in the
+    /// list must exist. Entities where the attribute is not set never match
+    ReferenceExists(Attribute),
+    /// Matches if the numeric attribute is between `center - tolerance`
+    /// and `center + tolerance`, inclusive
+    Near(Attribute, Value, Value),
+    /// Matches if the BigDecimal attribute has no fractional part
+    IsWhole(Attribute),
+    /// Matches if the list attribute is set, but has no elements. Unlike
+    /// `Equal(attribute, Value::Null)`, this does not match if the
+    /// attribute is null
+    ListEmpty(Attribute),
+    /// Matches if the list attribute has at least one element
+    ListNotEmpty(Attribute),
+    /// Like `In`, but for String and enum attributes, and ignoring case
+    InCaseInsensitive(Attribute, Vec<Value>),
+    /// Matches if the String attribute contains the value as a whole word,
+    /// ignoring case. Unlike `Contains`, the value does not match inside
+    /// a longer word
+    WordMatch(Attribute, Value),
+    /// Matches if all the bits that are set in the mask are also set in
+    /// the Int or BigInt attribute. The mask must fit into 64 bits, and
+    /// BigInt values that do not fit into 64 bits never match
+    BitsSet(Attribute, Value),
+    /// Matches if at least one of the bits that are set in the mask is
+    /// also set in the Int or BigInt attribute. The mask must fit into 64
+    /// bits, and BigInt values that do not fit into 64 bits never match
+    BitsAnySet(Attribute, Value),
+    /// Matches if the list attribute has exactly the given number of
+    /// elements. Like `ListEmpty`, none of the length filters match if the
+    /// attribute is null
+    LengthEqual(Attribute, i32),
+    /// Matches if the list attribute has more than the given number of
+    /// elements
+    LengthGreaterThan(Attribute, i32),
+    /// Matches if the list attribute has fewer than the given number of
+    /// elements
+    LengthLessThan(Attribute, i32),
+    /// Matches if the address in the `@inet` attribute lies within the
+    /// subnet, which is given in CIDR notation, e.g., `10.0.0.0/8`
+    InSubnet(Attribute, Value),
+    /// Matches if the Int or BigInt attribute leaves the given remainder
+    /// when divided by the modulus, which must not be zero. As in
+    /// Postgres, the remainder has the sign of the attribute
+    ModEquals(Attribute, i64, i64),
 }
 
 // Define some convenience methods
@@ -98,6 +151,18 @@ impl EntityFilter {
         )
     }
 
+    /// Filter on a boolean attribute that can be `true`, `false`, or null,
+    /// i.e., unknown. `Some(b)` only matches entities whose attribute is
+    /// `b`, and `None` only matches entities whose attribute is null, so
+    /// that the three filters divide all entities between them
+    pub fn new_bool_equal(attribute_name: impl Into<Attribute>, value: Option<bool>) -> Self {
+        let value = match value {
+            Some(b) => Value::Bool(b),
+            None => Value::Null,
+        };
+        EntityFilter::Equal(attribute_name.into(), value)
+    }
+
     pub fn and_maybe(self, other: Option<Self>) -> Self {
         use EntityFilter as f;
         match other {
@@ -245,6 +310,43 @@ pub type BlockNumber = i32;
 
 pub const BLOCK_NUMBER_MAX: BlockNumber = std::i32::MAX;
 
+/// A block number that the compiler keeps apart from other integers, like
+/// the index of a transaction or a log in its block. A function that takes
+/// a `BlockHeight` can not be passed a bare integer by accident; turning an
+/// integer into a `BlockHeight` has to be spelled out with `from` or `into`:
+///
+/// ```compile_fail
+/// use graph::prelude::BlockHeight;
+///
+/// fn at(_block: BlockHeight) {}
+///
+/// let transaction_index: i32 = 3;
+/// at(transaction_index);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BlockHeight(BlockNumber);
+
+impl BlockHeight {
+    /// The height to use to query the latest available block
+    pub const MAX: BlockHeight = BlockHeight(BLOCK_NUMBER_MAX);
+
+    pub fn number(self) -> BlockNumber {
+        self.0
+    }
+}
+
+impl From<BlockNumber> for BlockHeight {
+    fn from(number: BlockNumber) -> Self {
+        BlockHeight(number)
+    }
+}
+
+impl From<BlockHeight> for BlockNumber {
+    fn from(height: BlockHeight) -> Self {
+        height.0
+    }
+}
+
 /// A query for entities in a store.
 ///
 /// Details of how query generation for `EntityQuery` works can be found
@@ -747,12 +849,21 @@ pub enum StoreError {
     UnknownField(String),
     #[fail(display = "unknown table '{}'", _0)]
     UnknownTable(String),
+    #[fail(display = "entity type '{}' has no attribute '{}'", _0, _1)]
+    UnknownAttribute(String, String), // (entity_type, attribute)
     #[fail(display = "malformed directive '{}'", _0)]
     MalformedDirective(String),
     #[fail(display = "query execution failed: {}", _0)]
     QueryExecutionError(String),
     #[fail(display = "invalid identifier: {}", _0)]
     InvalidIdentifier(String),
+    #[fail(display = "inconsistent block: {}", _0)]
+    InconsistentBlock(String),
+    #[fail(
+        display = "tried to insert entity of type `{}` with ID \"{}\" but an entity with that ID already exists",
+        _0, _1
+    )]
+    DuplicateId(String, String), // (entity, id)
 }
 
 impl From<TransactionAbortError> for StoreError {
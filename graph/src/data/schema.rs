@@ -108,6 +108,11 @@ pub enum SchemaValidationError {
     FulltextIncludedFieldMissingRequiredProperty,
     #[fail(display = "Fulltext entity field, {}, not found or not a string", _0)]
     FulltextIncludedFieldInvalid(String),
+    #[fail(
+        display = "Fulltext include field weight, {}, is invalid; it must be one of A, B, C, D",
+        _0
+    )]
+    FulltextIncludedFieldWeightInvalid(String),
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -201,10 +206,57 @@ impl TryFrom<&String> for FulltextAlgorithm {
     }
 }
 
+/// The weight of a field in a fulltext search, from `A`, the highest, to
+/// `D`, the lowest. Fields without an explicit weight have weight `D`,
+/// which is also the weight Postgres gives to text that was not weighted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FulltextWeight {
+    A,
+    B,
+    C,
+    D,
+}
+
+impl TryFrom<&String> for FulltextWeight {
+    type Error = String;
+    fn try_from(weight: &String) -> Result<Self, Self::Error> {
+        match &weight[..] {
+            "A" => Ok(FulltextWeight::A),
+            "B" => Ok(FulltextWeight::B),
+            "C" => Ok(FulltextWeight::C),
+            "D" => Ok(FulltextWeight::D),
+            invalid => Err(format!(
+                "The provided fulltext weight {} is invalid. It must be one of: A, B, C, D",
+                invalid,
+            )),
+        }
+    }
+}
+
+impl FulltextWeight {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::A => "A",
+            Self::B => "B",
+            Self::C => "C",
+            Self::D => "D",
+        }
+    }
+}
+
+impl Default for FulltextWeight {
+    fn default() -> Self {
+        FulltextWeight::D
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct FulltextConfig {
     pub language: FulltextLanguage,
     pub algorithm: FulltextAlgorithm,
+    /// The weight of each included field, ordered by field name. Every
+    /// included field has an entry
+    pub weights: BTreeMap<String, FulltextWeight>,
 }
 
 pub struct FulltextDefinition {
@@ -237,24 +289,25 @@ impl From<&Directive> for FulltextDefinition {
         // Currently fulltext query fields are limited to 1 entity, so we just take the first (and only) included Entity
         let included_entity = included_entity_list.first().unwrap().as_object().unwrap();
         let included_field_values = included_entity.get("fields").unwrap().as_list().unwrap();
-        let included_fields: HashSet<String> = included_field_values
+        let weights: BTreeMap<String, FulltextWeight> = included_field_values
             .into_iter()
             .map(|field| {
-                field
-                    .as_object()
-                    .unwrap()
-                    .get("name")
-                    .unwrap()
-                    .as_string()
-                    .unwrap()
-                    .clone()
+                let field = field.as_object().unwrap();
+                let name = field.get("name").unwrap().as_string().unwrap().clone();
+                let weight = field
+                    .get("weight")
+                    .map(|weight| FulltextWeight::try_from(weight.as_enum().unwrap()).unwrap())
+                    .unwrap_or_default();
+                (name, weight)
             })
             .collect();
+        let included_fields: HashSet<String> = weights.keys().cloned().collect();
 
         FulltextDefinition {
             config: FulltextConfig {
                 language,
                 algorithm,
+                weights,
             },
             included_fields,
             name,
@@ -899,13 +952,30 @@ impl Schema {
                     };
 
                     for field_value in fields {
-                        let field_name = match field_value {
-                            Value::Object(field_map) => match field_map.get("name") {
-                                Some(Value::String(name)) => name,
-                                _ => return vec![SchemaValidationError::FulltextIncludedFieldMissingRequiredProperty],
-                            },
+                        let field_map = match field_value {
+                            Value::Object(field_map) => field_map,
                             _ => return vec![SchemaValidationError::FulltextIncludeEntityMissingOrIncorrectAttributes],
                         };
+                        let field_name = match field_map.get("name") {
+                            Some(Value::String(name)) => name,
+                            _ => {
+                                return vec![
+                                SchemaValidationError::FulltextIncludedFieldMissingRequiredProperty,
+                            ]
+                            }
+                        };
+                        match field_map.get("weight") {
+                            None => (),
+                            Some(Value::Enum(weight))
+                                if FulltextWeight::try_from(weight).is_ok() => {}
+                            Some(weight) => {
+                                return vec![
+                                    SchemaValidationError::FulltextIncludedFieldWeightInvalid(
+                                        weight.to_string(),
+                                    ),
+                                ]
+                            }
+                        }
 
                         // Validate the included field is a String field on the local entity types specified
                         if !&entity_type
@@ -1521,3 +1591,64 @@ type Gravatar @entity {
 
     assert_eq!(schema.validate_fulltext_directives(), vec![]);
 }
+
+#[test]
+fn test_fulltext_directive_weights() {
+    fn schema(weight: &str) -> Schema {
+        let raw = format!(
+            r#"
+type _Schema_ @fulltext(
+  name: "metadata"
+  language: en
+  algorithm: rank
+  include: [
+    {{
+      entity: "Gravatar",
+      fields: [
+        {{ name: "displayName", weight: {} }},
+        {{ name: "imageUrl" }},
+      ]
+    }}
+  ]
+)
+type Gravatar @entity {{
+  id: ID!
+  displayName: String!
+  imageUrl: String!
+}}"#,
+            weight
+        );
+        let document = graphql_parser::parse_schema(&raw).expect("Failed to parse schema");
+        Schema::new(SubgraphDeploymentId::new("id1").unwrap(), document)
+    }
+
+    let valid = schema("A");
+    assert_eq!(valid.validate_fulltext_directives(), vec![]);
+    let definition = FulltextDefinition::from(
+        valid
+            .subgraph_schema_object_type()
+            .and_then(|typ| typ.find_directive("fulltext".to_owned()))
+            .expect("schema has a fulltext directive"),
+    );
+    let weights: Vec<_> = definition.config.weights.into_iter().collect();
+    assert_eq!(
+        vec![
+            ("displayName".to_owned(), FulltextWeight::A),
+            ("imageUrl".to_owned(), FulltextWeight::D)
+        ],
+        weights
+    );
+
+    assert_eq!(
+        schema("E").validate_fulltext_directives(),
+        vec![SchemaValidationError::FulltextIncludedFieldWeightInvalid(
+            "E".to_owned()
+        )]
+    );
+    assert_eq!(
+        schema("\"A\"").validate_fulltext_directives(),
+        vec![SchemaValidationError::FulltextIncludedFieldWeightInvalid(
+            "\"A\"".to_owned()
+        )]
+    );
+}
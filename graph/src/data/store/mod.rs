@@ -234,8 +234,8 @@ impl Value {
                 // just a string.
                 match n.as_str() {
                     BYTES_SCALAR => Value::Bytes(scalar::Bytes::from_str(s)?),
-                    BIG_INT_SCALAR => Value::BigInt(scalar::BigInt::from_str(s)?),
-                    BIG_DECIMAL_SCALAR => Value::BigDecimal(scalar::BigDecimal::from_str(s)?),
+                    BIG_INT_SCALAR => Value::parse_bigint(s)?,
+                    BIG_DECIMAL_SCALAR => Value::parse_bigdecimal(s)?,
                     _ => Value::String(s.clone()),
                 }
             }
@@ -256,6 +256,31 @@ impl Value {
         })
     }
 
+    /// Parse `s` as a `BigInt` value. Unlike converting a string with
+    /// `into()`, which produces a `Value::String`, this fails if `s` is
+    /// empty or not a number
+    pub fn parse_bigint(s: &str) -> Result<Value, QueryExecutionError> {
+        if s.is_empty() {
+            return Err(QueryExecutionError::ValueParseError(
+                "BigInt".to_owned(),
+                "the value is empty".to_owned(),
+            ));
+        }
+        Ok(Value::BigInt(scalar::BigInt::from_str(s)?))
+    }
+
+    /// Parse `s` as a `BigDecimal` value; like `parse_bigint`, this fails
+    /// if `s` is empty or not a number
+    pub fn parse_bigdecimal(s: &str) -> Result<Value, QueryExecutionError> {
+        if s.is_empty() {
+            return Err(QueryExecutionError::ValueParseError(
+                "BigDecimal".to_owned(),
+                "the value is empty".to_owned(),
+            ));
+        }
+        Ok(Value::BigDecimal(scalar::BigDecimal::from_str(s)?))
+    }
+
     pub fn as_string(self) -> Option<String> {
         if let Value::String(s) = self {
             Some(s)
@@ -596,6 +621,12 @@ pub trait TryIntoEntity {
     fn try_into_entity(self) -> Result<Entity, Error>;
 }
 
+/// A value that can (maybe) be reconstructed from an `Entity`; the
+/// counterpart to `TryIntoEntity`.
+pub trait TryFromEntity: Sized {
+    fn try_from_entity(entity: &Entity) -> Result<Self, Error>;
+}
+
 /// A value that can be converted to an `Entity` ID.
 pub trait ToEntityId {
     fn to_entity_id(&self) -> String;
@@ -658,3 +689,24 @@ fn value_bigint() {
     );
     assert_eq!(query::Value::from(from_query), graphql_value);
 }
+
+#[test]
+fn value_parse_bigint() {
+    assert_eq!(
+        Value::BigInt(scalar::BigInt::from(-42)),
+        Value::parse_bigint("-42").unwrap()
+    );
+    assert!(Value::parse_bigint("").is_err());
+    assert!(Value::parse_bigint("12abc").is_err());
+    assert!(Value::parse_bigint("1.5").is_err());
+}
+
+#[test]
+fn value_parse_bigdecimal() {
+    assert_eq!(
+        Value::BigDecimal(scalar::BigDecimal::from_str("1.25").unwrap()),
+        Value::parse_bigdecimal("1.25").unwrap()
+    );
+    assert!(Value::parse_bigdecimal("").is_err());
+    assert!(Value::parse_bigdecimal("one").is_err());
+}
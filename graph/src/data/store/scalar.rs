@@ -34,6 +34,33 @@ pub(crate) fn big_decimal_stable_hash(
     big_int_stable_hash(&int, sequence_number, state);
 }
 
+/// Return `decimal` with exactly `scale` digits after the decimal point.
+/// Unlike `BigDecimal::with_scale`, which truncates, digits that are cut
+/// off are rounded half to even, so that `1.125` becomes `1.12` and
+/// `1.135` becomes `1.14` at scale 2
+pub fn big_decimal_round_half_even(decimal: &BigDecimal, scale: i64) -> BigDecimal {
+    use num_traits::{One, Signed, Zero};
+
+    let (digits, exp) = decimal.as_bigint_and_exponent();
+    if exp <= scale {
+        return decimal.with_scale(scale);
+    }
+    let divisor = num_traits::pow(num_bigint::BigInt::from(10), (exp - scale) as usize);
+    let quotient = &digits / &divisor;
+    let twice_remainder = (&digits % &divisor).abs() * num_bigint::BigInt::from(2);
+    let away_from_zero = match twice_remainder.cmp(&divisor) {
+        std::cmp::Ordering::Less => false,
+        std::cmp::Ordering::Equal => !(&quotient % num_bigint::BigInt::from(2)).is_zero(),
+        std::cmp::Ordering::Greater => true,
+    };
+    let quotient = match (away_from_zero, digits.is_negative()) {
+        (false, _) => quotient,
+        (true, false) => quotient + num_bigint::BigInt::one(),
+        (true, true) => quotient - num_bigint::BigInt::one(),
+    };
+    BigDecimal::new(quotient, scale)
+}
+
 fn big_int_stable_hash(
     int: &num_bigint::BigInt,
     sequence_number: impl SequenceNumber,
@@ -120,6 +147,13 @@ impl BigInt {
         self.try_into().unwrap()
     }
 
+    /// Convert to an `i64`, returning `None` if the value does not fit
+    pub fn to_i64_checked(&self) -> Option<i64> {
+        use num_traits::ToPrimitive;
+
+        self.0.to_i64()
+    }
+
     pub fn from_unsigned_u256(n: &U256) -> Self {
         let mut bytes: [u8; 32] = [0; 32];
         n.to_little_endian(&mut bytes);
@@ -361,13 +395,30 @@ impl<'de> Deserialize<'de> for Bytes {
 
 #[cfg(test)]
 mod test {
-    use super::{big_decimal_stable_hash, BigDecimal, BigInt};
+    use super::{big_decimal_round_half_even, big_decimal_stable_hash, BigDecimal, BigInt};
     use stable_hash::prelude::*;
     use stable_hash::utils::stable_hash_with_hasher;
     use std::str::FromStr;
     use twox_hash::XxHash64;
     use web3::types::U64;
 
+    #[test]
+    fn big_decimal_round_half_even_to_scale() {
+        let round = |s: &str, scale: i64| {
+            big_decimal_round_half_even(&BigDecimal::from_str(s).unwrap(), scale).to_string()
+        };
+        assert_eq!("184.40", round("184.4", 2));
+        assert_eq!("184.40", round("184.400000000000", 2));
+        assert_eq!("1.12", round("1.125", 2));
+        assert_eq!("1.14", round("1.135", 2));
+        assert_eq!("1.13", round("1.1251", 2));
+        assert_eq!("-1.12", round("-1.125", 2));
+        assert_eq!("-1.14", round("-1.135", 2));
+        assert_eq!("0.00", round("0.004", 2));
+        assert_eq!("12", round("12.5", 0));
+        assert_eq!("13", round("12.51", 0));
+    }
+
     #[test]
     fn bigint_to_from_u64() {
         for n in 0..100 {
@@ -377,6 +428,29 @@ mod test {
         }
     }
 
+    #[test]
+    fn bigint_to_i64_checked() {
+        assert_eq!(Some(0), BigInt::from(0).to_i64_checked());
+        assert_eq!(Some(-17), BigInt::from(-17).to_i64_checked());
+        assert_eq!(
+            Some(std::i64::MAX),
+            BigInt::from(std::i64::MAX).to_i64_checked()
+        );
+        assert_eq!(
+            Some(std::i64::MIN),
+            BigInt::from(std::i64::MIN).to_i64_checked()
+        );
+        assert_eq!(
+            None,
+            (BigInt::from(std::i64::MAX) + BigInt::from(1)).to_i64_checked()
+        );
+        assert_eq!(
+            None,
+            (BigInt::from(std::i64::MIN) - BigInt::from(1)).to_i64_checked()
+        );
+        assert_eq!(None, BigInt::from(std::i64::MAX).pow(17).to_i64_checked());
+    }
+
     fn xx_stable_hash(value: impl StableHash) -> u64 {
         stable_hash_with_hasher::<XxHash64, _>(&value)
     }
@@ -203,6 +203,7 @@ fn print_diesel_tables(layout: &Layout) {
             ColumnType::Bytes => "Binary",
             ColumnType::Int => "Integer",
             ColumnType::String | ColumnType::Enum(_) | ColumnType::TSVector(_) => "Text",
+            ColumnType::Inet => "Inet",
         }
         .to_owned();
 
@@ -222,6 +223,7 @@ fn print_diesel_tables(layout: &Layout) {
             ColumnType::Bytes => "Vec<u8>",
             ColumnType::Int => "i32",
             ColumnType::String | ColumnType::Enum(_) | ColumnType::TSVector(_) => "String",
+            ColumnType::Inet => "IpNetwork",
         }
         .to_owned();
 
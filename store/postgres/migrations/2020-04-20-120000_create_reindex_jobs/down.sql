@@ -0,0 +1 @@
+drop table reindex_jobs;
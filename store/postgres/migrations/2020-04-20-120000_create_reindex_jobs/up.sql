@@ -0,0 +1,11 @@
+-- Progress of rebuilding the indexes of one table with `Layout::reindex`.
+-- Indexes are rebuilt in the order of their names, and `last_index` is the
+-- name of the last index that was rebuilt
+create table reindex_jobs (
+  schema_name text not null,
+  table_name text not null,
+  last_index text,
+  started_at timestamptz not null default now(),
+  finished_at timestamptz,
+  primary key(schema_name, table_name)
+);
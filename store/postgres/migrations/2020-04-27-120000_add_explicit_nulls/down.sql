@@ -0,0 +1,15 @@
+do $$
+declare
+  t record;
+begin
+  for t in
+    select c.table_schema, c.table_name
+      from information_schema.columns c, deployment_schemas ds
+     where c.table_schema = ds.name
+       and c.column_name = 'g$explicit_nulls'
+  loop
+    execute format('alter table %I.%I drop column "g$explicit_nulls"',
+                   t.table_schema, t.table_name);
+  end loop;
+end;
+$$;
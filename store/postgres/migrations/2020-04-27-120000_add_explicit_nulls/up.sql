@@ -0,0 +1,20 @@
+-- Record which fields of an entity version were explicitly set to null;
+-- tables that are created from now on get the column from their DDL. The
+-- tables for subgraph metadata do not record explicit nulls
+do $$
+declare
+  t record;
+begin
+  for t in
+    select c.table_schema, c.table_name
+      from information_schema.columns c, deployment_schemas ds
+     where c.table_schema = ds.name
+       and ds.version = 'relational'
+       and ds.subgraph <> 'subgraphs'
+       and c.column_name = 'block_range'
+  loop
+    execute format('alter table %I.%I add column if not exists "g$explicit_nulls" text[]',
+                   t.table_schema, t.table_name);
+  end loop;
+end;
+$$;
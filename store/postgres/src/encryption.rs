@@ -0,0 +1,121 @@
+//! Encryption of attributes marked with `@encrypted` before they are
+//! written to the database
+//!
+//! Values are encrypted deterministically: the IV for AES-256 in CTR mode
+//! is derived from an HMAC-SHA256 of the plaintext (a synthetic IV, as in
+//! AES-SIV) and stored in front of the ciphertext. Because of that, the
+//! same plaintext always produces the same ciphertext, which is what makes
+//! it possible to use encrypted attributes in equality filters, and to
+//! index them. The tradeoff is that anybody with access to the database
+//! can tell which rows have equal values for an encrypted attribute, and
+//! how long each value is, even though they can not see the values
+//! themselves. Comparisons other than equality, and text searches, are
+//! not possible on encrypted attributes. Grouping and counting distinct
+//! values work on the ciphertext for the same reason that equality does;
+//! `Layout::group_count` decrypts the value of each group, but orders
+//! groups of the same size by their ciphertext rather than their value
+use aes_ctr::stream_cipher::generic_array::GenericArray;
+use aes_ctr::stream_cipher::{NewStreamCipher, SyncStreamCipher};
+use aes_ctr::Aes256Ctr;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+
+use graph::prelude::{format_err, StoreError};
+
+/// The length of the key material that `EncryptionKey::new` needs; the
+/// first half is used for AES, the second half for the HMAC
+pub const KEY_LEN: usize = 64;
+
+/// The length of the synthetic IV that precedes each ciphertext
+const IV_LEN: usize = 16;
+
+#[derive(Clone)]
+pub struct EncryptionKey {
+    cipher_key: [u8; 32],
+    mac_key: [u8; 32],
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never print the actual key
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+impl EncryptionKey {
+    pub fn new(key: &[u8]) -> Result<Self, StoreError> {
+        if key.len() != KEY_LEN {
+            return Err(StoreError::Unknown(format_err!(
+                "an encryption key must be exactly {} bytes long, but the key has {} bytes",
+                KEY_LEN,
+                key.len()
+            )));
+        }
+        let mut cipher_key = [0u8; 32];
+        let mut mac_key = [0u8; 32];
+        cipher_key.copy_from_slice(&key[..32]);
+        mac_key.copy_from_slice(&key[32..]);
+        Ok(EncryptionKey {
+            cipher_key,
+            mac_key,
+        })
+    }
+
+    fn synthetic_iv(&self, plaintext: &[u8]) -> [u8; IV_LEN] {
+        let mut mac =
+            Hmac::<Sha256>::new_varkey(&self.mac_key).expect("HMAC accepts keys of any length");
+        mac.input(plaintext);
+        let mut iv = [0u8; IV_LEN];
+        iv.copy_from_slice(&mac.result().code()[..IV_LEN]);
+        iv
+    }
+
+    fn apply_keystream(&self, iv: &[u8], data: &mut [u8]) {
+        let mut cipher = Aes256Ctr::new(
+            GenericArray::from_slice(&self.cipher_key),
+            GenericArray::from_slice(iv),
+        );
+        cipher.apply_keystream(data);
+    }
+
+    /// Encrypt `plaintext`. The result consists of the synthetic IV
+    /// followed by the ciphertext
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let iv = self.synthetic_iv(plaintext);
+        let mut data = Vec::with_capacity(IV_LEN + plaintext.len());
+        data.extend_from_slice(&iv);
+        data.extend_from_slice(plaintext);
+        self.apply_keystream(&iv, &mut data[IV_LEN..]);
+        data
+    }
+
+    /// Decrypt data produced by `encrypt`. Return an error if the data
+    /// was not encrypted with this key, or was changed after encryption
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, StoreError> {
+        if data.len() < IV_LEN {
+            return Err(StoreError::Unknown(format_err!(
+                "encrypted data must be at least {} bytes long, but has only {} bytes",
+                IV_LEN,
+                data.len()
+            )));
+        }
+        let (iv, ciphertext) = data.split_at(IV_LEN);
+        let mut plaintext = ciphertext.to_vec();
+        self.apply_keystream(iv, &mut plaintext);
+
+        // Compare in constant time so that we do not leak how much of
+        // the IV matched
+        let expected = self.synthetic_iv(&plaintext);
+        let diff = expected
+            .iter()
+            .zip(iv.iter())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b));
+        if diff != 0 {
+            return Err(StoreError::Unknown(format_err!(
+                "failed to decrypt data: it was encrypted with a different key or was tampered with"
+            )));
+        }
+        Ok(plaintext)
+    }
+}
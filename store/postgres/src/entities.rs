@@ -44,8 +44,8 @@ use std::time::Instant;
 use graph::data::schema::Schema as SubgraphSchema;
 use graph::data::subgraph::schema::{POI_OBJECT, POI_TABLE, SUBGRAPHS_ID};
 use graph::prelude::{
-    debug, format_err, info, serde_json, warn, AttributeIndexDefinition, BlockNumber, Entity,
-    EntityChange, EntityChangeOperation, EntityCollection, EntityFilter, EntityKey,
+    debug, format_err, info, serde_json, warn, AttributeIndexDefinition, BlockHeight, BlockNumber,
+    Entity, EntityChange, EntityChangeOperation, EntityCollection, EntityFilter, EntityKey,
     EntityModification, EntityOrder, EntityRange, Error, EthereumBlockPointer, Logger,
     QueryExecutionError, StoreError, StoreEvent, SubgraphDeploymentId, ValueType, BLOCK_NUMBER_MAX,
 };
@@ -420,7 +420,7 @@ impl Connection<'_> {
     ) -> Result<Option<Entity>, StoreError> {
         match &*self.storage {
             Storage::Json(json) => json.find(&self.conn, entity, id),
-            Storage::Relational(layout) => layout.find(&self.conn, entity, id, block),
+            Storage::Relational(layout) => layout.find(&self.conn, entity, id, block.into()),
         }
     }
 
@@ -448,7 +448,7 @@ impl Connection<'_> {
                 Ok(entities)
             }
 
-            Storage::Relational(layout) => layout.find_many(&self.conn, ids_for_type, block),
+            Storage::Relational(layout) => layout.find_many(&self.conn, ids_for_type, block.into()),
         }
     }
 
@@ -475,9 +475,15 @@ impl Connection<'_> {
                 }
                 json.query(&self.conn, collection, filter, order, range)
             }
-            Storage::Relational(layout) => {
-                layout.query(logger, &self.conn, collection, filter, order, range, block)
-            }
+            Storage::Relational(layout) => layout.query(
+                logger,
+                &self.conn,
+                collection,
+                filter,
+                order,
+                range,
+                block.into(),
+            ),
         }
     }
 
@@ -506,7 +512,7 @@ impl Connection<'_> {
                 .map(|_| ()),
             Storage::Relational(layout) => match history_event {
                 Some(history_event) => {
-                    layout.insert(&self.conn, key, entity, block_number(&history_event))
+                    layout.insert(&self.conn, key, entity, block_number(&history_event).into())
                 }
                 None => layout.insert_unversioned(&self.conn, key, entity),
             },
@@ -528,7 +534,7 @@ impl Connection<'_> {
                 .map(|_| ()),
             Storage::Relational(layout) => match history_event {
                 Some(history_event) => {
-                    layout.update(&self.conn, key, entity, block_number(&history_event))
+                    layout.update(&self.conn, key, entity, block_number(&history_event).into())
                 }
                 None => layout
                     .overwrite_unversioned(&self.conn, key, entity)
@@ -554,7 +560,7 @@ impl Connection<'_> {
         id: &String,
     ) -> Result<Option<Entity>, StoreError> {
         self.metadata_layout()
-            .find(&self.conn, entity, id, BLOCK_NUMBER_MAX)
+            .find(&self.conn, entity, id, BlockHeight::MAX)
     }
 
     pub(crate) fn delete(
@@ -565,7 +571,9 @@ impl Connection<'_> {
         match self.storage_for(key) {
             Storage::Json(json) => json.delete(&self.conn, key, history_event),
             Storage::Relational(layout) => match history_event {
-                Some(history_event) => layout.delete(&self.conn, key, block_number(&history_event)),
+                Some(history_event) => {
+                    layout.delete(&self.conn, key, block_number(&history_event).into())
+                }
                 None => layout.delete_unversioned(&self.conn, key),
             },
         }
@@ -587,7 +595,7 @@ impl Connection<'_> {
     ) -> Result<(StoreEvent, i32), StoreError> {
         // At 1 block per 15 seconds, the maximum i32
         // value affords just over 1020 years of blocks.
-        let block = block_ptr
+        let block: BlockNumber = block_ptr
             .number
             .try_into()
             .expect("block numbers fit into an i32");
@@ -595,16 +603,18 @@ impl Connection<'_> {
         // Revert the block in the subgraph itself
         let (event, count) = match &*self.storage {
             Storage::Json(json) => json.revert_block(&self.conn, block_ptr.hash_hex())?,
-            Storage::Relational(layout) => layout.revert_block(&self.conn, block)?,
+            Storage::Relational(layout) => layout.revert_block(&self.conn, block.into())?,
         };
         // Revert the meta data changes that correspond to this subgraph.
         // Only certain meta data changes need to be reverted, most
         // importantly creation of dynamic data sources. We ensure in the
         // rest of the code that we only record history for those meta data
         // changes that might need to be reverted
-        let meta_event =
-            self.metadata_layout()
-                .revert_metadata(&self.conn, &self.storage.subgraph(), block)?;
+        let meta_event = self.metadata_layout().revert_metadata(
+            &self.conn,
+            &self.storage.subgraph(),
+            block.into(),
+        )?;
         Ok((event.extend(meta_event), count))
     }
 
@@ -13,6 +13,7 @@ use graph::prelude::serde_json;
 use graph::prelude::{BigDecimal, BigInt};
 
 use crate::entities::{EntitySource, STRING_PREFIX_SIZE};
+use crate::relational_queries::{bitmask, escape_like, escape_regex};
 use crate::sql_value::SqlValue;
 
 #[derive(Debug)]
@@ -195,6 +196,66 @@ impl<QS> IntoArrayFilter<QS, SqlValue> for Vec<SqlValue> {
     }
 }
 
+/// Build the filter `(attribute & mask) = mask` if `all` is set, and
+/// `(attribute & mask) <> 0` otherwise. Values that do not fit into an
+/// `int8` never match
+fn bits_set_filter<QS>(
+    attribute: String,
+    mask: Value,
+    all: bool,
+) -> Result<FilterExpression<QS>, UnsupportedFilter>
+where
+    QS: EntitySource + 'static,
+{
+    let bits = match bitmask(&mask) {
+        Some(bits) => bits.to_string(),
+        None => {
+            return Err(UnsupportedFilter {
+                filter: if all { "bits_set" } else { "bits_any_set" }.to_owned(),
+                value: mask,
+            })
+        }
+    };
+    // The `case` keeps values that are too big for an `int8` from making
+    // the cast, and with it the whole query, fail
+    let masked = sql("case when (c.data -> ")
+        .bind::<Text, _>(attribute.clone())
+        .sql(&format!(
+            " ->> 'data')::numeric between {} and {} then ((c.data -> ",
+            std::i64::MIN,
+            std::i64::MAX
+        ))
+        .bind::<Text, _>(attribute)
+        .sql(" ->> 'data')::numeric::int8 & ")
+        .bind::<Text, _>(bits.clone())
+        .sql("::int8)");
+    if all {
+        Ok(Box::new(
+            masked
+                .sql(" = ")
+                .bind::<Text, _>(bits)
+                .sql("::int8 else false end"),
+        ) as FilterExpression<QS>)
+    } else {
+        Ok(Box::new(masked.sql(" <> 0 else false end")) as FilterExpression<QS>)
+    }
+}
+
+/// Build the filter `length(attribute) op length`. A null list has no
+/// length and never matches, just like it does not match `ListEmpty`
+fn list_length_filter<QS>(attribute: String, op: &str, length: i32) -> FilterExpression<QS>
+where
+    QS: EntitySource + 'static,
+{
+    Box::new(
+        sql("jsonb_array_length(nullif(c.data -> ")
+            .bind::<Text, _>(attribute)
+            .sql(" -> 'data', 'null'::jsonb))")
+            .sql(op)
+            .bind::<Integer, _>(length),
+    ) as FilterExpression<QS>
+}
+
 pub(crate) fn build_filter<QS>(
     filter: EntityFilter,
 ) -> Result<FilterExpression<QS>, UnsupportedFilter>
@@ -227,13 +288,9 @@ where
             };
 
             match value {
-                Value::String(s) => {
-                    if s.starts_with('%') || s.ends_with('%') {
-                        Ok(s.into_filter(attribute, op))
-                    } else {
-                        Ok(format!("%{}%", s).into_filter(attribute, op))
-                    }
-                }
+                // Postgres uses a backslash as the escape character for
+                // `like` patterns unless told otherwise
+                Value::String(s) => Ok(format!("%{}%", escape_like(&s)).into_filter(attribute, op)),
                 Value::Bytes(b) => Ok(format!("%{}%", b.to_string()).into_filter(attribute, op)),
                 Value::List(lst) => {
                     let s = serde_json::to_string(&lst).expect("failed to serialize list value");
@@ -390,7 +447,7 @@ where
             };
 
             match value {
-                Value::String(s) => Ok(format!("{}%", s).into_filter(attribute, op)),
+                Value::String(s) => Ok(format!("{}%", escape_like(&s)).into_filter(attribute, op)),
                 Value::Bool(_)
                 | Value::BigInt(_)
                 | Value::Bytes(_)
@@ -411,6 +468,145 @@ where
             }
         }
 
+        AnyFieldContains(attributes, value) => build_filter(Or(attributes
+            .into_iter()
+            .map(|attribute| Contains(attribute, value.clone()))
+            .collect())),
+
+        ChildNotExists(attribute, _) => Err(UnsupportedFilter {
+            filter: "child_not_exists".to_owned(),
+            value: Value::String(attribute),
+        }),
+
+        ReferenceExists(attribute) => Err(UnsupportedFilter {
+            filter: "reference_exists".to_owned(),
+            value: Value::String(attribute),
+        }),
+
+        Near(attribute, center, tolerance) => {
+            let big_decimal = |value: &Value| match value {
+                Value::Int(i) => Some(BigDecimal::from(*i)),
+                Value::BigInt(i) => Some(i.clone().to_big_decimal(BigInt::from(0))),
+                Value::BigDecimal(d) => Some(d.clone()),
+                _ => None,
+            };
+            let (low, high) = match (&center, &tolerance) {
+                (Value::Int(c), Value::Int(t)) => (
+                    Value::Int(c.saturating_sub(*t)),
+                    Value::Int(c.saturating_add(*t)),
+                ),
+                (Value::BigInt(c), Value::BigInt(t)) => (
+                    Value::BigInt(c.clone() - t.clone()),
+                    Value::BigInt(c.clone() + t.clone()),
+                ),
+                (Value::BigInt(c), Value::Int(t)) => (
+                    Value::BigInt(c.clone() - BigInt::from(*t)),
+                    Value::BigInt(c.clone() + BigInt::from(*t)),
+                ),
+                _ => match (big_decimal(&center), big_decimal(&tolerance)) {
+                    (Some(c), Some(t)) => (
+                        Value::BigDecimal(c.clone() - t.clone()),
+                        Value::BigDecimal(c + t),
+                    ),
+                    _ => {
+                        return Err(UnsupportedFilter {
+                            filter: "near".to_owned(),
+                            value: center.clone(),
+                        })
+                    }
+                },
+            };
+            build_filter(And(vec![
+                GreaterOrEqual(attribute.clone(), low),
+                LessOrEqual(attribute, high),
+            ]))
+        }
+
+        IsWhole(attribute) => Ok(Box::new(
+            sql("(c.data -> ")
+                .bind::<Text, _>(attribute.clone())
+                .sql(" ->> 'data')::numeric = trunc((c.data -> ")
+                .bind::<Text, _>(attribute)
+                .sql(" ->> 'data')::numeric)"),
+        ) as FilterExpression<QS>),
+
+        ListEmpty(attribute) => Ok(Box::new(
+            sql("c.data -> ")
+                .bind::<Text, _>(attribute)
+                .sql(" -> 'data' = '[]'::jsonb"),
+        ) as FilterExpression<QS>),
+
+        ListNotEmpty(attribute) => Ok(list_length_filter(attribute, " > ", 0)),
+
+        WordMatch(attribute, value) => match value {
+            Value::String(word) => Ok(Box::new(
+                sql("c.data -> ")
+                    .bind::<Text, _>(attribute)
+                    .sql(" ->> 'data' ~* ('\\m' || ")
+                    .bind::<Text, _>(escape_regex(&word))
+                    .sql(" || '\\M')"),
+            ) as FilterExpression<QS>),
+            value => Err(UnsupportedFilter {
+                filter: "word_match".to_owned(),
+                value,
+            }),
+        },
+
+        BitsSet(attribute, mask) => bits_set_filter(attribute, mask, true),
+        BitsAnySet(attribute, mask) => bits_set_filter(attribute, mask, false),
+        LengthEqual(attribute, length) => Ok(list_length_filter(attribute, " = ", length)),
+        LengthGreaterThan(attribute, length) => Ok(list_length_filter(attribute, " > ", length)),
+        LengthLessThan(attribute, length) => Ok(list_length_filter(attribute, " < ", length)),
+        InSubnet(attribute, subnet) => match subnet {
+            Value::String(subnet) => Ok(Box::new(
+                sql("(c.data -> ")
+                    .bind::<Text, _>(attribute)
+                    .sql(" ->> 'data')::inet << ")
+                    .bind::<Text, _>(subnet)
+                    .sql("::inet"),
+            ) as FilterExpression<QS>),
+            subnet => Err(UnsupportedFilter {
+                filter: "in_subnet".to_owned(),
+                value: subnet,
+            }),
+        },
+        ModEquals(_, 0, _) => Err(UnsupportedFilter {
+            filter: "mod_equals".to_owned(),
+            value: Value::BigInt(BigInt::from(0i64)),
+        }),
+        ModEquals(attribute, modulus, remainder) => Ok(Box::new(
+            sql("((c.data -> ")
+                .bind::<Text, _>(attribute)
+                .sql(" ->> 'data')::numeric % ")
+                .bind::<Text, _>(modulus.to_string())
+                .sql("::numeric) = ")
+                .bind::<Text, _>(remainder.to_string())
+                .sql("::numeric"),
+        ) as FilterExpression<QS>),
+
+        InCaseInsensitive(attribute, values) => {
+            if values.is_empty() {
+                return Ok(false_expr);
+            }
+            let values = values
+                .into_iter()
+                .map(|value| match value {
+                    Value::String(s) => Ok(s.to_lowercase()),
+                    value => Err(UnsupportedFilter {
+                        filter: "in_case_insensitive".to_owned(),
+                        value,
+                    }),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Box::new(
+                sql("lower(c.data -> ")
+                    .bind::<Text, _>(attribute)
+                    .sql(" ->> 'data') = ANY(")
+                    .bind::<Array<Text>, _>(values)
+                    .sql(")"),
+            ) as FilterExpression<QS>)
+        }
+
         EndsWith(..) | NotEndsWith(..) => {
             let (attribute, op, value) = match filter {
                 EndsWith(attribute, value) => (attribute, " LIKE ", value),
@@ -419,7 +615,7 @@ where
             };
 
             match value {
-                Value::String(s) => Ok(format!("%{}", s).into_filter(attribute, op)),
+                Value::String(s) => Ok(format!("%{}", escape_like(&s)).into_filter(attribute, op)),
                 Value::Bool(_)
                 | Value::BigInt(_)
                 | Value::Bytes(_)
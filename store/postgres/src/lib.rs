@@ -1,3 +1,4 @@
+extern crate aes_ctr;
 #[macro_use]
 extern crate derive_more;
 #[macro_use]
@@ -7,23 +8,27 @@ extern crate diesel_dynamic_schema;
 extern crate diesel_migrations;
 #[macro_use]
 extern crate diesel_derive_enum;
+extern crate either;
 extern crate failure;
 extern crate fallible_iterator;
 extern crate futures;
 extern crate graph;
 extern crate graph_graphql;
 extern crate graphql_parser;
+extern crate hmac;
 extern crate inflector;
 extern crate lazy_static;
 extern crate lru_time_cache;
 extern crate postgres;
 extern crate serde;
+extern crate sha2;
 extern crate uuid;
 
 mod block_range;
 mod chain_head_listener;
 pub mod connection_pool;
 mod db_schema;
+mod encryption;
 mod entities;
 mod filter;
 mod functions;
@@ -47,6 +52,7 @@ pub mod db_schema_for_tests {
 #[cfg(debug_assertions)]
 pub mod layout_for_tests {
     pub use crate::block_range::*;
+    pub use crate::encryption::EncryptionKey;
     pub use crate::entities::STRING_PREFIX_SIZE;
     pub use crate::relational::*;
 }
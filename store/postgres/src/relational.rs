@@ -7,35 +7,54 @@
 //! The pivotal struct in this module is the `Layout` which handles all the
 //! information about mapping a GraphQL schema to database tables
 use diesel::connection::SimpleConnection;
+use diesel::sql_types::{Bool, Double, Nullable, Text};
 use diesel::{
-    debug_query, ExpressionMethods, OptionalExtension, PgConnection, QueryDsl, RunQueryDsl,
+    debug_query, sql_query, Connection, ExpressionMethods, OptionalExtension, PgConnection,
+    QueryDsl, RunQueryDsl,
 };
+use either::Either;
 use graphql_parser::query as q;
 use graphql_parser::schema as s;
 use inflector::Inflector;
+use lru_time_cache::LruCache;
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::convert::{From, TryInto};
 use std::fmt::{self, Write};
+use std::ops::Deref;
+use std::slice;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 use std::time::{Duration, Instant};
 
 use crate::relational_queries::{
-    self as rq, ClampRangeQuery, ConflictingEntityQuery, DeleteByPrefixQuery,
-    DeleteDynamicDataSourcesQuery, DeleteQuery, EntityData, FilterCollection, FilterQuery,
-    FindManyQuery, FindQuery, InsertQuery, RevertClampQuery, RevertRemoveQuery, UpdateQuery,
+    self as rq, BlockEntityData, CachedQuery, ChangedSinceQuery, ClampRangeManyQuery,
+    ClampRangeQuery, ConflictingEntityQuery, CountDistinctData, CountDistinctQuery,
+    CumulativeGasData, CumulativeGasQuery, DanglingReferenceData, DanglingReferenceQuery,
+    DeleteByPrefixQuery, DeleteDynamicDataSourcesQuery, DeleteQuery, DumpQuery, DuplicateLiveData,
+    DuplicateLiveQuery, EntityData, EntitySizeData, EntitySizeQuery, EntityVersionData,
+    ExplainQuery, FilterCollection, FilterQuery, FilterShape, FindAtBlocksQuery, FindEarliestQuery,
+    FindManyQuery, FindQuery, GroupCountData, GroupCountQuery, HistoryQuery, InsertManyQuery,
+    InsertQuery, LatestPerGroupQuery, MaterializeQuery, PruneQuery, QueryFilter, QueryPlanLine,
+    RecencyQuery, RevertClampQuery, RevertRemoveQuery, SampleQuery, ScoredQuery, SelectivityData,
+    SelectivityQuery, UnionData, UnionQuery, UnionSource, UpdateQuery,
 };
+use graph::data::graphql::ext::{DirectiveExt, DirectiveFinder, ValueExt};
 use graph::data::schema::{FulltextConfig, FulltextDefinition, Schema, SCHEMA_TYPE_NAME};
+use graph::data::store::scalar::big_decimal_round_half_even;
 use graph::data::subgraph::schema::{
     DynamicEthereumContractDataSourceEntity, POI_OBJECT, POI_TABLE,
 };
 use graph::prelude::{
-    format_err, info, BlockNumber, Entity, EntityChange, EntityChangeOperation, EntityCollection,
-    EntityFilter, EntityKey, EntityOrder, EntityRange, EthereumBlockPointer, Logger,
-    QueryExecutionError, StoreError, StoreEvent, SubgraphDeploymentId, Value, ValueType,
+    format_err, info, BigInt, BlockHeight, BlockNumber, Entity, EntityChange,
+    EntityChangeOperation, EntityCollection, EntityFilter, EntityKey, EntityModification,
+    EntityOrder, EntityRange, EthereumBlockPointer, Logger, QueryExecutionError, StoreError,
+    StoreEvent, SubgraphDeploymentId, TransactionAbortError, Value, ValueType,
 };
 
 use crate::block_range::{BLOCK_RANGE_COLUMN, BLOCK_UNVERSIONED};
+use crate::encryption::EncryptionKey;
 use crate::entities::STRING_PREFIX_SIZE;
 
 /// A string we use as a SQL name for a table or column. The important thing
@@ -131,7 +150,9 @@ pub enum IdType {
     Bytes,
 }
 
-type EnumMap = BTreeMap<String, Arc<BTreeSet<String>>>;
+/// Maps the name of an enum to its values in the order in which they
+/// were declared in the GraphQL schema
+type EnumMap = BTreeMap<String, Arc<Vec<String>>>;
 
 #[derive(Debug, Clone)]
 pub struct Layout {
@@ -143,11 +164,23 @@ pub struct Layout {
     pub subgraph: SubgraphDeploymentId,
     /// The database schema for this subgraph
     pub schema: String,
-    /// Enums defined in the schema and their possible values. The names
-    /// are the original GraphQL names
+    /// Enums defined in the schema and their possible values in declaration
+    /// order. The names are the original GraphQL names
     pub enums: EnumMap,
     /// The query to count all entities
     pub count_query: String,
+    /// Whether `insert` and `update` should silently ignore attributes
+    /// that are not declared in the GraphQL schema rather than reject
+    /// them. This is off by default, and only meant to allow ingesting
+    /// entities written for a newer version of the schema; see
+    /// `with_unknown_attributes_allowed`
+    allow_unknown_attributes: bool,
+    /// The key for attributes marked with `@encrypted`. Without it,
+    /// entities with values for such attributes can neither be written
+    /// nor read
+    pub encryption_key: Option<EncryptionKey>,
+    /// The SQL text of queries we ran before; see `QueryCache`
+    query_cache: Arc<QueryCache>,
 }
 
 impl Layout {
@@ -176,36 +209,44 @@ impl Layout {
         let table_name = SqlName::verbatim(POI_TABLE.to_owned());
 
         if create_proof_of_indexing {
+            let columns = vec![
+                Column {
+                    name: SqlName::from("digest"),
+                    field: "digest".to_owned(),
+                    field_type: q::Type::NonNullType(Box::new(q::Type::NamedType(
+                        "String".to_owned(),
+                    ))),
+                    column_type: ColumnType::String,
+                    fulltext_fields: None,
+                    is_reference: false,
+                    encrypted: false,
+                },
+                Column {
+                    name: SqlName::from(PRIMARY_KEY_COLUMN),
+                    field: PRIMARY_KEY_COLUMN.to_owned(),
+                    field_type: q::Type::NonNullType(Box::new(q::Type::NamedType(
+                        "String".to_owned(),
+                    ))),
+                    column_type: ColumnType::String,
+                    fulltext_fields: None,
+                    is_reference: false,
+                    encrypted: false,
+                },
+            ];
             let poi_table = Table {
                 object: POI_OBJECT.to_owned(),
                 qualified_name: SqlName::qualified_name(&schema, &table_name),
                 name: table_name,
-                columns: vec![
-                    Column {
-                        name: SqlName::from("digest"),
-                        field: "digest".to_owned(),
-                        field_type: q::Type::NonNullType(Box::new(q::Type::NamedType(
-                            "String".to_owned(),
-                        ))),
-                        column_type: ColumnType::String,
-                        fulltext_fields: None,
-                        is_reference: false,
-                    },
-                    Column {
-                        name: SqlName::from(PRIMARY_KEY_COLUMN),
-                        field: PRIMARY_KEY_COLUMN.to_owned(),
-                        field_type: q::Type::NonNullType(Box::new(q::Type::NamedType(
-                            "String".to_owned(),
-                        ))),
-                        column_type: ColumnType::String,
-                        fulltext_fields: None,
-                        is_reference: false,
-                    },
-                ],
+                column_index: ColumnIndex::new(&columns),
+                columns,
+                derived_fields: vec![],
+                order_indexes: vec![],
+                interfaces: vec![],
                 /// The position of this table in all the tables for this layout; this
                 /// is really only needed for the tests to make the names of indexes
                 /// predictable
                 position: tables.len() as u32,
+                explicit_nulls: true,
             };
             tables.push(poi_table);
         }
@@ -227,11 +268,12 @@ impl Layout {
                 TypeDefinition(Interface(_)) => { /* we do not care about interfaces */ }
                 TypeDefinition(Enum(enum_type)) => {
                     SqlName::check_valid_identifier(&enum_type.name, "enum")?;
-                    let values: BTreeSet<_> = enum_type
-                        .values
-                        .iter()
-                        .map(|value| value.name.to_owned())
-                        .collect();
+                    let mut values: Vec<String> = Vec::new();
+                    for value in &enum_type.values {
+                        if !values.contains(&value.name) {
+                            values.push(value.name.to_owned());
+                        }
+                    }
                     enums.insert(enum_type.name.clone(), Arc::new(values));
                 }
                 other => {
@@ -243,19 +285,18 @@ impl Layout {
             }
         }
 
-        let tables: Vec<_> = tables.into_iter().map(|table| Arc::new(table)).collect();
-
-        let count_query = tables
-            .iter()
-            .map(|table| {
-                format!(
-                    "select count(*) from \"{}\".\"{}\" where upper_inf(block_range)",
-                    schema, table.name
-                )
+        // The tables for subgraph metadata are created by migrations and
+        // not from the layout; they do not record explicit nulls
+        let explicit_nulls = !subgraph.is_meta();
+        let tables: Vec<_> = tables
+            .into_iter()
+            .map(|mut table| {
+                table.explicit_nulls = explicit_nulls;
+                Arc::new(table)
             })
-            .collect::<Vec<_>>()
-            .join("\nunion all\n");
-        let count_query = format!("select sum(e.count) from ({}) e", count_query);
+            .collect();
+
+        let count_query = Self::count_query(schema, tables.iter());
 
         let tables: HashMap<_, _> = tables
             .into_iter()
@@ -271,6 +312,63 @@ impl Layout {
             tables,
             enums,
             count_query,
+            allow_unknown_attributes: false,
+            encryption_key: None,
+            query_cache: Arc::new(QueryCache::default()),
+        })
+    }
+
+    /// Make `insert` and `update` ignore attributes that are not declared
+    /// in the GraphQL schema instead of rejecting them
+    pub fn with_unknown_attributes_allowed(mut self) -> Self {
+        self.allow_unknown_attributes = true;
+        self
+    }
+
+    /// The query to count all current entities in `tables`, which live in
+    /// the database schema `schema`
+    fn count_query<'a>(schema: &str, tables: impl Iterator<Item = &'a Arc<Table>>) -> String {
+        let count_query = tables
+            .map(|table| {
+                format!(
+                    "select count(*) from \"{}\".\"{}\" where upper_inf(block_range)",
+                    schema, table.name
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\nunion all\n");
+        format!("select sum(e.count) from ({}) e", count_query)
+    }
+
+    /// Return a copy of this layout for the deployment `subgraph` whose
+    /// tables live in the database schema `schema`. Deployments of the
+    /// same GraphQL schema that are stored in different database schemas
+    /// can that way all be accessed through one layout, on the same
+    /// connection, with every query naming the tables of the right
+    /// deployment. The tables in `schema` must have been created for the
+    /// same GraphQL schema as this layout; that is not checked
+    pub fn for_deployment(
+        &self,
+        subgraph: SubgraphDeploymentId,
+        schema: &str,
+    ) -> Result<Layout, StoreError> {
+        SqlName::check_valid_identifier(schema, "database schema")?;
+        let tables: HashMap<_, _> = self
+            .tables
+            .iter()
+            .map(|(object, table)| (object.clone(), Arc::new(table.in_schema(schema))))
+            .collect();
+        let count_query = Self::count_query(schema, tables.values());
+        Ok(Layout {
+            id_type: self.id_type,
+            tables,
+            subgraph,
+            schema: schema.to_owned(),
+            enums: self.enums.clone(),
+            count_query,
+            allow_unknown_attributes: self.allow_unknown_attributes,
+            encryption_key: self.encryption_key.clone(),
+            query_cache: Arc::new(QueryCache::default()),
         })
     }
 
@@ -280,6 +378,7 @@ impl Layout {
         subgraph: SubgraphDeploymentId,
         document: &s::Document,
     ) -> Result<Layout, StoreError> {
+        check_interface_implementations(document)?;
         let layout =
             crate::relational::Layout::new(document, IdType::String, subgraph, schema_name, true)?;
         let sql = layout
@@ -348,8 +447,8 @@ impl Layout {
         let block_to_revert: BlockNumber = (block.number + 1)
             .try_into()
             .expect("block numbers fit into an i32");
-        self.revert_block(conn, block_to_revert)?;
-        metadata.revert_metadata(conn, &self.subgraph, block_to_revert)?;
+        self.revert_block(conn, block_to_revert.into())?;
+        metadata.revert_metadata(conn, &self.subgraph, block_to_revert.into())?;
         info!(logger, "Rewound subgraph to block {}", block.number;
               "time_ms" => start.elapsed().as_millis());
         Ok(())
@@ -390,7 +489,10 @@ impl Layout {
                 self.schema,
                 name.quoted()
             )?;
-            for value in values.iter() {
+            // The values of the Postgres enum are sorted so that the DDL
+            // does not depend on the order of the declaration; ordering by
+            // declaration order is done explicitly in queries
+            for value in values.iter().collect::<BTreeSet<_>>() {
                 write!(out, "{}'{}'", sep, value)?;
                 sep = ", "
             }
@@ -423,26 +525,396 @@ impl Layout {
             .ok_or_else(|| StoreError::UnknownTable(entity.to_owned()))
     }
 
+    /// Start building an entity of type `entity_type` whose attributes are
+    /// checked against this layout when the entity is built
+    pub fn entity_builder(&self, entity_type: &str) -> Result<EntityBuilder, StoreError> {
+        Ok(EntityBuilder {
+            table: self.table_for_entity(entity_type)?.as_ref(),
+            entity: Entity::new(),
+        })
+    }
+
+    /// The fields of `entity_type` as declared in the GraphQL schema. The
+    /// fields that are stored in the entity's table come first, in the
+    /// order in which the schema declares them, followed by the fields
+    /// that are derived with `@derivedFrom`. Fulltext search fields are
+    /// not part of the entity type and are not returned
+    pub fn graphql_fields(&self, entity_type: &str) -> Result<Vec<FieldDef>, StoreError> {
+        let table = self.table_for_entity(entity_type)?;
+        let stored = table
+            .columns
+            .iter()
+            .filter(|column| !column.is_fulltext())
+            .map(|column| FieldDef::new(&column.field, &column.field_type, false));
+        let derived = table
+            .derived_fields
+            .iter()
+            .map(|derived| FieldDef::new(&derived.field, &derived.field_type, true));
+        Ok(stored.chain(derived).collect())
+    }
+
     pub fn find(
         &self,
         conn: &PgConnection,
         entity: &str,
         id: &str,
-        block: BlockNumber,
+        block: BlockHeight,
     ) -> Result<Option<Entity>, StoreError> {
         let table = self.table_for_entity(entity)?;
-        FindQuery::new(table.as_ref(), id, block)
+        FindQuery::new(table.as_ref(), id, block.number())
             .get_result::<EntityData>(conn)
             .optional()?
             .map(|entity_data| entity_data.to_entity(self))
             .transpose()
     }
 
+    /// Return the number of bytes that the version of `entity[id]` that is
+    /// current at `block` takes up in the database. That is the size of
+    /// the whole row, including the block range and other bookkeeping
+    /// columns, and counts large values that Postgres compressed with their
+    /// compressed size. It is an error if the entity does not exist at
+    /// `block`
+    pub fn entity_size(
+        &self,
+        conn: &PgConnection,
+        entity: &str,
+        id: &str,
+        block: BlockHeight,
+    ) -> Result<i64, StoreError> {
+        let table = self.table_for_entity(entity)?;
+        EntitySizeQuery::new(table.as_ref(), id, block.number())
+            .get_result::<EntitySizeData>(conn)
+            .optional()?
+            .map(|data| data.size)
+            .ok_or_else(|| {
+                StoreError::QueryExecutionError(format!(
+                    "can not determine the size of {}[{}] since it does not exist at block {}",
+                    entity,
+                    id,
+                    block.number()
+                ))
+            })
+    }
+
+    /// Like `find`, but distinguish attributes that were explicitly set to
+    /// null from attributes that were never set. `find` leaves both out of
+    /// the entity; here, attributes that were written as `Value::Null` are
+    /// present in the entity as `Value::Null`. Entities that go through
+    /// the store have their nulls removed before they are written, and
+    /// only writes directly through the `Layout` record explicit nulls.
+    /// Tables for subgraph metadata do not record them at all
+    pub fn find_with_nulls(
+        &self,
+        conn: &PgConnection,
+        entity: &str,
+        id: &str,
+        block: BlockHeight,
+    ) -> Result<Option<Entity>, StoreError> {
+        let table = self.table_for_entity(entity)?;
+        FindQuery::new(table.as_ref(), id, block.number())
+            .get_result::<EntityData>(conn)
+            .optional()?
+            .map(|entity_data| {
+                let nulls = entity_data.explicit_nulls();
+                let mut entity = entity_data.to_entity(self)?;
+                for field in nulls {
+                    // Ignore fields that are no longer in the schema
+                    if table.column_for_field(&field).is_ok() {
+                        entity.insert(field, Value::Null);
+                    }
+                }
+                Ok(entity)
+            })
+            .transpose()
+    }
+
+    /// Like `find`, but if no version of the entity was current at `block`
+    /// and `fallback` is `true`, return the earliest version of the entity
+    /// instead. The result indicates which of the two cases happened
+    pub fn find_with_fallback(
+        &self,
+        conn: &PgConnection,
+        entity: &str,
+        id: &str,
+        block: BlockHeight,
+        fallback: bool,
+    ) -> Result<Option<FoundEntity>, StoreError> {
+        if let Some(found) = self.find(conn, entity, id, block)? {
+            return Ok(Some(FoundEntity::AtBlock(found)));
+        }
+        if !fallback {
+            return Ok(None);
+        }
+        let table = self.table_for_entity(entity)?;
+        FindEarliestQuery::new(table.as_ref(), id)
+            .get_result::<EntityData>(conn)
+            .optional()?
+            .map(|entity_data| entity_data.to_entity(self).map(FoundEntity::Earliest))
+            .transpose()
+    }
+
+    /// Return a random sample of `n` entities of type `entity` that are
+    /// current at `block`. The sample is determined by `seed`, which must
+    /// be between -1 and 1: as long as the entities do not change, calls
+    /// with the same seed return the same sample.
+    ///
+    /// We use `order by random()` after seeding Postgres' random number
+    /// generator with `setseed` rather than `tablesample`. `tablesample`
+    /// is much cheaper for large tables since it only reads some of the
+    /// table's pages, but it picks rows before versions that are not
+    /// current at `block` are removed, and can therefore return fewer than
+    /// `n` entities, and its `repeatable` option only guarantees the same
+    /// sample as long as the physical layout of the table does not change.
+    /// The downside of `order by random()` is that it reads all current
+    /// entities of the type, which makes it expensive for large tables
+    pub fn sample(
+        &self,
+        conn: &PgConnection,
+        entity: &str,
+        n: u32,
+        seed: f64,
+        block: BlockHeight,
+    ) -> Result<Vec<Entity>, StoreError> {
+        if !(-1.0..=1.0).contains(&seed) {
+            return Err(StoreError::QueryExecutionError(format!(
+                "the seed for sampling must be between -1 and 1, but is {}",
+                seed
+            )));
+        }
+        let table = self.table_for_entity(entity)?;
+        conn.transaction(|| {
+            sql_query("select setseed($1)")
+                .bind::<Double, _>(seed)
+                .execute(conn)?;
+            SampleQuery::new(table.as_ref(), n, block.number())
+                .load::<EntityData>(conn)?
+                .into_iter()
+                .map(|entity_data| entity_data.to_entity(self))
+                .collect()
+        })
+    }
+
+    /// Estimate which fraction of the entities of type `entity_type` that
+    /// are current at `block` match `filter` by checking the filter against
+    /// a random sample of roughly `sample_pct` percent of the table. The
+    /// sample is drawn with `tablesample`, which only looks at the sampled
+    /// rows and is therefore cheap even for large tables, but different
+    /// calls will produce different estimates. If the sample does not
+    /// contain any entities, the estimate is 0
+    pub fn estimate_selectivity(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+        filter: EntityFilter,
+        sample_pct: f64,
+        block: BlockHeight,
+    ) -> Result<f64, StoreError> {
+        let block = block.number();
+        if !(sample_pct > 0.0 && sample_pct <= 100.0) {
+            return Err(StoreError::QueryExecutionError(format!(
+                "the sample size must be more than 0 and at most 100 percent, but is {}",
+                sample_pct
+            )));
+        }
+        let table = self.table_for_entity(entity_type)?.as_ref();
+        let filter = QueryFilter::new(&filter, table, self, block)?;
+        let data = SelectivityQuery::new(table, filter, sample_pct, block)
+            .get_result::<SelectivityData>(conn)?;
+        if data.sampled == 0 {
+            return Ok(0.0);
+        }
+        Ok(data.matching as f64 / data.sampled as f64)
+    }
+
+    /// Return all entities of type `entity_type` that are current at
+    /// `block`, ordered by their id. Since the order does not depend on
+    /// how Postgres decides to execute the query, dumping the same data
+    /// always produces the same result
+    pub fn dump(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+        block: BlockHeight,
+    ) -> Result<Vec<Entity>, StoreError> {
+        let table = self.table_for_entity(entity_type)?;
+        DumpQuery::new(table.as_ref(), block.number())
+            .load::<EntityData>(conn)?
+            .into_iter()
+            .map(|entity_data| entity_data.to_entity(self))
+            .collect()
+    }
+
+    /// Return all entities of type `entity_type` that are current at
+    /// `block` and were created or last changed after `since_block`,
+    /// ordered by their id. Entities that were deleted after `since_block`
+    /// are not live at `block` and are therefore not part of the result
+    pub fn changed_since(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+        since_block: BlockHeight,
+        block: BlockHeight,
+    ) -> Result<Vec<Entity>, StoreError> {
+        let table = self.table_for_entity(entity_type)?;
+        ChangedSinceQuery::new(table.as_ref(), since_block.number(), block.number())
+            .load::<EntityData>(conn)?
+            .into_iter()
+            .map(|entity_data| entity_data.to_entity(self))
+            .collect()
+    }
+
+    /// Return the ids of all entities of type `entity_type` that are
+    /// current at `block` and whose `reference_attribute` points to an
+    /// entity that does not exist at `block`, ordered by id. Entities whose
+    /// reference is not set are not considered dangling
+    pub fn find_dangling_references(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+        reference_attribute: &str,
+        block: BlockHeight,
+    ) -> Result<Vec<String>, StoreError> {
+        let table = self.table_for_entity(entity_type)?;
+        let reference = table.column_for_field(reference_attribute)?;
+        if !reference.is_reference() {
+            return Err(StoreError::QueryExecutionError(format!(
+                "`{}.{}` has type {} and is not a reference to another entity",
+                table.object, reference_attribute, reference.field_type
+            )));
+        }
+        let target = self.table_for_entity(reference.named_type())?;
+        Ok(
+            DanglingReferenceQuery::new(table.as_ref(), reference, target.as_ref(), block.number())
+                .load::<DanglingReferenceData>(conn)?
+                .into_iter()
+                .map(|data| data.id)
+                .collect(),
+        )
+    }
+
+    /// Return the ids of entities of type `entity_type` that have more than
+    /// one live version, i.e., more than one version whose block range has
+    /// no upper bound, ordered by id. That should never happen, and this is
+    /// meant as an integrity check: such entities break queries at blocks
+    /// after their second live version was created
+    pub fn find_duplicate_live(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+    ) -> Result<Vec<String>, StoreError> {
+        let table = self.table_for_entity(entity_type)?;
+        Ok(DuplicateLiveQuery::new(table.as_ref())
+            .load::<DuplicateLiveData>(conn)?
+            .into_iter()
+            .map(|data| data.id)
+            .collect())
+    }
+
+    /// Check that the columns of all tables in the database have the types
+    /// that this layout expects. Manual changes to the database can make
+    /// the two diverge, which then leads to confusing errors when entities
+    /// are read. Return an error that lists all columns whose type does
+    /// not match, or which are missing entirely
+    pub fn verify_columns(&self, conn: &PgConnection) -> Result<(), StoreError> {
+        let actual = sql_query(
+            "select table_name, column_name, udt_name
+               from information_schema.columns
+              where table_schema = $1",
+        )
+        .bind::<Text, _>(&self.schema)
+        .load::<ColumnTypeInfo>(conn)?
+        .into_iter()
+        .map(|info| ((info.table_name, info.column_name), info.udt_name))
+        .collect::<HashMap<_, _>>();
+
+        let mut tables = self.tables.values().collect::<Vec<_>>();
+        tables.sort_by_key(|table| table.position);
+        let mut mismatches = Vec::new();
+        for table in tables {
+            for column in &table.columns {
+                let expected = column.udt_name();
+                let key = (
+                    table.name.as_str().to_owned(),
+                    column.name.as_str().to_owned(),
+                );
+                match actual.get(&key) {
+                    Some(udt_name) if udt_name == &expected => { /* all good */ }
+                    Some(udt_name) => mismatches.push(format!(
+                        "{}.{} should have type {} but has type {}",
+                        table.name, column.name, expected, udt_name
+                    )),
+                    None => mismatches.push(format!(
+                        "{}.{} of type {} does not exist",
+                        table.name, column.name, expected
+                    )),
+                }
+            }
+        }
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(StoreError::Unknown(format_err!(
+                "the tables in schema {} do not match the layout: {}",
+                self.schema,
+                mismatches.join(", ")
+            )))
+        }
+    }
+
+    /// Return all versions of the entity `entity[id]`, ordered by the block
+    /// at which they were created. Each entry consists of the version and
+    /// the lower and upper bound of its block range; the upper bound is
+    /// `None` for the current version
+    pub fn history(
+        &self,
+        conn: &PgConnection,
+        entity: &str,
+        id: &str,
+    ) -> Result<Vec<(Entity, BlockNumber, Option<BlockNumber>)>, StoreError> {
+        let table = self.table_for_entity(entity)?;
+        HistoryQuery::new(table.as_ref(), id)
+            .load::<EntityVersionData>(conn)?
+            .into_iter()
+            .map(|data| data.to_entity_version(self))
+            .collect()
+    }
+
+    /// Return the version of the entity `entity_type[id]` that was current
+    /// at each of `blocks`, in the order of `blocks`. The entity is `None`
+    /// for blocks at which it did not exist. This uses one query for all
+    /// blocks, which makes it much cheaper than calling `find` for each
+    /// block when following how an entity changed over time
+    pub fn find_at_blocks(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+        id: &str,
+        blocks: &[BlockHeight],
+    ) -> Result<Vec<(BlockHeight, Option<Entity>)>, StoreError> {
+        if blocks.is_empty() {
+            return Ok(vec![]);
+        }
+        let table = self.table_for_entity(entity_type)?;
+        let numbers = blocks
+            .iter()
+            .map(|block| block.number())
+            .collect::<Vec<_>>();
+        let versions = FindAtBlocksQuery::new(table.as_ref(), id, &numbers)
+            .load::<BlockEntityData>(conn)?
+            .into_iter()
+            .map(|data| data.to_entity(self))
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        Ok(blocks
+            .iter()
+            .map(|block| (*block, versions.get(&block.number()).cloned()))
+            .collect())
+    }
+
     pub fn find_many(
         &self,
         conn: &PgConnection,
         ids_for_type: BTreeMap<&str, Vec<&str>>,
-        block: BlockNumber,
+        block: BlockHeight,
     ) -> Result<BTreeMap<String, Vec<Entity>>, StoreError> {
         let mut tables = Vec::new();
         for entity_type in ids_for_type.keys() {
@@ -452,7 +924,7 @@ impl Layout {
             schema: &self.schema,
             ids_for_type,
             tables,
-            block,
+            block: block.number(),
         };
         let mut entities_for_type: BTreeMap<String, Vec<Entity>> = BTreeMap::new();
         for data in query.load::<EntityData>(conn)? {
@@ -464,28 +936,258 @@ impl Layout {
         Ok(entities_for_type)
     }
 
+    /// Find the entities of type `entity_type` with the given `ids`. The
+    /// result has one entry for each id, in the same order as `ids`; it is
+    /// `None` if there is no entity with that id at `block`
+    pub fn find_ordered(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+        ids: &[&str],
+        block: BlockHeight,
+    ) -> Result<Vec<Option<Entity>>, StoreError> {
+        let mut ids_for_type = BTreeMap::new();
+        ids_for_type.insert(entity_type, ids.to_vec());
+        let entities: HashMap<String, Entity> = self
+            .find_many(conn, ids_for_type, block)?
+            .remove(entity_type)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|entity| Ok((entity.id()?, entity)))
+            .collect::<Result<_, StoreError>>()?;
+        Ok(ids.iter().map(|id| entities.get(*id).cloned()).collect())
+    }
+
+    /// Return an error if `entity` has an attribute that `table` does not
+    /// declare, unless `allow_unknown_attributes` is set
+    fn check_attributes(&self, table: &Table, entity: &Entity) -> Result<(), StoreError> {
+        if self.allow_unknown_attributes {
+            return Ok(());
+        }
+        match entity.keys().find(|attr| !table.has_attribute(attr)) {
+            Some(attr) => Err(StoreError::UnknownAttribute(
+                table.object.clone(),
+                attr.clone(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn encryption_key(&self, column: &Column) -> Result<&EncryptionKey, StoreError> {
+        self.encryption_key.as_ref().ok_or_else(|| {
+            StoreError::QueryExecutionError(format!(
+                "the attribute {} is encrypted, but no encryption key was provided",
+                column.field
+            ))
+        })
+    }
+
+    /// Encrypt `value` for storing it in the encrypted `column`. Since the
+    /// encryption is deterministic, this is also how values in filters on
+    /// `column` are turned into something that can be compared with what
+    /// is stored
+    pub(crate) fn encrypt_value(
+        &self,
+        column: &Column,
+        value: &Value,
+    ) -> Result<Value, StoreError> {
+        let key = self.encryption_key(column)?;
+        match value {
+            Value::Null => Ok(Value::Null),
+            Value::String(s) => Ok(Value::Bytes(key.encrypt(s.as_bytes()).as_slice().into())),
+            Value::Bytes(b) => Ok(Value::Bytes(key.encrypt(b.as_slice()).as_slice().into())),
+            _ => Err(StoreError::QueryExecutionError(format!(
+                "the encrypted attribute {} can only hold a {}, but got {}",
+                column.field,
+                column.named_type(),
+                value
+            ))),
+        }
+    }
+
+    /// Turn a `value` read from the encrypted `column` back into the
+    /// plaintext
+    pub(crate) fn decrypt_value(&self, column: &Column, value: Value) -> Result<Value, StoreError> {
+        let data = match value {
+            Value::Bytes(data) => data,
+            value => return Ok(value),
+        };
+        let plaintext = self.encryption_key(column)?.decrypt(data.as_slice())?;
+        if column.named_type() == "String" {
+            String::from_utf8(plaintext)
+                .map(Value::String)
+                .map_err(|e| {
+                    StoreError::Unknown(format_err!(
+                        "the decrypted value of {} is not valid UTF-8: {}",
+                        column.field,
+                        e
+                    ))
+                })
+        } else {
+            Ok(Value::Bytes(plaintext.as_slice().into()))
+        }
+    }
+
+    /// Replace the values of all encrypted attributes of `entity` with
+    /// their ciphertext
+    fn encrypt_attributes(&self, table: &Table, entity: &mut Entity) -> Result<(), StoreError> {
+        for column in table.columns.iter().filter(|column| column.is_encrypted()) {
+            if let Some(value) = entity.get(&column.field) {
+                let value = self.encrypt_value(column, value)?;
+                entity.insert(column.field.clone(), value);
+            }
+        }
+        Ok(())
+    }
+
     pub fn insert(
         &self,
         conn: &PgConnection,
         key: &EntityKey,
-        entity: Entity,
-        block: BlockNumber,
+        mut entity: Entity,
+        block: BlockHeight,
     ) -> Result<(), StoreError> {
         let table = self.table_for_entity(&key.entity_type)?;
-        let query = InsertQuery::new(table, key, entity, block)?;
-        query.execute(conn)?;
+        self.check_attributes(table, &entity)?;
+        self.encrypt_attributes(table, &mut entity)?;
+        let query = InsertQuery::new(table, key, entity, block.number())?;
+        query
+            .execute(conn)
+            .map_err(|e| insert_error(table, slice::from_ref(key), e))?;
         Ok(())
     }
 
-    pub fn insert_unversioned(
+    /// Insert `entity` unless there already is a live version of an entity
+    /// with the same id. Return `Left` if `entity` was inserted, and `Right`
+    /// with the live version otherwise, which makes speculative writes
+    /// possible without having to read the entity first. It is an error if
+    /// the entity does not exist anymore, but has a version that was still
+    /// current at `block`
+    pub fn insert_or_get(
+        &self,
+        conn: &PgConnection,
+        key: &EntityKey,
+        mut entity: Entity,
+        block: BlockHeight,
+    ) -> Result<Either<(), Entity>, StoreError> {
+        let table = self.table_for_entity(&key.entity_type)?;
+        self.check_attributes(table, &entity)?;
+        self.encrypt_attributes(table, &mut entity)?;
+        let inserted = InsertQuery::new(table, key, entity, block.number())?
+            .do_nothing_on_conflict()
+            .execute(conn)
+            .map_err(|e| insert_error(table, slice::from_ref(key), e))?;
+        if inserted > 0 {
+            return Ok(Either::Left(()));
+        }
+        match self.find(conn, &key.entity_type, &key.entity_id, BlockHeight::MAX)? {
+            Some(existing) => Ok(Either::Right(existing)),
+            None => Err(StoreError::QueryExecutionError(format!(
+                "can not insert {}[{}] at block {} since a version of it that was \
+                 deleted later is current at that block",
+                key.entity_type,
+                key.entity_id,
+                block.number()
+            ))),
+        }
+    }
+
+    /// Like `insert`, but `key.entity_type` can also be an interface. The
+    /// entity is stored in the table for the type in its `__typename`,
+    /// which must either be `key.entity_type` or a type that implements
+    /// it. Without a `__typename`, this is the same as `insert`
+    pub fn insert_by_typename(
         &self,
         conn: &PgConnection,
         key: &EntityKey,
         entity: Entity,
+        block: BlockHeight,
+    ) -> Result<(), StoreError> {
+        let typename = match entity.get("__typename") {
+            None => return self.insert(conn, key, entity, block),
+            Some(Value::String(typename)) => typename.clone(),
+            Some(value) => {
+                return Err(StoreError::QueryExecutionError(format!(
+                    "the __typename of {}[{}] must be a string, but is {}",
+                    key.entity_type, key.entity_id, value
+                )))
+            }
+        };
+        if typename == key.entity_type {
+            return self.insert(conn, key, entity, block);
+        }
+        let table = self.table_for_entity(&typename)?;
+        if !table.interfaces.contains(&key.entity_type) {
+            return Err(StoreError::QueryExecutionError(format!(
+                "can not insert {}[{}] with __typename {} since {} does not implement {}",
+                key.entity_type, key.entity_id, typename, typename, key.entity_type
+            )));
+        }
+        let key = EntityKey {
+            entity_type: typename,
+            ..key.clone()
+        };
+        self.insert(conn, &key, entity, block)
+    }
+
+    /// Insert the entities of type `entity_type` that `iter` produces,
+    /// `chunk_size` of them at a time with one `insert` statement per
+    /// chunk. Entities are only pulled from `iter` as they are needed, and
+    /// at most `chunk_size` of them are held in memory at any point, so
+    /// that this can be used to import more entities than fit into
+    /// memory. Postgres limits a statement to 65535 bind parameters, and
+    /// `chunk_size` times the number of attributes of an entity must stay
+    /// below that. Return the number of entities that were inserted
+    pub fn insert_from_iter(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+        iter: impl Iterator<Item = (EntityKey, Entity)>,
+        block: BlockHeight,
+        chunk_size: usize,
+    ) -> Result<usize, StoreError> {
+        if chunk_size == 0 {
+            return Err(StoreError::QueryExecutionError(
+                "the chunk size for inserting entities must be positive".to_owned(),
+            ));
+        }
+        let table = self.table_for_entity(entity_type)?;
+
+        let mut count = 0;
+        let mut chunk = Vec::with_capacity(chunk_size);
+        for (key, mut entity) in iter {
+            if key.entity_type != entity_type {
+                return Err(StoreError::QueryExecutionError(format!(
+                    "can not insert entity {}[{}] together with entities of type {}",
+                    key.entity_type, key.entity_id, entity_type
+                )));
+            }
+            self.check_attributes(table, &entity)?;
+            self.encrypt_attributes(table, &mut entity)?;
+            chunk.push((key, entity));
+            if chunk.len() == chunk_size {
+                count += insert_chunk(conn, table, chunk.drain(..).collect(), block.number())?;
+            }
+        }
+        if !chunk.is_empty() {
+            count += insert_chunk(conn, table, chunk, block.number())?;
+        }
+        Ok(count)
+    }
+
+    pub fn insert_unversioned(
+        &self,
+        conn: &PgConnection,
+        key: &EntityKey,
+        mut entity: Entity,
     ) -> Result<(), StoreError> {
         let table = self.table_for_entity(&key.entity_type)?;
+        self.check_attributes(table, &entity)?;
+        self.encrypt_attributes(table, &mut entity)?;
         let query = InsertQuery::new(table, key, entity, BLOCK_UNVERSIONED)?;
-        query.execute(conn)?;
+        query
+            .execute(conn)
+            .map_err(|e| insert_error(table, slice::from_ref(key), e))?;
         Ok(())
     }
 
@@ -501,6 +1203,24 @@ impl Layout {
             .map(|data| data.entity))
     }
 
+    /// Like `conflicting_entity`, but only consider the versions of
+    /// entities that are current at `block`. An entity that was deleted
+    /// before `block`, or that was only created after it, does not
+    /// conflict
+    pub fn conflicting_entity_at(
+        &self,
+        conn: &PgConnection,
+        entity_id: &String,
+        entities: Vec<&String>,
+        block: BlockHeight,
+    ) -> Result<Option<String>, StoreError> {
+        Ok(ConflictingEntityQuery::new(self, entities, entity_id)?
+            .at_block(block.number())
+            .load(conn)?
+            .pop()
+            .map(|data| data.entity))
+    }
+
     /// order is a tuple (attribute, value_type, direction)
     pub fn query(
         &self,
@@ -510,19 +1230,401 @@ impl Layout {
         filter: Option<EntityFilter>,
         order: Option<(String, ValueType, EntityOrder)>,
         range: EntityRange,
-        block: BlockNumber,
+        block: BlockHeight,
     ) -> Result<Vec<Entity>, QueryExecutionError> {
-        fn log_query_timing(
-            logger: &Logger,
-            query: &FilterQuery,
-            elapsed: Duration,
-            entity_count: usize,
-        ) {
-            // 20kB
-            const MAXLEN: usize = 20_480;
+        self.query_with_options(
+            logger,
+            conn,
+            collection,
+            filter,
+            order,
+            range,
+            block,
+            QueryOptions::default(),
+        )
+    }
 
-            if !*graph::log::LOG_SQL_TIMING {
-                return;
+    /// Query several entity types at once, like `query` does for interfaces,
+    /// but with a separate filter for each type: the result consists of the
+    /// entities of each type in `type_filters` that match the filter for
+    /// that type, e.g., the cats with a certain name and the dogs with a
+    /// different one. Each type can only appear once; several conditions
+    /// for the same type need to be combined with `EntityFilter::Or`. The
+    /// order and range apply to the combined entities, and the order must
+    /// be on an attribute that all types have. Ordering by a fulltext
+    /// attribute is not supported
+    pub fn query_with_type_filters(
+        &self,
+        conn: &PgConnection,
+        type_filters: Vec<(String, Option<EntityFilter>)>,
+        order: Option<(String, ValueType, EntityOrder)>,
+        range: EntityRange,
+        block: BlockHeight,
+    ) -> Result<Vec<Entity>, QueryExecutionError> {
+        let mut seen = HashSet::new();
+        for (entity_type, _) in &type_filters {
+            if !seen.insert(entity_type) {
+                return Err(StoreError::QueryExecutionError(format!(
+                    "the entity type `{}` can only have one filter; use an `or` \
+                     filter to combine several conditions for it",
+                    entity_type
+                ))
+                .into());
+            }
+        }
+        let first = match type_filters.first() {
+            Some((entity_type, _)) => self.table_for_entity(entity_type)?,
+            None => return Ok(vec![]),
+        };
+        if let Some((attribute, _, _)) = &order {
+            if first.column_for_field(attribute)?.is_fulltext() {
+                return Err(StoreError::QueryExecutionError(format!(
+                    "queries with a filter for each entity type can not be ordered by \
+                     the fulltext attribute `{}`",
+                    attribute
+                ))
+                .into());
+            }
+        }
+
+        let block = block.number();
+        let collection = FilterCollection::with_type_filters(self, &type_filters, block)?;
+        let query = FilterQuery::new(&collection, None, order, range, block)?;
+        let query_clone = query.clone();
+        query
+            .load::<EntityData>(conn)
+            .map_err(|e| {
+                QueryExecutionError::ResolveEntitiesError(format!(
+                    "{}, query = {:?}",
+                    e,
+                    debug_query(&query_clone).to_string()
+                ))
+            })?
+            .into_iter()
+            .map(|entity_data| {
+                entity_data
+                    .to_entity(self)
+                    .map_err(QueryExecutionError::from)
+            })
+            .collect()
+    }
+
+    /// Return the first entity in `collection` that matches `filter` when
+    /// the entities are sorted by `order`, or `None` if no entity matches.
+    /// This only fetches one entity from the database
+    pub fn find_one(
+        &self,
+        conn: &PgConnection,
+        collection: EntityCollection,
+        filter: Option<EntityFilter>,
+        order: Option<(String, ValueType, EntityOrder)>,
+        block: BlockHeight,
+    ) -> Result<Option<Entity>, StoreError> {
+        let block = block.number();
+        let filter_collection = FilterCollection::new(&self, collection, filter.as_ref(), block)?;
+        let query = FilterQuery::new(
+            &filter_collection,
+            filter.as_ref(),
+            order,
+            EntityRange::first(1),
+            block,
+        )?;
+        query
+            .load::<EntityData>(conn)?
+            .into_iter()
+            .next()
+            .map(|data| data.to_entity(self))
+            .transpose()
+    }
+
+    /// Return one entity of type `entity_type` for each value of
+    /// `group_attr` among the entities that are current at `block`: the
+    /// one with the highest value of `order_attr`, e.g., the transaction
+    /// with the highest nonce for each sender. See `LatestPerGroupQuery`
+    /// for how ties and nulls are handled
+    pub fn latest_per_group(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+        group_attr: &str,
+        order_attr: &str,
+        block: BlockHeight,
+    ) -> Result<Vec<Entity>, StoreError> {
+        let table = self.table_for_entity(entity_type)?.as_ref();
+        let group = table.column_for_field(group_attr)?;
+        let order = table.column_for_field(order_attr)?;
+        LatestPerGroupQuery::new(table, group, order, block.number())
+            .load::<EntityData>(conn)?
+            .into_iter()
+            .map(|data| data.to_entity(self))
+            .collect()
+    }
+
+    /// Return the entities of type `entity_type` that match `filter` at
+    /// `block`, scored by the weighted `predicates`. The score of an entity
+    /// is the sum of the weights of the predicates that it satisfies, and
+    /// is returned as the attribute `__score`. Entities are ordered by
+    /// descending score, and entities with the same score by `id`.
+    /// Entities whose score is below `min_score` are left out
+    pub fn query_scored(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+        filter: Option<EntityFilter>,
+        predicates: Vec<(EntityFilter, i32)>,
+        min_score: Option<i32>,
+        range: EntityRange,
+        block: BlockHeight,
+    ) -> Result<Vec<Entity>, StoreError> {
+        let block = block.number();
+        let table = self.table_for_entity(entity_type)?.as_ref();
+        let filter = filter
+            .as_ref()
+            .map(|filter| QueryFilter::new(filter, table, self, block))
+            .transpose()?;
+        let predicates = predicates
+            .iter()
+            .map(|(predicate, weight)| {
+                QueryFilter::new(predicate, table, self, block).map(|filter| (filter, *weight))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        ScoredQuery::new(table, filter, predicates, min_score, range, block)
+            .load::<EntityData>(conn)?
+            .into_iter()
+            .map(|data| data.to_entity(self))
+            .collect()
+    }
+
+    /// Return the entities of type `entity_type` that match `filter` at
+    /// `block`, ordered by how recently they were created. The recency of
+    /// an entity is `exp(-(block - created) / halflife)`, where `created`
+    /// is the block at which the entity was first written, and is returned
+    /// as the attribute `__recency`. Ordering by descending recency puts
+    /// the newest entities first; entities with the same recency are
+    /// ordered by `id`. Since the recency is relative to `block`, this
+    /// should be used with the block of the subgraph's head rather than
+    /// with `BlockHeight::MAX`
+    pub fn query_by_recency(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+        filter: Option<EntityFilter>,
+        halflife: i32,
+        direction: EntityOrder,
+        range: EntityRange,
+        block: BlockHeight,
+    ) -> Result<Vec<Entity>, StoreError> {
+        let block = block.number();
+        if halflife <= 0 {
+            return Err(StoreError::QueryExecutionError(format!(
+                "the halflife for recency must be a positive number of blocks, not {}",
+                halflife
+            )));
+        }
+        let table = self.table_for_entity(entity_type)?.as_ref();
+        let filter = filter
+            .as_ref()
+            .map(|filter| QueryFilter::new(filter, table, self, block))
+            .transpose()?;
+        RecencyQuery::new(table, filter, halflife, direction, range, block)
+            .load::<EntityData>(conn)?
+            .into_iter()
+            .map(|data| data.to_entity(self))
+            .collect()
+    }
+
+    /// Like `query`, but return the entities column by column: for each
+    /// attribute that any of the entities has, the result contains a
+    /// vector with the value of that attribute for each entity, in the
+    /// order in which `query` would return the entities. Entities that do
+    /// not have an attribute have `Value::Null` in its vector
+    pub fn query_columnar(
+        &self,
+        logger: &Logger,
+        conn: &PgConnection,
+        collection: EntityCollection,
+        filter: Option<EntityFilter>,
+        order: Option<(String, ValueType, EntityOrder)>,
+        range: EntityRange,
+        block: BlockHeight,
+    ) -> Result<ColumnarEntities, QueryExecutionError> {
+        let entities = self.query(logger, conn, collection, filter, order, range, block)?;
+
+        let rows = entities.len();
+        let mut columns: HashMap<String, Vec<Value>> = HashMap::new();
+        for (row, entity) in entities.iter().enumerate() {
+            for (attr, value) in entity.iter() {
+                columns
+                    .entry(attr.clone())
+                    .or_insert_with(|| vec![Value::Null; rows])[row] = value.clone();
+            }
+        }
+        Ok(ColumnarEntities { rows, columns })
+    }
+
+    /// Like `query`, but only return the ids of the matching entities. The
+    /// database only has to produce the id of each entity, which makes this
+    /// considerably cheaper than `query` when the caller loads the entities
+    /// some other way
+    pub fn query_ids(
+        &self,
+        logger: &Logger,
+        conn: &PgConnection,
+        collection: EntityCollection,
+        filter: Option<EntityFilter>,
+        order: Option<(String, ValueType, EntityOrder)>,
+        range: EntityRange,
+        block: BlockHeight,
+    ) -> Result<Vec<String>, QueryExecutionError> {
+        let options = QueryOptions {
+            ids_only: true,
+            ..QueryOptions::default()
+        };
+        self.query_with_options(
+            logger, conn, collection, filter, order, range, block, options,
+        )?
+        .into_iter()
+        .map(|entity| {
+            entity
+                .id()
+                .map_err(|e| QueryExecutionError::ResolveEntitiesError(e.to_string()))
+        })
+        .collect()
+    }
+
+    /// Query the entities of type `entity_type` in all of `layouts` as if
+    /// they were stored in one table, and return each entity together with
+    /// the id of the subgraph it comes from. This is meant for several
+    /// deployments of the same schema; all layouts must have the type and
+    /// declare the attribute to order by with the same type. The `order`
+    /// and `range` apply to the combined entities and not to the entities
+    /// from each layout separately
+    pub fn query_union(
+        layouts: &[&Layout],
+        conn: &PgConnection,
+        entity_type: &str,
+        filter: Option<EntityFilter>,
+        order: Option<(String, ValueType, EntityOrder)>,
+        range: EntityRange,
+        block: BlockHeight,
+    ) -> Result<Vec<(SubgraphDeploymentId, Entity)>, QueryExecutionError> {
+        let block = block.number();
+        let mut sources = Vec::new();
+        let mut sort_type: Option<&q::Type> = None;
+        for layout in layouts {
+            let table = layout.table_for_entity(entity_type)?.as_ref();
+            let filter = filter
+                .as_ref()
+                .map(|filter| QueryFilter::new(filter, table, layout, block))
+                .transpose()?;
+            let sort_column = match &order {
+                Some((attribute, _, _)) => {
+                    let column = table.column_for_field(attribute)?;
+                    if column.is_fulltext() {
+                        return Err(StoreError::QueryExecutionError(format!(
+                            "queries across several subgraphs can not be ordered by the \
+                             fulltext field `{}`",
+                            attribute
+                        ))
+                        .into());
+                    }
+                    match sort_type {
+                        Some(field_type) if field_type != &column.field_type => {
+                            return Err(StoreError::QueryExecutionError(format!(
+                                "`{}.{}` has type {} in subgraph {}, but type {} in other \
+                                 subgraphs",
+                                entity_type,
+                                attribute,
+                                column.field_type,
+                                layout.subgraph,
+                                field_type
+                            ))
+                            .into());
+                        }
+                        _ => sort_type = Some(&column.field_type),
+                    }
+                    Some(column)
+                }
+                None => None,
+            };
+            sources.push(UnionSource {
+                table,
+                filter,
+                sort_column,
+            });
+        }
+        if sources.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let direction = order
+            .as_ref()
+            .map(|(_, _, direction)| *direction)
+            .unwrap_or(EntityOrder::Ascending);
+        UnionQuery::new(sources, direction, range, block)
+            .load::<UnionData>(conn)
+            .map_err(|e| {
+                QueryExecutionError::ResolveEntitiesError(format!(
+                    "failed to query `{}` across subgraphs: {}",
+                    entity_type, e
+                ))
+            })?
+            .into_iter()
+            .map(|data| -> Result<_, QueryExecutionError> {
+                let layout = layouts[data.source as usize];
+                Ok((layout.subgraph.clone(), data.to_entity(layout)?))
+            })
+            .collect()
+    }
+
+    /// Like `query`, but when the collection spans several entity types,
+    /// only return one entity for each id. If several entity types have an
+    /// entity with the same id, the one that comes first in the sort order
+    /// wins. The `range` is applied after removing duplicate ids so that a
+    /// page of `first` entities always contains `first` distinct ids
+    pub fn query_distinct_on_id(
+        &self,
+        logger: &Logger,
+        conn: &PgConnection,
+        collection: EntityCollection,
+        filter: Option<EntityFilter>,
+        order: Option<(String, ValueType, EntityOrder)>,
+        range: EntityRange,
+        block: BlockHeight,
+    ) -> Result<Vec<Entity>, QueryExecutionError> {
+        let options = QueryOptions {
+            distinct_on_id: true,
+            ..QueryOptions::default()
+        };
+        self.query_with_options(
+            logger, conn, collection, filter, order, range, block, options,
+        )
+    }
+
+    /// Like `query`, but with additional `options` that change what the
+    /// query returns
+    pub fn query_with_options(
+        &self,
+        logger: &Logger,
+        conn: &PgConnection,
+        collection: EntityCollection,
+        filter: Option<EntityFilter>,
+        order: Option<(String, ValueType, EntityOrder)>,
+        range: EntityRange,
+        block: BlockHeight,
+        options: QueryOptions,
+    ) -> Result<Vec<Entity>, QueryExecutionError> {
+        fn log_query_timing(
+            logger: &Logger,
+            query: &FilterQuery,
+            elapsed: Duration,
+            entity_count: usize,
+        ) {
+            // 20kB
+            const MAXLEN: usize = 20_480;
+
+            if !*graph::log::LOG_SQL_TIMING {
+                return;
             }
 
             let mut text = debug_query(&query).to_string().replace("\n", " ");
@@ -542,12 +1644,62 @@ impl Layout {
             );
         }
 
-        let filter_collection = FilterCollection::new(&self, collection, filter.as_ref())?;
-        let query = FilterQuery::new(&filter_collection, filter.as_ref(), order, range, block)?;
+        let shape = match &collection {
+            EntityCollection::All(entity_types) => Some(QueryShape::new(
+                entity_types,
+                filter.as_ref(),
+                &order,
+                &range,
+                &options,
+            )),
+            EntityCollection::Window(_) => None,
+        };
+
+        let block = block.number();
+        let filter_collection = FilterCollection::new(&self, collection, filter.as_ref(), block)?;
+        let mut query = FilterQuery::new(&filter_collection, filter.as_ref(), order, range, block)?;
+        if options.distinct_on_id {
+            query = query.distinct_on_id();
+        }
+        if options.interleave_types {
+            query = query.interleave_types();
+        }
+        if options.list_heads {
+            query = query.with_list_heads();
+        }
+        if let Some(seed) = options.tiebreak_seed {
+            query = query.with_tiebreak_seed(seed)?;
+        }
+        if let Some(collation) = &options.collation {
+            Self::check_collation(conn, collation)?;
+            query = query.with_collation(collation)?;
+        }
+        if let Some(field) = &options.child_count {
+            query = query.with_child_count(self, field)?;
+        }
+        if options.blocks_old {
+            query = query.with_blocks_old(self)?;
+        }
+        if options.ids_only {
+            query = query.with_ids_only();
+        }
+        if options.unclamped {
+            query = query.with_unclamped()?;
+        }
+        if options.unordered {
+            query = query.with_unordered()?;
+        }
         let query_clone = query.clone();
 
         let start = Instant::now();
-        let values = query.load::<EntityData>(conn).map_err(|e| {
+        let values = match shape {
+            Some(shape) => self
+                .query_cache
+                .sql(shape, &query)
+                .and_then(|sql| CachedQuery::new(sql, query).load::<EntityData>(conn)),
+            None => query.load::<EntityData>(conn),
+        };
+        let values = values.map_err(|e| {
             QueryExecutionError::ResolveEntitiesError(format!(
                 "{}, query = {:?}",
                 e,
@@ -557,21 +1709,298 @@ impl Layout {
         log_query_timing(logger, &query_clone, start.elapsed(), values.len());
         values
             .into_iter()
-            .map(|entity_data| entity_data.to_entity(self).map_err(|e| e.into()))
+            .map(|entity_data| -> Result<Entity, QueryExecutionError> {
+                let mut entity = entity_data.to_entity(self)?;
+                if let Some(scale) = options.decimal_scale {
+                    for value in entity.values_mut() {
+                        round_decimals(value, scale);
+                    }
+                }
+                Ok(entity)
+            })
             .collect()
     }
 
+    /// Return an error if the database does not have a collation with
+    /// the name `collation`
+    fn check_collation(conn: &PgConnection, collation: &str) -> Result<(), StoreError> {
+        let exists =
+            sql_query("select exists (select 1 from pg_collation where collname = $1) as found")
+                .bind::<Text, _>(collation)
+                .get_result::<CollationExists>(conn)?;
+        if !exists.found {
+            return Err(StoreError::QueryExecutionError(format!(
+                "the collation `{}` does not exist",
+                collation
+            )));
+        }
+        Ok(())
+    }
+
+    /// The number of times `query_with_options` reused the SQL text of an
+    /// earlier query with the same shape
+    pub fn query_cache_hits(&self) -> usize {
+        self.query_cache.hits.load(Ordering::SeqCst)
+    }
+
+    /// Return the plan Postgres would use to execute the same SQL query
+    /// as `query` with these arguments, one line of the plan per entry.
+    /// This is only meant for diagnosing query performance
+    pub fn explain_query(
+        &self,
+        conn: &PgConnection,
+        collection: EntityCollection,
+        filter: Option<EntityFilter>,
+        order: Option<(String, ValueType, EntityOrder)>,
+        range: EntityRange,
+        block: BlockHeight,
+    ) -> Result<Vec<String>, QueryExecutionError> {
+        let block = block.number();
+        let filter_collection = FilterCollection::new(&self, collection, filter.as_ref(), block)?;
+        let query = FilterQuery::new(&filter_collection, filter.as_ref(), order, range, block)?;
+        let plan = ExplainQuery::new(query)
+            .load::<QueryPlanLine>(conn)
+            .map_err(|e| QueryExecutionError::ResolveEntitiesError(e.to_string()))?;
+        Ok(plan.into_iter().map(|line| line.line).collect())
+    }
+
+    /// Like `query`, but return an iterator that fetches the matching
+    /// entities from the database in batches of `batch_size` instead of
+    /// loading all of them at once. If `progress` is given, it is called
+    /// with the total number of entities fetched so far after each batch
+    /// has been loaded; since it is called on the thread that drives the
+    /// stream, it should return quickly. Use `EntityStream::with_prefetch`
+    /// to fetch the next batch while the current one is being consumed
+    pub fn query_stream<'a>(
+        &'a self,
+        conn: &'a PgConnection,
+        collection: EntityCollection,
+        filter: Option<EntityFilter>,
+        order: Option<(String, ValueType, EntityOrder)>,
+        block: BlockHeight,
+        batch_size: u32,
+        progress: Option<Box<dyn FnMut(usize) + 'a>>,
+    ) -> EntityStream<'a> {
+        EntityStream {
+            layout: self,
+            conn,
+            query: StreamQuery {
+                collection,
+                filter,
+                order,
+                block,
+                batch_size,
+            },
+            progress,
+            batch: Vec::new().into_iter(),
+            count: 0,
+            done: false,
+            prefetch: None,
+        }
+    }
+
+    /// Copy all versions of all entities of type `entity` into the table
+    /// for that type in the database schema `target_schema`. The table
+    /// there, and any enums it uses, must already exist and be compatible
+    /// with the table in this layout, for example, because they were
+    /// created from the same GraphQL schema. Returns the number of rows
+    /// that were copied
+    pub fn copy_type_to(
+        &self,
+        conn: &PgConnection,
+        entity: &str,
+        target_schema: &str,
+    ) -> Result<u64, StoreError> {
+        SqlName::check_valid_identifier(target_schema, "database schema")?;
+        let src = self.table_for_entity(entity)?;
+        let dst = src.in_schema(target_schema);
+        let count = rq::CopyEntityDataQuery::new(&dst, src)?.execute(conn)?;
+        Ok(count as u64)
+    }
+
+    /// Find the nonces that are missing from the transactions that
+    /// `sender` sent as of `block`. Transactions are entities of type
+    /// `entity_type` that have the `from` and `nonce` attributes of the
+    /// network subgraph's `Transaction` type. Only gaps between the lowest
+    /// and the highest stored nonce are reported, in ascending order
+    pub fn nonce_gaps(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+        sender: &Value,
+        block: BlockHeight,
+    ) -> Result<Vec<BigInt>, StoreError> {
+        let block = block.number();
+        const FROM: &str = "from";
+        const NONCE: &str = "nonce";
+
+        let filter = EntityFilter::Equal(FROM.to_owned(), sender.clone());
+        let collection = EntityCollection::All(vec![entity_type.to_owned()]);
+        let order = Some((NONCE.to_owned(), ValueType::BigInt, EntityOrder::Ascending));
+        let range = EntityRange {
+            first: None,
+            skip: 0,
+        };
+
+        let filter_collection = FilterCollection::new(&self, collection, Some(&filter), block)?;
+        let query = FilterQuery::new(&filter_collection, Some(&filter), order, range, block)?;
+
+        let mut gaps = Vec::new();
+        let mut prev: Option<BigInt> = None;
+        for data in query.load::<EntityData>(conn)? {
+            let nonce = match data.to_entity(self)?.get(NONCE) {
+                Some(Value::BigInt(nonce)) => nonce.clone(),
+                Some(Value::Int(nonce)) => BigInt::from(*nonce),
+                other => {
+                    return Err(StoreError::Unknown(format_err!(
+                        "transaction of {} has an invalid nonce {:?}",
+                        sender,
+                        other
+                    )))
+                }
+            };
+            if let Some(prev) = prev {
+                let mut missing = prev + BigInt::from(1);
+                while missing < nonce {
+                    gaps.push(missing.clone());
+                    missing = missing + BigInt::from(1);
+                }
+            }
+            prev = Some(nonce);
+        }
+        Ok(gaps)
+    }
+
+    /// Return the running total of gas used by the transactions that
+    /// `sender` sent as of `block`, as pairs of transaction id and the
+    /// total up to and including that transaction. Transactions are
+    /// ordered by the number of their block and their index in it. See
+    /// `CumulativeGasQuery` for the attributes that `entity_type` needs
+    pub fn cumulative_gas_used(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+        sender: &Value,
+        block: BlockHeight,
+    ) -> Result<Vec<(String, BigInt)>, StoreError> {
+        let block = block.number();
+        Ok(CumulativeGasQuery::new(self, entity_type, sender, block)?
+            .load::<CumulativeGasData>(conn)?
+            .into_iter()
+            .map(|data| {
+                let (total, _) = data.total.with_scale(0).as_bigint_and_exponent();
+                (data.id, BigInt::from(total))
+            })
+            .collect())
+    }
+
+    /// Count the entities of type `entity_type` that are current at
+    /// `block`, grouped by the value of `attribute`, and return the
+    /// distinct values together with their counts, largest groups first.
+    /// Entities where `attribute` is not set form a group whose value is
+    /// `Value::Null`. With `min_count`, groups with fewer entities than
+    /// that are left out. The values of an encrypted attribute are
+    /// decrypted; see `encryption.rs` for how their groups are ordered
+    pub fn group_count(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+        attribute: &str,
+        min_count: Option<u32>,
+        block: BlockHeight,
+    ) -> Result<Vec<(Value, i64)>, StoreError> {
+        let table = self.table_for_entity(entity_type)?;
+        let column = table.column_for_field(attribute)?;
+        GroupCountQuery::new(table.as_ref(), column, min_count, block.number())
+            .load::<GroupCountData>(conn)?
+            .into_iter()
+            .map(|data| data.to_group(self, column))
+            .collect()
+    }
+
+    /// Count the distinct values of `attribute` among the entities of type
+    /// `entity_type` that are current at `block` and match `filter`.
+    /// Entities where `attribute` is not set are not counted, i.e., `null`
+    /// does not count as a value, the same as for SQL's `count(distinct ..)`
+    pub fn count_distinct(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+        attribute: &str,
+        filter: Option<EntityFilter>,
+        block: BlockHeight,
+    ) -> Result<i64, StoreError> {
+        let block = block.number();
+        let table = self.table_for_entity(entity_type)?.as_ref();
+        let column = table.column_for_field(attribute)?;
+        let filter = filter
+            .as_ref()
+            .map(|filter| QueryFilter::new(filter, table, self, block))
+            .transpose()?;
+        let data = CountDistinctQuery::new(table, column, filter, block)
+            .get_result::<CountDistinctData>(conn)?;
+        Ok(data.count)
+    }
+
+    /// Freeze the entities in `collection` that match `filter` at `block`
+    /// into the new table `target_table` in the schema of this layout, and
+    /// return the number of rows in it. The collection must consist of
+    /// exactly one entity type. The new table has the same columns as the
+    /// table for that type, but no block range; it is a snapshot and does
+    /// not change when the entities change
+    pub fn materialize(
+        &self,
+        conn: &PgConnection,
+        collection: EntityCollection,
+        filter: Option<EntityFilter>,
+        block: BlockHeight,
+        target_table: &str,
+    ) -> Result<u64, StoreError> {
+        let block = block.number();
+        SqlName::check_valid_identifier(target_table, "table")?;
+        let table = match &collection {
+            EntityCollection::All(entity_types) if entity_types.len() == 1 => {
+                self.table_for_entity(&entity_types[0])?.as_ref()
+            }
+            _ => {
+                return Err(StoreError::QueryExecutionError(format!(
+                    "only a single entity type can be materialized, but got {:?}",
+                    collection
+                )))
+            }
+        };
+        let target =
+            SqlName::qualified_name(&self.schema, &SqlName::verbatim(target_table.to_owned()));
+        let filter = filter
+            .as_ref()
+            .map(|filter| QueryFilter::new(filter, table, self, block))
+            .transpose()?;
+        let query = MaterializeQuery::new(table, &target, filter, block);
+        // Postgres does not allow bind variables in `create table .. as`,
+        // and we therefore create the table first and fill it afterwards
+        conn.transaction(|| {
+            conn.batch_execute(&query.create_table_sql())?;
+            let count = query.execute(conn)?;
+            Ok(count as u64)
+        })
+    }
+
     pub fn update(
         &self,
         conn: &PgConnection,
         key: &EntityKey,
-        entity: Entity,
-        block: BlockNumber,
+        mut entity: Entity,
+        block: BlockHeight,
     ) -> Result<(), StoreError> {
+        let block = block.number();
         let table = self.table_for_entity(&key.entity_type)?;
+        self.check_attributes(table, &entity)?;
+        self.encrypt_attributes(table, &mut entity)?;
         ClampRangeQuery::new(table, key, block).execute(conn)?;
         let query = InsertQuery::new(table, key, entity, block)?;
-        query.execute(conn)?;
+        query
+            .execute(conn)
+            .map_err(|e| insert_error(table, slice::from_ref(key), e))?;
         Ok(())
     }
 
@@ -582,6 +2011,12 @@ impl Layout {
         entity: &Entity,
     ) -> Result<usize, StoreError> {
         let table = self.table_for_entity(&key.entity_type)?;
+        self.check_attributes(table, entity)?;
+        if table.columns.iter().any(|column| column.is_encrypted()) {
+            let mut entity = entity.clone();
+            self.encrypt_attributes(table, &mut entity)?;
+            return Ok(UpdateQuery::new(table, key, &entity)?.execute(conn)?);
+        }
         let query = UpdateQuery::new(table, key, entity)?;
         Ok(query.execute(conn)?)
     }
@@ -593,6 +2028,7 @@ impl Layout {
         mut entity: Entity,
     ) -> Result<usize, StoreError> {
         let table = self.table_for_entity(&key.entity_type)?;
+        let explicit_nulls = rq::explicit_nulls(table, &entity);
         // Set any attributes not mentioned in the entity to
         // their default (NULL)
         for column in table.columns.iter() {
@@ -600,7 +2036,8 @@ impl Layout {
                 entity.insert(column.field.clone(), Value::Null);
             }
         }
-        let query = UpdateQuery::new(table, key, &entity)?;
+        self.encrypt_attributes(table, &mut entity)?;
+        let query = UpdateQuery::new(table, key, &entity)?.explicit_nulls(explicit_nulls);
         Ok(query.execute(conn)?)
     }
 
@@ -608,10 +2045,295 @@ impl Layout {
         &self,
         conn: &PgConnection,
         key: &EntityKey,
-        block: BlockNumber,
+        block: BlockHeight,
     ) -> Result<usize, StoreError> {
         let table = self.table_for_entity(&key.entity_type)?;
-        Ok(ClampRangeQuery::new(table, key, block).execute(conn)?)
+        Ok(ClampRangeQuery::new(table, key, block.number()).execute(conn)?)
+    }
+
+    /// Delete all entities of type `entity_type` whose id is in `ids` as
+    /// of `block` with a single statement, and return how many entities
+    /// were deleted. Ids of entities that do not exist, or that were
+    /// already deleted, are ignored
+    pub fn delete_many(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+        ids: &[String],
+        block: BlockHeight,
+    ) -> Result<usize, StoreError> {
+        let table = self.table_for_entity(entity_type)?;
+        Ok(ClampRangeManyQuery::new(table, ids, block.number()).execute(conn)?)
+    }
+
+    /// Swap the ids of the entities `id_a` and `id_b` of type `entity_type`
+    /// as of `block`, so that `id_a` has the data `id_b` had before, and
+    /// vice versa. Both entities must exist. The swap happens in one
+    /// transaction; the current versions of both entities are ended at
+    /// `block` before the swapped versions are inserted, which keeps the
+    /// ids unique at every step without the need for a temporary id
+    pub fn swap_ids(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+        id_a: &str,
+        id_b: &str,
+        block: BlockHeight,
+    ) -> Result<(), StoreError> {
+        let block = block.number();
+        if id_a == id_b {
+            return Ok(());
+        }
+        let table = self.table_for_entity(entity_type)?;
+        let find = |id: &str| -> Result<Entity, StoreError> {
+            FindQuery::new(table.as_ref(), id, block)
+                .get_result::<EntityData>(conn)
+                .optional()?
+                .map(|entity_data| entity_data.to_entity(self))
+                .transpose()?
+                .ok_or_else(|| {
+                    StoreError::QueryExecutionError(format!(
+                        "can not swap the ids of {}[{}] and {}[{}] since {}[{}] does not exist",
+                        entity_type, id_a, entity_type, id_b, entity_type, id
+                    ))
+                })
+        };
+        let key = |id: &str| EntityKey {
+            subgraph_id: self.subgraph.clone(),
+            entity_type: entity_type.to_owned(),
+            entity_id: id.to_owned(),
+        };
+
+        conn.transaction(|| {
+            let mut entity_a = find(id_a)?;
+            let mut entity_b = find(id_b)?;
+            entity_a.set("id", id_b);
+            entity_b.set("id", id_a);
+            // `find` returned the plaintext of encrypted attributes
+            self.encrypt_attributes(table, &mut entity_a)?;
+            self.encrypt_attributes(table, &mut entity_b)?;
+
+            let (key_a, key_b) = (key(id_a), key(id_b));
+            ClampRangeQuery::new(table, &key_a, block).execute(conn)?;
+            ClampRangeQuery::new(table, &key_b, block).execute(conn)?;
+            InsertQuery::new(table, &key_a, entity_b, block)?
+                .execute(conn)
+                .map_err(|e| insert_error(table, slice::from_ref(&key_a), e))?;
+            InsertQuery::new(table, &key_b, entity_a, block)?
+                .execute(conn)
+                .map_err(|e| insert_error(table, slice::from_ref(&key_b), e))?;
+            Ok(())
+        })
+    }
+
+    /// Apply all of `mods` at `block` in one transaction. There must be at
+    /// most one modification for each entity, as `EntityCache` produces
+    /// them. The modifications are grouped by entity type; first, the
+    /// current versions of all removed and overwritten entities are ended
+    /// with one statement per type, and then all new versions of a type are
+    /// inserted in as few statements as possible
+    pub fn apply_modifications(
+        &self,
+        conn: &PgConnection,
+        mods: Vec<EntityModification>,
+        block: BlockHeight,
+    ) -> Result<(), StoreError> {
+        // For each entity type, the ids of the entities whose current
+        // version ends at `block`, and the entities to write at `block`
+        let mut groups: BTreeMap<String, (Vec<String>, Vec<(EntityKey, Entity)>)> = BTreeMap::new();
+        for modification in mods {
+            use EntityModification::*;
+
+            let group = groups
+                .entry(modification.entity_key().entity_type.clone())
+                .or_default();
+            match modification {
+                Insert { key, data } => group.1.push((key, data)),
+                Overwrite { key, data } => {
+                    group.0.push(key.entity_id.clone());
+                    group.1.push((key, data));
+                }
+                Remove { key } => group.0.push(key.entity_id),
+            }
+        }
+
+        conn.transaction(|| {
+            for (entity_type, (clamped, _)) in &groups {
+                if !clamped.is_empty() {
+                    let table = self.table_for_entity(entity_type)?;
+                    ClampRangeManyQuery::new(table, clamped, block.number()).execute(conn)?;
+                }
+            }
+            for (entity_type, (_, written)) in groups {
+                if !written.is_empty() {
+                    let table = self.table_for_entity(&entity_type)?;
+                    let chunk_size = MAX_BIND_PARAMETERS / (table.columns.len() + 1);
+                    self.insert_from_iter(
+                        conn,
+                        &entity_type,
+                        written.into_iter(),
+                        block,
+                        chunk_size,
+                    )?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Remove all versions of entities of type `entity_type` that were no
+    /// longer current at `before_block` or any later block, and return
+    /// how many versions were removed. Current versions are never removed.
+    /// After pruning, queries for blocks before `before_block` might
+    /// return wrong results since some of the versions they need are gone
+    pub fn prune(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+        before_block: BlockHeight,
+    ) -> Result<u64, StoreError> {
+        let table = self.table_for_entity(entity_type)?;
+        Ok(PruneQuery::new(table, before_block.number()).execute(conn)? as u64)
+    }
+
+    /// Start rebuilding the indexes of the table for `entity_type`. This
+    /// only records the job in `public.reindex_jobs`; the indexes are
+    /// rebuilt by calling `reindex` until it reports that the job is
+    /// finished. Starting a job for a table that already has one, finished
+    /// or not, starts over from the beginning
+    pub fn start_reindex(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+    ) -> Result<ReindexStatus, StoreError> {
+        let table = self.table_for_entity(entity_type)?;
+        sql_query(
+            "insert into public.reindex_jobs(schema_name, table_name)
+             values ($1, $2)
+             on conflict(schema_name, table_name)
+             do update set last_index = null, started_at = now(), finished_at = null",
+        )
+        .bind::<Text, _>(&self.schema)
+        .bind::<Text, _>(table.name.as_str())
+        .execute(conn)?;
+        self.reindex_status(conn, entity_type)?
+            .ok_or_else(|| StoreError::Unknown(format_err!("reindex job was not recorded")))
+    }
+
+    /// Rebuild at most `max_indexes` of the indexes of the table for
+    /// `entity_type` that the job started with `start_reindex` has not
+    /// rebuilt yet. Each index is rebuilt in its own transaction together
+    /// with recording the progress of the job, so that a job that gets
+    /// interrupted, e.g., because the node crashes, resumes where it left
+    /// off with the next call. Rebuilding all indexes of a large table in
+    /// one go can take longer than we want to hold locks on it; this is
+    /// meant to be called repeatedly from a background task
+    pub fn reindex(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+        max_indexes: usize,
+    ) -> Result<ReindexStatus, StoreError> {
+        let table = self.table_for_entity(entity_type)?;
+        let job = self.reindex_job(conn, table)?.ok_or_else(|| {
+            StoreError::QueryExecutionError(format!(
+                "no reindex job has been started for `{}`",
+                entity_type
+            ))
+        })?;
+
+        if !job.finished {
+            let pending: Vec<_> = self
+                .index_names(conn, table)?
+                .into_iter()
+                .filter(|name| job.last_index.as_ref().map_or(true, |last| name > last))
+                .collect();
+            for index in pending.iter().take(max_indexes) {
+                conn.transaction(|| -> Result<(), StoreError> {
+                    conn.batch_execute(&format!(
+                        "reindex index \"{}\".\"{}\"",
+                        self.schema, index
+                    ))?;
+                    sql_query(
+                        "update public.reindex_jobs set last_index = $3
+                          where schema_name = $1 and table_name = $2",
+                    )
+                    .bind::<Text, _>(&self.schema)
+                    .bind::<Text, _>(table.name.as_str())
+                    .bind::<Text, _>(index)
+                    .execute(conn)?;
+                    Ok(())
+                })?;
+            }
+            if pending.len() <= max_indexes {
+                sql_query(
+                    "update public.reindex_jobs set finished_at = now()
+                      where schema_name = $1 and table_name = $2",
+                )
+                .bind::<Text, _>(&self.schema)
+                .bind::<Text, _>(table.name.as_str())
+                .execute(conn)?;
+            }
+        }
+        self.reindex_status(conn, entity_type)?
+            .ok_or_else(|| StoreError::Unknown(format_err!("reindex job disappeared")))
+    }
+
+    /// Report the progress of rebuilding the indexes of the table for
+    /// `entity_type`, or `None` if that was never started
+    pub fn reindex_status(
+        &self,
+        conn: &PgConnection,
+        entity_type: &str,
+    ) -> Result<Option<ReindexStatus>, StoreError> {
+        let table = self.table_for_entity(entity_type)?;
+        let job = match self.reindex_job(conn, table)? {
+            Some(job) => job,
+            None => return Ok(None),
+        };
+        let indexes = self.index_names(conn, table)?;
+        let done = match &job.last_index {
+            Some(last) => indexes.iter().filter(|name| *name <= last).count(),
+            None => 0,
+        };
+        Ok(Some(ReindexStatus {
+            entity_type: table.object.clone(),
+            done,
+            total: indexes.len(),
+            finished: job.finished,
+        }))
+    }
+
+    fn reindex_job(
+        &self,
+        conn: &PgConnection,
+        table: &Table,
+    ) -> Result<Option<ReindexJob>, StoreError> {
+        Ok(sql_query(
+            "select last_index, finished_at is not null as finished
+               from public.reindex_jobs
+              where schema_name = $1 and table_name = $2",
+        )
+        .bind::<Text, _>(&self.schema)
+        .bind::<Text, _>(table.name.as_str())
+        .get_result::<ReindexJob>(conn)
+        .optional()?)
+    }
+
+    /// The names of the indexes on `table` in the order in which `reindex`
+    /// rebuilds them
+    fn index_names(&self, conn: &PgConnection, table: &Table) -> Result<Vec<String>, StoreError> {
+        Ok(sql_query(
+            "select indexname from pg_indexes
+              where schemaname = $1 and tablename = $2
+              order by indexname",
+        )
+        .bind::<Text, _>(&self.schema)
+        .bind::<Text, _>(table.name.as_str())
+        .load::<IndexName>(conn)?
+        .into_iter()
+        .map(|index| index.indexname)
+        .collect())
     }
 
     pub fn delete_unversioned(
@@ -626,8 +2348,9 @@ impl Layout {
     pub fn revert_block(
         &self,
         conn: &PgConnection,
-        block: BlockNumber,
+        block: BlockHeight,
     ) -> Result<(StoreEvent, i32), StoreError> {
+        let block = block.number();
         let mut changes: Vec<EntityChange> = Vec::new();
         let mut count: i32 = 0;
 
@@ -675,79 +2398,547 @@ impl Layout {
             });
             changes.extend(set);
         }
-        Ok((StoreEvent::new(changes), count))
+        Ok((StoreEvent::new(changes), count))
+    }
+
+    /// Revert the metadata (dynamic data sources and related entities) for
+    /// the given `subgraph`. This function can only be called on the `Layout`
+    /// for the metadata subgraph.
+    ///
+    /// For metadata, reversion always means deletion since the metadata that
+    /// is subject to reversion is only ever created but never updated
+    pub fn revert_metadata(
+        &self,
+        conn: &PgConnection,
+        subgraph: &SubgraphDeploymentId,
+        block: BlockHeight,
+    ) -> Result<StoreEvent, StoreError> {
+        let block = block.number();
+        assert!(self.subgraph.is_meta());
+        const DDS: &str = "DynamicEthereumContractDataSource";
+
+        // Delete dynamic data sources for this subgraph at the given block
+        // and get their id's
+        let dds = DeleteDynamicDataSourcesQuery::new(subgraph.as_str(), block)
+            .get_results(conn)?
+            .into_iter()
+            .map(|data| data.id)
+            .collect::<Vec<_>>();
+
+        // Calculate how long id's are and make sure they have all the same length
+        let prefix_len = dds.iter().map(|id| id.len()).min();
+        assert_eq!(prefix_len, dds.iter().map(|id| id.len()).max());
+        let prefix_len = prefix_len.unwrap_or(0) as i32;
+
+        let mut changes: Vec<EntityChange> = dds
+            .iter()
+            .map(|id| EntityChange {
+                subgraph_id: self.subgraph.clone(),
+                entity_type: DDS.to_owned(),
+                entity_id: id.to_owned(),
+                operation: EntityChangeOperation::Removed,
+            })
+            .collect();
+
+        if !dds.is_empty() {
+            // Remove subordinate entities for the dynamic data sources from
+            // the various metadata tables. We do not need to consider the
+            // table for dynamic data sources, not any table whose name starts
+            // with 'Subgraph'. Since the set of metadata entities might change
+            // in the future, it is safer to exclude entity types that we know
+            // do not contain data of interest rather than check for inclusion
+            // in a whitelist, as it should generally be safe to do the deletion
+            // on all metadata tables
+            //
+            // This code is far from ideal since it relies on a few unenforceable
+            // assumptions, most importantly, that the id of any entity that
+            // belongs to a dynmaic data source starts with the id of that data
+            // source
+            for table in self
+                .tables
+                .values()
+                .filter(|table| table.object != DDS && !table.object.starts_with("Subgraph"))
+            {
+                let deleted = DeleteByPrefixQuery::new(table, &dds, prefix_len)
+                    .get_results(conn)?
+                    .into_iter()
+                    .map(|data| EntityChange {
+                        subgraph_id: self.subgraph.clone(),
+                        entity_type: table.object.clone(),
+                        entity_id: data.id,
+                        operation: EntityChangeOperation::Removed,
+                    });
+                changes.extend(deleted);
+            }
+        }
+        Ok(StoreEvent::new(changes))
+    }
+}
+
+/// Everything about a query that `Layout::query_with_options` runs that
+/// determines its SQL text. Queries with the same shape only differ in the
+/// values of their bind parameters
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct QueryShape {
+    entity_types: Vec<String>,
+    filter: Option<FilterShape>,
+    /// The attribute to order by and the direction
+    order: Option<(String, &'static str)>,
+    /// Whether the query has a limit and an offset; their values are bind
+    /// parameters
+    first: bool,
+    skip: bool,
+    options: QueryOptions,
+}
+
+impl QueryShape {
+    fn new(
+        entity_types: &[String],
+        filter: Option<&EntityFilter>,
+        order: &Option<(String, ValueType, EntityOrder)>,
+        range: &EntityRange,
+        options: &QueryOptions,
+    ) -> Self {
+        let mut options = options.clone();
+        // The seed is a bind parameter, and the scale is only applied to
+        // the entities after they have been loaded
+        options.tiebreak_seed = options.tiebreak_seed.map(|_| 0);
+        options.decimal_scale = None;
+        QueryShape {
+            entity_types: entity_types.to_vec(),
+            filter: filter.map(FilterShape::new),
+            order: order
+                .as_ref()
+                .map(|(attribute, _, direction)| (attribute.clone(), direction.to_sql())),
+            first: range.first.is_some(),
+            skip: range.skip > 0,
+            options,
+        }
+    }
+}
+
+/// The SQL text for the queries that `Layout::query_with_options` ran,
+/// keyed by the shape of the query, which lets us skip generating the SQL
+/// text for queries whose shape we have seen before. Queries for windowed
+/// collections are not cached
+struct QueryCache {
+    sql: Mutex<LruCache<QueryShape, Arc<String>>>,
+    hits: AtomicUsize,
+}
+
+impl QueryCache {
+    /// The maximum number of query shapes we keep; once the cache is full,
+    /// the shapes that were used least recently are evicted
+    const MAX_SHAPES: usize = 1000;
+
+    /// Return the SQL text for `query`, which has the given `shape`,
+    /// generating and remembering it if we have not seen the shape yet
+    fn sql(&self, shape: QueryShape, query: &FilterQuery) -> diesel::QueryResult<Arc<String>> {
+        let cached = self.sql.lock().unwrap().get(&shape).cloned();
+        if let Some(sql) = cached {
+            self.hits.fetch_add(1, Ordering::SeqCst);
+            return Ok(sql);
+        }
+        // Generate the SQL text without holding the lock. Two threads that
+        // see a new shape at the same time both generate the same text
+        let sql = Arc::new(rq::query_sql(query)?);
+        self.sql.lock().unwrap().insert(shape, sql.clone());
+        Ok(sql)
+    }
+}
+
+impl Default for QueryCache {
+    fn default() -> Self {
+        QueryCache {
+            sql: Mutex::new(LruCache::with_capacity(Self::MAX_SHAPES)),
+            hits: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl fmt::Debug for QueryCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("QueryCache")
+            .field("hits", &self.hits)
+            .finish()
+    }
+}
+
+/// The progress of rebuilding the indexes of the table for one entity
+/// type; see `Layout::reindex`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReindexStatus {
+    /// The GraphQL name of the entity type
+    pub entity_type: String,
+    /// How many of the table's indexes have been rebuilt so far
+    pub done: usize,
+    /// How many indexes the table has
+    pub total: usize,
+    pub finished: bool,
+}
+
+/// Build an entity of one type, typically in tests. Attributes can be set
+/// to any value, and `build` checks them against the table for the type
+/// so that mistakes are reported with the attribute at fault rather than
+/// as a database error when the entity is written
+pub struct EntityBuilder<'a> {
+    table: &'a Table,
+    entity: Entity,
+}
+
+impl<'a> EntityBuilder<'a> {
+    pub fn set(mut self, attribute: &str, value: impl Into<Value>) -> Self {
+        self.entity.set(attribute, value);
+        self
+    }
+
+    /// Return the entity if all its attributes are declared in the GraphQL
+    /// schema with a type that matches their value, and all non-nullable
+    /// attributes are set
+    pub fn build(self) -> Result<Entity, StoreError> {
+        let table = self.table;
+        for (attribute, value) in self.entity.iter() {
+            let column = table.column_for_field(attribute)?;
+            if !column.accepts(value) {
+                return Err(StoreError::QueryExecutionError(format!(
+                    "`{}.{}` has type {} and can not be set to `{}` of type {}",
+                    table.object,
+                    attribute,
+                    column.field_type,
+                    value,
+                    value.type_name()
+                )));
+            }
+        }
+        let missing = table.columns.iter().find(|column| {
+            !column.is_nullable()
+                && !column.is_fulltext()
+                && self.entity.get(&column.field).unwrap_or(&Value::Null) == &Value::Null
+        });
+        if let Some(column) = missing {
+            return Err(StoreError::QueryExecutionError(format!(
+                "`{}.{}` has type {} and must be set",
+                table.object, column.field, column.field_type
+            )));
+        }
+        Ok(self.entity)
+    }
+}
+
+/// A row from `public.reindex_jobs`
+#[derive(QueryableByName)]
+struct ReindexJob {
+    #[sql_type = "Nullable<Text>"]
+    last_index: Option<String>,
+    #[sql_type = "Bool"]
+    finished: bool,
+}
+
+#[derive(QueryableByName)]
+struct IndexName {
+    #[sql_type = "Text"]
+    indexname: String,
+}
+
+/// A row from `information_schema.columns`
+#[derive(QueryableByName)]
+struct ColumnTypeInfo {
+    #[sql_type = "Text"]
+    table_name: String,
+    #[sql_type = "Text"]
+    column_name: String,
+    #[sql_type = "Text"]
+    udt_name: String,
+}
+
+#[derive(QueryableByName)]
+struct CollationExists {
+    #[sql_type = "Bool"]
+    found: bool,
+}
+
+/// The definition of a field of an entity type; see `Layout::graphql_fields`
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldDef {
+    /// The GraphQL name of the field
+    pub name: String,
+    /// The GraphQL type of the field, e.g., `[String!]`
+    pub field_type: String,
+    /// The name of the type of the field, or of its elements for a list,
+    /// e.g., `String`
+    pub named_type: String,
+    pub is_list: bool,
+    pub is_nullable: bool,
+    /// Set if the field is not stored with the entity, but derived from
+    /// references in other entities with `@derivedFrom`
+    pub is_derived: bool,
+}
+
+impl FieldDef {
+    fn new(name: &str, field_type: &q::Type, is_derived: bool) -> Self {
+        let (is_nullable, inner) = match field_type {
+            q::Type::NonNullType(inner) => (false, inner.as_ref()),
+            _ => (true, field_type),
+        };
+        let is_list = match inner {
+            q::Type::ListType(_) => true,
+            _ => false,
+        };
+        FieldDef {
+            name: name.to_owned(),
+            field_type: field_type.to_string(),
+            named_type: named_type(field_type).to_owned(),
+            is_list,
+            is_nullable,
+            is_derived,
+        }
+    }
+}
+
+/// The result of `Layout::query_columnar`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ColumnarEntities {
+    /// The number of entities
+    pub rows: usize,
+    /// Maps each attribute to its values; every vector has `rows` entries
+    pub columns: HashMap<String, Vec<Value>>,
+}
+
+/// Options for `Layout::query_with_options`
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct QueryOptions {
+    /// Only return one entity per id; see `Layout::query_distinct_on_id`
+    pub distinct_on_id: bool,
+    /// When querying several entity types, alternate between the types
+    /// rather than ordering all entities together; see
+    /// `FilterQuery::interleave_types`
+    pub interleave_types: bool,
+    /// For each list attribute `attr`, also return its first element as
+    /// the attribute `attr_first`. It is `Null` if the list is empty or
+    /// null. The additional attributes are only returned, and can not be
+    /// used to filter or order the query
+    pub list_heads: bool,
+    /// Break ties in the order of the query by `md5(id || seed)` so that
+    /// tied entities are shuffled, but in the same way every time the
+    /// query is run with the same seed. Only possible when the query is
+    /// against a single entity type
+    pub tiebreak_seed: Option<u32>,
+    /// Also return, as the attribute `__childCount`, the number of
+    /// entities that reference each entity through this derived field,
+    /// e.g. the number of transactions that point to a block through the
+    /// block's `transactions` field
+    pub child_count: Option<String>,
+    /// Also return, as the attribute `blocksOld`, how many blocks before
+    /// the query's block the block is that each entity references through
+    /// its `block` attribute; see `FilterQuery::with_blocks_old`
+    pub blocks_old: bool,
+    /// Only return the `id` of each entity; see `Layout::query_ids`
+    pub ids_only: bool,
+    /// Return all BigDecimal values, including the elements of lists,
+    /// with exactly this many digits after the decimal point, rounding
+    /// half to even. This only changes the entities that are returned,
+    /// not the stored values, and does not affect filtering or ordering
+    pub decimal_scale: Option<i64>,
+    /// Sort the String attribute that the query is ordered by with this
+    /// collation, e.g., `de-u-co-phonebk-x-icu`, instead of the database's
+    /// default collation. The collation must exist in the database, and
+    /// can only be used for queries against a single entity type
+    pub collation: Option<String>,
+    /// Return every version of the matching entities rather than only the
+    /// versions that are current at the query's block. This is an admin
+    /// option for looking at the full history of entities, and must not be
+    /// used to answer queries from subgraph users. Windowed queries do not
+    /// support it
+    pub unclamped: bool,
+    /// Queries without an order return entities ordered by `id` so that
+    /// results are stable and can be paged through. Setting this skips
+    /// that sort for callers that do not care about the order; see
+    /// `FilterQuery::with_unordered`
+    pub unordered: bool,
+}
+
+/// Round `value` to `scale` digits after the decimal point if it is a
+/// BigDecimal or a list of them; see `QueryOptions::decimal_scale`
+fn round_decimals(value: &mut Value, scale: i64) {
+    match value {
+        Value::BigDecimal(decimal) => *decimal = big_decimal_round_half_even(decimal, scale),
+        Value::List(values) => values
+            .iter_mut()
+            .for_each(|value| round_decimals(value, scale)),
+        _ => (),
+    }
+}
+
+/// The result of `Layout::find_with_fallback`
+#[derive(Clone, Debug, PartialEq)]
+pub enum FoundEntity {
+    /// The version of the entity that was current at the requested block
+    AtBlock(Entity),
+    /// No version was current at the requested block; this is the
+    /// earliest version of the entity
+    Earliest(Entity),
+}
+
+impl FoundEntity {
+    pub fn entity(&self) -> &Entity {
+        match self {
+            FoundEntity::AtBlock(entity) | FoundEntity::Earliest(entity) => entity,
+        }
+    }
+
+    pub fn is_fallback(&self) -> bool {
+        match self {
+            FoundEntity::AtBlock(_) => false,
+            FoundEntity::Earliest(_) => true,
+        }
     }
+}
 
-    /// Revert the metadata (dynamic data sources and related entities) for
-    /// the given `subgraph`. This function can only be called on the `Layout`
-    /// for the metadata subgraph.
-    ///
-    /// For metadata, reversion always means deletion since the metadata that
-    /// is subject to reversion is only ever created but never updated
-    pub fn revert_metadata(
+/// Which entities an `EntityStream` fetches. This is kept separate from
+/// the stream so that it can be moved to the thread that prefetches
+/// batches
+#[derive(Clone)]
+struct StreamQuery {
+    collection: EntityCollection,
+    filter: Option<EntityFilter>,
+    order: Option<(String, ValueType, EntityOrder)>,
+    block: BlockHeight,
+    batch_size: u32,
+}
+
+impl StreamQuery {
+    /// Fetch the batch that starts after the first `skip` entities
+    fn fetch(
         &self,
+        layout: &Layout,
         conn: &PgConnection,
-        subgraph: &SubgraphDeploymentId,
-        block: BlockNumber,
-    ) -> Result<StoreEvent, StoreError> {
-        assert!(self.subgraph.is_meta());
-        const DDS: &str = "DynamicEthereumContractDataSource";
-
-        // Delete dynamic data sources for this subgraph at the given block
-        // and get their id's
-        let dds = DeleteDynamicDataSourcesQuery::new(subgraph.as_str(), block)
-            .get_results(conn)?
+        skip: usize,
+    ) -> Result<Vec<Entity>, QueryExecutionError> {
+        let filter_collection = FilterCollection::new(
+            layout,
+            self.collection.clone(),
+            self.filter.as_ref(),
+            self.block.number(),
+        )?;
+        let range = EntityRange {
+            first: Some(self.batch_size),
+            skip: skip as u32,
+        };
+        let query = FilterQuery::new(
+            &filter_collection,
+            self.filter.as_ref(),
+            self.order.clone(),
+            range,
+            self.block.number(),
+        )?;
+        query
+            .load::<EntityData>(conn)
+            .map_err(|e| QueryExecutionError::ResolveEntitiesError(e.to_string()))?
             .into_iter()
-            .map(|data| data.id)
-            .collect::<Vec<_>>();
+            .map(|entity_data| entity_data.to_entity(layout).map_err(|e| e.into()))
+            .collect()
+    }
 
-        // Calculate how long id's are and make sure they have all the same length
-        let prefix_len = dds.iter().map(|id| id.len()).min();
-        assert_eq!(prefix_len, dds.iter().map(|id| id.len()).max());
-        let prefix_len = prefix_len.unwrap_or(0) as i32;
+    /// Whether `batch` is the last batch there is, either because it is
+    /// short or because fetching it failed
+    fn is_last(&self, batch: &Result<Vec<Entity>, QueryExecutionError>) -> bool {
+        match batch {
+            Ok(entities) => entities.len() < self.batch_size as usize,
+            Err(_) => true,
+        }
+    }
+}
 
-        let mut changes: Vec<EntityChange> = dds
-            .iter()
-            .map(|id| EntityChange {
-                subgraph_id: self.subgraph.clone(),
-                entity_type: DDS.to_owned(),
-                entity_id: id.to_owned(),
-                operation: EntityChangeOperation::Removed,
-            })
-            .collect();
+/// The iterator returned by `Layout::query_stream`. Batches are fetched
+/// with `limit`/`offset` and the same ordering as `Layout::query` uses,
+/// which always breaks ties by `id` and therefore makes paging stable
+pub struct EntityStream<'a> {
+    layout: &'a Layout,
+    conn: &'a PgConnection,
+    query: StreamQuery,
+    progress: Option<Box<dyn FnMut(usize) + 'a>>,
+    /// The entities from the last batch that we have not returned yet
+    batch: std::vec::IntoIter<Entity>,
+    /// The number of entities fetched so far
+    count: usize,
+    /// Set once we know that there are no more entities to fetch
+    done: bool,
+    /// Where batches come from if they are fetched in the background
+    prefetch: Option<mpsc::Receiver<Result<Vec<Entity>, QueryExecutionError>>>,
+}
 
-        if !dds.is_empty() {
-            // Remove subordinate entities for the dynamic data sources from
-            // the various metadata tables. We do not need to consider the
-            // table for dynamic data sources, not any table whose name starts
-            // with 'Subgraph'. Since the set of metadata entities might change
-            // in the future, it is safer to exclude entity types that we know
-            // do not contain data of interest rather than check for inclusion
-            // in a whitelist, as it should generally be safe to do the deletion
-            // on all metadata tables
-            //
-            // This code is far from ideal since it relies on a few unenforceable
-            // assumptions, most importantly, that the id of any entity that
-            // belongs to a dynmaic data source starts with the id of that data
-            // source
-            for table in self
-                .tables
-                .values()
-                .filter(|table| table.object != DDS && !table.object.starts_with("Subgraph"))
-            {
-                let deleted = DeleteByPrefixQuery::new(table, &dds, prefix_len)
-                    .get_results(conn)?
-                    .into_iter()
-                    .map(|data| EntityChange {
-                        subgraph_id: self.subgraph.clone(),
-                        entity_type: table.object.clone(),
-                        entity_id: data.id,
-                        operation: EntityChangeOperation::Removed,
-                    });
-                changes.extend(deleted);
+impl<'a> EntityStream<'a> {
+    /// Fetch batches on a background thread that uses `conn`, so that the
+    /// next batch is loaded while the current one is being consumed. The
+    /// thread stays at most one batch ahead of the consumer, and stops
+    /// when the stream is dropped. Since `conn` is a different connection
+    /// from the one the stream was created with, the stream will only see
+    /// changes that have been committed
+    pub fn with_prefetch<C>(mut self, conn: C) -> Self
+    where
+        C: Deref<Target = PgConnection> + Send + 'static,
+    {
+        // With a rendezvous channel, the thread holds on to the batch it
+        // just fetched until the consumer asks for it, and does not start
+        // on the batch after that until then
+        let (sender, receiver) = mpsc::sync_channel(0);
+        let layout = self.layout.clone();
+        let query = self.query.clone();
+        let mut skip = self.count;
+        thread::spawn(move || loop {
+            let batch = query.fetch(&layout, &conn, skip);
+            let last = query.is_last(&batch);
+            if let Ok(entities) = &batch {
+                skip += entities.len();
+            }
+            if sender.send(batch).is_err() || last {
+                break;
+            }
+        });
+        self.prefetch = Some(receiver);
+        self
+    }
+
+    fn fetch(&mut self) -> Result<Vec<Entity>, QueryExecutionError> {
+        match &self.prefetch {
+            // The thread only goes away after it has sent the last batch
+            Some(receiver) => receiver.recv().unwrap_or_else(|_| Ok(Vec::new())),
+            None => self.query.fetch(self.layout, self.conn, self.count),
+        }
+    }
+}
+
+impl<'a> Iterator for EntityStream<'a> {
+    type Item = Result<Entity, QueryExecutionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entity) = self.batch.next() {
+            return Some(Ok(entity));
+        }
+        if self.done {
+            return None;
+        }
+        match self.fetch() {
+            Ok(entities) => {
+                // A short batch means we have seen everything and can
+                // save ourselves one more roundtrip to the database
+                self.done = entities.len() < self.query.batch_size as usize;
+                if entities.is_empty() {
+                    self.done = true;
+                    return None;
+                }
+                self.count += entities.len();
+                if let Some(progress) = self.progress.as_mut() {
+                    progress(self.count);
+                }
+                self.batch = entities.into_iter();
+                self.batch.next().map(Ok)
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
             }
         }
-        Ok(StoreEvent::new(changes))
     }
 }
 
@@ -756,13 +2947,18 @@ impl Layout {
 pub struct EnumType {
     /// The name of the Postgres enum we created, fully qualified with the schema
     pub name: SqlName,
-    /// The possible values the enum can take
-    values: Arc<BTreeSet<String>>,
+    /// The possible values the enum can take, in the order in which they
+    /// were declared
+    pub values: Arc<Vec<String>>,
 }
 
 impl EnumType {
     fn is_assignable_from(&self, source: &Self) -> Option<String> {
-        if source.values.is_subset(self.values.as_ref()) {
+        if source
+            .values
+            .iter()
+            .all(|value| self.values.contains(value))
+        {
             None
         } else {
             Some(format!(
@@ -786,6 +2982,9 @@ pub enum ColumnType {
     String,
     TSVector(FulltextConfig),
     Enum(EnumType),
+    /// A String attribute with an `@inet` directive that holds an IPv4 or
+    /// IPv6 address, optionally with a netmask
+    Inet,
 }
 
 impl From<IdType> for ColumnType {
@@ -798,6 +2997,23 @@ impl From<IdType> for ColumnType {
 }
 
 impl ColumnType {
+    /// Return `true` if `value` is a single value of this type. Fulltext
+    /// columns are computed from other attributes and do not accept any
+    /// values
+    fn accepts(&self, value: &Value) -> bool {
+        match (self, value) {
+            (ColumnType::Boolean, Value::Bool(_))
+            | (ColumnType::BigDecimal, Value::BigDecimal(_))
+            | (ColumnType::BigInt, Value::BigInt(_))
+            | (ColumnType::Bytes, Value::Bytes(_))
+            | (ColumnType::Int, Value::Int(_))
+            | (ColumnType::String, Value::String(_))
+            | (ColumnType::Inet, Value::String(_)) => true,
+            (ColumnType::Enum(enum_type), Value::String(s)) => enum_type.values.contains(s),
+            _ => false,
+        }
+    }
+
     fn from_field_type(
         field_type: &q::Type,
         schema: &str,
@@ -845,6 +3061,7 @@ impl ColumnType {
             ColumnType::String => "text",
             ColumnType::TSVector(_) => "tsvector",
             ColumnType::Enum(enum_type) => enum_type.name.as_str(),
+            ColumnType::Inet => "inet",
         }
     }
 }
@@ -857,6 +3074,7 @@ pub struct Column {
     pub column_type: ColumnType,
     pub fulltext_fields: Option<HashSet<String>>,
     is_reference: bool,
+    encrypted: bool,
 }
 
 impl Column {
@@ -872,14 +3090,47 @@ impl Column {
         let is_reference =
             sql_name.as_str() != PRIMARY_KEY_COLUMN && is_object_type(&field.field_type, enums);
 
-        Ok(Column {
+        let encrypted = field.find_directive("encrypted".to_owned()).is_some();
+        let column_type = if encrypted {
+            let name = named_type(&field.field_type);
+            if name != "String" && name != "Bytes" {
+                return Err(StoreError::Unknown(format_err!(
+                    "@encrypted can only be used on String and Bytes attributes, but {} has type {}",
+                    field.name,
+                    field.field_type
+                )));
+            }
+            // The ciphertext is stored as raw bytes
+            ColumnType::Bytes
+        } else if field.find_directive("inet".to_owned()).is_some() {
+            if named_type(&field.field_type) != "String" {
+                return Err(StoreError::Unknown(format_err!(
+                    "@inet can only be used on String attributes, but {} has type {}",
+                    field.name,
+                    field.field_type
+                )));
+            }
+            ColumnType::Inet
+        } else {
+            ColumnType::from_field_type(&field.field_type, schema, enums, id_type)?
+        };
+
+        let column = Column {
             name: sql_name,
             field: field.name.clone(),
-            column_type: ColumnType::from_field_type(&field.field_type, schema, enums, id_type)?,
+            column_type,
             field_type: field.field_type.clone(),
             fulltext_fields: None,
             is_reference,
-        })
+            encrypted,
+        };
+        if encrypted && (column.is_list() || column.is_primary_key()) {
+            return Err(StoreError::Unknown(format_err!(
+                "@encrypted can not be used on lists or on the id, but it is used on {}",
+                field.name
+            )));
+        }
+        Ok(column)
     }
 
     fn new_fulltext(def: &FulltextDefinition) -> Result<Column, StoreError> {
@@ -893,6 +3144,7 @@ impl Column {
             column_type: ColumnType::TSVector(def.config.clone()),
             fulltext_fields: Some(def.included_fields.clone()),
             is_reference: false,
+            encrypted: false,
         })
     }
 
@@ -900,6 +3152,29 @@ impl Column {
         self.column_type.sql_type()
     }
 
+    /// The name of the type of this column as Postgres reports it in
+    /// `information_schema.columns.udt_name`. That uses internal names
+    /// like `int4`, and the name of arrays has a leading `_`
+    fn udt_name(&self) -> String {
+        let name = match &self.column_type {
+            ColumnType::Boolean => "bool".to_owned(),
+            ColumnType::BigDecimal | ColumnType::BigInt => "numeric".to_owned(),
+            ColumnType::Bytes => "bytea".to_owned(),
+            ColumnType::Int => "int4".to_owned(),
+            ColumnType::String => "text".to_owned(),
+            ColumnType::TSVector(_) => "tsvector".to_owned(),
+            // The name of the enum in `ColumnType` is qualified with the
+            // schema, but `udt_name` is not
+            ColumnType::Enum(_) => SqlName::from(self.named_type()).as_str().to_owned(),
+            ColumnType::Inet => "inet".to_owned(),
+        };
+        if self.is_list() {
+            format!("_{}", name)
+        } else {
+            name
+        }
+    }
+
     pub fn is_nullable(&self) -> bool {
         fn is_nullable(field_type: &q::Type) -> bool {
             match field_type {
@@ -939,15 +3214,76 @@ impl Column {
         self.is_reference
     }
 
+    /// Return `true` if the attribute has an `@encrypted` directive. The
+    /// column then holds the ciphertext of the value as `bytea`
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    /// The name of the GraphQL type of this column after stripping any
+    /// list and non-null wrappers
+    pub fn named_type(&self) -> &str {
+        named_type(&self.field_type)
+    }
+
     pub fn is_primary_key(&self) -> bool {
         self.name.as_str() == PRIMARY_KEY_COLUMN
     }
 
+    /// Return `true` if `value` can be stored in this column, taking into
+    /// account whether the column and the elements of list columns are
+    /// nullable
+    pub fn accepts(&self, value: &Value) -> bool {
+        fn elements_nullable(field_type: &q::Type) -> bool {
+            match field_type {
+                q::Type::NonNullType(inner) => elements_nullable(inner),
+                q::Type::ListType(inner) => match inner.as_ref() {
+                    q::Type::NonNullType(_) => false,
+                    _ => true,
+                },
+                q::Type::NamedType(_) => false,
+            }
+        }
+
+        match value {
+            Value::Null => self.is_nullable(),
+            Value::List(values) => {
+                self.is_list()
+                    && values.iter().all(|value| match value {
+                        Value::Null => elements_nullable(&self.field_type),
+                        value => self.column_type.accepts(value),
+                    })
+            }
+            // Encrypted columns accept the plaintext, not the ciphertext
+            Value::String(_) if self.encrypted => self.named_type() == "String",
+            Value::Bytes(_) if self.encrypted => self.named_type() == "Bytes",
+            value => !self.is_list() && self.column_type.accepts(value),
+        }
+    }
+
     /// Return `true` if this column stores user-supplied text. Such
     /// columns may contain very large values and need to be handled
     /// specially for indexing
     pub fn is_text(&self) -> bool {
-        named_type(&self.field_type) == "String" && !self.is_list()
+        named_type(&self.field_type) == "String"
+            && !self.is_list()
+            && self.column_type == ColumnType::String
+    }
+
+    pub fn is_inet(&self) -> bool {
+        self.column_type == ColumnType::Inet
+    }
+
+    /// Return `true` if this column stores a single number that the
+    /// database can compute with, i.e., an `Int`, `BigInt` or `BigDecimal`
+    /// that is neither a list nor encrypted
+    pub fn is_numeric(&self) -> bool {
+        match self.column_type {
+            ColumnType::Int | ColumnType::BigInt | ColumnType::BigDecimal => {
+                !self.is_list() && !self.is_encrypted()
+            }
+            _ => false,
+        }
     }
 
     pub fn is_assignable_from(&self, source: &Self, object: &str) -> Option<String> {
@@ -996,6 +3332,9 @@ impl Column {
     }
 }
 
+/// The maximum number of bind parameters Postgres allows in one statement
+const MAX_BIND_PARAMETERS: usize = 65535;
+
 /// The name for the primary key column of a table; hardcoded for now
 pub(crate) const PRIMARY_KEY_COLUMN: &str = "id";
 
@@ -1003,6 +3342,66 @@ pub(crate) const PRIMARY_KEY_COLUMN: &str = "id";
 /// synthetic primary key. This is the name of the column we use.
 pub(crate) const VID_COLUMN: &str = "vid";
 
+/// The column in which we record the fields that were explicitly set to
+/// null in a version of an entity. The database stores such a field and a
+/// field that was never set the same way, as a null in its column
+pub(crate) const EXPLICIT_NULLS_COLUMN: &str = "g$explicit_nulls";
+
+/// A field that is not stored in the table for its type, but derived
+/// from the references to entities of that type in `entity_type` with
+/// `@derivedFrom`
+#[derive(Clone, Debug)]
+pub struct DerivedField {
+    /// The GraphQL name of the field
+    pub field: String,
+    /// The entity type that stores the references
+    pub entity_type: String,
+    /// The GraphQL name of the attribute of `entity_type` that stores
+    /// the references
+    pub attribute: String,
+    /// The GraphQL type of the field
+    pub field_type: q::Type,
+}
+
+impl DerivedField {
+    fn new(field: &s::Field) -> Option<DerivedField> {
+        field
+            .find_directive("derivedFrom".to_owned())
+            .and_then(|directive| directive.argument("field"))
+            .and_then(|attribute| attribute.as_string())
+            .map(|attribute| DerivedField {
+                field: field.name.clone(),
+                entity_type: named_type(&field.field_type).to_owned(),
+                attribute: attribute.clone(),
+                field_type: field.field_type.clone(),
+            })
+    }
+}
+
+/// Maps the names of the columns of a table to their position in
+/// `Table.columns` so that looking up a column does not require a scan
+/// over all columns
+#[derive(Clone, Debug, Default)]
+struct ColumnIndex {
+    /// Lookup by SQL name; fulltext columns can not be found this way
+    by_name: HashMap<SqlName, usize>,
+    /// Lookup by GraphQL field name
+    by_field: HashMap<String, usize>,
+}
+
+impl ColumnIndex {
+    fn new(columns: &[Column]) -> Self {
+        let mut index = ColumnIndex::default();
+        for (pos, column) in columns.iter().enumerate() {
+            if !column.is_fulltext() {
+                index.by_name.entry(column.name.clone()).or_insert(pos);
+            }
+            index.by_field.entry(column.field.clone()).or_insert(pos);
+        }
+        index
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Table {
     /// The name of the GraphQL object type ('Thing')
@@ -1016,10 +3415,25 @@ pub struct Table {
     pub qualified_name: SqlName,
 
     pub columns: Vec<Column>,
+    /// Lookup tables for `columns`, computed once when the table is
+    /// created
+    column_index: ColumnIndex,
+    /// Fields of the GraphQL type that are not stored in this table since
+    /// they are derived from references in other tables
+    pub derived_fields: Vec<DerivedField>,
+    /// Composite indexes requested with `@order_index(fields: [..])` on
+    /// the GraphQL type. Each entry lists the columns of one index; the
+    /// index also covers `id` as its last column so that it can be used
+    /// for sorting with an `id` tiebreak
+    pub order_indexes: Vec<Vec<SqlName>>,
+    /// The names of the GraphQL interfaces that the object type implements
+    pub interfaces: Vec<s::Name>,
     /// The position of this table in all the tables for this layout; this
     /// is really only needed for the tests to make the names of indexes
     /// predictable
     position: u32,
+    /// Whether the table has the `EXPLICIT_NULLS_COLUMN`
+    explicit_nulls: bool,
 }
 
 impl Table {
@@ -1041,35 +3455,144 @@ impl Table {
             .map(|field| Column::new(field, schema, enums, id_type))
             .chain(fulltexts.iter().map(|def| Column::new_fulltext(def)))
             .collect::<Result<Vec<Column>, StoreError>>()?;
+        let derived_fields = defn
+            .fields
+            .iter()
+            .filter_map(|field| DerivedField::new(field))
+            .collect();
 
-        let table = Table {
+        let mut table = Table {
             object: defn.name.clone(),
             name: table_name.clone(),
             qualified_name: SqlName::qualified_name(schema, &table_name),
+            column_index: ColumnIndex::new(&columns),
             columns,
+            derived_fields,
+            order_indexes: vec![],
+            interfaces: defn.implements_interfaces.clone(),
             position,
+            explicit_nulls: true,
         };
+        table.order_indexes = defn
+            .directives
+            .iter()
+            .filter(|directive| directive.name == "order_index")
+            .map(|directive| table.order_index(directive))
+            .collect::<Result<_, _>>()?;
         Ok(table)
     }
 
+    /// Find the columns for an `@order_index(fields: [..])` directive. The
+    /// fields must be scalar attributes of this table
+    fn order_index(&self, directive: &s::Directive) -> Result<Vec<SqlName>, StoreError> {
+        let fields = directive
+            .argument("fields")
+            .and_then(|fields| fields.as_list())
+            .filter(|fields| !fields.is_empty())
+            .ok_or_else(|| {
+                StoreError::Unknown(format_err!(
+                    "@order_index on {} needs a non-empty list of `fields`",
+                    self.object
+                ))
+            })?;
+        fields
+            .iter()
+            .map(|field| {
+                let field = field.as_string().ok_or_else(|| {
+                    StoreError::Unknown(format_err!(
+                        "the fields of @order_index on {} must be strings",
+                        self.object
+                    ))
+                })?;
+                let column = self.column_for_field(field)?;
+                if column.is_list() || column.is_fulltext() {
+                    return Err(StoreError::Unknown(format_err!(
+                        "@order_index on {} can not include the list or fulltext field {}",
+                        self.object,
+                        field
+                    )));
+                }
+                Ok(column.name.clone())
+            })
+            .collect()
+    }
+
+    /// Return `true` if there is an `@order_index` on exactly `column`,
+    /// i.e., an index that can be used to sort by `column` and `id`
+    pub fn has_order_index(&self, column: &Column) -> bool {
+        self.order_indexes
+            .iter()
+            .any(|index| index.len() == 1 && index[0] == column.name)
+    }
+
+    /// Return a copy of this table that lives in the database schema
+    /// `schema` instead of the one it was created in
+    fn in_schema(&self, schema: &str) -> Table {
+        let mut table = self.clone();
+        table.qualified_name = SqlName::qualified_name(schema, &table.name);
+        for column in table.columns.iter_mut() {
+            let enum_name = SqlName::from(named_type(&column.field_type));
+            if let ColumnType::Enum(enum_type) = &mut column.column_type {
+                enum_type.name = SqlName::qualified_name(schema, &enum_name);
+            }
+        }
+        table
+    }
+
+    /// The name of the exclusion constraint that keeps versions of the same
+    /// entity from overlapping. Tables created before the DDL named the
+    /// constraint have the name that Postgres generated for it, and we use
+    /// the same name, including how Postgres shortens the table name so
+    /// that the constraint name fits into 63 bytes
+    fn id_exclusion_constraint(&self) -> String {
+        let table_name: String = self
+            .name
+            .as_str()
+            .chars()
+            .take(63 - ID_EXCLUSION_SUFFIX.len())
+            .collect();
+        format!("{}{}", table_name, ID_EXCLUSION_SUFFIX)
+    }
+
+    /// Whether the table records which fields were explicitly set to null
+    /// in the `EXPLICIT_NULLS_COLUMN`
+    pub(crate) fn has_explicit_nulls(&self) -> bool {
+        self.explicit_nulls
+    }
+
     /// Find the column `name` in this table. The name must be in snake case,
     /// i.e., use SQL conventions
     pub fn column(&self, name: &SqlName) -> Option<&Column> {
-        self.columns
+        self.column_index
+            .by_name
+            .get(name)
+            .map(|pos| &self.columns[*pos])
+    }
+
+    /// Find the derived field `field` of this table's type. The name must
+    /// be the GraphQL name of the field
+    pub fn derived_field(&self, field: &str) -> Option<&DerivedField> {
+        self.derived_fields
             .iter()
-            .filter(|column| match column.column_type {
-                ColumnType::TSVector(_) => false,
-                _ => true,
-            })
-            .find(|column| &column.name == name)
+            .find(|derived| derived.field == field)
+    }
+
+    /// Return `true` if `attribute` can be set on entities stored in this
+    /// table: it is either stored in a column or derived from other
+    /// entities, or it is the `__typename` that entities carry around
+    pub fn has_attribute(&self, attribute: &str) -> bool {
+        attribute == "__typename"
+            || self.column_index.by_field.contains_key(attribute)
+            || self.derived_field(attribute).is_some()
     }
 
     /// Find the column for `field` in this table. The name must be the
     /// GraphQL name of an entity field
     pub fn column_for_field(&self, field: &str) -> Result<&Column, StoreError> {
-        self.columns
-            .iter()
-            .find(|column| &column.field == field)
+        self.column_index
+            .by_field
+            .get(field)
+            .map(|pos| &self.columns[*pos])
             .ok_or_else(|| StoreError::UnknownField(field.to_string()))
     }
 
@@ -1114,11 +3637,23 @@ impl Table {
         write!(
             out,
             "\n        {vid}                  bigserial primary key,\
-             \n        {block_range}          int4range not null,
-        exclude using gist   (id with =, {block_range} with &&)\n);\n",
+             \n        {block_range}          int4range not null,\n",
             vid = VID_COLUMN,
             block_range = BLOCK_RANGE_COLUMN
         )?;
+        if self.explicit_nulls {
+            writeln!(out, "        {}     text[],", EXPLICIT_NULLS_COLUMN)?;
+        }
+        writeln!(
+            out,
+            "        constraint \"{}\"",
+            self.id_exclusion_constraint()
+        )?;
+        write!(
+            out,
+            "        exclude using gist   (id with =, {} with &&)\n);\n",
+            BLOCK_RANGE_COLUMN
+        )?;
 
         // Create indexes. Skip columns whose type is an array of enum,
         // since there is no good way to index them with Postgres 9.6.
@@ -1157,10 +3692,107 @@ impl Table {
                 index_expr = index_expr,
             )?;
         }
+
+        // Composite indexes from `@order_index`. They index the full value
+        // of text columns rather than a prefix since they are meant for
+        // sorting; very large values in these columns will therefore make
+        // inserts fail
+        for (i, index) in self.order_indexes.iter().enumerate() {
+            let columns = index
+                .iter()
+                .map(|name| name.quoted())
+                .chain(std::iter::once(SqlName::from(PRIMARY_KEY_COLUMN).quoted()))
+                .collect::<Vec<_>>()
+                .join(", ");
+            write!(
+                out,
+                "create index order_{table_index}_{index}_{table_name}\n    on {schema_name}.\"{table_name}\" using btree({columns});\n",
+                table_index = self.position,
+                index = i,
+                table_name = self.name,
+                schema_name = layout.schema,
+                columns = columns,
+            )?;
+        }
         writeln!(out)
     }
 }
 
+/// The suffix of the name of the exclusion constraint
+/// `exclude using gist (id with =, block_range with &&)` that keeps
+/// versions of the same entity from overlapping; see
+/// `Table::id_exclusion_constraint`
+const ID_EXCLUSION_SUFFIX: &str = "_id_block_range_excl";
+
+/// Insert all entities in `chunk` into `table` with one statement
+fn insert_chunk(
+    conn: &PgConnection,
+    table: &Table,
+    chunk: Vec<(EntityKey, Entity)>,
+    block: BlockNumber,
+) -> Result<usize, StoreError> {
+    let keys = chunk.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>();
+    InsertManyQuery::new(table, chunk.into_iter(), block)?
+        .execute(conn)
+        .map_err(|e| insert_error(table, &keys, e))
+}
+
+/// Turn the error from inserting the entities `keys` into `table` into a
+/// `StoreError` that lets callers tell why the insert failed. Inserting an
+/// entity whose id is already in use violates either a unique constraint
+/// (SQLSTATE 23505) or, for versioned tables, the exclusion constraint on
+/// the id and block range (SQLSTATE 23P01). Diesel does not report the
+/// latter as its own kind of error, and we recognize it by the name of the
+/// constraint. When several entities were inserted with one statement, the
+/// details of the error tell us which id is the duplicate; if they don't
+/// mention any of the ids, we can not report a `DuplicateId`
+fn insert_error(table: &Table, keys: &[EntityKey], e: diesel::result::Error) -> StoreError {
+    use diesel::result::{DatabaseErrorInformation, DatabaseErrorKind, Error as DieselError};
+
+    let duplicate_id = |info: &dyn DatabaseErrorInformation| {
+        // Postgres describes the offending row as `Key (id)=(..)` for unique
+        // constraints, and as `Key (id, block_range)=(.., ..)` for the
+        // exclusion constraint. It prints Bytes ids as `\x..`
+        let details = info.details().unwrap_or("");
+        let mentions = |id: &str| {
+            details.contains(&format!("=({})", id)) || details.contains(&format!("=({}, ", id))
+        };
+        let key = match keys {
+            [key] => Some(key),
+            _ => keys.iter().find(|key| {
+                mentions(&key.entity_id)
+                    || (key.entity_id.starts_with("0x")
+                        && mentions(&format!("\\x{}", &key.entity_id[2..])))
+            }),
+        };
+        match key {
+            Some(key) => StoreError::DuplicateId(key.entity_type.clone(), key.entity_id.clone()),
+            None => StoreError::QueryExecutionError(format!(
+                "one of the {} entities of type {} that were inserted together has an id \
+                 that is already in use, but the database did not say which one: {}",
+                keys.len(),
+                table.object,
+                info.message()
+            )),
+        }
+    };
+
+    match &e {
+        DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info) => {
+            duplicate_id(info.as_ref())
+        }
+        DieselError::DatabaseError(_, info)
+            if info.constraint_name() == Some(table.id_exclusion_constraint().as_str()) =>
+        {
+            duplicate_id(info.as_ref())
+        }
+        DieselError::DatabaseError(DatabaseErrorKind::SerializationFailure, info) => {
+            StoreError::Aborted(TransactionAbortError::Other(info.message().to_owned()))
+        }
+        _ => StoreError::from(e),
+    }
+}
+
 /// Return the enclosed named type for a field type, i.e., the type after
 /// stripping List and NonNull.
 fn named_type(field_type: &q::Type) -> &str {
@@ -1171,6 +3803,104 @@ fn named_type(field_type: &q::Type) -> &str {
     }
 }
 
+/// Check that every object type in `document` declares all the fields of
+/// the interfaces it implements, with types that are compatible with the
+/// ones in the interface. Queries against an interface select the
+/// interface's fields from all implementing tables, and would fail in
+/// confusing ways at query time otherwise
+fn check_interface_implementations(document: &s::Document) -> Result<(), StoreError> {
+    use s::Definition::TypeDefinition;
+    use s::TypeDefinition::{Interface, Object};
+
+    // Return `true` if `name` is `interface` or an object type that
+    // implements `interface`
+    let implements = |name: &str, interface: &str| {
+        name == interface
+            || document.definitions.iter().any(|defn| match defn {
+                TypeDefinition(Object(obj_type)) => {
+                    obj_type.name == name
+                        && obj_type
+                            .implements_interfaces
+                            .iter()
+                            .any(|implemented| implemented == interface)
+                }
+                _ => false,
+            })
+    };
+
+    // A field in an implementing type can be stricter than the one in
+    // the interface: it can be non-null where the interface allows null,
+    // and refer to an implementation of an interface the interface refers to
+    fn compatible(
+        interface: &q::Type,
+        object: &q::Type,
+        implements: &dyn Fn(&str, &str) -> bool,
+    ) -> bool {
+        use q::Type::*;
+        match (interface, object) {
+            (NonNullType(interface), NonNullType(object)) => {
+                compatible(interface, object, implements)
+            }
+            (interface, NonNullType(object)) => compatible(interface, object, implements),
+            (ListType(interface), ListType(object)) => compatible(interface, object, implements),
+            (NamedType(interface), NamedType(object)) => implements(object, interface),
+            _ => false,
+        }
+    }
+
+    let interfaces: HashMap<&str, &s::InterfaceType> = document
+        .definitions
+        .iter()
+        .filter_map(|defn| match defn {
+            TypeDefinition(Interface(interface)) => Some((interface.name.as_str(), interface)),
+            _ => None,
+        })
+        .collect();
+
+    for defn in &document.definitions {
+        let obj_type = match defn {
+            TypeDefinition(Object(obj_type)) => obj_type,
+            _ => continue,
+        };
+        for name in &obj_type.implements_interfaces {
+            let interface = interfaces.get(name.as_str()).ok_or_else(|| {
+                StoreError::Unknown(format_err!(
+                    "type {} implements the interface {} which is not defined",
+                    obj_type.name,
+                    name
+                ))
+            })?;
+            for ifield in &interface.fields {
+                let field = obj_type
+                    .fields
+                    .iter()
+                    .find(|field| field.name == ifield.name)
+                    .ok_or_else(|| {
+                        StoreError::Unknown(format_err!(
+                            "type {} implements the interface {} but does not \
+                             declare its field {}",
+                            obj_type.name,
+                            interface.name,
+                            ifield.name
+                        ))
+                    })?;
+                if !compatible(&ifield.field_type, &field.field_type, &implements) {
+                    return Err(StoreError::Unknown(format_err!(
+                        "the field {}.{} has type {} which is not compatible with \
+                         the type {} of the field in the interface {}",
+                        obj_type.name,
+                        field.name,
+                        field.field_type,
+                        ifield.field_type,
+                        interface.name
+                    )));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
 fn derived_column(field: &s::Field) -> bool {
     field
         .directives
@@ -1199,6 +3929,26 @@ mod tests {
             .expect("Failed to construct Layout")
     }
 
+    #[test]
+    fn id_exclusion_constraint() {
+        let layout = test_layout(
+            "type Thing @entity { id: ID! }
+             type ThingWithAVeryLongNameThatPostgresHasToShorten @entity {
+                 id: ID!
+             }",
+        );
+        let table = layout.table(&"thing".into()).unwrap();
+        assert_eq!("thing_id_block_range_excl", table.id_exclusion_constraint());
+        // Postgres shortens the table name, not the suffix
+        let table = layout
+            .table(&"thing_with_a_very_long_name_that_postgres_has_to_shorten".into())
+            .unwrap();
+        assert_eq!(
+            "thing_with_a_very_long_name_that_postgres_h_id_block_range_excl",
+            table.id_exclusion_constraint()
+        );
+    }
+
     #[test]
     fn table_is_sane() {
         let layout = test_layout(THING_GQL);
@@ -1225,6 +3975,22 @@ mod tests {
         assert!(table.column(&bad_sql_name).is_none());
     }
 
+    #[test]
+    fn column_index_is_populated() {
+        let layout = test_layout(MUSIC_GQL);
+        for table in layout.tables.values() {
+            assert_eq!(table.columns.len(), table.column_index.by_field.len());
+            for column in &table.columns {
+                let by_field = table
+                    .column_for_field(&column.field)
+                    .expect("every field has a column");
+                assert_eq!(column.name, by_field.name);
+                let by_name = table.column(&column.name).expect("every column has a name");
+                assert_eq!(column.field, by_name.field);
+            }
+        }
+    }
+
     #[test]
     fn generate_ddl() {
         let layout = test_layout(THING_GQL);
@@ -1244,6 +4010,64 @@ mod tests {
         assert_eq!(FULLTEXT_DDL, sql);
     }
 
+    #[test]
+    fn interface_implementations() {
+        const PETS: &str = "
+            interface Pet { id: ID!, name: String!, friends: [Pet!] }
+            type Cat implements Pet @entity { id: ID!, name: String!, friends: [Cat!]! }
+            type Dog implements Pet @entity { id: ID!, name: String!, friends: [Pet!] }";
+        let document = parse_schema(PETS).unwrap();
+        assert!(check_interface_implementations(&document).is_ok());
+
+        let broken = format!(
+            "{}\ntype Ferret implements Pet @entity {{ id: ID!, friends: [Pet!] }}",
+            PETS
+        );
+        let document = parse_schema(&broken).unwrap();
+        let err = check_interface_implementations(&document).unwrap_err();
+        assert_eq!(
+            "store error: type Ferret implements the interface Pet \
+             but does not declare its field name",
+            err.to_string()
+        );
+
+        let broken = format!(
+            "{}\ntype Ferret implements Pet @entity {{ id: ID!, name: Int!, friends: [Pet!] }}",
+            PETS
+        );
+        let document = parse_schema(&broken).unwrap();
+        let err = check_interface_implementations(&document).unwrap_err();
+        assert!(err.to_string().contains("Ferret.name"));
+    }
+
+    #[test]
+    fn order_index() {
+        let layout = test_layout(
+            "type User @entity @order_index(fields: [\"name\"]) {
+                id: ID!,
+                name: String!,
+                age: Int
+            }",
+        );
+        let table = layout.table(&"user".into()).unwrap();
+        assert_eq!(vec![vec![SqlName::from("name")]], table.order_indexes);
+        assert!(table.has_order_index(table.column(&"name".into()).unwrap()));
+        assert!(!table.has_order_index(table.column(&"age".into()).unwrap()));
+
+        let sql = layout.as_ddl().expect("Failed to generate DDL");
+        assert!(sql.contains(
+            "create index order_0_0_user\n    on rel.\"user\" using btree(\"name\", \"id\");\n"
+        ));
+
+        // Unknown fields are rejected
+        let schema = parse_schema(
+            "type User @entity @order_index(fields: [\"nope\"]) { id: ID!, name: String! }",
+        )
+        .unwrap();
+        let subgraph = SubgraphDeploymentId::new("subgraph").unwrap();
+        assert!(Layout::new(&schema, IdType::String, subgraph, "rel", false).is_err());
+    }
+
     #[test]
     fn can_copy_from() {
         let source = test_layout(THING_GQL);
@@ -1338,6 +4162,8 @@ create table rel.\"thing\" (
 
         vid                  bigserial primary key,
         block_range          int4range not null,
+        g$explicit_nulls     text[],
+        constraint \"thing_id_block_range_excl\"
         exclude using gist   (id with =, block_range with &&)
 );
 create index attr_0_0_thing_id
@@ -1357,6 +4183,8 @@ create table rel.\"scalar\" (
 
         vid                  bigserial primary key,
         block_range          int4range not null,
+        g$explicit_nulls     text[],
+        constraint \"scalar_id_block_range_excl\"
         exclude using gist   (id with =, block_range with &&)
 );
 create index attr_1_0_scalar_id
@@ -1413,6 +4241,8 @@ type SongStat @entity {
 
         vid                  bigserial primary key,
         block_range          int4range not null,
+        g$explicit_nulls     text[],
+        constraint \"musician_id_block_range_excl\"
         exclude using gist   (id with =, block_range with &&)
 );
 create index attr_0_0_musician_id
@@ -1431,6 +4261,8 @@ create table rel.\"band\" (
 
         vid                  bigserial primary key,
         block_range          int4range not null,
+        g$explicit_nulls     text[],
+        constraint \"band_id_block_range_excl\"
         exclude using gist   (id with =, block_range with &&)
 );
 create index attr_1_0_band_id
@@ -1447,6 +4279,8 @@ create table rel.\"song\" (
 
         vid                  bigserial primary key,
         block_range          int4range not null,
+        g$explicit_nulls     text[],
+        constraint \"song_id_block_range_excl\"
         exclude using gist   (id with =, block_range with &&)
 );
 create index attr_2_0_song_id
@@ -1462,6 +4296,8 @@ create table rel.\"song_stat\" (
 
         vid                  bigserial primary key,
         block_range          int4range not null,
+        g$explicit_nulls     text[],
+        constraint \"song_stat_id_block_range_excl\"
         exclude using gist   (id with =, block_range with &&)
 );
 create index attr_3_0_song_stat_id
@@ -1498,6 +4334,8 @@ type Habitat @entity {
 
         vid                  bigserial primary key,
         block_range          int4range not null,
+        g$explicit_nulls     text[],
+        constraint \"animal_id_block_range_excl\"
         exclude using gist   (id with =, block_range with &&)
 );
 create index attr_0_0_animal_id
@@ -1510,6 +4348,8 @@ create table rel.\"forest\" (
 
         vid                  bigserial primary key,
         block_range          int4range not null,
+        g$explicit_nulls     text[],
+        constraint \"forest_id_block_range_excl\"
         exclude using gist   (id with =, block_range with &&)
 );
 create index attr_1_0_forest_id
@@ -1522,6 +4362,8 @@ create table rel.\"habitat\" (
 
         vid                  bigserial primary key,
         block_range          int4range not null,
+        g$explicit_nulls     text[],
+        constraint \"habitat_id_block_range_excl\"
         exclude using gist   (id with =, block_range with &&)
 );
 create index attr_2_0_habitat_id
@@ -1572,6 +4414,8 @@ type Habitat @entity {
 
         vid                  bigserial primary key,
         block_range          int4range not null,
+        g$explicit_nulls     text[],
+        constraint \"animal_id_block_range_excl\"
         exclude using gist   (id with =, block_range with &&)
 );
 create index attr_0_0_animal_id
@@ -1590,6 +4434,8 @@ create table rel.\"forest\" (
 
         vid                  bigserial primary key,
         block_range          int4range not null,
+        g$explicit_nulls     text[],
+        constraint \"forest_id_block_range_excl\"
         exclude using gist   (id with =, block_range with &&)
 );
 create index attr_1_0_forest_id
@@ -1602,6 +4448,8 @@ create table rel.\"habitat\" (
 
         vid                  bigserial primary key,
         block_range          int4range not null,
+        g$explicit_nulls     text[],
+        constraint \"habitat_id_block_range_excl\"
         exclude using gist   (id with =, block_range with &&)
 );
 create index attr_2_0_habitat_id
@@ -5,18 +5,26 @@
 ///!
 ///! Code in this module works very hard to minimize the number of allocations
 ///! that it performs
-use diesel::pg::{Pg, PgConnection};
-use diesel::query_builder::{AstPass, QueryFragment, QueryId};
+use diesel::backend::Backend;
+use diesel::pg::{Pg, PgConnection, PgQueryBuilder};
+use diesel::query_builder::{AstPass, QueryBuilder, QueryFragment, QueryId};
 use diesel::query_dsl::{LoadQuery, RunQueryDsl};
 use diesel::result::QueryResult;
-use diesel::sql_types::{Array, Binary, Bool, Integer, Jsonb, Numeric, Range, Text};
+use diesel::sql_types::{
+    Array, BigInt, Binary, Bool, Integer, Jsonb, Nullable, Numeric, Range, Text,
+};
 use diesel::Connection;
+use std::borrow::Cow;
 use std::collections::{BTreeMap, HashSet};
 use std::convert::TryFrom;
 use std::iter::FromIterator;
 use std::str::FromStr;
+use std::sync::Arc;
 
-use graph::data::{schema::FulltextAlgorithm, store::scalar};
+use graph::data::{
+    schema::{FulltextAlgorithm, FulltextConfig},
+    store::scalar,
+};
 use graph::prelude::{
     format_err, serde_json, Attribute, BlockNumber, Entity, EntityCollection, EntityFilter,
     EntityKey, EntityLink, EntityOrder, EntityRange, EntityWindow, ParentLink, QueryExecutionError,
@@ -28,8 +36,10 @@ use crate::block_range::{
 };
 use crate::entities::STRING_PREFIX_SIZE;
 use crate::filter::UnsupportedFilter;
-use crate::relational::{Column, ColumnType, Layout, SqlName, Table, PRIMARY_KEY_COLUMN};
-use crate::sql_value::SqlValue;
+use crate::relational::{
+    Column, ColumnType, Layout, SqlName, Table, EXPLICIT_NULLS_COLUMN, PRIMARY_KEY_COLUMN,
+};
+use crate::sql_value::{big_int_to_numeric, SqlValue};
 
 /// Helper struct for retrieving entities from the database. With diesel, we
 /// can only run queries that return columns whose number and type are known
@@ -95,9 +105,9 @@ impl EntityData {
                 number,
                 column_type
             ))),
-            (j::String(s), ColumnType::String) | (j::String(s), ColumnType::Enum(_)) => {
-                Ok(g::String(s))
-            }
+            (j::String(s), ColumnType::String)
+            | (j::String(s), ColumnType::Enum(_))
+            | (j::String(s), ColumnType::Inet) => Ok(g::String(s)),
             (j::String(s), ColumnType::Bytes) => {
                 scalar::Bytes::from_str(s.trim_start_matches("\\x"))
                     .map(|b| g::Bytes(b))
@@ -110,6 +120,8 @@ impl EntityData {
                 s,
                 column_type
             ))),
+            // `to_jsonb` turns arrays into JSON arrays with elements in the
+            // same order, which is the order in which they were written
             (j::Array(values), _) => Ok(g::List(
                 values
                     .into_iter()
@@ -126,6 +138,21 @@ impl EntityData {
         self.entity.clone()
     }
 
+    /// The fields that were explicitly set to null when this version of
+    /// the entity was written
+    pub fn explicit_nulls(&self) -> Vec<String> {
+        self.data
+            .get(EXPLICIT_NULLS_COLUMN)
+            .and_then(|nulls| nulls.as_array())
+            .map(|nulls| {
+                nulls
+                    .iter()
+                    .filter_map(|field| field.as_str().map(|field| field.to_owned()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
     /// Map the `EntityData` to an entity using the schema information
     /// in `Layout`
     pub fn to_entity(self, layout: &Layout) -> Result<Entity, StoreError> {
@@ -146,8 +173,28 @@ impl EntityData {
                     if key == "g$parent_id" {
                         let value = Self::value_from_json(&ColumnType::String, json)?;
                         entity.insert("g$parent_id".to_owned(), value);
-                    } else if let Some(column) = table.column(&SqlName::verbatim(key)) {
+                    } else if key.starts_with(LIST_HEAD_PREFIX) {
+                        let field = &key[LIST_HEAD_PREFIX.len()..];
+                        let column = table.column_for_field(field)?;
                         let value = Self::value_from_json(&column.column_type, json)?;
+                        entity.insert(format!("{}_first", field), value);
+                    } else if key == CHILD_COUNT_KEY {
+                        let value = Self::value_from_json(&ColumnType::Int, json)?;
+                        entity.insert(CHILD_COUNT_KEY.to_owned(), value);
+                    } else if key == BLOCKS_OLD_KEY {
+                        let value = Self::value_from_json(&ColumnType::Int, json)?;
+                        entity.insert(BLOCKS_OLD_KEY.to_owned(), value);
+                    } else if key == SCORE_KEY {
+                        let value = Self::value_from_json(&ColumnType::Int, json)?;
+                        entity.insert(SCORE_KEY.to_owned(), value);
+                    } else if key == RECENCY_KEY {
+                        let value = Self::value_from_json(&ColumnType::BigDecimal, json)?;
+                        entity.insert(RECENCY_KEY.to_owned(), value);
+                    } else if let Some(column) = table.column(&SqlName::verbatim(key)) {
+                        let mut value = Self::value_from_json(&column.column_type, json)?;
+                        if column.is_encrypted() {
+                            value = layout.decrypt_value(column, value)?;
+                        }
                         if value != Value::Null {
                             entity.insert(column.field.clone(), value);
                         }
@@ -162,6 +209,66 @@ impl EntityData {
     }
 }
 
+/// The prefix of the keys under which queries return the first element of
+/// list attributes when `FilterQuery::with_list_heads` is used
+const LIST_HEAD_PREFIX: &str = "g$first$";
+
+/// The key under which queries return the number of children when
+/// `FilterQuery::with_child_count` is used
+pub const CHILD_COUNT_KEY: &str = "__childCount";
+
+/// The key under which queries return how many blocks old a transaction
+/// is when `FilterQuery::with_blocks_old` is used
+pub const BLOCKS_OLD_KEY: &str = "blocksOld";
+
+/// The key under which `ScoredQuery` returns the score of each entity
+pub const SCORE_KEY: &str = "__score";
+
+/// The key under which `RecencyQuery` returns the recency of each entity
+pub const RECENCY_KEY: &str = "__recency";
+
+/// Escape the characters in `s` that have a special meaning in Postgres'
+/// regular expressions so that the result matches `s` literally
+pub fn escape_regex(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\.^$|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Convert the mask for the `bits_set` and `bits_any_set` filters to the
+/// 64 bit integer that we use for them in queries. Returns `None` if
+/// `value` is not an integer or does not fit into 64 bits
+pub fn bitmask(value: &Value) -> Option<i64> {
+    match value {
+        Value::Int(i) => Some(*i as i64),
+        Value::BigInt(i) => i.to_i64_checked(),
+        _ => None,
+    }
+}
+
+/// The escape character for patterns in `like` expressions; every such
+/// expression declares it with `escape` explicitly
+const LIKE_ESCAPE: char = '\\';
+
+/// Escape the characters in `s` that have a special meaning in `like`
+/// patterns, i.e., `%`, `_`, and the escape character itself, so that
+/// the result matches `s` literally
+pub fn escape_like(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '%' || c == '_' || c == LIKE_ESCAPE {
+            escaped.push(LIKE_ESCAPE);
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
 /// A `QueryValue` makes it possible to bind a `Value` into a SQL query
 /// using the metadata from Column
 struct QueryValue<'a>(&'a Value, &'a ColumnType);
@@ -186,8 +293,13 @@ impl<'a> QueryFragment<Pg> for QueryValue<'a> {
                     out.push_sql(")");
                     Ok(())
                 }
+                ColumnType::Inet => {
+                    out.push_bind_param::<Text, _>(s)?;
+                    out.push_sql("::inet");
+                    Ok(())
+                }
                 _ => unreachable!(
-                    "only string, enum and tsvector columns have values of type string"
+                    "only string, enum, tsvector and inet columns have values of type string"
                 ),
             },
             Value::Int(i) => out.push_bind_param::<Integer, _>(i),
@@ -206,6 +318,13 @@ impl<'a> QueryFragment<Pg> for QueryValue<'a> {
             }
             Value::Bool(b) => out.push_bind_param::<Bool, _>(b),
             Value::List(values) => {
+                // TSVector will only be in a Value::List() for inserts
+                if let ColumnType::TSVector(config) = &column_type {
+                    return push_tsvector(values, config, out);
+                }
+                // An empty list is bound as an array without elements, and
+                // therefore stays distinct from `Value::Null`, which is
+                // stored as SQL `null`
                 let values = SqlValue::new_array(values.clone());
                 match &column_type {
                     ColumnType::BigDecimal | ColumnType::BigInt => {
@@ -222,21 +341,12 @@ impl<'a> QueryFragment<Pg> for QueryValue<'a> {
                         out.push_sql("[]");
                         Ok(())
                     }
-                    // TSVector will only be in a Value::List() for inserts so "to_tsvector" can always be used here
-                    ColumnType::TSVector(config) => {
-                        out.push_sql("(");
-                        for (i, value) in values.iter().enumerate() {
-                            if i > 0 {
-                                out.push_sql(") || ");
-                            }
-                            out.push_sql("to_tsvector(");
-                            out.push_bind_param::<Text, _>(&config.language.as_str().to_string())?;
-                            out.push_sql("::regconfig, ");
-                            out.push_bind_param::<Text, _>(&value)?;
-                        }
-                        out.push_sql("))");
+                    ColumnType::Inet => {
+                        out.push_bind_param::<Array<Text>, _>(&values)?;
+                        out.push_sql("::inet[]");
                         Ok(())
                     }
+                    ColumnType::TSVector(_) => unreachable!("fulltext values were handled above"),
                 }
             }
             Value::Null => {
@@ -244,11 +354,43 @@ impl<'a> QueryFragment<Pg> for QueryValue<'a> {
                 Ok(())
             }
             Value::Bytes(b) => out.push_bind_param::<Binary, _>(&b.as_slice()),
-            Value::BigInt(i) => {
-                out.push_bind_param::<Numeric, _>(&i.clone().to_big_decimal(0.into()))
-            }
+            Value::BigInt(i) => out.push_bind_param::<Numeric, _>(&big_int_to_numeric(i)),
+        }
+    }
+}
+
+/// Generate
+///   setweight(to_tsvector($language::regconfig, $value), 'weight') || ..
+/// for the fulltext field values in `values`, which are in the order of
+/// `config.weights`. Values that are not set are left out
+fn push_tsvector(
+    values: &Vec<Value>,
+    config: &FulltextConfig,
+    mut out: AstPass<Pg>,
+) -> QueryResult<()> {
+    let weighted: Vec<_> = config
+        .weights
+        .values()
+        .zip(values.iter())
+        .filter_map(|(weight, value)| value.as_str().map(|text| (weight, text)))
+        .collect();
+    if weighted.is_empty() {
+        out.push_sql("''::tsvector");
+        return Ok(());
+    }
+    for (i, (weight, text)) in weighted.into_iter().enumerate() {
+        if i > 0 {
+            out.push_sql(" || ");
         }
+        out.push_sql("setweight(to_tsvector(");
+        out.push_bind_param::<Text, _>(&config.language.as_str().to_string())?;
+        out.push_sql("::regconfig, ");
+        out.push_bind_param::<Text, _>(&text)?;
+        out.push_sql("), '");
+        out.push_sql(weight.as_str());
+        out.push_sql("')");
     }
+    Ok(())
 }
 
 #[derive(Copy, Clone, PartialEq)]
@@ -412,6 +554,17 @@ impl<'a> QueryFragment<Pg> for PrefixComparison<'a> {
     }
 }
 
+/// How the rows of a table are connected to the rows of a child table
+/// in a `ChildNotExists` filter
+enum ChildLink<'a> {
+    /// The parent stores the id (or list of ids) of its children in this
+    /// column of the parent table
+    Parent(&'a Column),
+    /// The child stores the id (or list of ids) of its parents in this
+    /// column of the child table
+    Child(&'a Column),
+}
+
 /// A `QueryFilter` adds the conditions represented by the `filter` to
 /// the `where` clause of a SQL query. The attributes mentioned in
 /// the `filter` must all come from the given `table`, which is used to
@@ -421,21 +574,117 @@ impl<'a> QueryFragment<Pg> for PrefixComparison<'a> {
 pub struct QueryFilter<'a> {
     filter: &'a EntityFilter,
     table: &'a Table,
+    layout: &'a Layout,
+    block: BlockNumber,
+    /// The alias under which `table` appears in the query
+    alias: String,
 }
 
 impl<'a> QueryFilter<'a> {
-    pub fn new(filter: &'a EntityFilter, table: &'a Table) -> Result<Self, StoreError> {
-        Self::valid_attributes(filter, table)?;
-        Ok(QueryFilter { filter, table })
+    pub fn new(
+        filter: &'a EntityFilter,
+        table: &'a Table,
+        layout: &'a Layout,
+        block: BlockNumber,
+    ) -> Result<Self, StoreError> {
+        Self::valid_attributes(filter, table, layout)?;
+        Ok(QueryFilter {
+            filter,
+            table,
+            layout,
+            block,
+            alias: "c".to_owned(),
+        })
+    }
+
+    /// Find the table for the children that `attribute` of `table` refers
+    /// to, and how they are linked to rows in `table`. The attribute can
+    /// either be a reference stored in `table`, or a field derived from
+    /// references in the child table
+    fn child_link(
+        layout: &'a Layout,
+        table: &'a Table,
+        attribute: &str,
+    ) -> Result<(&'a Table, ChildLink<'a>), StoreError> {
+        if let Some(derived) = table.derived_field(attribute) {
+            let child = layout.table_for_entity(&derived.entity_type)?.as_ref();
+            let column = child.column_for_field(&derived.attribute)?;
+            return Ok((child, ChildLink::Child(column)));
+        }
+        let column = table.column_for_field(attribute)?;
+        if !column.is_reference() {
+            return Err(StoreError::QueryExecutionError(format!(
+                "the filter `child_not_exists` can only be used with references \
+                 to other entities, but `{}.{}` has type {}",
+                table.object, attribute, column.field_type
+            )));
+        }
+        let child = layout.table_for_entity(column.named_type())?.as_ref();
+        Ok((child, ChildLink::Parent(column)))
+    }
+
+    /// The value that `=`, `!=`, `in` and `not in` compare `column` with.
+    /// Bytes attributes can also be compared with a string that holds the
+    /// `0x`-hex form of the bytes, which is how API consumers usually pass
+    /// them. Values for encrypted attributes are encrypted so that they can
+    /// be compared with what is stored
+    fn comparison_value<'v>(
+        layout: &Layout,
+        column: &Column,
+        value: &'v Value,
+    ) -> Result<Cow<'v, Value>, StoreError> {
+        let value = match value {
+            Value::String(s) if column.named_type() == "Bytes" => {
+                let bytes = if s.starts_with("0x") {
+                    scalar::Bytes::from_str(s).map_err(|e| e.to_string())
+                } else {
+                    Err("it does not start with `0x`".to_owned())
+                };
+                let bytes = bytes.map_err(|e| {
+                    StoreError::QueryExecutionError(format!(
+                        "the Bytes attribute `{}` can only be compared with strings in \
+                         0x-hex form, but `{}` is not valid: {}",
+                        column.field, s, e
+                    ))
+                })?;
+                Cow::Owned(Value::Bytes(bytes))
+            }
+            value => Cow::Borrowed(value),
+        };
+        if column.is_encrypted() {
+            return Ok(Cow::Owned(layout.encrypt_value(column, &value)?));
+        }
+        Ok(value)
     }
 
-    fn valid_attributes(filter: &'a EntityFilter, table: &'a Table) -> Result<(), StoreError> {
+    fn valid_attributes(
+        filter: &'a EntityFilter,
+        table: &'a Table,
+        layout: &'a Layout,
+    ) -> Result<(), StoreError> {
         use EntityFilter::*;
         match filter {
             And(filters) | Or(filters) => {
                 for filter in filters {
-                    Self::valid_attributes(filter, table)?;
+                    Self::valid_attributes(filter, table, layout)?;
+                }
+            }
+
+            ChildNotExists(attr, filter) => {
+                let (child, _) = Self::child_link(layout, table, attr)?;
+                Self::valid_attributes(filter, child, layout)?;
+            }
+
+            ReferenceExists(attr) => {
+                let column = table.column_for_field(attr)?;
+                if !column.is_reference() {
+                    return Err(StoreError::QueryExecutionError(format!(
+                        "the filter `reference_exists` can only be used with references \
+                         to other entities, but `{}.{}` has type {}",
+                        table.object, attr, column.field_type
+                    )));
                 }
+                layout.table_for_entity(column.named_type())?;
             }
 
             Contains(attr, _)
@@ -452,7 +701,189 @@ impl<'a> QueryFilter<'a> {
             | NotStartsWith(attr, _)
             | EndsWith(attr, _)
             | NotEndsWith(attr, _) => {
-                table.column_for_field(attr)?;
+                let column = table.column_for_field(attr)?;
+                match filter {
+                    Equal(_, value) | Not(_, value) => {
+                        Self::comparison_value(layout, column, value)?;
+                    }
+                    In(_, values) | NotIn(_, values) => {
+                        for value in values {
+                            Self::comparison_value(layout, column, value)?;
+                        }
+                    }
+                    // Encrypted attributes can only be compared for equality
+                    // since all the database sees is their ciphertext
+                    _ if column.is_encrypted() => {
+                        return Err(StoreError::QueryExecutionError(format!(
+                            "the encrypted attribute `{}.{}` can only be used in the \
+                             filters `=`, `not`, `in` and `not_in`",
+                            table.object, column.field
+                        )))
+                    }
+                    _ => (),
+                }
+            }
+
+            Near(attr, _, _) => {
+                let column = table.column_for_field(attr)?;
+                if column.is_encrypted() {
+                    return Err(StoreError::QueryExecutionError(format!(
+                        "the encrypted attribute `{}.{}` can not be used with the \
+                         filter `near`",
+                        table.object, column.field
+                    )));
+                }
+                if !column.is_numeric() {
+                    return Err(StoreError::QueryExecutionError(format!(
+                        "the filter `near` can only be used with Int, BigInt, and \
+                         BigDecimal attributes, but `{}.{}` has type {}",
+                        table.object, attr, column.field_type
+                    )));
+                }
+            }
+
+            IsWhole(attr) => {
+                let column = table.column_for_field(attr)?;
+                if column.column_type != ColumnType::BigDecimal || column.is_list() {
+                    return Err(StoreError::QueryExecutionError(format!(
+                        "the filter `is_whole` can only be used with BigDecimal \
+                         attributes, but `{}.{}` has type {}",
+                        table.object, attr, column.field_type
+                    )));
+                }
+            }
+
+            ListEmpty(attr) | ListNotEmpty(attr) => {
+                let column = table.column_for_field(attr)?;
+                if !column.is_list() {
+                    return Err(StoreError::QueryExecutionError(format!(
+                        "the filters `list_empty` and `list_not_empty` can only be used \
+                         with list attributes, but `{}.{}` has type {}",
+                        table.object, attr, column.field_type
+                    )));
+                }
+            }
+
+            InSubnet(attr, subnet) => {
+                let column = table.column_for_field(attr)?;
+                if !column.is_inet() || column.is_list() {
+                    return Err(StoreError::QueryExecutionError(format!(
+                        "the filter `in_subnet` can only be used with @inet \
+                         attributes, but `{}.{}` has type {}",
+                        table.object, attr, column.field_type
+                    )));
+                }
+                if !subnet.is_string() {
+                    return Err(StoreError::QueryExecutionError(format!(
+                        "the filter `in_subnet` needs a subnet like `10.0.0.0/8`, \
+                         but `{}` is not a string",
+                        subnet
+                    )));
+                }
+            }
+
+            ModEquals(attr, modulus, _) => {
+                let column = table.column_for_field(attr)?;
+                let integer = match column.column_type {
+                    ColumnType::Int | ColumnType::BigInt => true,
+                    _ => false,
+                };
+                if !integer || column.is_list() {
+                    return Err(StoreError::QueryExecutionError(format!(
+                        "the filter `mod_equals` can only be used with Int and \
+                         BigInt attributes, but `{}.{}` has type {}",
+                        table.object, attr, column.field_type
+                    )));
+                }
+                if *modulus == 0 {
+                    return Err(StoreError::QueryExecutionError(format!(
+                        "the filter `mod_equals` on `{}.{}` needs a modulus \
+                         that is not zero",
+                        table.object, attr
+                    )));
+                }
+            }
+
+            LengthEqual(attr, _) | LengthGreaterThan(attr, _) | LengthLessThan(attr, _) => {
+                let column = table.column_for_field(attr)?;
+                if !column.is_list() {
+                    return Err(StoreError::QueryExecutionError(format!(
+                        "the filters `length`, `length_gt` and `length_lt` can only be \
+                         used with list attributes, but `{}.{}` has type {}",
+                        table.object, attr, column.field_type
+                    )));
+                }
+            }
+
+            InCaseInsensitive(attr, values) => {
+                let column = table.column_for_field(attr)?;
+                if !(column.is_text() || (column.is_enum() && !column.is_list())) {
+                    return Err(StoreError::QueryExecutionError(format!(
+                        "the filter `in_case_insensitive` can only be used with String \
+                         and enum attributes, but `{}.{}` has type {}",
+                        table.object, attr, column.field_type
+                    )));
+                }
+                if let Some(value) = values.iter().find(|value| !value.is_string()) {
+                    return Err(StoreError::QueryExecutionError(format!(
+                        "the filter `in_case_insensitive` can only be used with strings, \
+                         but `{}` is not a string",
+                        value
+                    )));
+                }
+            }
+
+            WordMatch(attr, value) => {
+                let column = table.column_for_field(attr)?;
+                if !column.is_text() {
+                    return Err(StoreError::QueryExecutionError(format!(
+                        "the filter `word_match` can only be used with String \
+                         attributes, but `{}.{}` has type {}",
+                        table.object, attr, column.field_type
+                    )));
+                }
+                if !value.is_string() {
+                    return Err(StoreError::QueryExecutionError(format!(
+                        "the filter `word_match` can only be used with strings, \
+                         but `{}` is not a string",
+                        value
+                    )));
+                }
+            }
+
+            BitsSet(attr, mask) | BitsAnySet(attr, mask) => {
+                let column = table.column_for_field(attr)?;
+                let integer = match column.column_type {
+                    ColumnType::Int | ColumnType::BigInt => true,
+                    _ => false,
+                };
+                if !integer || column.is_list() {
+                    return Err(StoreError::QueryExecutionError(format!(
+                        "the filters `bits_set` and `bits_any_set` can only be used \
+                         with Int and BigInt attributes, but `{}.{}` has type {}",
+                        table.object, attr, column.field_type
+                    )));
+                }
+                if bitmask(mask).is_none() {
+                    return Err(StoreError::QueryExecutionError(format!(
+                        "the filters `bits_set` and `bits_any_set` need an integer \
+                         mask that fits into 64 bits, but `{}` does not",
+                        mask
+                    )));
+                }
+            }
+
+            AnyFieldContains(attrs, _) => {
+                for attr in attrs {
+                    let column = table.column_for_field(attr)?;
+                    if !column.is_text() {
+                        return Err(StoreError::QueryExecutionError(format!(
+                            "the filter `any_field_contains` can only be used with \
+                             String attributes, but `{}.{}` has type {}",
+                            table.object, attr, column.field_type
+                        )));
+                    }
+                }
             }
         }
         Ok(())
@@ -462,6 +893,9 @@ impl<'a> QueryFilter<'a> {
         QueryFilter {
             filter,
             table: self.table,
+            layout: self.layout,
+            block: self.block,
+            alias: self.alias.clone(),
         }
     }
 
@@ -493,6 +927,201 @@ impl<'a> QueryFilter<'a> {
         Ok(())
     }
 
+    /// Generate `col is not null and coalesce(array_length(col, 1), 0) = 0`
+    /// for an empty list, and `array_length(col, 1) > 0` for a list that is
+    /// not empty. `array_length` is null for empty arrays as well as for
+    /// null, which is why we need to check for null explicitly
+    fn list_empty(
+        &self,
+        attribute: &Attribute,
+        empty: bool,
+        mut out: AstPass<Pg>,
+    ) -> QueryResult<()> {
+        let column = self.column(attribute);
+
+        if empty {
+            out.push_identifier(column.name.as_str())?;
+            out.push_sql(" is not null and coalesce(array_length(");
+            out.push_identifier(column.name.as_str())?;
+            out.push_sql(", 1), 0) = 0");
+        } else {
+            out.push_sql("array_length(");
+            out.push_identifier(column.name.as_str())?;
+            out.push_sql(", 1) > 0");
+        }
+        Ok(())
+    }
+
+    /// Generate
+    ///   attribute = trunc(attribute)
+    fn is_whole(&self, attribute: &Attribute, mut out: AstPass<Pg>) -> QueryResult<()> {
+        let column = self.column(attribute);
+
+        out.push_identifier(column.name.as_str())?;
+        out.push_sql(" = trunc(");
+        out.push_identifier(column.name.as_str())?;
+        out.push_sql(")");
+        Ok(())
+    }
+
+    /// Generate
+    ///   (attribute % $modulus) = $remainder
+    /// BigInt columns are `numeric`, and Postgres computes the remainder
+    /// for them in `numeric`, too, so that large values do not overflow
+    fn mod_equals(
+        &self,
+        attribute: &Attribute,
+        modulus: i64,
+        remainder: i64,
+        mut out: AstPass<Pg>,
+    ) -> QueryResult<()> {
+        let column = self.column(attribute);
+
+        out.push_sql("(");
+        out.push_identifier(column.name.as_str())?;
+        out.push_sql(" % ");
+        out.push_bind_param::<BigInt, _>(&modulus)?;
+        out.push_sql(") = ");
+        out.push_bind_param::<BigInt, _>(&remainder)?;
+        Ok(())
+    }
+
+    /// Generate
+    ///   attribute << $subnet::inet
+    fn in_subnet(
+        &self,
+        attribute: &Attribute,
+        subnet: &Value,
+        mut out: AstPass<Pg>,
+    ) -> QueryResult<()> {
+        let column = self.column(attribute);
+
+        out.push_identifier(column.name.as_str())?;
+        out.push_sql(" << ");
+        out.push_bind_param::<Text, _>(&subnet.as_str().unwrap_or_default().to_owned())?;
+        out.push_sql("::inet");
+        Ok(())
+    }
+
+    /// Generate
+    ///   cardinality(attribute) op $length
+    /// A null list has no length and never matches, just like it does not
+    /// match `list_empty`
+    fn list_length(
+        &self,
+        attribute: &Attribute,
+        length: i32,
+        op: Comparison,
+        mut out: AstPass<Pg>,
+    ) -> QueryResult<()> {
+        let column = self.column(attribute);
+
+        out.push_sql("cardinality(");
+        out.push_identifier(column.name.as_str())?;
+        out.push_sql(")");
+        out.push_sql(op.as_str());
+        out.push_bind_param::<Integer, _>(&length)?;
+        Ok(())
+    }
+
+    /// Generate
+    ///   lower(attribute) = any($values)
+    /// where `values` have already been lowercased. Enums are compared
+    /// through their text representation
+    fn in_case_insensitive(
+        &self,
+        attribute: &Attribute,
+        values: &Vec<Value>,
+        mut out: AstPass<Pg>,
+    ) -> QueryResult<()> {
+        let column = self.column(attribute);
+
+        if values.is_empty() {
+            out.push_sql("false");
+            return Ok(());
+        }
+
+        let values: Vec<String> = values
+            .iter()
+            .filter_map(|value| value.as_str())
+            .map(|s| s.to_lowercase())
+            .collect();
+        out.push_sql("lower(");
+        out.push_identifier(column.name.as_str())?;
+        if column.is_enum() {
+            out.push_sql("::text");
+        }
+        out.push_sql(") = any(");
+        out.push_bind_param::<Array<Text>, _>(&values)?;
+        out.push_sql(")");
+        Ok(())
+    }
+
+    /// Generate
+    ///   attribute ~* ('\m' || $word || '\M')
+    /// where `\m` and `\M` match the beginning and end of a word. Regular
+    /// expression metacharacters in `word` are escaped so that it is
+    /// matched literally
+    fn word_match(
+        &self,
+        attribute: &Attribute,
+        value: &Value,
+        mut out: AstPass<Pg>,
+    ) -> QueryResult<()> {
+        let column = self.column(attribute);
+        let word = escape_regex(value.as_str().unwrap_or_default());
+
+        out.push_identifier(column.name.as_str())?;
+        out.push_sql(" ~* ('\\m' || ");
+        out.push_bind_param::<Text, _>(&word)?;
+        out.push_sql(" || '\\M')");
+        Ok(())
+    }
+
+    /// Generate `(col & $mask) = $mask` if `all` is set, and
+    /// `(col & $mask) <> 0` otherwise. Postgres has no bitwise operators
+    /// for `numeric`, and BigInt columns are therefore cast to `int8`.
+    /// Values that do not fit into an `int8` never match; the cast is
+    /// guarded with a `case` so that they do not make the query fail
+    fn bits_set(
+        &self,
+        attribute: &Attribute,
+        mask: &Value,
+        all: bool,
+        mut out: AstPass<Pg>,
+    ) -> QueryResult<()> {
+        let column = self.column(attribute);
+        let mask = bitmask(mask).expect("the constructor already checked the mask");
+
+        let big_int = column.column_type == ColumnType::BigInt;
+        if big_int {
+            out.push_sql("case when ");
+            out.push_identifier(column.name.as_str())?;
+            out.push_sql(&format!(
+                " between {} and {} then ",
+                std::i64::MIN,
+                std::i64::MAX
+            ));
+        }
+        out.push_sql("(");
+        out.push_identifier(column.name.as_str())?;
+        if big_int {
+            out.push_sql("::int8");
+        }
+        out.push_sql(" & ");
+        out.push_bind_param::<BigInt, _>(&mask)?;
+        if all {
+            out.push_sql(") = ");
+            out.push_bind_param::<BigInt, _>(&mask)?;
+        } else {
+            out.push_sql(") <> 0");
+        }
+        if big_int {
+            out.push_sql(" else false end");
+        }
+        Ok(())
+    }
+
     fn contains(
         &self,
         attribute: &Attribute,
@@ -510,12 +1139,9 @@ impl<'a> QueryFilter<'a> {
                 } else {
                     out.push_sql(" like ")
                 };
-                if s.starts_with('%') || s.ends_with('%') {
-                    out.push_bind_param::<Text, _>(s)?;
-                } else {
-                    let s = format!("%{}%", s);
-                    out.push_bind_param::<Text, _>(&s)?;
-                }
+                let s = format!("%{}%", escape_like(s));
+                out.push_bind_param::<Text, _>(&s)?;
+                out.push_sql(" escape '\\'");
             }
             Value::Bytes(b) => {
                 out.push_sql("position(");
@@ -552,33 +1178,184 @@ impl<'a> QueryFilter<'a> {
         Ok(())
     }
 
-    fn equals(
+    fn child_not_exists(
         &self,
         attribute: &Attribute,
-        value: &Value,
-        op: Comparison,
+        filter: &'a EntityFilter,
         mut out: AstPass<Pg>,
     ) -> QueryResult<()> {
-        let column = self.column(attribute);
-
-        if column.is_text() && value.is_string() {
-            PrefixComparison::new(op, column, value).walk_ast(out.reborrow())?;
-        } else if column.is_fulltext() {
-            out.push_identifier(column.name.as_str())?;
-            out.push_sql(Comparison::Match.as_str());
-            QueryValue(value, &column.column_type).walk_ast(out)?;
-        } else {
-            out.push_identifier(column.name.as_str())?;
+        let (child, link) = Self::child_link(self.layout, self.table, attribute)
+            .expect("the constructor already checked that all attribute names are valid");
+        let child_filter = QueryFilter {
+            filter,
+            table: child,
+            layout: self.layout,
+            block: self.block,
+            alias: format!("{}c", self.alias),
+        };
+        let parent = self.alias.as_str();
+        let alias = child_filter.alias.as_str();
 
-            match value {
-                Value::String(_)
-                | Value::BigInt(_)
-                | Value::Bool(_)
-                | Value::Bytes(_)
-                | Value::BigDecimal(_)
-                | Value::Int(_)
-                | Value::List(_) => {
-                    out.push_sql(op.as_str());
+        // Generate
+        //   not exists (select 1 from {child} {alias}
+        //                where {alias}.block_range @> $block
+        //                  and {link}
+        //                  and {filter})
+        // Attributes in `filter` are not qualified with the table alias;
+        // they therefore refer to the child table in the subquery
+        out.push_sql("not exists (select 1 from ");
+        out.push_sql(child.qualified_name.as_str());
+        out.push_sql(" ");
+        out.push_sql(alias);
+        out.push_sql(" where ");
+        let prefix = format!("{}.", alias);
+        BlockRangeContainsClause::new(&prefix, self.block).walk_ast(out.reborrow())?;
+        out.push_sql(" and ");
+        match link {
+            ChildLink::Parent(column) => {
+                // {alias}.id = {parent}.{column} or
+                // {alias}.id = any({parent}.{column})
+                out.push_sql(alias);
+                out.push_sql(".");
+                out.push_identifier(PRIMARY_KEY_COLUMN)?;
+                if column.is_list() {
+                    out.push_sql(" = any(");
+                } else {
+                    out.push_sql(" = (");
+                }
+                out.push_sql(parent);
+                out.push_sql(".");
+                out.push_identifier(column.name.as_str())?;
+                out.push_sql(")");
+            }
+            ChildLink::Child(column) => {
+                // {parent}.id = {alias}.{column} or
+                // {parent}.id = any({alias}.{column})
+                out.push_sql(parent);
+                out.push_sql(".");
+                out.push_identifier(PRIMARY_KEY_COLUMN)?;
+                if column.is_list() {
+                    out.push_sql(" = any(");
+                } else {
+                    out.push_sql(" = (");
+                }
+                out.push_sql(alias);
+                out.push_sql(".");
+                out.push_identifier(column.name.as_str())?;
+                out.push_sql(")");
+            }
+        }
+        out.push_sql(" and ");
+        child_filter.walk_ast(out.reborrow())?;
+        out.push_sql(")");
+        Ok(())
+    }
+
+    fn reference_exists(&self, attribute: &Attribute, mut out: AstPass<Pg>) -> QueryResult<()> {
+        let column = self.column(attribute);
+        let target = self
+            .layout
+            .table_for_entity(column.named_type())
+            .expect("the constructor already checked that the referenced type exists");
+        let alias = format!("{}r", self.alias);
+
+        // Generate
+        //   exists (select 1 from {target} {alias}
+        //            where {alias}.id = {parent}.{column}
+        //              and {alias}.block_range @> $block)
+        // For list-valued references, generate
+        //   {parent}.{column} is not null
+        //   and not exists (select 1 from unnest({parent}.{column}) {alias}l(id)
+        //                    where not exists (select 1 from {target} {alias}
+        //                                       where {alias}.id = {alias}l.id
+        //                                         and {alias}.block_range @> $block))
+        let reference = if column.is_list() {
+            out.push_sql(&self.alias);
+            out.push_sql(".");
+            out.push_identifier(column.name.as_str())?;
+            out.push_sql(" is not null and not exists (select 1 from unnest(");
+            out.push_sql(&self.alias);
+            out.push_sql(".");
+            out.push_identifier(column.name.as_str())?;
+            out.push_sql(") ");
+            out.push_sql(&alias);
+            out.push_sql("l(id) where not ");
+            format!("{}l.id", alias)
+        } else {
+            format!("{}.\"{}\"", self.alias, column.name.as_str())
+        };
+        out.push_sql("exists (select 1 from ");
+        out.push_sql(target.qualified_name.as_str());
+        out.push_sql(" ");
+        out.push_sql(&alias);
+        out.push_sql(" where ");
+        out.push_sql(&alias);
+        out.push_sql(".");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        out.push_sql(" = ");
+        out.push_sql(&reference);
+        out.push_sql(" and ");
+        let prefix = format!("{}.", alias);
+        BlockRangeContainsClause::new(&prefix, self.block).walk_ast(out.reborrow())?;
+        out.push_sql(")");
+        if column.is_list() {
+            out.push_sql(")");
+        }
+        Ok(())
+    }
+
+    fn any_field_contains(
+        &self,
+        attributes: &Vec<Attribute>,
+        value: &Value,
+        mut out: AstPass<Pg>,
+    ) -> QueryResult<()> {
+        if attributes.is_empty() {
+            out.push_sql(" false ");
+            return Ok(());
+        }
+        out.push_sql("(");
+        for (i, attribute) in attributes.iter().enumerate() {
+            if i > 0 {
+                out.push_sql(" or ");
+            }
+            self.contains(attribute, value, false, out.reborrow())?;
+        }
+        out.push_sql(")");
+        Ok(())
+    }
+
+    fn equals(
+        &self,
+        attribute: &Attribute,
+        value: &Value,
+        op: Comparison,
+        mut out: AstPass<Pg>,
+    ) -> QueryResult<()> {
+        let column = self.column(attribute);
+
+        let value = Self::comparison_value(self.layout, column, value)
+            .expect("the constructor already checked the value");
+        let value = value.as_ref();
+
+        if column.is_text() && value.is_string() {
+            PrefixComparison::new(op, column, value).walk_ast(out.reborrow())?;
+        } else if column.is_fulltext() {
+            out.push_identifier(column.name.as_str())?;
+            out.push_sql(Comparison::Match.as_str());
+            QueryValue(value, &column.column_type).walk_ast(out)?;
+        } else {
+            out.push_identifier(column.name.as_str())?;
+
+            match value {
+                Value::String(_)
+                | Value::BigInt(_)
+                | Value::Bool(_)
+                | Value::Bytes(_)
+                | Value::BigDecimal(_)
+                | Value::Int(_)
+                | Value::List(_) => {
+                    out.push_sql(op.as_str());
                     QueryValue(value, &column.column_type).walk_ast(out)?;
                 }
                 Value::Null => {
@@ -624,6 +1401,66 @@ impl<'a> QueryFilter<'a> {
         Ok(())
     }
 
+    fn near(
+        &self,
+        attribute: &Attribute,
+        center: &Value,
+        tolerance: &Value,
+        mut out: AstPass<Pg>,
+    ) -> QueryResult<()> {
+        let column = self.column(attribute);
+
+        if !column.is_numeric() {
+            return Err(UnsupportedFilter {
+                filter: "near".to_owned(),
+                value: center.clone(),
+            }
+            .into());
+        }
+        for value in &[center, tolerance] {
+            match value {
+                Value::BigInt(_) | Value::BigDecimal(_) | Value::Int(_) => {}
+                Value::Bool(_)
+                | Value::Bytes(_)
+                | Value::List(_)
+                | Value::Null
+                | Value::String(_) => {
+                    return Err(UnsupportedFilter {
+                        filter: "near".to_owned(),
+                        value: (*value).clone(),
+                    }
+                    .into());
+                }
+            }
+        }
+
+        // Generate
+        //   column between ($center - $tolerance) and ($center + $tolerance)
+        out.push_identifier(column.name.as_str())?;
+        out.push_sql(" between (");
+        Self::near_operand(center, column, out.reborrow())?;
+        out.push_sql(" - ");
+        Self::near_operand(tolerance, column, out.reborrow())?;
+        out.push_sql(") and (");
+        Self::near_operand(center, column, out.reborrow())?;
+        out.push_sql(" + ");
+        Self::near_operand(tolerance, column, out.reborrow())?;
+        out.push_sql(")");
+        Ok(())
+    }
+
+    /// Push `value` as an operand of the bounds that `near` computes.
+    /// `Int` values are widened to `int8` so that adding the tolerance to
+    /// the center can not overflow, which matches the saturating bounds
+    /// of the JSONB storage scheme
+    fn near_operand(value: &Value, column: &Column, mut out: AstPass<Pg>) -> QueryResult<()> {
+        QueryValue(value, &column.column_type).walk_ast(out.reborrow())?;
+        if let Value::Int(_) = value {
+            out.push_sql("::int8");
+        }
+        Ok(())
+    }
+
     fn in_array(
         &self,
         attribute: &Attribute,
@@ -643,8 +1480,11 @@ impl<'a> QueryFilter<'a> {
         //   attribute {in|not in} (value1, value2, ...)
         // and for NULL values we generate
         //   attribute {is|is not} null
-        // If we have both NULL and non-NULL values we join these
-        // two clauses with OR.
+        // If we have both NULL and non-NULL values we join these two
+        // clauses with OR for `in` and with AND for `not in`, since an
+        // attribute that is NULL is neither equal nor unequal to any
+        // non-NULL value; for a boolean attribute, NULL is neither `true`
+        // nor `false`.
         //
         // Note that when we have no non-NULL values at all, we must
         // not generate `attribute {in|not in} ()` since the empty `()`
@@ -668,7 +1508,11 @@ impl<'a> QueryFilter<'a> {
         }
 
         if have_nulls && have_non_nulls {
-            out.push_sql(" or ");
+            if negated {
+                out.push_sql(" and ");
+            } else {
+                out.push_sql(" or ");
+            }
         }
 
         if have_non_nulls {
@@ -701,6 +1545,8 @@ impl<'a> QueryFilter<'a> {
                 if i > 0 {
                     out.push_sql(", ");
                 }
+                let value = Self::comparison_value(self.layout, column, value)
+                    .expect("the constructor already checked the value");
                 QueryValue(&value, &column.column_type).walk_ast(out.reborrow())?;
             }
             out.push_sql(")");
@@ -727,11 +1573,12 @@ impl<'a> QueryFilter<'a> {
         match value {
             Value::String(s) => {
                 let s = if starts_with {
-                    format!("{}%", s)
+                    format!("{}%", escape_like(s))
                 } else {
-                    format!("%{}", s)
+                    format!("%{}", escape_like(s))
                 };
-                out.push_bind_param::<Text, _>(&s)?
+                out.push_bind_param::<Text, _>(&s)?;
+                out.push_sql(" escape '\\'");
             }
             Value::Bool(_)
             | Value::BigInt(_)
@@ -763,6 +1610,24 @@ impl<'a> QueryFragment<Pg> for QueryFilter<'a> {
 
             Contains(attr, value) => self.contains(attr, value, false, out)?,
             NotContains(attr, value) => self.contains(attr, value, true, out)?,
+            AnyFieldContains(attrs, value) => self.any_field_contains(attrs, value, out)?,
+            Near(attr, center, tolerance) => self.near(attr, center, tolerance, out)?,
+            IsWhole(attr) => self.is_whole(attr, out)?,
+            ChildNotExists(attr, filter) => self.child_not_exists(attr, filter, out)?,
+            ReferenceExists(attr) => self.reference_exists(attr, out)?,
+            ListEmpty(attr) => self.list_empty(attr, true, out)?,
+            ListNotEmpty(attr) => self.list_empty(attr, false, out)?,
+            InCaseInsensitive(attr, values) => self.in_case_insensitive(attr, values, out)?,
+            WordMatch(attr, value) => self.word_match(attr, value, out)?,
+            BitsSet(attr, mask) => self.bits_set(attr, mask, true, out)?,
+            BitsAnySet(attr, mask) => self.bits_set(attr, mask, false, out)?,
+            LengthEqual(attr, length) => self.list_length(attr, *length, c::Equal, out)?,
+            LengthGreaterThan(attr, length) => self.list_length(attr, *length, c::Greater, out)?,
+            LengthLessThan(attr, length) => self.list_length(attr, *length, c::Less, out)?,
+            InSubnet(attr, subnet) => self.in_subnet(attr, subnet, out)?,
+            ModEquals(attr, modulus, remainder) => {
+                self.mod_equals(attr, *modulus, *remainder, out)?
+            }
 
             Equal(attr, value) => self.equals(attr, value, c::Equal, out)?,
             Not(attr, value) => self.equals(attr, value, c::NotEqual, out)?,
@@ -832,171 +1697,1820 @@ impl<'a> LoadQuery<PgConnection, EntityData> for FindQuery<'a> {
 
 impl<'a, Conn> RunQueryDsl<Conn> for FindQuery<'a> {}
 
-#[derive(Debug, Clone, Constructor)]
-pub struct FindManyQuery<'a> {
-    pub(crate) schema: &'a str,
-    pub(crate) tables: Vec<&'a Table>,
+#[derive(Debug, QueryableByName)]
+pub struct EntitySizeData {
+    #[sql_type = "BigInt"]
+    pub size: i64,
+}
 
-    // Maps object name to ids.
-    pub(crate) ids_for_type: BTreeMap<&'a str, Vec<&'a str>>,
-    pub(crate) block: BlockNumber,
+/// Find the number of bytes that the version of the entity with the
+/// given `id` that is current at `block` takes up, as reported by
+/// `pg_column_size`. Values that Postgres compressed count with their
+/// compressed size
+#[derive(Debug, Clone, Constructor)]
+pub struct EntitySizeQuery<'a> {
+    table: &'a Table,
+    id: &'a str,
+    block: BlockNumber,
 }
 
-impl<'a> QueryFragment<Pg> for FindManyQuery<'a> {
+impl<'a> QueryFragment<Pg> for EntitySizeQuery<'a> {
     fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
         out.unsafe_to_cache_prepared();
 
         // Generate
-        //    select $object0 as entity, to_jsonb(e.*) as data
-        //      from schema.<table0> e where id = any($ids0)
-        //    union all
-        //    select $object1 as entity, to_jsonb(e.*) as data
-        //      from schema.<table1> e where id = any($ids1)
-        //    union all
-        //    ...
-        for (i, table) in self.tables.iter().enumerate() {
-            if i > 0 {
-                out.push_sql("\nunion all\n");
-            }
-            out.push_sql("select ");
-            out.push_bind_param::<Text, _>(&table.object)?;
-            out.push_sql(" as entity, to_jsonb(e.*) as data\n");
-            out.push_sql("  from ");
-            out.push_sql(table.qualified_name.as_str());
-            out.push_sql(" e\n where ");
-            out.push_identifier(PRIMARY_KEY_COLUMN)?;
-            out.push_sql(" = any(");
-            out.push_bind_param::<Array<Text>, _>(&self.ids_for_type[table.object.as_str()])?;
-            out.push_sql(") and ");
-            BlockRangeContainsClause::new("e.", self.block).walk_ast(out.reborrow())?;
-        }
-        Ok(())
+        //    select pg_column_size(e.*)::int8 as size
+        //      from schema.table e where id = $1 and block_range @> $block
+        out.push_sql("select pg_column_size(e.*)::int8 as size\n");
+        out.push_sql("  from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql(" e\n where ");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        out.push_sql(" = ");
+        out.push_bind_param::<Text, _>(&self.id)?;
+        out.push_sql(" and ");
+        BlockRangeContainsClause::new("e.", self.block).walk_ast(out)
     }
 }
 
-impl<'a> QueryId for FindManyQuery<'a> {
+impl<'a> QueryId for EntitySizeQuery<'a> {
     type QueryId = ();
 
     const HAS_STATIC_QUERY_ID: bool = false;
 }
 
-impl<'a> LoadQuery<PgConnection, EntityData> for FindManyQuery<'a> {
-    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<EntityData>> {
+impl<'a> LoadQuery<PgConnection, EntitySizeData> for EntitySizeQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<EntitySizeData>> {
         conn.query_by_name(&self)
     }
 }
 
-impl<'a, Conn> RunQueryDsl<Conn> for FindManyQuery<'a> {}
+impl<'a, Conn> RunQueryDsl<Conn> for EntitySizeQuery<'a> {}
 
-#[derive(Debug, Clone)]
-pub struct InsertQuery<'a> {
+/// Find the version of the entity with the given `id` that was created
+/// first, regardless of whether it is still current or not
+#[derive(Debug, Clone, Constructor)]
+pub struct FindEarliestQuery<'a> {
     table: &'a Table,
-    key: &'a EntityKey,
-    entity: Entity,
-    block: BlockNumber,
-}
-
-impl<'a> InsertQuery<'a> {
-    pub fn new(
-        table: &'a Table,
-        key: &'a EntityKey,
-        entity: Entity,
-        block: BlockNumber,
-    ) -> Result<InsertQuery<'a>, StoreError> {
-        let mut entity = entity;
-        for column in table.columns.iter() {
-            match column.fulltext_fields.as_ref() {
-                Some(fields) => {
-                    let fulltext_updates = fields
-                        .iter()
-                        .filter_map(|field| entity.get(field))
-                        .cloned()
-                        .collect();
-                    entity.insert(column.field.to_string(), Value::List(fulltext_updates));
-                }
-                None => (),
-            }
-            if !column.is_nullable() && !entity.contains_key(&column.field) {
-                return Err(StoreError::QueryExecutionError(format!(
-                    "can not insert entity {}[{}] since value for non-nullable attribute {} is missing. \
-                     To fix this, mark the attribute as nullable in the GraphQL schema or change the \
-                     mapping code to always set this attribute.",
-                    key.entity_type, key.entity_id, column.field
-                )));
-            }
-        }
-
-        Ok(InsertQuery {
-            table,
-            key,
-            entity,
-            block,
-        })
-    }
+    id: &'a str,
 }
 
-impl<'a> QueryFragment<Pg> for InsertQuery<'a> {
+impl<'a> QueryFragment<Pg> for FindEarliestQuery<'a> {
     fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
         out.unsafe_to_cache_prepared();
 
-        // Construct a query
-        //   insert into schema.table(column, ...)
-        //   values ($1, ...)
-        // and convert and bind the entity's values into it
-        out.push_sql("insert into ");
+        // Generate
+        //    select '..' as entity, to_jsonb(e.*) as data
+        //      from schema.table e where id = $1
+        //     order by lower(e.block_range)
+        //     limit 1
+        out.push_sql("select ");
+        out.push_bind_param::<Text, _>(&self.table.object)?;
+        out.push_sql(" as entity, to_jsonb(e.*) as data\n");
+        out.push_sql("  from ");
         out.push_sql(self.table.qualified_name.as_str());
-
-        out.push_sql("(");
-        for column in self.table.columns.iter() {
-            if self.entity.contains_key(&column.field) {
-                out.push_identifier(column.name.as_str())?;
-                out.push_sql(", ");
-            }
-        }
+        out.push_sql(" e\n where ");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        out.push_sql(" = ");
+        out.push_bind_param::<Text, _>(&self.id)?;
+        out.push_sql("\n order by lower(e.");
         out.push_identifier(BLOCK_RANGE_COLUMN)?;
-
-        out.push_sql(")\nvalues(");
-        for column in self.table.columns.iter() {
-            if let Some(value) = self.entity.get(&column.field) {
-                QueryValue(value, &column.column_type).walk_ast(out.reborrow())?;
-                out.push_sql(", ");
-            }
-        }
-        let block_range: BlockRange = (self.block..).into();
-        out.push_bind_param::<Range<Integer>, _>(&block_range)?;
-        out.push_sql(")");
+        out.push_sql(")\n limit 1");
         Ok(())
     }
 }
 
-impl<'a> QueryId for InsertQuery<'a> {
+impl<'a> QueryId for FindEarliestQuery<'a> {
     type QueryId = ();
 
     const HAS_STATIC_QUERY_ID: bool = false;
 }
 
-impl<'a, Conn> RunQueryDsl<Conn> for InsertQuery<'a> {}
+impl<'a> LoadQuery<PgConnection, EntityData> for FindEarliestQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<EntityData>> {
+        conn.query_by_name(&self)
+    }
+}
 
-/// Update an existing entity in place. The `entity` only needs to contain
-/// the attributes that should be changed, and not the entire entity. In
-/// particular, it might not have an `id` attribute. If the entity has
-/// attributes that are not in the table, they are silently ignored.
-#[derive(Debug, Clone)]
-pub struct UpdateQuery<'a> {
+impl<'a, Conn> RunQueryDsl<Conn> for FindEarliestQuery<'a> {}
+
+/// Select a random sample of `n` entities that are current at `block`.
+/// The sample only depends on the state of the random number generator
+/// when the query starts, so that calling `setseed` first makes it
+/// reproducible
+#[derive(Debug, Clone, Constructor)]
+pub struct SampleQuery<'a> {
     table: &'a Table,
-    key: &'a EntityKey,
-    entity: &'a Entity,
+    n: u32,
+    block: BlockNumber,
 }
 
-impl<'a> UpdateQuery<'a> {
-    pub fn new(
-        table: &'a Table,
-        key: &'a EntityKey,
-        entity: &'a Entity,
-    ) -> Result<Self, StoreError> {
-        if let Some(Value::String(id)) = entity.get("id") {
-            if id != &key.entity_id {
-                return Err(StoreError::QueryExecutionError(format!(
+impl<'a> QueryFragment<Pg> for SampleQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Generate
+        //    select '..' as entity, to_jsonb(c.*) as data
+        //      from (select * from schema.table c
+        //             where c.block_range @> $block
+        //             order by c.vid) c
+        //     order by random()
+        //     limit $n
+        // The inner `order by` makes sure that `random()` sees the rows
+        // in the same order every time, independent of how Postgres
+        // decides to scan the table
+        out.push_sql("select ");
+        out.push_bind_param::<Text, _>(&self.table.object)?;
+        out.push_sql(" as entity, to_jsonb(c.*) as data\n");
+        out.push_sql("  from (select * from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql(" c\n where ");
+        BlockRangeContainsClause::new("c.", self.block).walk_ast(out.reborrow())?;
+        out.push_sql("\n order by c.vid) c\n order by random()\n limit ");
+        out.push_sql(&self.n.to_string());
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for SampleQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, EntityData> for SampleQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<EntityData>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for SampleQuery<'a> {}
+
+#[derive(Debug, QueryableByName)]
+pub struct SelectivityData {
+    #[sql_type = "BigInt"]
+    pub sampled: i64,
+    #[sql_type = "BigInt"]
+    pub matching: i64,
+}
+
+/// Count how many of the rows in a random sample of `sample_pct` percent
+/// of the rows in `table` are current at `block`, and how many of those
+/// match `filter`. Since `tablesample` picks rows before the block range
+/// is checked, the number of sampled entities varies from run to run
+#[derive(Debug, Clone, Constructor)]
+pub struct SelectivityQuery<'a> {
+    table: &'a Table,
+    filter: QueryFilter<'a>,
+    sample_pct: f64,
+    block: BlockNumber,
+}
+
+impl<'a> QueryFragment<Pg> for SelectivityQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Generate
+        //    select count(*) as sampled,
+        //           count(*) filter (where query_filter) as matching
+        //      from schema.table c tablesample bernoulli ($sample_pct)
+        //     where c.block_range @> $block
+        // We use `bernoulli` rather than `system` sampling since `system`
+        // picks whole pages, which makes the sample much less random for
+        // small tables
+        out.push_sql("select count(*) as sampled,\n");
+        out.push_sql("       count(*) filter (where ");
+        self.filter.walk_ast(out.reborrow())?;
+        out.push_sql(") as matching\n  from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql(" c tablesample bernoulli (");
+        out.push_sql(&self.sample_pct.to_string());
+        out.push_sql(")\n where ");
+        BlockRangeContainsClause::new("c.", self.block).walk_ast(out.reborrow())?;
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for SelectivityQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, SelectivityData> for SelectivityQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<SelectivityData>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for SelectivityQuery<'a> {}
+
+/// Retrieve all entities of a type that are current at a block, ordered
+/// by id
+#[derive(Debug, Clone, Constructor)]
+pub struct DumpQuery<'a> {
+    table: &'a Table,
+    block: BlockNumber,
+}
+
+impl<'a> QueryFragment<Pg> for DumpQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Generate
+        //    select '..' as entity, to_jsonb(c.*) as data
+        //      from schema.table c
+        //     where c.block_range @> $block
+        //     order by c.id
+        out.push_sql("select ");
+        out.push_bind_param::<Text, _>(&self.table.object)?;
+        out.push_sql(" as entity, to_jsonb(c.*) as data\n  from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql(" c\n where ");
+        BlockRangeContainsClause::new("c.", self.block).walk_ast(out.reborrow())?;
+        out.push_sql("\n order by c.");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for DumpQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, EntityData> for DumpQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<EntityData>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for DumpQuery<'a> {}
+
+/// Retrieve the entities of a type that are current at `block` and whose
+/// current version was written after `since`, ordered by id
+#[derive(Debug, Clone, Constructor)]
+pub struct ChangedSinceQuery<'a> {
+    table: &'a Table,
+    since: BlockNumber,
+    block: BlockNumber,
+}
+
+impl<'a> QueryFragment<Pg> for ChangedSinceQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Generate
+        //    select '..' as entity, to_jsonb(c.*) as data
+        //      from schema.table c
+        //     where c.block_range @> $block
+        //       and lower(c.block_range) > $since
+        //     order by c.id
+        out.push_sql("select ");
+        out.push_bind_param::<Text, _>(&self.table.object)?;
+        out.push_sql(" as entity, to_jsonb(c.*) as data\n  from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql(" c\n where ");
+        BlockRangeContainsClause::new("c.", self.block).walk_ast(out.reborrow())?;
+        out.push_sql("\n   and lower(c.");
+        out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        out.push_sql(") > ");
+        out.push_bind_param::<Integer, _>(&self.since)?;
+        out.push_sql("\n order by c.");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for ChangedSinceQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, EntityData> for ChangedSinceQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<EntityData>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for ChangedSinceQuery<'a> {}
+
+/// Helper struct for retrieving the entities found by a `UnionQuery`.
+/// The `source` is the position of the table the entity comes from in
+/// the query's sources
+#[derive(QueryableByName)]
+pub struct UnionData {
+    #[sql_type = "Integer"]
+    pub source: i32,
+    #[sql_type = "Text"]
+    entity: String,
+    #[sql_type = "Jsonb"]
+    data: serde_json::Value,
+}
+
+impl UnionData {
+    pub fn to_entity(self, layout: &Layout) -> Result<Entity, StoreError> {
+        EntityData {
+            entity: self.entity,
+            data: self.data,
+        }
+        .to_entity(layout)
+    }
+}
+
+/// One of the tables that a `UnionQuery` combines, together with the
+/// filter for it and the column to sort by
+#[derive(Debug, Clone)]
+pub struct UnionSource<'a> {
+    pub table: &'a Table,
+    pub filter: Option<QueryFilter<'a>>,
+    pub sort_column: Option<&'a Column>,
+}
+
+/// Generate `case {prefix}name::text when $v0 then 0 .. end`, the
+/// position of the value of `name` in the declaration of its enum, so that
+/// enums sort in declaration order rather than alphabetically
+fn enum_position(
+    values: &[String],
+    prefix: &str,
+    name: &str,
+    out: &mut AstPass<Pg>,
+) -> QueryResult<()> {
+    out.push_sql("case ");
+    out.push_sql(prefix);
+    out.push_identifier(name)?;
+    out.push_sql("::text");
+    for (position, value) in values.iter().enumerate() {
+        out.push_sql(" when ");
+        out.push_bind_param::<Text, _>(value)?;
+        out.push_sql(" then ");
+        out.push_sql(&position.to_string());
+    }
+    out.push_sql(" end");
+    Ok(())
+}
+
+/// Query several tables for the same entity type, usually from different
+/// deployments, as if their rows were all in one table. The order and
+/// range apply to the combined rows
+#[derive(Debug, Clone, Constructor)]
+pub struct UnionQuery<'a> {
+    sources: Vec<UnionSource<'a>>,
+    direction: EntityOrder,
+    range: EntityRange,
+    block: BlockNumber,
+}
+
+impl<'a> UnionQuery<'a> {
+    /// Generate the value to sort the rows from `column` by; for enums,
+    /// that is the position of the value in the enum declaration, since
+    /// the enum types of different database schemas can not be compared
+    fn sort_key(column: &Column, out: &mut AstPass<Pg>) -> QueryResult<()> {
+        match &column.column_type {
+            ColumnType::Enum(enum_type) => {
+                enum_position(&enum_type.values, "c.", column.name.as_str(), out)?;
+            }
+            _ => {
+                out.push_sql("c.");
+                out.push_identifier(column.name.as_str())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> QueryFragment<Pg> for UnionQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Generate
+        //    select u.source, u.entity, u.data
+        //      from (select 0 as source, '..' as entity, to_jsonb(c.*) as data,
+        //                   c.id, {sort_key} as sort_key
+        //              from schema.table c
+        //             where c.block_range @> $block
+        //               and query_filter
+        //            union all
+        //            ...) u
+        //     order by u.sort_key {direction} nulls last, u.id {direction}
+        //     limit $first offset $skip
+        // When there is no sort column, we only order by id
+        let sorted = self
+            .sources
+            .iter()
+            .all(|source| source.sort_column.is_some());
+        out.push_sql("select u.source, u.entity, u.data\n  from (");
+        for (i, source) in self.sources.iter().enumerate() {
+            if i > 0 {
+                out.push_sql("\nunion all\n");
+            }
+            out.push_sql("select ");
+            out.push_sql(&i.to_string());
+            out.push_sql(" as source, ");
+            out.push_bind_param::<Text, _>(&source.table.object)?;
+            out.push_sql(" as entity, to_jsonb(c.*) as data, c.");
+            out.push_identifier(PRIMARY_KEY_COLUMN)?;
+            if let (true, Some(column)) = (sorted, source.sort_column) {
+                out.push_sql(", ");
+                Self::sort_key(column, &mut out)?;
+                out.push_sql(" as sort_key");
+            }
+            out.push_sql("\n  from ");
+            out.push_sql(source.table.qualified_name.as_str());
+            out.push_sql(" c\n where ");
+            BlockRangeContainsClause::new("c.", self.block).walk_ast(out.reborrow())?;
+            if let Some(filter) = &source.filter {
+                out.push_sql(" and ");
+                filter.walk_ast(out.reborrow())?;
+            }
+        }
+        out.push_sql(") u\n order by ");
+        if sorted {
+            out.push_sql("u.sort_key ");
+            out.push_sql(self.direction.to_sql());
+            out.push_sql(" nulls last, ");
+        }
+        out.push_sql("u.");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        out.push_sql(" ");
+        out.push_sql(self.direction.to_sql());
+        FilterRange(self.range.clone()).walk_ast(out.reborrow())
+    }
+}
+
+impl<'a> QueryId for UnionQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, UnionData> for UnionQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<UnionData>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for UnionQuery<'a> {}
+
+/// Helper struct for retrieving the ids of entities with dangling
+/// references
+#[derive(QueryableByName)]
+pub struct DanglingReferenceData {
+    #[sql_type = "Text"]
+    pub id: String,
+}
+
+/// Find the entities in `table` that are current at `block` and whose
+/// `reference` points to an entity that does not exist in `target` at
+/// `block`. For list-valued references, an entity is reported if any of
+/// the entries in the list is dangling. Entities where `reference` is not
+/// set are never reported
+#[derive(Debug, Clone, Constructor)]
+pub struct DanglingReferenceQuery<'a> {
+    table: &'a Table,
+    reference: &'a Column,
+    target: &'a Table,
+    block: BlockNumber,
+}
+
+impl<'a> DanglingReferenceQuery<'a> {
+    /// Generate
+    ///    not exists (select 1 from target p
+    ///                 where p.id = {id}
+    ///                   and p.block_range @> $block)
+    fn missing_target(&self, id: &str, out: &mut AstPass<Pg>) -> QueryResult<()> {
+        out.push_sql("not exists (select 1 from ");
+        out.push_sql(self.target.qualified_name.as_str());
+        out.push_sql(" p where p.");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        out.push_sql(" = ");
+        out.push_sql(id);
+        out.push_sql(" and ");
+        BlockRangeContainsClause::new("p.", self.block).walk_ast(out.reborrow())?;
+        out.push_sql(")");
+        Ok(())
+    }
+}
+
+impl<'a> QueryFragment<Pg> for DanglingReferenceQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Generate
+        //    select c.id
+        //      from schema.table c
+        //     where c.block_range @> $block
+        //       and c.reference is not null
+        //       and {missing_target(c.reference)}
+        //     order by c.id
+        // For list-valued references, the last condition becomes
+        //       and exists (select 1 from unnest(c.reference) r(id)
+        //                     where {missing_target(r.id)})
+        out.push_sql("select c.");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        out.push_sql("\n  from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql(" c\n where ");
+        BlockRangeContainsClause::new("c.", self.block).walk_ast(out.reborrow())?;
+        out.push_sql("\n   and c.");
+        out.push_identifier(self.reference.name.as_str())?;
+        out.push_sql(" is not null\n   and ");
+        if self.reference.is_list() {
+            out.push_sql("exists (select 1 from unnest(c.");
+            out.push_identifier(self.reference.name.as_str())?;
+            out.push_sql(") r(id) where ");
+            self.missing_target("r.id", &mut out)?;
+            out.push_sql(")");
+        } else {
+            let id = format!("c.\"{}\"", self.reference.name.as_str());
+            self.missing_target(&id, &mut out)?;
+        }
+        out.push_sql("\n order by c.");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for DanglingReferenceQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, DanglingReferenceData> for DanglingReferenceQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<DanglingReferenceData>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for DanglingReferenceQuery<'a> {}
+
+/// Helper struct for retrieving the ids of entities that have more than
+/// one live version
+#[derive(QueryableByName)]
+pub struct DuplicateLiveData {
+    #[sql_type = "Text"]
+    pub id: String,
+}
+
+/// Find the ids in `table` that have more than one version whose block
+/// range is unbounded above. The exclusion constraint on the table should
+/// make that impossible, but if it happens anyway, queries at any block
+/// after the second version was created return both versions
+#[derive(Debug, Clone, Constructor)]
+pub struct DuplicateLiveQuery<'a> {
+    table: &'a Table,
+}
+
+impl<'a> QueryFragment<Pg> for DuplicateLiveQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Generate
+        //   select c.id from table c
+        //    where upper_inf(c.block_range)
+        //    group by c.id
+        //   having count(*) > 1
+        //    order by c.id
+        out.push_sql("select c.");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        out.push_sql(" from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql(" c\n where upper_inf(c.");
+        out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        out.push_sql(")\n group by c.");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        out.push_sql("\nhaving count(*) > 1\n order by c.");
+        out.push_identifier(PRIMARY_KEY_COLUMN)
+    }
+}
+
+impl<'a> QueryId for DuplicateLiveQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, DuplicateLiveData> for DuplicateLiveQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<DuplicateLiveData>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for DuplicateLiveQuery<'a> {}
+
+/// Helper struct for retrieving the running total of gas used by the
+/// transactions of one sender
+#[derive(QueryableByName)]
+pub struct CumulativeGasData {
+    #[sql_type = "Text"]
+    pub id: String,
+    #[sql_type = "Numeric"]
+    pub total: scalar::BigDecimal,
+}
+
+/// Compute the running total of `gasUsed` over the transactions of one
+/// sender, ordered by the number of the block they are in and their index
+/// in that block. Transactions are entities that have the attributes
+/// `from`, `gasUsed`, `index`, and `block`, a reference to an entity with
+/// a `number` attribute
+#[derive(Debug, Clone)]
+pub struct CumulativeGasQuery<'a> {
+    transactions: &'a Table,
+    blocks: &'a Table,
+    from: &'a Column,
+    gas_used: &'a Column,
+    index: &'a Column,
+    block_ref: &'a Column,
+    number: &'a Column,
+    sender: &'a Value,
+    block: BlockNumber,
+}
+
+impl<'a> CumulativeGasQuery<'a> {
+    pub fn new(
+        layout: &'a Layout,
+        entity_type: &str,
+        sender: &'a Value,
+        block: BlockNumber,
+    ) -> Result<Self, StoreError> {
+        let transactions = layout.table_for_entity(entity_type)?.as_ref();
+        let block_ref = transactions.column_for_field("block")?;
+        let blocks = layout.table_for_entity(block_ref.named_type())?.as_ref();
+        Ok(CumulativeGasQuery {
+            transactions,
+            blocks,
+            from: transactions.column_for_field("from")?,
+            gas_used: transactions.column_for_field("gasUsed")?,
+            index: transactions.column_for_field("index")?,
+            block_ref,
+            number: blocks.column_for_field("number")?,
+            sender,
+            block,
+        })
+    }
+
+    /// Generate `b.number, t.index, t.id`
+    fn order(&self, out: &mut AstPass<Pg>) -> QueryResult<()> {
+        out.push_sql("b.");
+        out.push_identifier(self.number.name.as_str())?;
+        out.push_sql(", t.");
+        out.push_identifier(self.index.name.as_str())?;
+        out.push_sql(", t.");
+        out.push_identifier(PRIMARY_KEY_COLUMN)
+    }
+}
+
+impl<'a> QueryFragment<Pg> for CumulativeGasQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Generate
+        //    select t.id,
+        //           coalesce(sum(t.gas_used) over (order by {order}
+        //                      rows between unbounded preceding
+        //                               and current row), 0) as total
+        //      from {transactions} t, {blocks} b
+        //     where b.id = t.block
+        //       and t.block_range @> $block
+        //       and b.block_range @> $block
+        //       and t.from = $sender
+        //     order by {order}
+        // where {order} is b.number, t.index, t.id
+        out.push_sql("select t.");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        out.push_sql(", coalesce(sum(t.");
+        out.push_identifier(self.gas_used.name.as_str())?;
+        out.push_sql(") over (order by ");
+        self.order(&mut out)?;
+        out.push_sql(" rows between unbounded preceding and current row), 0)::numeric as total\n");
+        out.push_sql("  from ");
+        out.push_sql(self.transactions.qualified_name.as_str());
+        out.push_sql(" t, ");
+        out.push_sql(self.blocks.qualified_name.as_str());
+        out.push_sql(" b\n where b.");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        out.push_sql(" = t.");
+        out.push_identifier(self.block_ref.name.as_str())?;
+        out.push_sql("\n   and ");
+        BlockRangeContainsClause::new("t.", self.block).walk_ast(out.reborrow())?;
+        out.push_sql("\n   and ");
+        BlockRangeContainsClause::new("b.", self.block).walk_ast(out.reborrow())?;
+        out.push_sql("\n   and t.");
+        out.push_identifier(self.from.name.as_str())?;
+        out.push_sql(" = ");
+        QueryValue(self.sender, &self.from.column_type).walk_ast(out.reborrow())?;
+        out.push_sql("\n order by ");
+        self.order(&mut out)
+    }
+}
+
+impl<'a> QueryId for CumulativeGasQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, CumulativeGasData> for CumulativeGasQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<CumulativeGasData>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for CumulativeGasQuery<'a> {}
+
+/// Everything about a value in a filter that influences the SQL text
+/// that `QueryFilter` generates for it: whether it is null, whether a
+/// string is short enough to only compare its prefix, and the number of
+/// elements of a list
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum ValueShape {
+    Null,
+    String { large: bool },
+    Int,
+    BigDecimal,
+    Bool,
+    Bytes,
+    BigInt,
+    List(Vec<ValueShape>),
+}
+
+impl ValueShape {
+    fn new(value: &Value) -> Self {
+        match value {
+            Value::Null => ValueShape::Null,
+            Value::String(s) => ValueShape::String {
+                large: s.len() > STRING_PREFIX_SIZE - 1,
+            },
+            Value::Int(_) => ValueShape::Int,
+            Value::BigDecimal(_) => ValueShape::BigDecimal,
+            Value::Bool(_) => ValueShape::Bool,
+            Value::Bytes(_) => ValueShape::Bytes,
+            Value::BigInt(_) => ValueShape::BigInt,
+            Value::List(values) => ValueShape::List(values.iter().map(ValueShape::new).collect()),
+        }
+    }
+}
+
+/// The shape of an `EntityFilter`: `QueryFilter` generates the same SQL
+/// text for two filters with the same shape, and they only differ in the
+/// values that are passed as bind parameters
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FilterShape {
+    /// The kind of filter, e.g., `Equal`
+    op: &'static str,
+    attrs: Vec<Attribute>,
+    values: Vec<ValueShape>,
+    /// Parameters of the filter that are written into the SQL text
+    /// rather than passed as bind parameters
+    literals: Vec<i32>,
+    /// The nested filters of `And`, `Or` and `ChildNotExists`
+    filters: Vec<FilterShape>,
+}
+
+impl FilterShape {
+    pub fn new(filter: &EntityFilter) -> Self {
+        use EntityFilter::*;
+        match filter {
+            And(filters) => Self::nested("And", &[], filters),
+            Or(filters) => Self::nested("Or", &[], filters),
+            Equal(attr, value) => Self::attr("Equal", attr, Some(value)),
+            Not(attr, value) => Self::attr("Not", attr, Some(value)),
+            GreaterThan(attr, value) => Self::attr("GreaterThan", attr, Some(value)),
+            LessThan(attr, value) => Self::attr("LessThan", attr, Some(value)),
+            GreaterOrEqual(attr, value) => Self::attr("GreaterOrEqual", attr, Some(value)),
+            LessOrEqual(attr, value) => Self::attr("LessOrEqual", attr, Some(value)),
+            In(attr, values) => Self::attr("In", attr, values),
+            NotIn(attr, values) => Self::attr("NotIn", attr, values),
+            Contains(attr, value) => Self::attr("Contains", attr, Some(value)),
+            NotContains(attr, value) => Self::attr("NotContains", attr, Some(value)),
+            StartsWith(attr, value) => Self::attr("StartsWith", attr, Some(value)),
+            NotStartsWith(attr, value) => Self::attr("NotStartsWith", attr, Some(value)),
+            EndsWith(attr, value) => Self::attr("EndsWith", attr, Some(value)),
+            NotEndsWith(attr, value) => Self::attr("NotEndsWith", attr, Some(value)),
+            AnyFieldContains(attrs, value) => FilterShape {
+                op: "AnyFieldContains",
+                attrs: attrs.clone(),
+                values: vec![ValueShape::new(value)],
+                ..Default::default()
+            },
+            ChildNotExists(attr, filter) => {
+                Self::nested("ChildNotExists", &[attr.clone()], Some(filter.as_ref()))
+            }
+            ReferenceExists(attr) => Self::attr("ReferenceExists", attr, None),
+            Near(attr, center, tolerance) => Self::attr("Near", attr, vec![center, tolerance]),
+            IsWhole(attr) => Self::attr("IsWhole", attr, None),
+            ListEmpty(attr) => Self::attr("ListEmpty", attr, None),
+            ListNotEmpty(attr) => Self::attr("ListNotEmpty", attr, None),
+            InCaseInsensitive(attr, values) => Self::attr("InCaseInsensitive", attr, values),
+            WordMatch(attr, value) => Self::attr("WordMatch", attr, Some(value)),
+            BitsSet(attr, mask) => Self::attr("BitsSet", attr, Some(mask)),
+            BitsAnySet(attr, mask) => Self::attr("BitsAnySet", attr, Some(mask)),
+            LengthEqual(attr, length) => Self::attr("LengthEqual", attr, None).literal(*length),
+            LengthGreaterThan(attr, length) => {
+                Self::attr("LengthGreaterThan", attr, None).literal(*length)
+            }
+            LengthLessThan(attr, length) => {
+                Self::attr("LengthLessThan", attr, None).literal(*length)
+            }
+            InSubnet(attr, subnet) => Self::attr("InSubnet", attr, Some(subnet)),
+            // The modulus and remainder are bind parameters
+            ModEquals(attr, _, _) => Self::attr("ModEquals", attr, None),
+        }
+    }
+
+    fn attr<'v>(
+        op: &'static str,
+        attr: &Attribute,
+        values: impl IntoIterator<Item = &'v Value>,
+    ) -> Self {
+        FilterShape {
+            op,
+            attrs: vec![attr.clone()],
+            values: values.into_iter().map(ValueShape::new).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn nested<'f>(
+        op: &'static str,
+        attrs: &[Attribute],
+        filters: impl IntoIterator<Item = &'f EntityFilter>,
+    ) -> Self {
+        FilterShape {
+            op,
+            attrs: attrs.to_vec(),
+            filters: filters.into_iter().map(FilterShape::new).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn literal(mut self, literal: i32) -> Self {
+        self.literals.push(literal);
+        self
+    }
+}
+
+/// Generate the SQL text for `query`
+pub fn query_sql<Q: QueryFragment<Pg>>(query: &Q) -> QueryResult<String> {
+    let mut builder = PgQueryBuilder::default();
+    query.to_sql(&mut builder)?;
+    Ok(builder.finish())
+}
+
+/// Run `query` with SQL text that was generated earlier for a query of
+/// the same shape. Only the bind parameters are taken from `query`, and
+/// running it walks `query` once to collect them rather than also for
+/// generating the SQL text and checking whether it can be cached as a
+/// prepared statement. It is up to the caller to make sure that `sql` is
+/// the SQL text that `query` would generate
+#[derive(Debug, Clone, Constructor)]
+pub struct CachedQuery<'a> {
+    sql: Arc<String>,
+    query: FilterQuery<'a>,
+}
+
+impl<'a> QueryFragment<Pg> for CachedQuery<'a> {
+    fn walk_ast(&self, out: AstPass<Pg>) -> QueryResult<()> {
+        self.query.walk_ast(out)
+    }
+
+    fn to_sql(&self, out: &mut <Pg as Backend>::QueryBuilder) -> QueryResult<()> {
+        out.push_sql(self.sql.as_str());
+        Ok(())
+    }
+
+    fn is_safe_to_cache_prepared(&self) -> QueryResult<bool> {
+        // `FilterQuery` is never safe to cache
+        Ok(false)
+    }
+}
+
+impl<'a> QueryId for CachedQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, EntityData> for CachedQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<EntityData>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for CachedQuery<'a> {}
+
+/// Helper struct for retrieving the number of entities that have a given
+/// value for an attribute
+#[derive(QueryableByName)]
+pub struct GroupCountData {
+    #[sql_type = "Jsonb"]
+    value: serde_json::Value,
+    #[sql_type = "BigInt"]
+    count: i64,
+}
+
+impl GroupCountData {
+    pub fn to_group(self, layout: &Layout, column: &Column) -> Result<(Value, i64), StoreError> {
+        let mut value = EntityData::value_from_json(&column.column_type, self.value)?;
+        if column.is_encrypted() {
+            value = layout.decrypt_value(column, value)?;
+        }
+        Ok((value, self.count))
+    }
+}
+
+/// Count the entities that are current at `block`, grouped by the value
+/// of `column`. If `min_count` is given, only groups with at least that
+/// many entities are returned. Groups are ordered by descending count, and
+/// by value for groups of the same size; for an encrypted column, that is
+/// the order of the ciphertexts
+#[derive(Debug, Clone, Constructor)]
+pub struct GroupCountQuery<'a> {
+    table: &'a Table,
+    column: &'a Column,
+    min_count: Option<u32>,
+    block: BlockNumber,
+}
+
+impl<'a> QueryFragment<Pg> for GroupCountQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Generate
+        //    select to_jsonb(c.column) as value, count(*) as count
+        //      from schema.table c
+        //     where c.block_range @> $block
+        //     group by c.column
+        //    having count(*) >= $min_count
+        //     order by count(*) desc, c.column
+        out.push_sql("select to_jsonb(c.");
+        out.push_identifier(self.column.name.as_str())?;
+        out.push_sql(") as value, count(*) as count\n");
+        out.push_sql("  from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql(" c\n where ");
+        BlockRangeContainsClause::new("c.", self.block).walk_ast(out.reborrow())?;
+        out.push_sql("\n group by c.");
+        out.push_identifier(self.column.name.as_str())?;
+        if let Some(min_count) = self.min_count {
+            out.push_sql("\nhaving count(*) >= ");
+            out.push_bind_param::<BigInt, _>(&(min_count as i64))?;
+        }
+        out.push_sql("\n order by count(*) desc, c.");
+        out.push_identifier(self.column.name.as_str())
+    }
+}
+
+impl<'a> QueryId for GroupCountQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, GroupCountData> for GroupCountQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<GroupCountData>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for GroupCountQuery<'a> {}
+
+/// Helper struct for retrieving the result of a `CountDistinctQuery`
+#[derive(QueryableByName)]
+pub struct CountDistinctData {
+    #[sql_type = "BigInt"]
+    pub count: i64,
+}
+
+/// Count the distinct values of `column` among the entities that are
+/// current at `block` and match `filter`. Like SQL's `count(distinct ..)`,
+/// null values are not counted
+#[derive(Debug, Clone, Constructor)]
+pub struct CountDistinctQuery<'a> {
+    table: &'a Table,
+    column: &'a Column,
+    filter: Option<QueryFilter<'a>>,
+    block: BlockNumber,
+}
+
+impl<'a> QueryFragment<Pg> for CountDistinctQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Generate
+        //    select count(distinct c.column) as count
+        //      from schema.table c
+        //     where c.block_range @> $block
+        //       and query_filter
+        out.push_sql("select count(distinct c.");
+        out.push_identifier(self.column.name.as_str())?;
+        out.push_sql(") as count\n");
+        out.push_sql("  from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql(" c\n where ");
+        BlockRangeContainsClause::new("c.", self.block).walk_ast(out.reborrow())?;
+        if let Some(filter) = &self.filter {
+            out.push_sql(" and ");
+            filter.walk_ast(out.reborrow())?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for CountDistinctQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, CountDistinctData> for CountDistinctQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<CountDistinctData>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for CountDistinctQuery<'a> {}
+
+/// Copy the entities in `table` that are current at `block` and match
+/// `filter` into the table `target`, which must have been created with
+/// the statement from `create_table_sql`. Only the columns of the entity
+/// type are copied; the block range is not
+#[derive(Debug, Clone, Constructor)]
+pub struct MaterializeQuery<'a> {
+    table: &'a Table,
+    target: &'a SqlName,
+    filter: Option<QueryFilter<'a>>,
+    block: BlockNumber,
+}
+
+impl<'a> MaterializeQuery<'a> {
+    /// The statement that creates the empty target table, with the same
+    /// columns and column types as `table`, except for the block range
+    pub fn create_table_sql(&self) -> String {
+        let columns = self
+            .table
+            .columns
+            .iter()
+            .map(|column| format!("c.{}", column.name.quoted()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!(
+            "create table {} as select {} from {} c with no data",
+            self.target.as_str(),
+            columns,
+            self.table.qualified_name.as_str()
+        )
+    }
+}
+
+impl<'a> QueryFragment<Pg> for MaterializeQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Generate
+        //    insert into target
+        //    select c.column1, c.column2, ...
+        //      from schema.table c
+        //     where c.block_range @> $block
+        //       and query_filter
+        out.push_sql("insert into ");
+        out.push_sql(self.target.as_str());
+        out.push_sql("\nselect ");
+        for (i, column) in self.table.columns.iter().enumerate() {
+            if i > 0 {
+                out.push_sql(", ");
+            }
+            out.push_sql("c.");
+            out.push_identifier(column.name.as_str())?;
+        }
+        out.push_sql("\n  from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql(" c\n where ");
+        BlockRangeContainsClause::new("c.", self.block).walk_ast(out.reborrow())?;
+        if let Some(filter) = &self.filter {
+            out.push_sql(" and ");
+            filter.walk_ast(out.reborrow())?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for MaterializeQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for MaterializeQuery<'a> {}
+
+/// Find the entities in `table` that are current at `block` and match
+/// `filter`, and score them by the weighted `predicates`: the score of an
+/// entity is the sum of the weights of the predicates it satisfies. The
+/// score is returned as the attribute `__score`, and entities are ordered
+/// by descending score, and then by `id`. If `min_score` is set, entities
+/// with a lower score are not returned
+#[derive(Debug, Clone, Constructor)]
+pub struct ScoredQuery<'a> {
+    table: &'a Table,
+    filter: Option<QueryFilter<'a>>,
+    predicates: Vec<(QueryFilter<'a>, i32)>,
+    min_score: Option<i32>,
+    range: EntityRange,
+    block: BlockNumber,
+}
+
+impl<'a> QueryFragment<Pg> for ScoredQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Generate
+        //    select '..' as entity,
+        //           to_jsonb(c.*) || jsonb_build_object('__score', s.g$score) as data
+        //      from schema.table c,
+        //           lateral (select (case when predicate1 then $weight1 else 0 end)
+        //                         + (case when predicate2 then $weight2 else 0 end)
+        //                         + ... as g$score) s
+        //     where c.block_range @> $block
+        //       and query_filter
+        //       and s.g$score >= $min_score
+        //     order by s.g$score desc, c.id
+        //     limit $first offset $skip
+        out.push_sql("select ");
+        out.push_bind_param::<Text, _>(&self.table.object)?;
+        out.push_sql(" as entity, to_jsonb(c.*) || jsonb_build_object('");
+        out.push_sql(SCORE_KEY);
+        out.push_sql("', s.g$score) as data\n  from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql(" c,\n       lateral (select ");
+        if self.predicates.is_empty() {
+            out.push_sql("0");
+        }
+        for (i, (predicate, weight)) in self.predicates.iter().enumerate() {
+            if i > 0 {
+                out.push_sql(" + ");
+            }
+            out.push_sql("(case when ");
+            predicate.walk_ast(out.reborrow())?;
+            out.push_sql(" then ");
+            out.push_bind_param::<Integer, _>(weight)?;
+            out.push_sql(" else 0 end)");
+        }
+        out.push_sql(" as g$score) s\n where ");
+        BlockRangeContainsClause::new("c.", self.block).walk_ast(out.reborrow())?;
+        if let Some(filter) = &self.filter {
+            out.push_sql(" and ");
+            filter.walk_ast(out.reborrow())?;
+        }
+        if let Some(min_score) = &self.min_score {
+            out.push_sql(" and s.g$score >= ");
+            out.push_bind_param::<Integer, _>(min_score)?;
+        }
+        out.push_sql("\n order by s.g$score desc, c.");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        FilterRange(self.range.clone()).walk_ast(out)
+    }
+}
+
+impl<'a> QueryId for ScoredQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, EntityData> for ScoredQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<EntityData>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for ScoredQuery<'a> {}
+
+/// Find the entities in `table` that are current at `block` and match
+/// `filter`, and return how recently each of them was created as the
+/// attribute `__recency`, which is
+/// `exp(-($block - created) / halflife)` where `created` is the block at
+/// which the first version of the entity was written. The recency is 1
+/// for entities created at `block` and decays towards 0 for older
+/// entities. Entities are ordered by their recency in `direction`, and
+/// then by `id`. Since the recency only depends on `created`, we order by
+/// that rather than by the recency itself so that the order is still
+/// right for entities that are so old that their recency rounds to 0
+#[derive(Debug, Clone, Constructor)]
+pub struct RecencyQuery<'a> {
+    table: &'a Table,
+    filter: Option<QueryFilter<'a>>,
+    halflife: i32,
+    direction: EntityOrder,
+    range: EntityRange,
+    block: BlockNumber,
+}
+
+impl<'a> QueryFragment<Pg> for RecencyQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Generate
+        //    select '..' as entity,
+        //           to_jsonb(c.*)
+        //             || jsonb_build_object('__recency',
+        //                  exp(-($block - r.g$created)::numeric / $halflife)) as data
+        //      from schema.table c,
+        //           lateral (select min(lower(v.block_range)) as g$created
+        //                      from schema.table v
+        //                     where v.id = c.id) r
+        //     where c.block_range @> $block
+        //       and query_filter
+        //     order by r.g$created {direction}, c.id
+        //     limit $first offset $skip
+        //
+        // Numeric `exp` rounds to 0 where `exp` for doubles would fail
+        // with an underflow for very old entities
+        out.push_sql("select ");
+        out.push_bind_param::<Text, _>(&self.table.object)?;
+        out.push_sql(" as entity, to_jsonb(c.*) || jsonb_build_object('");
+        out.push_sql(RECENCY_KEY);
+        out.push_sql("', exp(-(");
+        out.push_bind_param::<Integer, _>(&self.block)?;
+        out.push_sql(" - r.g$created)::numeric / ");
+        out.push_bind_param::<Integer, _>(&self.halflife)?;
+        out.push_sql(")) as data\n  from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql(" c,\n       lateral (select min(lower(v.");
+        out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        out.push_sql(")) as g$created from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql(" v where v.");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        out.push_sql(" = c.");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        out.push_sql(") r\n where ");
+        BlockRangeContainsClause::new("c.", self.block).walk_ast(out.reborrow())?;
+        if let Some(filter) = &self.filter {
+            out.push_sql(" and ");
+            filter.walk_ast(out.reborrow())?;
+        }
+        out.push_sql("\n order by r.g$created ");
+        out.push_sql(self.direction.to_sql());
+        out.push_sql(", c.");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        FilterRange(self.range.clone()).walk_ast(out)
+    }
+}
+
+impl<'a> QueryId for RecencyQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, EntityData> for RecencyQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<EntityData>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for RecencyQuery<'a> {}
+
+/// For each value of `group` among the entities in `table` that are
+/// current at `block`, find the entity with the highest value of `order`,
+/// breaking ties by the highest `id`. Entities where `group` is null are
+/// ignored, and entities where `order` is null only win if all entities in
+/// their group have a null `order`. The entities are returned ordered by
+/// their value of `group`
+#[derive(Debug, Clone, Constructor)]
+pub struct LatestPerGroupQuery<'a> {
+    table: &'a Table,
+    group: &'a Column,
+    order: &'a Column,
+    block: BlockNumber,
+}
+
+impl<'a> QueryFragment<Pg> for LatestPerGroupQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Generate
+        //    select distinct on (c.group) '..' as entity, to_jsonb(c.*) as data
+        //      from schema.table c
+        //     where c.block_range @> $block
+        //       and c.group is not null
+        //     order by c.group, c.order desc nulls last, c.id desc
+        out.push_sql("select distinct on (c.");
+        out.push_identifier(self.group.name.as_str())?;
+        out.push_sql(") ");
+        out.push_bind_param::<Text, _>(&self.table.object)?;
+        out.push_sql(" as entity, to_jsonb(c.*) as data\n  from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql(" c\n where ");
+        BlockRangeContainsClause::new("c.", self.block).walk_ast(out.reborrow())?;
+        out.push_sql("\n   and c.");
+        out.push_identifier(self.group.name.as_str())?;
+        out.push_sql(" is not null\n order by c.");
+        out.push_identifier(self.group.name.as_str())?;
+        out.push_sql(", c.");
+        out.push_identifier(self.order.name.as_str())?;
+        out.push_sql(" desc nulls last, c.");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        out.push_sql(" desc");
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for LatestPerGroupQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, EntityData> for LatestPerGroupQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<EntityData>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for LatestPerGroupQuery<'a> {}
+
+/// Helper struct for retrieving all versions of an entity. In addition to
+/// what `EntityData` contains, it has the bounds of the block range of
+/// each version; `upper` is `None` for the current version
+#[derive(QueryableByName)]
+pub struct EntityVersionData {
+    #[sql_type = "Text"]
+    entity: String,
+    #[sql_type = "Jsonb"]
+    data: serde_json::Value,
+    #[sql_type = "Integer"]
+    lower: BlockNumber,
+    #[sql_type = "Nullable<Integer>"]
+    upper: Option<BlockNumber>,
+}
+
+impl EntityVersionData {
+    pub fn to_entity_version(
+        self,
+        layout: &Layout,
+    ) -> Result<(Entity, BlockNumber, Option<BlockNumber>), StoreError> {
+        let data = EntityData {
+            entity: self.entity,
+            data: self.data,
+        };
+        Ok((data.to_entity(layout)?, self.lower, self.upper))
+    }
+}
+
+/// Find all versions of the entity with the given `id`, regardless of
+/// the block at which they were current, ordered by the block at which
+/// each version was created
+#[derive(Debug, Clone, Constructor)]
+pub struct HistoryQuery<'a> {
+    table: &'a Table,
+    id: &'a str,
+}
+
+impl<'a> QueryFragment<Pg> for HistoryQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Generate
+        //    select '..' as entity, to_jsonb(e.*) as data,
+        //           lower(e.block_range) as lower, upper(e.block_range) as upper
+        //      from schema.table e where id = $1
+        //     order by lower(e.block_range)
+        out.push_sql("select ");
+        out.push_bind_param::<Text, _>(&self.table.object)?;
+        out.push_sql(" as entity, to_jsonb(e.*) as data,\n");
+        out.push_sql("       lower(e.");
+        out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        out.push_sql(") as lower, upper(e.");
+        out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        out.push_sql(") as upper\n");
+        out.push_sql("  from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql(" e\n where ");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        out.push_sql(" = ");
+        out.push_bind_param::<Text, _>(&self.id)?;
+        out.push_sql("\n order by lower(e.");
+        out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        out.push_sql(")");
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for HistoryQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, EntityVersionData> for HistoryQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<EntityVersionData>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for HistoryQuery<'a> {}
+
+/// A version of an entity together with one of the blocks at which it
+/// was current; see `FindAtBlocksQuery`
+#[derive(QueryableByName)]
+pub struct BlockEntityData {
+    #[sql_type = "Integer"]
+    pub block: BlockNumber,
+    #[sql_type = "Text"]
+    entity: String,
+    #[sql_type = "Jsonb"]
+    data: serde_json::Value,
+}
+
+impl BlockEntityData {
+    pub fn to_entity(self, layout: &Layout) -> Result<(BlockNumber, Entity), StoreError> {
+        let data = EntityData {
+            entity: self.entity,
+            data: self.data,
+        };
+        Ok((self.block, data.to_entity(layout)?))
+    }
+}
+
+/// Find the versions of the entity with the given `id` that were current
+/// at each of `blocks`. There is no row for blocks at which the entity
+/// did not exist. `blocks` must not be empty
+#[derive(Debug, Clone, Constructor)]
+pub struct FindAtBlocksQuery<'a> {
+    table: &'a Table,
+    id: &'a str,
+    blocks: &'a [BlockNumber],
+}
+
+impl<'a> QueryFragment<Pg> for FindAtBlocksQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Generate
+        //    select b.block, '..' as entity, to_jsonb(e.*) as data
+        //      from (values ($1), ($2), ...) as b(block)
+        //      join schema.table e on e.block_range @> b.block
+        //     where e.id = $id
+        out.push_sql("select b.block, ");
+        out.push_bind_param::<Text, _>(&self.table.object)?;
+        out.push_sql(" as entity, to_jsonb(e.*) as data\n");
+        out.push_sql("  from (values ");
+        for (i, block) in self.blocks.iter().enumerate() {
+            if i > 0 {
+                out.push_sql(", ");
+            }
+            out.push_sql("(");
+            out.push_bind_param::<Integer, _>(block)?;
+            out.push_sql(")");
+        }
+        out.push_sql(") as b(block)\n  join ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql(" e on e.");
+        out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        out.push_sql(" @> b.block\n where e.");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        out.push_sql(" = ");
+        out.push_bind_param::<Text, _>(&self.id)?;
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for FindAtBlocksQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, BlockEntityData> for FindAtBlocksQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<BlockEntityData>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for FindAtBlocksQuery<'a> {}
+
+#[derive(Debug, Clone, Constructor)]
+pub struct FindManyQuery<'a> {
+    pub(crate) schema: &'a str,
+    pub(crate) tables: Vec<&'a Table>,
+
+    // Maps object name to ids.
+    pub(crate) ids_for_type: BTreeMap<&'a str, Vec<&'a str>>,
+    pub(crate) block: BlockNumber,
+}
+
+impl<'a> QueryFragment<Pg> for FindManyQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Generate
+        //    select $object0 as entity, to_jsonb(e.*) as data
+        //      from schema.<table0> e where id = any($ids0)
+        //    union all
+        //    select $object1 as entity, to_jsonb(e.*) as data
+        //      from schema.<table1> e where id = any($ids1)
+        //    union all
+        //    ...
+        for (i, table) in self.tables.iter().enumerate() {
+            if i > 0 {
+                out.push_sql("\nunion all\n");
+            }
+            out.push_sql("select ");
+            out.push_bind_param::<Text, _>(&table.object)?;
+            out.push_sql(" as entity, to_jsonb(e.*) as data\n");
+            out.push_sql("  from ");
+            out.push_sql(table.qualified_name.as_str());
+            out.push_sql(" e\n where ");
+            out.push_identifier(PRIMARY_KEY_COLUMN)?;
+            out.push_sql(" = any(");
+            out.push_bind_param::<Array<Text>, _>(&self.ids_for_type[table.object.as_str()])?;
+            out.push_sql(") and ");
+            BlockRangeContainsClause::new("e.", self.block).walk_ast(out.reborrow())?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for FindManyQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, EntityData> for FindManyQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<EntityData>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for FindManyQuery<'a> {}
+
+/// The fields of `entity` that it explicitly sets to null. Nothing is
+/// recorded for tables that do not track explicit nulls
+pub(crate) fn explicit_nulls(table: &Table, entity: &Entity) -> Vec<String> {
+    if !table.has_explicit_nulls() {
+        return vec![];
+    }
+    table
+        .columns
+        .iter()
+        .filter(|column| !column.is_fulltext() && entity.get(&column.field) == Some(&Value::Null))
+        .map(|column| column.field.clone())
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct InsertQuery<'a> {
+    table: &'a Table,
+    key: &'a EntityKey,
+    entity: Entity,
+    block: BlockNumber,
+    /// The fields that `entity` explicitly sets to null
+    explicit_nulls: Vec<String>,
+    /// Skip the insert if it conflicts with an existing version of the
+    /// entity rather than fail
+    do_nothing_on_conflict: bool,
+}
+
+impl<'a> InsertQuery<'a> {
+    pub fn new(
+        table: &'a Table,
+        key: &'a EntityKey,
+        entity: Entity,
+        block: BlockNumber,
+    ) -> Result<InsertQuery<'a>, StoreError> {
+        let mut entity = entity;
+        for column in table.columns.iter() {
+            if let ColumnType::TSVector(config) = &column.column_type {
+                // The values must be in the order of `config.weights` so
+                // that each of them gets the right weight
+                let fulltext_updates = config
+                    .weights
+                    .keys()
+                    .map(|field| entity.get(field).cloned().unwrap_or(Value::Null))
+                    .collect();
+                entity.insert(column.field.to_string(), Value::List(fulltext_updates));
+            }
+            if !column.is_nullable() && !entity.contains_key(&column.field) {
+                return Err(StoreError::QueryExecutionError(format!(
+                    "can not insert entity {}[{}] since value for non-nullable attribute {} is missing. \
+                     To fix this, mark the attribute as nullable in the GraphQL schema or change the \
+                     mapping code to always set this attribute.",
+                    key.entity_type, key.entity_id, column.field
+                )));
+            }
+        }
+
+        let explicit_nulls = explicit_nulls(table, &entity);
+        Ok(InsertQuery {
+            table,
+            key,
+            entity,
+            block,
+            explicit_nulls,
+            do_nothing_on_conflict: false,
+        })
+    }
+
+    /// Do not insert anything if the block range of the new version
+    /// overlaps with that of an existing version of the entity. Executing
+    /// the query then affects no rows instead of violating the exclusion
+    /// constraint on the table
+    pub fn do_nothing_on_conflict(mut self) -> Self {
+        self.do_nothing_on_conflict = true;
+        self
+    }
+}
+
+impl<'a> QueryFragment<Pg> for InsertQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Construct a query
+        //   insert into schema.table(column, ...)
+        //   values ($1, ...)
+        // and convert and bind the entity's values into it
+        out.push_sql("insert into ");
+        out.push_sql(self.table.qualified_name.as_str());
+
+        out.push_sql("(");
+        for column in self.table.columns.iter() {
+            if self.entity.contains_key(&column.field) {
+                out.push_identifier(column.name.as_str())?;
+                out.push_sql(", ");
+            }
+        }
+        out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        if !self.explicit_nulls.is_empty() {
+            out.push_sql(", ");
+            out.push_identifier(EXPLICIT_NULLS_COLUMN)?;
+        }
+
+        out.push_sql(")\nvalues(");
+        for column in self.table.columns.iter() {
+            if let Some(value) = self.entity.get(&column.field) {
+                QueryValue(value, &column.column_type).walk_ast(out.reborrow())?;
+                out.push_sql(", ");
+            }
+        }
+        let block_range: BlockRange = (self.block..).into();
+        out.push_bind_param::<Range<Integer>, _>(&block_range)?;
+        if !self.explicit_nulls.is_empty() {
+            out.push_sql(", ");
+            out.push_bind_param::<Array<Text>, _>(&self.explicit_nulls)?;
+        }
+        out.push_sql(")");
+        if self.do_nothing_on_conflict {
+            out.push_sql("\non conflict do nothing");
+        }
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for InsertQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for InsertQuery<'a> {}
+
+/// Insert several entities into the same table with one statement. Each
+/// entity is checked in the same way as for `InsertQuery`. Entities that
+/// do not have a value for a column that other entities in the batch
+/// have a value for get `null` for that column
+#[derive(Debug, Clone)]
+pub struct InsertManyQuery<'a> {
+    table: &'a Table,
+    entities: Vec<Entity>,
+    /// The fields that each entity explicitly sets to null, in the same
+    /// order as `entities`
+    explicit_nulls: Vec<Vec<String>>,
+    /// The columns for which at least one of the entities has a value
+    columns: Vec<&'a Column>,
+    block: BlockNumber,
+}
+
+impl<'a> InsertManyQuery<'a> {
+    pub fn new(
+        table: &'a Table,
+        entities: impl Iterator<Item = (EntityKey, Entity)>,
+        block: BlockNumber,
+    ) -> Result<InsertManyQuery<'a>, StoreError> {
+        let (entities, explicit_nulls): (Vec<_>, Vec<_>) = entities
+            .map(|(key, entity)| {
+                InsertQuery::new(table, &key, entity, block)
+                    .map(|query| (query.entity, query.explicit_nulls))
+            })
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .unzip();
+        let columns = table
+            .columns
+            .iter()
+            .filter(|column| {
+                entities
+                    .iter()
+                    .any(|entity| entity.contains_key(&column.field))
+            })
+            .collect();
+        Ok(InsertManyQuery {
+            table,
+            entities,
+            explicit_nulls,
+            columns,
+            block,
+        })
+    }
+}
+
+impl<'a> QueryFragment<Pg> for InsertManyQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Construct a query
+        //   insert into schema.table(column, ...)
+        //   values ($1, ...), ($n, ...), ...
+        // and convert and bind the values of each entity into it
+        out.push_sql("insert into ");
+        out.push_sql(self.table.qualified_name.as_str());
+
+        out.push_sql("(");
+        for column in &self.columns {
+            out.push_identifier(column.name.as_str())?;
+            out.push_sql(", ");
+        }
+        out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        let has_nulls = self.explicit_nulls.iter().any(|nulls| !nulls.is_empty());
+        if has_nulls {
+            out.push_sql(", ");
+            out.push_identifier(EXPLICIT_NULLS_COLUMN)?;
+        }
+
+        out.push_sql(")\nvalues");
+        let block_range: BlockRange = (self.block..).into();
+        for (i, (entity, nulls)) in self
+            .entities
+            .iter()
+            .zip(self.explicit_nulls.iter())
+            .enumerate()
+        {
+            if i > 0 {
+                out.push_sql(",\n      ");
+            }
+            out.push_sql("(");
+            for column in &self.columns {
+                let value = entity.get(&column.field).unwrap_or(&Value::Null);
+                QueryValue(value, &column.column_type).walk_ast(out.reborrow())?;
+                out.push_sql(", ");
+            }
+            out.push_bind_param::<Range<Integer>, _>(&block_range)?;
+            if has_nulls {
+                out.push_sql(", ");
+                out.push_bind_param::<Array<Text>, _>(nulls)?;
+            }
+            out.push_sql(")");
+        }
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for InsertManyQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for InsertManyQuery<'a> {}
+
+/// Update an existing entity in place. The `entity` only needs to contain
+/// the attributes that should be changed, and not the entire entity. In
+/// particular, it might not have an `id` attribute. If the entity has
+/// attributes that are not in the table, they are silently ignored.
+#[derive(Debug, Clone)]
+pub struct UpdateQuery<'a> {
+    table: &'a Table,
+    key: &'a EntityKey,
+    entity: &'a Entity,
+    /// The fields that the update changes
+    changed: Vec<String>,
+    /// The fields that the update explicitly sets to null
+    explicit_nulls: Vec<String>,
+}
+
+impl<'a> UpdateQuery<'a> {
+    pub fn new(
+        table: &'a Table,
+        key: &'a EntityKey,
+        entity: &'a Entity,
+    ) -> Result<Self, StoreError> {
+        if let Some(Value::String(id)) = entity.get("id") {
+            if id != &key.entity_id {
+                return Err(StoreError::QueryExecutionError(format!(
                     "changing the id of an \
                      entity is not allowed, and the attempt to change the id of \
                      entity {}[{}] to {} has failed",
@@ -1005,7 +3519,28 @@ impl<'a> UpdateQuery<'a> {
             }
         }
 
-        Ok(UpdateQuery { table, key, entity })
+        let changed = table
+            .columns
+            .iter()
+            .filter(|column| column.name.as_str() != PRIMARY_KEY_COLUMN)
+            .filter(|column| entity.contains_key(&column.field))
+            .map(|column| column.field.clone())
+            .collect();
+        let explicit_nulls = explicit_nulls(table, entity);
+        Ok(UpdateQuery {
+            table,
+            key,
+            entity,
+            changed,
+            explicit_nulls,
+        })
+    }
+
+    /// Record `nulls` as the fields that the update explicitly sets to
+    /// null instead of all the fields that are null in the entity
+    pub fn explicit_nulls(mut self, nulls: Vec<String>) -> Self {
+        self.explicit_nulls = nulls;
+        self
     }
 }
 
@@ -1049,6 +3584,21 @@ impl<'a> QueryFragment<Pg> for UpdateQuery<'a> {
             out.push_sql(" = ");
             QueryValue(value, &column.column_type).walk_ast(out.reborrow())?;
         }
+        if self.table.has_explicit_nulls() && !self.changed.is_empty() {
+            // Forget the explicit nulls for the changed fields, and record
+            // the new ones
+            //   g$explicit_nulls = array(select f
+            //                              from unnest(coalesce(g$explicit_nulls, '{}')) f
+            //                             where f <> all($changed)) || $nulls
+            out.push_sql(",\n   ");
+            out.push_identifier(EXPLICIT_NULLS_COLUMN)?;
+            out.push_sql(" = array(select f from unnest(coalesce(");
+            out.push_identifier(EXPLICIT_NULLS_COLUMN)?;
+            out.push_sql(", '{}')) f where f <> all(");
+            out.push_bind_param::<Array<Text>, _>(&self.changed)?;
+            out.push_sql(")) || ");
+            out.push_bind_param::<Array<Text>, _>(&self.explicit_nulls)?;
+        }
         out.push_sql("\n where ");
         out.push_identifier(PRIMARY_KEY_COLUMN)?;
         out.push_sql(" = ");
@@ -1102,6 +3652,9 @@ pub struct ConflictingEntityQuery<'a> {
     layout: &'a Layout,
     tables: Vec<&'a Table>,
     entity_id: &'a String,
+    /// Only consider versions that are current at this block; if it is
+    /// `None`, consider all versions
+    block: Option<BlockNumber>,
 }
 impl<'a> ConflictingEntityQuery<'a> {
     pub fn new(
@@ -1117,8 +3670,16 @@ impl<'a> ConflictingEntityQuery<'a> {
             layout,
             tables,
             entity_id,
+            block: None,
         })
     }
+
+    /// Only look for conflicts among the versions that are current at
+    /// `block`
+    pub fn at_block(mut self, block: BlockNumber) -> Self {
+        self.block = Some(block);
+        self
+    }
 }
 
 impl<'a> QueryFragment<Pg> for ConflictingEntityQuery<'a> {
@@ -1126,11 +3687,13 @@ impl<'a> QueryFragment<Pg> for ConflictingEntityQuery<'a> {
         out.unsafe_to_cache_prepared();
 
         // Construct a query
-        //   select 'Type1' as entity from schema.table1 where id = $1
+        //   select 'Type1' as entity from schema.table1 c where id = $1
         //   union all
-        //   select 'Type2' as entity from schema.table2 where id = $1
+        //   select 'Type2' as entity from schema.table2 c where id = $1
         //   union all
         //   ...
+        // If a block is given, each branch also gets
+        //   and c.block_range @> $block
         for (i, table) in self.tables.iter().enumerate() {
             if i > 0 {
                 out.push_sql("\nunion all\n");
@@ -1139,8 +3702,12 @@ impl<'a> QueryFragment<Pg> for ConflictingEntityQuery<'a> {
             out.push_bind_param::<Text, _>(&table.object)?;
             out.push_sql(" as entity from ");
             out.push_sql(table.qualified_name.as_str());
-            out.push_sql(" where id = ");
+            out.push_sql(" c where id = ");
             out.push_bind_param::<Text, _>(self.entity_id)?;
+            if let Some(block) = self.block {
+                out.push_sql(" and ");
+                BlockRangeContainsClause::new("c.", block).walk_ast(out.reborrow())?;
+            }
         }
         Ok(())
     }
@@ -1268,6 +3835,7 @@ impl<'a> FilterWindow<'a> {
         layout: &'a Layout,
         window: EntityWindow,
         query_filter: Option<&'a EntityFilter>,
+        block: BlockNumber,
     ) -> Result<Self, QueryExecutionError> {
         let EntityWindow {
             child_type,
@@ -1276,7 +3844,7 @@ impl<'a> FilterWindow<'a> {
         } = window;
         let table = layout.table_for_entity(&child_type).map(|rc| rc.as_ref())?;
         let query_filter = query_filter
-            .map(|filter| QueryFilter::new(filter, table))
+            .map(|filter| QueryFilter::new(filter, table, layout, block))
             .transpose()?;
         let link = TableLink::new(table, link)?;
         Ok(FilterWindow {
@@ -1447,6 +4015,7 @@ impl<'a> FilterCollection<'a> {
         layout: &'a Layout,
         collection: EntityCollection,
         filter: Option<&'a EntityFilter>,
+        block: BlockNumber,
     ) -> Result<Self, QueryExecutionError> {
         match collection {
             EntityCollection::All(entities) => {
@@ -1462,7 +4031,7 @@ impl<'a> FilterCollection<'a> {
                             .map(|rc| rc.as_ref())
                             .and_then(|table| {
                                 filter
-                                    .map(|filter| QueryFilter::new(filter, table))
+                                    .map(|filter| QueryFilter::new(filter, table, layout, block))
                                     .transpose()
                                     .map(|filter| (table, filter))
                             })
@@ -1473,7 +4042,7 @@ impl<'a> FilterCollection<'a> {
             EntityCollection::Window(windows) => {
                 let windows = windows
                     .into_iter()
-                    .map(|window| FilterWindow::new(layout, window, filter))
+                    .map(|window| FilterWindow::new(layout, window, filter, block))
                     .collect::<Result<Vec<_>, _>>()?;
                 let collection = if windows.len() == 1 {
                     let mut windows = windows;
@@ -1489,6 +4058,37 @@ impl<'a> FilterCollection<'a> {
         }
     }
 
+    /// Like `new` for `EntityCollection::All`, but with a separate filter
+    /// for each entity type rather than one filter for all of them
+    pub fn with_type_filters(
+        layout: &'a Layout,
+        type_filters: &'a [(String, Option<EntityFilter>)],
+        block: BlockNumber,
+    ) -> Result<Self, QueryExecutionError> {
+        let entities = type_filters
+            .iter()
+            .map(|(entity, filter)| {
+                let table = layout.table_for_entity(entity)?.as_ref();
+                let filter = filter
+                    .as_ref()
+                    .map(|filter| QueryFilter::new(filter, table, layout, block))
+                    .transpose()?;
+                Ok((table, filter))
+            })
+            .collect::<Result<Vec<_>, StoreError>>()?;
+        Ok(FilterCollection::All(entities))
+    }
+
+    fn tables(&self) -> Vec<&'a Table> {
+        match self {
+            FilterCollection::All(entities) => entities.iter().map(|pair| pair.0).collect(),
+            FilterCollection::SingleWindow(window) => vec![window.table],
+            FilterCollection::MultiWindow(windows, _) => {
+                windows.iter().map(|window| window.table).collect()
+            }
+        }
+    }
+
     fn first_table(&self) -> Option<&Table> {
         match self {
             FilterCollection::All(entities) => entities.first().map(|pair| pair.0),
@@ -1513,10 +4113,23 @@ pub struct SortKey<'a> {
     column: Option<&'a Column>,
     value: Option<&'a str>,
     direction: EntityOrder,
+    /// Set if the table has an `@order_index` on `column` and `column`
+    /// can not be null; we can then leave out `nulls last` so that the
+    /// order matches the index in both directions
+    order_index: bool,
+    /// If set, rows that have the same value for `column` are ordered by
+    /// `md5(id || seed)` before they are ordered by `id`
+    tiebreak_seed: Option<u32>,
+    /// If set, the values of the text column `column` are compared with
+    /// this collation instead of the database's default collation
+    collation: Option<&'a str>,
 }
 
 impl<'a> SortKey<'a> {
-    /// Generate selecting the sort key if it is needed
+    /// Generate selecting the sort key if it is needed. For enum columns,
+    /// also select the position of the value in the enum as `g$enum_pos`
+    /// since Postgres only allows ordering the result of a `union` by
+    /// the columns it returns
     fn select(&self, out: &mut AstPass<Pg>) -> QueryResult<()> {
         if let Some(column) = self.column {
             let name = column.name.as_str();
@@ -1524,12 +4137,48 @@ impl<'a> SortKey<'a> {
                 out.push_sql(", c.");
                 out.push_identifier(name)?;
             }
+            if let ColumnType::Enum(enum_type) = &column.column_type {
+                out.push_sql(", ");
+                enum_position(&enum_type.values, "c.", name, out)?;
+                out.push_sql(" as ");
+                out.push_sql(ENUM_POSITION_COLUMN);
+            }
+        }
+        Ok(())
+    }
+
+    /// Generate `, id direction` unless we are already sorting by `id`,
+    /// so that rows with the same value for the sort key always come
+    /// back in the same order, and in the direction of the primary order
+    fn id_tiebreak(&self, name: &str, out: &mut AstPass<Pg>) -> QueryResult<()> {
+        if name != PRIMARY_KEY_COLUMN {
+            out.push_sql(", ");
+            self.seed_tiebreak(out)?;
+            out.push_identifier(PRIMARY_KEY_COLUMN)?;
+            out.push_sql(" ");
+            out.push_sql(self.direction.to_sql());
+        }
+        Ok(())
+    }
+
+    /// Generate `md5(id || $seed) direction, ` if there is a tiebreak
+    /// seed. That shuffles rows with the same value for the sort key in a
+    /// way that only depends on their id and the seed
+    fn seed_tiebreak(&self, out: &mut AstPass<Pg>) -> QueryResult<()> {
+        if let Some(seed) = self.tiebreak_seed {
+            out.push_sql("md5(");
+            out.push_identifier(PRIMARY_KEY_COLUMN)?;
+            out.push_sql(" || ");
+            out.push_bind_param::<Text, _>(&seed.to_string())?;
+            out.push_sql(") ");
+            out.push_sql(self.direction.to_sql());
+            out.push_sql(", ");
         }
         Ok(())
     }
 
     /// Generate
-    ///   order by [name direction,] id
+    ///   order by [name direction,] id [direction]
     fn order_by(&self, out: &mut AstPass<Pg>) -> QueryResult<()> {
         if let Some(column) = self.column {
             match &column.column_type {
@@ -1547,50 +4196,103 @@ impl<'a> SortKey<'a> {
                     out.push_sql(")) ");
                     out.push_sql(self.direction.to_sql());
                     out.push_sql(" nulls last");
-                    if name != PRIMARY_KEY_COLUMN {
-                        out.push_sql(", ");
-                        out.push_identifier(PRIMARY_KEY_COLUMN)?;
-                    }
-                    Ok(())
+                    self.id_tiebreak(name, out)
+                }
+                ColumnType::Enum(enum_type) => {
+                    let name = column.name.as_str();
+                    enum_position(&enum_type.values, "", name, out)?;
+                    out.push_sql(" ");
+                    out.push_sql(self.direction.to_sql());
+                    out.push_sql(" nulls last");
+                    self.id_tiebreak(name, out)
                 }
                 _ => {
                     let name = column.name.as_str();
                     out.push_identifier(name)?;
+                    if let Some(collation) = self.collation {
+                        out.push_sql(" collate ");
+                        out.push_identifier(collation)?;
+                    }
                     out.push_sql(" ");
                     out.push_sql(self.direction.to_sql());
-                    out.push_sql(" nulls last");
-                    if name != PRIMARY_KEY_COLUMN {
-                        out.push_sql(", ");
-                        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+                    // Since the column is not nullable, leaving out
+                    // `nulls last` does not change the order
+                    if !self.order_index {
+                        out.push_sql(" nulls last");
                     }
-                    Ok(())
+                    self.id_tiebreak(name, out)
                 }
             }
         } else {
+            self.seed_tiebreak(out)?;
             out.push_identifier(PRIMARY_KEY_COLUMN)
         }
     }
+
+    /// Like `order_by`, but for ordering the result of a `union` whose
+    /// branches select the sort key with `select`. Enum columns are
+    /// ordered by the `g$enum_pos` column that `select` adds rather than by
+    /// an expression, which Postgres does not allow there
+    fn order_by_selected(&self, out: &mut AstPass<Pg>) -> QueryResult<()> {
+        match self.column {
+            Some(column) if column.is_enum() => {
+                out.push_sql(ENUM_POSITION_COLUMN);
+                out.push_sql(" ");
+                out.push_sql(self.direction.to_sql());
+                out.push_sql(" nulls last");
+                self.id_tiebreak(column.name.as_str(), out)
+            }
+            _ => self.order_by(out),
+        }
+    }
 }
 
-/// Generate `[limit {first}] [offset {skip}]
+/// The column in which `SortKey::select` returns the position of the
+/// value of an enum sort key
+const ENUM_POSITION_COLUMN: &str = "g$enum_pos";
+
+/// Generate `[limit $first] [offset $skip]`. The values are bind
+/// parameters so that paging through results does not change the SQL text
+/// of the query
 #[derive(Debug, Clone)]
 pub struct FilterRange(EntityRange);
 
 impl QueryFragment<Pg> for FilterRange {
     fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
         let range = &self.0;
-        if let Some(first) = &range.first {
+        if let Some(first) = range.first {
             out.push_sql("\n limit ");
-            out.push_sql(&first.to_string());
+            out.push_bind_param::<BigInt, _>(&(first as i64))?;
         }
         if range.skip > 0 {
             out.push_sql("\noffset ");
-            out.push_sql(&range.skip.to_string());
+            out.push_bind_param::<BigInt, _>(&(range.skip as i64))?;
         }
         Ok(())
     }
 }
 
+/// The children of the rows of `parent` that `FilterQuery::with_child_count`
+/// counts; they are the rows of `child` that reference the parent in
+/// `column`
+#[derive(Debug, Clone)]
+struct ChildCount<'a> {
+    parent: &'a str,
+    child: &'a Table,
+    column: &'a Column,
+}
+
+/// How `FilterQuery::with_blocks_old` finds the number of the block that
+/// the rows of `parent` reference in `block_ref`: it is the `number` of the
+/// row in `blocks`
+#[derive(Debug, Clone)]
+struct BlocksOld<'a> {
+    parent: &'a str,
+    block_ref: &'a Column,
+    blocks: &'a Table,
+    number: &'a Column,
+}
+
 /// The parallel to `EntityQuery`.
 ///
 /// Details of how query generation for `FilterQuery` works can be found
@@ -1601,6 +4303,24 @@ pub struct FilterQuery<'a> {
     sort_key: SortKey<'a>,
     range: FilterRange,
     block: BlockNumber,
+    /// Only return one row per id when querying multiple entity types
+    distinct_on_id: bool,
+    /// Alternate between the entity types when querying multiple entity
+    /// types instead of ordering all rows together
+    interleave_types: bool,
+    /// Also return the first element of each list attribute
+    list_heads: bool,
+    /// Also return the number of children for a derived field
+    child_count: Vec<ChildCount<'a>>,
+    /// Also return the age of transactions in blocks
+    blocks_old: Vec<BlocksOld<'a>>,
+    /// Only return the id of each entity instead of all its attributes
+    ids_only: bool,
+    /// Return every version of the matching entities, not just the ones
+    /// that are current at `block`
+    unclamped: bool,
+    /// Do not order the result when the query has no order
+    unordered: bool,
 }
 
 impl<'a> FilterQuery<'a> {
@@ -1631,6 +4351,9 @@ impl<'a> FilterQuery<'a> {
                                     column: Some(column),
                                     value: sort_value,
                                     direction,
+                                    order_index: false,
+                                    tiebreak_seed: None,
+                                    collation: None,
                                 }
                             }
                             _ => unreachable!(),
@@ -1642,6 +4365,9 @@ impl<'a> FilterQuery<'a> {
                         column: Some(column),
                         value: None,
                         direction,
+                        order_index: first_table.has_order_index(column) && !column.is_nullable(),
+                        tiebreak_seed: None,
+                        collation: None,
                     }
                 }
             }
@@ -1649,6 +4375,9 @@ impl<'a> FilterQuery<'a> {
                 column: None,
                 value: None,
                 direction: EntityOrder::Ascending,
+                order_index: false,
+                tiebreak_seed: None,
+                collation: None,
             },
         };
 
@@ -1657,15 +4386,340 @@ impl<'a> FilterQuery<'a> {
             sort_key,
             range: FilterRange(range),
             block,
+            distinct_on_id: false,
+            interleave_types: false,
+            list_heads: false,
+            child_count: Vec::new(),
+            blocks_old: Vec::new(),
+            ids_only: false,
+            unclamped: false,
+            unordered: false,
         })
     }
 
+    /// Only return the `id` of each entity rather than all its attributes.
+    /// The filter, order, and range work the same as when all attributes
+    /// are returned
+    pub fn with_ids_only(mut self) -> Self {
+        self.ids_only = true;
+        self
+    }
+
+    /// Do not restrict the rows to the versions that are current at the
+    /// query's block, and return every version of the matching entities
+    /// instead. The filter still only sees one version at a time. This is
+    /// only meant for admin tooling that needs to see the full history of
+    /// entities; windowed queries do not support it
+    pub fn with_unclamped(mut self) -> Result<Self, QueryExecutionError> {
+        if let FilterCollection::All(_) = self.collection {
+            self.unclamped = true;
+            Ok(self)
+        } else {
+            Err(StoreError::QueryExecutionError(
+                "only queries that are not windowed can return all versions".to_owned(),
+            )
+            .into())
+        }
+    }
+
+    /// Return entities in whatever order Postgres produces them instead of
+    /// ordering them by `id`, which is what we do for queries without an
+    /// order. That saves sorting the matching entities, but results are
+    /// not stable, and paging through them with a range will skip or
+    /// repeat entities. It is an error to use this for queries that have
+    /// an order, that are windowed, or that use any of the options that
+    /// change the order
+    pub fn with_unordered(mut self) -> Result<Self, QueryExecutionError> {
+        let problem = if self.sort_key.column.is_some() {
+            Some("ordered by an attribute")
+        } else if let FilterCollection::All(_) = self.collection {
+            if self.interleave_types {
+                Some("interleaved by entity type")
+            } else if self.sort_key.tiebreak_seed.is_some() {
+                Some("using a tiebreak seed")
+            } else {
+                None
+            }
+        } else {
+            Some("windowed")
+        };
+        match problem {
+            Some(problem) => Err(StoreError::QueryExecutionError(format!(
+                "a query that is {} can not be unordered",
+                problem
+            ))
+            .into()),
+            None => {
+                self.unordered = true;
+                Ok(self)
+            }
+        }
+    }
+
+    /// Generate `to_jsonb(c.*)`, or `jsonb_build_object('id', c.id)` if we
+    /// only return ids
+    fn row_data(&self, out: &mut AstPass<Pg>) -> QueryResult<()> {
+        if self.ids_only {
+            out.push_sql("jsonb_build_object('");
+            out.push_sql(PRIMARY_KEY_COLUMN);
+            out.push_sql("', c.");
+            out.push_identifier(PRIMARY_KEY_COLUMN)?;
+            out.push_sql(")");
+        } else {
+            out.push_sql("to_jsonb(c.*)");
+        }
+        Ok(())
+    }
+
+    /// For each list attribute `attr`, also return its first element as the
+    /// attribute `attr_first`, which is `Null` if the list is empty or null
+    pub fn with_list_heads(mut self) -> Self {
+        self.list_heads = true;
+        self
+    }
+
+    /// If `list_heads` is set, generate
+    ///   || jsonb_build_object('g$first$attr', c.attr[1], ..)
+    /// for all list attributes of `table`, to be appended to `to_jsonb(c.*)`
+    fn list_heads(&self, table: &Table, out: &mut AstPass<Pg>) -> QueryResult<()> {
+        if !self.list_heads {
+            return Ok(());
+        }
+        let lists: Vec<_> = table.columns.iter().filter(|col| col.is_list()).collect();
+        if lists.is_empty() {
+            return Ok(());
+        }
+        out.push_sql(" || jsonb_build_object(");
+        for (i, column) in lists.into_iter().enumerate() {
+            if i > 0 {
+                out.push_sql(", ");
+            }
+            // Field names are GraphQL names and can not contain quotes
+            out.push_sql("'");
+            out.push_sql(LIST_HEAD_PREFIX);
+            out.push_sql(&column.field);
+            out.push_sql("', c.");
+            out.push_identifier(column.name.as_str())?;
+            out.push_sql("[1]");
+        }
+        out.push_sql(")");
+        Ok(())
+    }
+
+    /// Also return the number of entities that reference each returned
+    /// entity through the derived field `field` as the attribute
+    /// `__childCount`. The count is computed from the referencing rows
+    /// that are current at the query's block, and not taken from any
+    /// attribute stored with the entity. All entity types in the query
+    /// must have the derived field `field`
+    pub fn with_child_count(
+        mut self,
+        layout: &'a Layout,
+        field: &str,
+    ) -> Result<Self, QueryExecutionError> {
+        let mut child_count = Vec::new();
+        for table in self.collection.tables() {
+            let derived = table.derived_field(field).ok_or_else(|| {
+                StoreError::QueryExecutionError(format!(
+                    "`{}` is not a derived field of `{}` and its children can not be counted",
+                    field, table.object
+                ))
+            })?;
+            let child = layout.table_for_entity(&derived.entity_type)?.as_ref();
+            let column = child.column_for_field(&derived.attribute)?;
+            child_count.push(ChildCount {
+                parent: table.object.as_str(),
+                child,
+                column,
+            });
+        }
+        self.child_count = child_count;
+        Ok(self)
+    }
+
+    /// If `child_count` is set, generate
+    ///   || jsonb_build_object('__childCount',
+    ///        (select count(*) from child cc
+    ///          where cc.block_range @> $block and c.id = cc.column))
+    /// to be appended to `to_jsonb(c.*)`
+    fn child_count(&self, table: &Table, out: &mut AstPass<Pg>) -> QueryResult<()> {
+        let count = match self
+            .child_count
+            .iter()
+            .find(|count| count.parent == table.object)
+        {
+            Some(count) => count,
+            None => return Ok(()),
+        };
+        out.push_sql(" || jsonb_build_object('");
+        out.push_sql(CHILD_COUNT_KEY);
+        out.push_sql("', (select count(*) from ");
+        out.push_sql(count.child.qualified_name.as_str());
+        out.push_sql(" cc where ");
+        BlockRangeContainsClause::new("cc.", self.block).walk_ast(out.reborrow())?;
+        out.push_sql(" and c.");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        if count.column.is_list() {
+            out.push_sql(" = any(cc.");
+        } else {
+            out.push_sql(" = (cc.");
+        }
+        out.push_identifier(count.column.name.as_str())?;
+        out.push_sql(")))");
+        Ok(())
+    }
+
+    /// Also return, as the attribute `blocksOld`, how many blocks before
+    /// the query's block the block that each returned entity references
+    /// through its `block` attribute is, i.e., `$block - block.number`.
+    /// The attribute is `Null` if the entity does not reference a block,
+    /// or if the referenced block does not exist at the query's block. All
+    /// entity types in the query must have a `block` attribute that
+    /// references a type with a `number` attribute
+    pub fn with_blocks_old(mut self, layout: &'a Layout) -> Result<Self, QueryExecutionError> {
+        let mut blocks_old = Vec::new();
+        for table in self.collection.tables() {
+            let block_ref = table.column_for_field("block")?;
+            if !block_ref.is_reference() || block_ref.is_list() {
+                return Err(StoreError::QueryExecutionError(format!(
+                    "`{}.block` has type {} and can not be used to compute `{}`",
+                    table.object, block_ref.field_type, BLOCKS_OLD_KEY
+                ))
+                .into());
+            }
+            let blocks = layout.table_for_entity(block_ref.named_type())?.as_ref();
+            blocks_old.push(BlocksOld {
+                parent: table.object.as_str(),
+                block_ref,
+                blocks,
+                number: blocks.column_for_field("number")?,
+            });
+        }
+        self.blocks_old = blocks_old;
+        Ok(self)
+    }
+
+    /// If `blocks_old` is set, generate
+    ///   || jsonb_build_object('blocksOld',
+    ///        (select $block - b.number from blocks b
+    ///          where b.block_range @> $block and b.id = c.block))
+    /// to be appended to `to_jsonb(c.*)`
+    fn blocks_old(&self, table: &Table, out: &mut AstPass<Pg>) -> QueryResult<()> {
+        let blocks_old = match self
+            .blocks_old
+            .iter()
+            .find(|blocks_old| blocks_old.parent == table.object)
+        {
+            Some(blocks_old) => blocks_old,
+            None => return Ok(()),
+        };
+        out.push_sql(" || jsonb_build_object('");
+        out.push_sql(BLOCKS_OLD_KEY);
+        out.push_sql("', (select ");
+        out.push_bind_param::<Integer, _>(&self.block)?;
+        out.push_sql(" - b.");
+        out.push_identifier(blocks_old.number.name.as_str())?;
+        out.push_sql(" from ");
+        out.push_sql(blocks_old.blocks.qualified_name.as_str());
+        out.push_sql(" b where ");
+        BlockRangeContainsClause::new("b.", self.block).walk_ast(out.reborrow())?;
+        out.push_sql(" and b.");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        out.push_sql(" = c.");
+        out.push_identifier(blocks_old.block_ref.name.as_str())?;
+        out.push_sql("))");
+        Ok(())
+    }
+
+    /// Order rows that are tied on the sort key by `md5(id || seed)`
+    /// rather than just by `id`. This gives a shuffle of tied rows that is
+    /// the same for every query with the same `seed`. Postgres only allows
+    /// ordering the result of a `union` by columns, and the tiebreak can
+    /// therefore only be used with queries that involve one entity type
+    pub fn with_tiebreak_seed(mut self, seed: u32) -> Result<Self, QueryExecutionError> {
+        let single = match self.collection {
+            FilterCollection::All(entities) => entities.len() == 1,
+            FilterCollection::SingleWindow(_) => true,
+            FilterCollection::MultiWindow(_, _) => false,
+        };
+        if !single {
+            return Err(StoreError::QueryExecutionError(
+                "a tiebreak seed can only be used when querying a single entity type".to_owned(),
+            )
+            .into());
+        }
+        self.sort_key.tiebreak_seed = Some(seed);
+        Ok(self)
+    }
+
+    /// Sort the values of the text attribute that the query is ordered by
+    /// with `collation`, e.g., `de-u-co-phonebk-x-icu`, rather than with
+    /// the database's default collation. The caller must make sure that
+    /// the collation exists. Like the tiebreak seed, this is only possible
+    /// for queries that involve one entity type
+    pub fn with_collation(mut self, collation: &'a str) -> Result<Self, QueryExecutionError> {
+        let single = match self.collection {
+            FilterCollection::All(entities) => entities.len() == 1,
+            FilterCollection::SingleWindow(_) => true,
+            FilterCollection::MultiWindow(_, _) => false,
+        };
+        if !single {
+            return Err(StoreError::QueryExecutionError(
+                "a collation can only be used when querying a single entity type".to_owned(),
+            )
+            .into());
+        }
+        match self.sort_key.column {
+            Some(column) if column.is_text() => (),
+            Some(column) => {
+                return Err(StoreError::QueryExecutionError(format!(
+                    "a collation can only be used when ordering by a String attribute, \
+                     but `{}` has type {}",
+                    column.field, column.field_type
+                ))
+                .into())
+            }
+            None => {
+                return Err(StoreError::QueryExecutionError(
+                    "a collation can only be used when the query is ordered by an attribute"
+                        .to_owned(),
+                )
+                .into())
+            }
+        }
+        self.sort_key.collation = Some(collation);
+        Ok(self)
+    }
+
+    /// Only return one row for each id if the query spans multiple entity
+    /// types, and apply the range to the deduplicated rows. Queries against
+    /// a single entity type never return duplicate ids. Windowed queries
+    /// are not affected by this setting
+    pub fn distinct_on_id(mut self) -> Self {
+        self.distinct_on_id = true;
+        self
+    }
+
+    /// When the query spans multiple entity types, return the first entity
+    /// of each type, then the second entity of each type, and so on, rather
+    /// than ordering the entities of all types together. The entities of
+    /// each type keep the order of the sort key, and types are taken in
+    /// the order of their names. Once a type runs out of entities, the
+    /// remaining types continue to alternate. The range applies to the
+    /// interleaved rows. Queries against a single entity type and windowed
+    /// queries are not affected by this setting
+    pub fn interleave_types(mut self) -> Self {
+        self.interleave_types = true;
+        self
+    }
+
     /// Generate
     ///     from schema.table c
     ///    where block_range @> $block
     ///      and query_filter
     /// Only used when the query is against a `FilterCollection::All`, i.e.
-    /// when we do not need to window
+    /// when we do not need to window. For unclamped queries, the condition
+    /// on the block range is replaced with `true`
     fn filtered_rows(
         &self,
         table: &Table,
@@ -1676,7 +4730,11 @@ impl<'a> FilterQuery<'a> {
         out.push_sql(table.qualified_name.as_str());
         out.push_sql(" c");
         out.push_sql("\n where ");
-        BlockRangeContainsClause::new("c.", self.block).walk_ast(out.reborrow())?;
+        if self.unclamped {
+            out.push_sql("true");
+        } else {
+            BlockRangeContainsClause::new("c.", self.block).walk_ast(out.reborrow())?;
+        }
         if let Some(filter) = table_filter {
             out.push_sql(" and ");
             filter.walk_ast(out.reborrow())?;
@@ -1685,10 +4743,16 @@ impl<'a> FilterQuery<'a> {
         Ok(())
     }
 
-    fn select_entity_and_data(table: &Table, out: &mut AstPass<Pg>) {
+    fn select_entity_and_data(&self, table: &Table, out: &mut AstPass<Pg>) -> QueryResult<()> {
         out.push_sql("select '");
         out.push_sql(&table.object);
-        out.push_sql("' as entity, to_jsonb(c.*) as data");
+        out.push_sql("' as entity, ");
+        self.row_data(out)?;
+        self.list_heads(table, out)?;
+        self.child_count(table, out)?;
+        self.blocks_old(table, out)?;
+        out.push_sql(" as data");
+        Ok(())
     }
 
     /// Only one table/filter pair, and no window
@@ -1709,11 +4773,13 @@ impl<'a> FilterQuery<'a> {
         filter: &Option<QueryFilter>,
         mut out: AstPass<Pg>,
     ) -> QueryResult<()> {
-        Self::select_entity_and_data(table, &mut out);
+        self.select_entity_and_data(table, &mut out)?;
         out.push_sql(" from (select * ");
         self.filtered_rows(table, filter, out.reborrow())?;
-        out.push_sql("\n order by ");
-        self.sort_key.order_by(&mut out)?;
+        if !self.unordered {
+            out.push_sql("\n order by ");
+            self.sort_key.order_by(&mut out)?;
+        }
         self.range.walk_ast(out.reborrow())?;
         out.push_sql(") c");
         Ok(())
@@ -1731,7 +4797,7 @@ impl<'a> FilterQuery<'a> {
         window: &FilterWindow,
         mut out: AstPass<Pg>,
     ) -> QueryResult<()> {
-        Self::select_entity_and_data(&window.table, &mut out);
+        self.select_entity_and_data(&window.table, &mut out)?;
         out.push_sql(" from (\n");
         out.push_sql("select c.*, p.id as g$parent_id");
         window.children(
@@ -1774,8 +4840,46 @@ impl<'a> FilterQuery<'a> {
         //  ...
         //  order by c.{sort_key}
 
+        //
+        // If `distinct_on_id` is set, the union in the matches CTE is
+        // deduplicated before it is sorted and limited, i.e., it becomes
+        //
+        //   select * from (
+        //     select distinct on (id) * from (
+        //       select '...' as entity, id, vid, {sort_key} ...
+        //        union all
+        //        ...) u
+        //      order by id, {sort_key}) u
+        //    order by {sort_key}
+        //    limit n offset m
+        //
+        // If `interleave_types` is set, each row in the matches CTE gets
+        // its position among the rows of its entity type as `g$turn`, and
+        // rows are sorted by that first, i.e., the union (after removing
+        // duplicate ids) becomes
+        //
+        //   select * from (
+        //     select *, row_number() over (partition by entity
+        //                                  order by {sort_key}) as g$turn
+        //       from (...) u) u
+        //    order by g$turn, entity, {sort_key}
+        //    limit n offset m
+        //
+        // and the outer query also selects `m.g$turn` and orders by it in
+        // the same way
+
         // Step 1: build matches CTE
         out.push_sql("with matches as (");
+        if self.interleave_types {
+            out.push_sql(
+                "select * from (select *, row_number() over (partition by entity order by ",
+            );
+            self.sort_key.order_by(&mut out)?;
+            out.push_sql(") as g$turn from (");
+        }
+        if self.distinct_on_id {
+            out.push_sql("select * from (select distinct on (id) * from (");
+        }
         for (i, (table, filter)) in entities.iter().enumerate() {
             if i > 0 {
                 out.push_sql("\nunion all\n");
@@ -1790,8 +4894,18 @@ impl<'a> FilterQuery<'a> {
             self.sort_key.select(&mut out)?;
             self.filtered_rows(table, filter, out.reborrow())?;
         }
-        out.push_sql("\n order by ");
-        self.sort_key.order_by(&mut out)?;
+        if self.distinct_on_id {
+            out.push_sql(") u\n order by id, ");
+            self.sort_key.order_by(&mut out)?;
+            out.push_sql(") u");
+        }
+        if self.interleave_types {
+            out.push_sql(") u) u");
+        }
+        if !self.unordered {
+            out.push_sql("\n order by ");
+            self.interleaved_order_by(&mut out)?;
+        }
         self.range.walk_ast(out.reborrow())?;
 
         out.push_sql(")\n");
@@ -1801,8 +4915,16 @@ impl<'a> FilterQuery<'a> {
             if i > 0 {
                 out.push_sql("\nunion all\n");
             }
-            out.push_sql("select m.entity, to_jsonb(c.*) as data, c.id");
+            out.push_sql("select m.entity, ");
+            self.row_data(&mut out)?;
+            self.list_heads(table, &mut out)?;
+            self.child_count(table, &mut out)?;
+            self.blocks_old(table, &mut out)?;
+            out.push_sql(" as data, c.id");
             self.sort_key.select(&mut out)?;
+            if self.interleave_types {
+                out.push_sql(", m.g$turn");
+            }
             out.push_sql("\n  from ");
             out.push_sql(table.qualified_name.as_str());
             out.push_sql(" c,");
@@ -1810,9 +4932,20 @@ impl<'a> FilterQuery<'a> {
             out.push_sql("\n where c.vid = m.vid and m.entity = ");
             out.push_bind_param::<Text, _>(&table.object)?;
         }
+        if self.unordered {
+            return Ok(());
+        }
         out.push_sql("\n order by ");
-        self.sort_key.order_by(&mut out)?;
-        Ok(())
+        self.interleaved_order_by(&mut out)
+    }
+
+    /// Generate `g$turn, entity, {sort_key}` if we interleave entity types,
+    /// and just `{sort_key}` otherwise
+    fn interleaved_order_by(&self, out: &mut AstPass<Pg>) -> QueryResult<()> {
+        if self.interleave_types {
+            out.push_sql("g$turn, entity, ");
+        }
+        self.sort_key.order_by_selected(out)
     }
 
     /// Multiple windows
@@ -1861,7 +4994,7 @@ impl<'a> FilterQuery<'a> {
             window.children_uniform(&self.sort_key, self.block, out.reborrow())?;
         }
         out.push_sql("\norder by ");
-        self.sort_key.order_by(&mut out)?;
+        self.sort_key.order_by_selected(&mut out)?;
         self.range.walk_ast(out.reborrow())?;
         out.push_sql(") c)\n");
 
@@ -1871,28 +5004,33 @@ impl<'a> FilterQuery<'a> {
         // windows that use the same table for the children. We need to make
         // sure each table only appears once in the 'union all' otherwise we'll
         // duplicate entities in the result
-        // We only use a table's qualified name and object to save ourselves
-        // the hassle of making `Table` hashable
+        // We only use a table's qualified name to save ourselves the hassle
+        // of making `Table` hashable
+        let mut seen = HashSet::new();
         let unique_child_tables = windows
             .iter()
-            .map(|window| (&window.table.qualified_name, &window.table.object))
-            .collect::<HashSet<_>>();
-        for (i, (table_name, object)) in unique_child_tables.into_iter().enumerate() {
+            .map(|window| window.table)
+            .filter(|table| seen.insert(&table.qualified_name))
+            .collect::<Vec<_>>();
+        for (i, table) in unique_child_tables.into_iter().enumerate() {
             if i > 0 {
                 out.push_sql("\nunion all\n");
             }
-            out.push_sql(
-                "select m.*, \
-                 to_jsonb(c.*) || jsonb_build_object('g$parent_id', m.g$parent_id) as data",
-            );
+            out.push_sql("select m.*, ");
+            self.row_data(&mut out)?;
+            out.push_sql(" || jsonb_build_object('g$parent_id', m.g$parent_id)");
+            self.list_heads(table, &mut out)?;
+            self.child_count(table, &mut out)?;
+            self.blocks_old(table, &mut out)?;
+            out.push_sql(" as data");
             out.push_sql("\n  from ");
-            out.push_sql(table_name.as_str());
+            out.push_sql(table.qualified_name.as_str());
             out.push_sql(" c, matches m\n where c.vid = m.vid and m.entity = '");
-            out.push_sql(object);
+            out.push_sql(&table.object);
             out.push_sql("'");
         }
         out.push_sql("\n order by g$parent_id,");
-        self.sort_key.order_by(&mut out)
+        self.sort_key.order_by_selected(&mut out)
     }
 }
 
@@ -1944,6 +5082,42 @@ impl<'a> LoadQuery<PgConnection, EntityData> for FilterQuery<'a> {
 
 impl<'a, Conn> RunQueryDsl<Conn> for FilterQuery<'a> {}
 
+/// One line of the output of `explain`
+#[derive(QueryableByName)]
+pub struct QueryPlanLine {
+    #[sql_type = "Text"]
+    #[column_name = "QUERY PLAN"]
+    pub line: String,
+}
+
+/// Run `explain` for a `FilterQuery` to see how Postgres would execute it
+#[derive(Debug, Clone, Constructor)]
+pub struct ExplainQuery<'a> {
+    query: FilterQuery<'a>,
+}
+
+impl<'a> QueryFragment<Pg> for ExplainQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+        out.push_sql("explain ");
+        self.query.walk_ast(out)
+    }
+}
+
+impl<'a> QueryId for ExplainQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> LoadQuery<PgConnection, QueryPlanLine> for ExplainQuery<'a> {
+    fn internal_load(self, conn: &PgConnection) -> QueryResult<Vec<QueryPlanLine>> {
+        conn.query_by_name(&self)
+    }
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for ExplainQuery<'a> {}
+
 /// Reduce the upper bound of the current entry's block range to `block` as
 /// long as that does not result in an empty block range
 #[derive(Debug, Clone, Constructor)]
@@ -1987,6 +5161,83 @@ impl<'a> QueryId for ClampRangeQuery<'a> {
 
 impl<'a, Conn> RunQueryDsl<Conn> for ClampRangeQuery<'a> {}
 
+/// Like `ClampRangeQuery`, but for the current entries of all the entities
+/// in `table` whose id is one of `ids`
+#[derive(Debug, Clone, Constructor)]
+pub struct ClampRangeManyQuery<'a> {
+    table: &'a Table,
+    ids: &'a [String],
+    block: BlockNumber,
+}
+
+impl<'a> QueryFragment<Pg> for ClampRangeManyQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        // update table
+        //    set block_range = int4range(lower(block_range), $block)
+        //  where id = any($ids)
+        //    and block_range @> INTMAX
+        out.unsafe_to_cache_prepared();
+        out.push_sql("update ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql("\n   set ");
+        out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        out.push_sql(" = int4range(lower(");
+        out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        out.push_sql("), ");
+        out.push_bind_param::<Integer, _>(&self.block)?;
+        out.push_sql(")\n where ");
+        out.push_identifier(PRIMARY_KEY_COLUMN)?;
+        out.push_sql(" = any(");
+        out.push_bind_param::<Array<Text>, _>(&self.ids)?;
+        out.push_sql(") and (");
+        out.push_sql(BLOCK_RANGE_CURRENT);
+        out.push_sql(")");
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for ClampRangeManyQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for ClampRangeManyQuery<'a> {}
+
+/// A query that deletes all versions that stopped being current before
+/// `before`, i.e., whose block range lies entirely below `before`. Since
+/// the block range of the current version has no upper bound, it is
+/// never deleted
+#[derive(Debug, Clone, Constructor)]
+pub struct PruneQuery<'a> {
+    table: &'a Table,
+    before: BlockNumber,
+}
+
+impl<'a> QueryFragment<Pg> for PruneQuery<'a> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        // Construct a query
+        //   delete from table
+        //    where upper(block_range) <= $before
+        out.push_sql("delete from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql("\n where upper(");
+        out.push_identifier(BLOCK_RANGE_COLUMN)?;
+        out.push_sql(") <= ");
+        out.push_bind_param::<Integer, _>(&self.before)
+    }
+}
+
+impl<'a> QueryId for PruneQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for PruneQuery<'a> {}
+
 /// Helper struct for returning the id's touched by the RevertRemove and
 /// RevertExtend queries
 #[derive(QueryableByName, PartialEq, Eq, Hash)]
@@ -2225,7 +5476,13 @@ impl<'a> QueryFragment<Pg> for CopyEntityDataQuery<'a> {
             out.push_identifier(column.name.as_str())?;
             out.push_sql(", ");
         }
-        out.push_sql("block_range)");
+        out.push_sql("block_range");
+        let explicit_nulls = self.src.has_explicit_nulls() && self.dst.has_explicit_nulls();
+        if explicit_nulls {
+            out.push_sql(", ");
+            out.push_identifier(EXPLICIT_NULLS_COLUMN)?;
+        }
+        out.push_sql(")");
         out.push_sql("\nselect ");
         for column in &self.columns {
             out.push_identifier(column.name.as_str())?;
@@ -2236,7 +5493,12 @@ impl<'a> QueryFragment<Pg> for CopyEntityDataQuery<'a> {
             }
             out.push_sql(", ");
         }
-        out.push_sql("block_range from ");
+        out.push_sql("block_range");
+        if explicit_nulls {
+            out.push_sql(", ");
+            out.push_identifier(EXPLICIT_NULLS_COLUMN)?;
+        }
+        out.push_sql(" from ");
         out.push_sql(self.src.qualified_name.as_str());
         Ok(())
     }
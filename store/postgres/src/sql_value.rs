@@ -3,12 +3,26 @@ use diesel::serialize::{self, Output, ToSql};
 use diesel::sql_types::{Binary, Bool, Integer, Numeric, Text};
 use std::io::Write;
 
+use graph::data::store::scalar::{BigDecimal, BigInt};
 use graph::data::store::Value;
 
+/// Convert a `BigInt` to the `BigDecimal` that we send to Postgres as a
+/// `numeric`. The conversion is exact: the result has scale 0 and keeps
+/// all digits of `number`, no matter how large it is. It must never go
+/// through a fixed-size integer type like `i64`, which would truncate
+/// large values
+pub(crate) fn big_int_to_numeric(number: &BigInt) -> BigDecimal {
+    number.clone().to_big_decimal(0.into())
+}
+
 #[derive(Clone, Debug, PartialEq, AsExpression)]
 pub struct SqlValue(Value);
 
 impl SqlValue {
+    /// Turn the elements of a list into an array of `SqlValue`. The order
+    /// of the elements of a list is significant, and the array has them
+    /// in exactly the order in which they appear in `values` so that they
+    /// get stored, and later read back, in that order
     pub fn new_array(values: Vec<Value>) -> Vec<Self> {
         values.into_iter().map(SqlValue).collect()
     }
@@ -37,7 +51,7 @@ impl ToSql<Numeric, Pg> for SqlValue {
         match &self.0 {
             Value::BigDecimal(d) => <_ as ToSql<Numeric, Pg>>::to_sql(&d, out),
             Value::BigInt(number) => {
-                <_ as ToSql<Numeric, Pg>>::to_sql(&number.clone().to_big_decimal(0.into()), out)
+                <_ as ToSql<Numeric, Pg>>::to_sql(&big_int_to_numeric(number), out)
             }
             _ => panic!("Failed to convert attribute value to bigint in SQL"),
         }
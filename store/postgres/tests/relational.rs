@@ -10,11 +10,14 @@ use std::str::FromStr;
 
 use graph::data::store::scalar::{BigDecimal, BigInt, Bytes};
 use graph::prelude::{
-    bigdecimal::One, web3::types::H256, Entity, EntityCollection, EntityFilter, EntityKey,
-    EntityOrder, EntityQuery, EntityRange, Future01CompatExt, Schema, SubgraphDeploymentId, Value,
+    bigdecimal::One, web3::types::H256, BlockHeight, BlockNumber, Entity, EntityCollection,
+    EntityFilter, EntityKey, EntityModification, EntityOrder, EntityQuery, EntityRange,
+    Future01CompatExt, QueryExecutionError, Schema, StoreError, SubgraphDeploymentId, Value,
     ValueType, BLOCK_NUMBER_MAX,
 };
-use graph_store_postgres::layout_for_tests::{Layout, STRING_PREFIX_SIZE};
+use graph_store_postgres::layout_for_tests::{
+    EncryptionKey, EntityStream, FieldDef, Layout, QueryOptions, STRING_PREFIX_SIZE,
+};
 
 use test_store::*;
 
@@ -27,8 +30,8 @@ const THINGS_GQL: &str = r#"
             {
                 entity: "User",
                 fields: [
-                    { name: "name"},
-                    { name: "email"},
+                    { name: "name", weight: A },
+                    { name: "email" },
                 ]
             }
         ]
@@ -55,25 +58,29 @@ const THINGS_GQL: &str = r#"
 
     interface Pet {
         id: ID!,
-        name: String!
+        name: String!,
+        color: Color
     }
 
     type Cat implements Pet @entity {
         id: ID!,
-        name: String!
+        name: String!,
+        color: Color
     }
 
     type Dog implements Pet @entity {
         id: ID!,
-        name: String!
+        name: String!,
+        color: Color
     }
 
     type Ferret implements Pet @entity {
         id: ID!,
-        name: String!
+        name: String!,
+        color: Color
     }
 
-    type User @entity {
+    type User @entity @order_index(fields: ["name"]) {
         id: ID!,
         name: String!,
         bin_name: Bytes!,
@@ -81,10 +88,37 @@ const THINGS_GQL: &str = r#"
         age: Int!,
         seconds_age: BigInt!,
         weight: BigDecimal!,
-        coffee: Boolean!,
+        coffee: Boolean,
         favorite_color: Color,
         drinks: [String!]
     }
+
+    type Transaction @entity {
+        id: ID!,
+        from: Bytes!,
+        nonce: BigInt!,
+        block: Block,
+        index: Int,
+        gasUsed: BigInt,
+        transfersValue: Boolean
+    }
+
+    type Block @entity {
+        id: ID!,
+        number: Int!,
+        transactionCount: Int,
+        transactions: [Transaction!]! @derivedFrom(field: "block")
+    }
+
+    type Peer @entity {
+        id: ID!,
+        address: String! @inet
+    }
+
+    type Patient @entity {
+        id: ID!,
+        email: String @encrypted
+    }
 "#;
 
 const SCHEMA_NAME: &str = "layout";
@@ -147,7 +181,9 @@ fn insert_entity(conn: &PgConnection, layout: &Layout, entity_type: &str, entity
         entity_id: entity.id().unwrap(),
     };
     let errmsg = format!("Failed to insert entity {}[{}]", entity_type, key.entity_id);
-    layout.insert(&conn, &key, entity, 0).expect(&errmsg);
+    layout
+        .insert(&conn, &key, entity, BlockHeight::from(0))
+        .expect(&errmsg);
 }
 
 fn update_entity(conn: &PgConnection, layout: &Layout, entity_type: &str, entity: Entity) {
@@ -157,7 +193,9 @@ fn update_entity(conn: &PgConnection, layout: &Layout, entity_type: &str, entity
         entity_id: entity.id().unwrap(),
     };
     let errmsg = format!("Failed to update entity {}[{}]", entity_type, key.entity_id);
-    layout.update(&conn, &key, entity, 1).expect(&errmsg);
+    layout
+        .update(&conn, &key, entity, BlockHeight::from(1))
+        .expect(&errmsg);
 }
 
 fn insert_user_entity(
@@ -389,19 +427,19 @@ fn find() {
 
         // Happy path: find existing entity
         let entity = layout
-            .find(conn, "Scalar", "one", BLOCK_NUMBER_MAX)
+            .find(conn, "Scalar", "one", BlockHeight::MAX)
             .expect("Failed to read Scalar[one]")
             .unwrap();
         assert_entity_eq!(scrub(&*SCALAR_ENTITY), entity);
 
         // Find non-existing entity
         let entity = layout
-            .find(conn, "Scalar", "noone", BLOCK_NUMBER_MAX)
+            .find(conn, "Scalar", "noone", BlockHeight::MAX)
             .expect("Failed to read Scalar[noone]");
         assert!(entity.is_none());
 
         // Find for non-existing entity type
-        let err = layout.find(conn, "NoEntity", "one", BLOCK_NUMBER_MAX);
+        let err = layout.find(conn, "NoEntity", "one", BlockHeight::MAX);
         match err {
             Err(e) => assert_eq!("unknown table 'NoEntity'", e.to_string()),
             _ => {
@@ -413,6 +451,371 @@ fn find() {
     });
 }
 
+#[test]
+fn find_with_fallback() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        // Create the entity at block 1 and update it at block 2
+        let key = EntityKey {
+            subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+            entity_type: "Scalar".to_owned(),
+            entity_id: "one".to_owned(),
+        };
+        layout
+            .insert(&conn, &key, SCALAR_ENTITY.clone(), BlockHeight::from(1))
+            .expect("Failed to insert");
+        let mut entity = SCALAR_ENTITY.clone();
+        entity.set("string", "updated");
+        layout
+            .update(&conn, &key, entity.clone(), BlockHeight::from(2))
+            .expect("Failed to update");
+
+        // Without the fallback, there is nothing at block 0
+        let found = layout
+            .find_with_fallback(conn, "Scalar", "one", BlockHeight::from(0), false)
+            .expect("Failed to read Scalar[one]");
+        assert!(found.is_none());
+
+        // With the fallback, we get the version created at block 1
+        let found = layout
+            .find_with_fallback(conn, "Scalar", "one", BlockHeight::from(0), true)
+            .expect("Failed to read Scalar[one]")
+            .unwrap();
+        assert!(found.is_fallback());
+        assert_entity_eq!(scrub(&*SCALAR_ENTITY), found.entity().clone());
+
+        // When there is a version at the block, the fallback is not used
+        let found = layout
+            .find_with_fallback(conn, "Scalar", "one", BlockHeight::MAX, true)
+            .expect("Failed to read Scalar[one]")
+            .unwrap();
+        assert!(!found.is_fallback());
+        assert_entity_eq!(scrub(&entity), found.entity().clone());
+
+        // Entities that never existed are not found either way
+        let found = layout
+            .find_with_fallback(conn, "Scalar", "noone", BlockHeight::from(0), true)
+            .expect("Failed to read Scalar[noone]");
+        assert!(found.is_none());
+        Ok(())
+    });
+}
+
+#[test]
+fn empty_list_is_not_null() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        // User 1 has a null list of drinks
+        insert_users(conn, layout);
+        insert_user_entity(
+            conn,
+            layout,
+            "4",
+            "User",
+            "Tobin",
+            "tobin@email.com",
+            37 as i32,
+            171.2,
+            true,
+            None,
+            Some(vec![]),
+        );
+
+        let drinks = |id: &str| {
+            layout
+                .find_with_nulls(conn, "User", id, BlockHeight::MAX)
+                .expect("Failed to read User")
+                .unwrap()
+                .get("drinks")
+                .cloned()
+        };
+        assert_eq!(Some(Value::List(vec![])), drinks("4"));
+        assert_eq!(Some(Value::Null), drinks("1"));
+
+        // Updating the null list to an empty one and vice versa swaps them
+        update_user_entity(
+            conn,
+            layout,
+            "1",
+            "User",
+            "Johnton",
+            "tonofjohn@email.com",
+            67 as i32,
+            184.4,
+            false,
+            Some("yellow"),
+            Some(vec![]),
+        );
+        update_user_entity(
+            conn,
+            layout,
+            "4",
+            "User",
+            "Tobin",
+            "tobin@email.com",
+            37 as i32,
+            171.2,
+            true,
+            None,
+            None,
+        );
+        assert_eq!(Some(Value::List(vec![])), drinks("1"));
+        assert_eq!(Some(Value::Null), drinks("4"));
+        Ok(())
+    });
+}
+
+#[test]
+fn graphql_fields() {
+    run_test(|_, layout| -> Result<(), ()> {
+        let fields = layout
+            .graphql_fields("User")
+            .expect("Failed to get the fields of User");
+        let field = |name: &str| {
+            fields
+                .iter()
+                .find(|field| field.name == name)
+                .cloned()
+                .expect("field exists")
+        };
+
+        assert_eq!(
+            FieldDef {
+                name: "name".to_owned(),
+                field_type: "String!".to_owned(),
+                named_type: "String".to_owned(),
+                is_list: false,
+                is_nullable: false,
+                is_derived: false,
+            },
+            field("name")
+        );
+        assert_eq!(
+            FieldDef {
+                name: "drinks".to_owned(),
+                field_type: "[String!]".to_owned(),
+                named_type: "String".to_owned(),
+                is_list: true,
+                is_nullable: true,
+                is_derived: false,
+            },
+            field("drinks")
+        );
+        // The fulltext search field is not a field of `User`
+        assert!(fields.iter().all(|field| field.name != "userSearch"));
+        assert_eq!("id", fields[0].name);
+
+        let fields = layout
+            .graphql_fields("Block")
+            .expect("Failed to get the fields of Block");
+        let transactions = fields.last().unwrap();
+        assert_eq!("transactions", transactions.name);
+        assert_eq!("[Transaction!]!", transactions.field_type);
+        assert!(transactions.is_derived && transactions.is_list && !transactions.is_nullable);
+
+        assert!(layout.graphql_fields("NoEntity").is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn entity_builder() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        let user = || {
+            layout
+                .entity_builder("User")
+                .expect("User is an entity type")
+                .set("id", "1")
+                .set("name", "Johnton")
+                .set("bin_name", Value::Bytes(Bytes::from_str("0x0102").unwrap()))
+                .set("email", "tonofjohn@email.com")
+                .set("age", 67)
+                .set("seconds_age", BigInt::from(2114359200u64))
+                .set("weight", BigDecimal::from(184.4))
+                .set("favorite_color", "yellow")
+                .set("drinks", vec!["beer", "wine"])
+        };
+
+        let entity = user().build().expect("A well-typed user can be built");
+        insert_entity(conn, layout, "User", entity.clone());
+        let found = layout
+            .find(conn, "User", "1", BlockHeight::MAX)
+            .expect("Failed to read user")
+            .expect("User was inserted");
+        assert_eq!(entity.get("age"), found.get("age"));
+
+        // Setting an attribute to a value of the wrong type is rejected
+        assert!(user().set("age", "sixty-seven").build().is_err());
+        assert!(user().set("coffee", 1).build().is_err());
+        assert!(user().set("drinks", "beer").build().is_err());
+        assert!(user().set("drinks", vec![1, 2]).build().is_err());
+        assert!(user().set("favorite_color", "purple").build().is_err());
+        // Attributes must be declared, and non-nullable ones must be set
+        assert!(user().set("nickname", "Jono").build().is_err());
+        assert!(user().set("email", Value::Null).build().is_err());
+        assert!(layout
+            .entity_builder("User")
+            .unwrap()
+            .set("id", "2")
+            .build()
+            .is_err());
+        // Nullable attributes can be left out or set to null
+        assert!(user().set("coffee", Value::Null).build().is_ok());
+
+        assert!(layout.entity_builder("Unicorn").is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn find_with_nulls() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_entity(&conn, &layout, "Scalar", SCALAR_ENTITY.clone());
+
+        let mut entity = SCALAR_ENTITY.clone();
+        entity.remove("strings");
+        entity.set("color", Value::Null);
+        update_entity(&conn, &layout, "Scalar", entity.clone());
+
+        // `find` leaves out both the removed and the null attribute
+        let found = layout
+            .find(conn, "Scalar", "one", BlockHeight::MAX)
+            .expect("Failed to read Scalar[one]")
+            .unwrap();
+        assert!(!found.contains_key("strings"));
+        assert!(!found.contains_key("color"));
+        assert_entity_eq!(scrub(&entity), found);
+
+        // `find_with_nulls` reports the attribute that was set to null,
+        // but not the one that was removed
+        let found = layout
+            .find_with_nulls(conn, "Scalar", "one", BlockHeight::MAX)
+            .expect("Failed to read Scalar[one]")
+            .unwrap();
+        assert_eq!(Some(&Value::Null), found.get("color"));
+        assert!(!found.contains_key("strings"));
+        assert!(!found.contains_key("nonexistent"));
+        assert_entity_eq!(entity, found);
+
+        // The version before the update has no explicit nulls
+        let found = layout
+            .find_with_nulls(conn, "Scalar", "one", BlockHeight::from(0))
+            .expect("Failed to read Scalar[one]")
+            .unwrap();
+        assert_entity_eq!(SCALAR_ENTITY.clone(), found);
+
+        let found = layout
+            .find_with_nulls(conn, "Scalar", "noone", BlockHeight::MAX)
+            .expect("Failed to read Scalar[noone]");
+        assert!(found.is_none());
+        Ok(())
+    });
+}
+
+#[test]
+fn list_order_is_preserved() {
+    fn strings(values: &[&str]) -> Value {
+        Value::from(values.iter().map(|s| Value::from(*s)).collect::<Vec<_>>())
+    }
+
+    fn find_lists(conn: &PgConnection, layout: &Layout) -> (Value, Value, Value) {
+        let scalar = layout
+            .find(conn, "Scalar", "one", BlockHeight::MAX)
+            .expect("Failed to read Scalar[one]")
+            .unwrap();
+        let user = layout
+            .find(conn, "User", "1", BlockHeight::MAX)
+            .expect("Failed to read User[1]")
+            .unwrap();
+        (
+            scalar.get("strings").unwrap().clone(),
+            scalar.get("byteArray").unwrap().clone(),
+            user.get("drinks").unwrap().clone(),
+        )
+    }
+
+    run_test(|conn, layout| -> Result<(), ()> {
+        // Neither sorted nor free of duplicates
+        let strings1 = strings(&["zeta", "alpha", "mu", "alpha"]);
+        let byte_array1 = Value::from(
+            vec![*BYTES_VALUE3, *BYTES_VALUE, *BYTES_VALUE2]
+                .into_iter()
+                .map(|b| Value::from(b))
+                .collect::<Vec<_>>(),
+        );
+        let drinks1 = strings(&["wine", "beer", "water"]);
+
+        let mut scalar = SCALAR_ENTITY.clone();
+        scalar.set("strings", strings1.clone());
+        scalar.set("byteArray", byte_array1.clone());
+        insert_entity(conn, layout, "Scalar", scalar.clone());
+        insert_user_entity(
+            conn,
+            layout,
+            "1",
+            "User",
+            "Johnton",
+            "tonofjohn@email.com",
+            67 as i32,
+            184.4,
+            false,
+            Some("yellow"),
+            Some(vec!["wine", "beer", "water"]),
+        );
+        assert_eq!((strings1, byte_array1, drinks1), find_lists(conn, layout));
+
+        // Rewriting the lists stores the new order
+        let strings2 = strings(&["mu", "alpha", "zeta"]);
+        let byte_array2 = Value::from(
+            vec![*BYTES_VALUE2, *BYTES_VALUE3, *BYTES_VALUE]
+                .into_iter()
+                .map(|b| Value::from(b))
+                .collect::<Vec<_>>(),
+        );
+        let drinks2 = strings(&["water", "wine", "beer"]);
+
+        scalar.set("strings", strings2.clone());
+        scalar.set("byteArray", byte_array2.clone());
+        update_entity(conn, layout, "Scalar", scalar);
+        update_user_entity(
+            conn,
+            layout,
+            "1",
+            "User",
+            "Johnton",
+            "tonofjohn@email.com",
+            67 as i32,
+            184.4,
+            false,
+            Some("yellow"),
+            Some(vec!["water", "wine", "beer"]),
+        );
+        assert_eq!((strings2, byte_array2, drinks2), find_lists(conn, layout));
+        Ok(())
+    });
+}
+
+#[test]
+fn find_ordered() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_entity(&conn, &layout, "Scalar", SCALAR_ENTITY.clone());
+        let mut two = SCALAR_ENTITY.clone();
+        two.set("id", "two");
+        insert_entity(&conn, &layout, "Scalar", two);
+
+        let entities = layout
+            .find_ordered(conn, "Scalar", &["two", "missing", "one"], BlockHeight::MAX)
+            .expect("Failed to read Scalars");
+        let ids: Vec<_> = entities
+            .iter()
+            .map(|entity| entity.as_ref().map(|entity| entity.id().unwrap()))
+            .collect();
+        assert_eq!(
+            vec![Some("two".to_owned()), None, Some("one".to_owned())],
+            ids
+        );
+        Ok(())
+    });
+}
+
 #[test]
 fn update() {
     run_test(|conn, layout| -> Result<(), ()> {
@@ -429,7 +832,7 @@ fn update() {
             entity_id: entity.id().unwrap().clone(),
         };
         layout
-            .update(&conn, &key, entity.clone(), 1)
+            .update(&conn, &key, entity.clone(), BlockHeight::from(1))
             .expect("Failed to update");
 
         // The missing 'strings' will show up as Value::Null in the
@@ -437,7 +840,7 @@ fn update() {
         entity.set("strings", Value::Null);
 
         let actual = layout
-            .find(conn, "Scalar", "one", BLOCK_NUMBER_MAX)
+            .find(conn, "Scalar", "one", BlockHeight::MAX)
             .expect("Failed to read Scalar[one]")
             .unwrap();
         assert_entity_eq!(scrub(&entity), actual);
@@ -445,6 +848,146 @@ fn update() {
     });
 }
 
+#[test]
+fn history() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_entity(&conn, &layout, "Scalar", SCALAR_ENTITY.clone());
+
+        let key = EntityKey {
+            subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+            entity_type: "Scalar".to_owned(),
+            entity_id: "one".to_owned(),
+        };
+        for block in 1..3 {
+            let mut entity = SCALAR_ENTITY.clone();
+            entity.set("int", block);
+            layout
+                .update(&conn, &key, entity, BlockHeight::from(block))
+                .expect("Failed to update");
+        }
+
+        let history = layout
+            .history(&conn, "Scalar", "one")
+            .expect("Failed to read history of Scalar[one]");
+        let ranges: Vec<_> = history
+            .iter()
+            .map(|(_, lower, upper)| (*lower, *upper))
+            .collect();
+        assert_eq!(vec![(0, Some(1)), (1, Some(2)), (2, None)], ranges);
+
+        let ints: Vec<_> = history
+            .iter()
+            .map(|(entity, _, _)| entity.get("int").cloned())
+            .collect();
+        assert_eq!(
+            vec![
+                Some(Value::Int(std::i32::MAX)),
+                Some(Value::Int(1)),
+                Some(Value::Int(2))
+            ],
+            ints
+        );
+        Ok(())
+    });
+}
+
+#[test]
+fn entity_size() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        let mut small = Entity::new();
+        small.set("id", "small");
+        insert_entity(conn, layout, "Scalar", small);
+
+        // Use bytes that do not follow a simple pattern so that Postgres
+        // can not compress them much
+        let mut seed: u32 = 17;
+        let byte_array = (0..100)
+            .map(|_| {
+                let bytes: Vec<u8> = (0..32)
+                    .map(|_| {
+                        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                        (seed >> 16) as u8
+                    })
+                    .collect();
+                Value::Bytes(Bytes::from(bytes.as_slice()))
+            })
+            .collect::<Vec<_>>();
+        let mut large = Entity::new();
+        large.set("id", "large");
+        large.set("byteArray", byte_array);
+        insert_entity(conn, layout, "Scalar", large);
+
+        let size = |id: &str| layout.entity_size(conn, "Scalar", id, BlockHeight::MAX);
+        let small_size = size("small").expect("Failed to get the size of Scalar[small]");
+        let large_size = size("large").expect("Failed to get the size of Scalar[large]");
+        assert!(small_size > 0);
+        assert!(
+            large_size > small_size + 2_000,
+            "Scalar[large] has size {} and Scalar[small] has size {}",
+            large_size,
+            small_size
+        );
+
+        assert!(size("none").is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn find_at_blocks() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_entity(&conn, &layout, "Scalar", SCALAR_ENTITY.clone());
+
+        let key = EntityKey {
+            subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+            entity_type: "Scalar".to_owned(),
+            entity_id: "one".to_owned(),
+        };
+        for block in &[2, 5] {
+            let mut entity = SCALAR_ENTITY.clone();
+            entity.set("int", *block);
+            layout
+                .update(&conn, &key, entity, BlockHeight::from(*block))
+                .expect("Failed to update");
+        }
+
+        let heights = |blocks: &[BlockNumber]| -> Vec<BlockHeight> {
+            blocks
+                .iter()
+                .map(|block| BlockHeight::from(*block))
+                .collect()
+        };
+        let versions = layout
+            .find_at_blocks(&conn, "Scalar", "one", &heights(&[0, 3, 6]))
+            .expect("Failed to read Scalar[one] at several blocks");
+        let ints: Vec<_> = versions
+            .iter()
+            .map(|(block, entity)| {
+                let entity = entity.as_ref().expect("Scalar[one] exists at all blocks");
+                (block.number(), entity.get("int").cloned())
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (0, Some(Value::Int(std::i32::MAX))),
+                (3, Some(Value::Int(2))),
+                (6, Some(Value::Int(5)))
+            ],
+            ints
+        );
+
+        // An entity that does not exist is `None` at every block
+        let versions = layout
+            .find_at_blocks(&conn, "Scalar", "none", &heights(&[0, 3]))
+            .expect("Failed to read Scalar[none] at several blocks");
+        assert_eq!(
+            vec![(BlockHeight::from(0), None), (BlockHeight::from(3), None)],
+            versions
+        );
+        Ok(())
+    });
+}
+
 /// Test that we properly handle BigDecimal values with a negative scale.
 #[test]
 fn serialize_bigdecimal() {
@@ -464,11 +1007,11 @@ fn serialize_bigdecimal() {
             entity_id: entity.id().unwrap().clone(),
         };
         layout
-            .update(&conn, &key, entity.clone(), 1)
+            .update(&conn, &key, entity.clone(), BlockHeight::from(1))
             .expect("Failed to update");
 
         let actual = layout
-            .find(conn, "Scalar", "one", BLOCK_NUMBER_MAX)
+            .find(conn, "Scalar", "one", BlockHeight::MAX)
             .expect("Failed to read Scalar[one]")
             .unwrap();
         assert_entity_eq!(&entity, actual);
@@ -493,79 +1036,441 @@ fn count_scalar_entities(conn: &PgConnection, layout: &Layout) -> usize {
                 first: None,
                 skip: 0,
             },
-            BLOCK_NUMBER_MAX,
+            BlockHeight::MAX,
         )
         .expect("Count query failed")
         .len()
 }
 
 #[test]
-fn delete() {
+fn insert_duplicate_id() {
     run_test(|conn, layout| -> Result<(), ()> {
-        insert_entity(&conn, &layout, "Scalar", SCALAR_ENTITY.clone());
-        let mut two = SCALAR_ENTITY.clone();
-        two.set("id", "two");
-        insert_entity(&conn, &layout, "Scalar", two);
+        insert_pet(conn, layout, "Cat", "garfield", "Garfield");
 
-        // Delete where nothing is getting deleted
-        let mut key = EntityKey {
+        let key = |entity_type: &str| EntityKey {
             subgraph_id: THINGS_SUBGRAPH_ID.clone(),
-            entity_type: "Scalar".to_owned(),
-            entity_id: "no such entity".to_owned(),
+            entity_type: entity_type.to_owned(),
+            entity_id: "garfield".to_owned(),
         };
-        let count = layout.delete(&conn, &key, 1).expect("Failed to delete");
-        assert_eq!(0, count);
-        assert_eq!(2, count_scalar_entities(conn, layout));
+        let mut cat = Entity::new();
+        cat.set("id", "garfield");
+        cat.set("name", "Tom");
+
+        match layout.insert(conn, &key("Cat"), cat.clone(), BlockHeight::from(1)) {
+            Err(StoreError::DuplicateId(entity_type, id)) => {
+                assert_eq!("Cat", entity_type);
+                assert_eq!("garfield", id);
+            }
+            other => panic!("expected a duplicate id error, but got {:?}", other),
+        }
 
-        // Delete entity two
-        key.entity_id = "two".to_owned();
-        let count = layout.delete(&conn, &key, 1).expect("Failed to delete");
-        assert_eq!(1, count);
-        assert_eq!(1, count_scalar_entities(conn, layout));
+        // When several entities are inserted at once, the error names the
+        // one whose id is taken, even if it is not the first one
+        let mut tom = cat.clone();
+        tom.set("id", "tom");
+        let tom_key = EntityKey {
+            entity_id: "tom".to_owned(),
+            ..key("Cat")
+        };
+        let cats = vec![(tom_key, tom), (key("Cat"), cat.clone())];
+        match layout.insert_from_iter(conn, "Cat", cats.into_iter(), BlockHeight::from(1), 10) {
+            Err(StoreError::DuplicateId(entity_type, id)) => {
+                assert_eq!("Cat", entity_type);
+                assert_eq!("garfield", id);
+            }
+            other => panic!("expected a duplicate id error, but got {:?}", other),
+        }
+
+        // Inserting into a table that does not exist is a different error
+        match layout.insert(conn, &key("Unicorn"), cat, BlockHeight::from(1)) {
+            Err(StoreError::UnknownTable(entity_type)) => assert_eq!("Unicorn", entity_type),
+            other => panic!("expected an unknown table error, but got {:?}", other),
+        }
         Ok(())
-    });
+    })
 }
 
 #[test]
-fn conflicting_entity() {
+fn insert_or_get() {
     run_test(|conn, layout| -> Result<(), ()> {
-        let id = "fred";
-        let cat = "Cat".to_owned();
-        let dog = "Dog".to_owned();
-        let ferret = "Ferret".to_owned();
+        let key = |id: &str| EntityKey {
+            subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+            entity_type: "Cat".to_owned(),
+            entity_id: id.to_owned(),
+        };
+        let cat = |id: &str, name: &str| {
+            let mut cat = Entity::new();
+            cat.set("id", id);
+            cat.set("name", name);
+            cat
+        };
 
-        let mut fred = Entity::new();
-        fred.set("id", id);
-        fred.set("name", id);
-        insert_entity(&conn, &layout, "Cat", fred);
+        // The cat does not exist yet and gets inserted
+        let res = layout
+            .insert_or_get(
+                conn,
+                &key("felix"),
+                cat("felix", "Felix"),
+                BlockHeight::from(0),
+            )
+            .expect("Failed to insert Cat[felix]");
+        assert!(res.is_left());
 
-        // If we wanted to create Fred the dog, which is forbidden, we'd run this:
-        let conflict = layout
-            .conflicting_entity(&conn, &id.to_owned(), vec![&cat, &ferret])
+        // Now it exists and we get the existing cat back
+        let existing = layout
+            .insert_or_get(
+                conn,
+                &key("felix"),
+                cat("felix", "Tom"),
+                BlockHeight::from(1),
+            )
+            .expect("Failed to get Cat[felix]")
+            .right()
+            .expect("Cat[felix] already exists");
+        assert_eq!(Some(&Value::from("Felix")), existing.get("name"));
+
+        // The existing cat is unchanged
+        let felix = layout
+            .find(conn, "Cat", "felix", BlockHeight::MAX)
+            .expect("Failed to read Cat[felix]")
             .unwrap();
-        assert_eq!(Some("Cat".to_owned()), conflict);
+        assert_eq!(Some(&Value::from("Felix")), felix.get("name"));
+        Ok(())
+    })
+}
 
-        // If we wanted to manipulate Fred the cat, which is ok, we'd run:
-        let conflict = layout
-            .conflicting_entity(&conn, &id.to_owned(), vec![&dog, &ferret])
+#[test]
+fn insert_from_iter() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        const COUNT: usize = 5000;
+        const CHUNK_SIZE: usize = 300;
+
+        let count_ferrets = || {
+            layout
+                .query(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["Ferret".to_owned()]),
+                    None,
+                    None,
+                    EntityRange {
+                        first: None,
+                        skip: 0,
+                    },
+                    BlockHeight::MAX,
+                )
+                .expect("Failed to count ferrets")
+                .len()
+        };
+
+        // Before producing each entity, check that no more than a chunk's
+        // worth of entities has been pulled without being written
+        let ferrets = (0..COUNT).map(|i| {
+            if i % 100 == 0 {
+                let pending = i - count_ferrets();
+                assert!(pending <= CHUNK_SIZE, "{} ferrets are buffered", pending);
+            }
+            let id = format!("ferret{:04}", i);
+            let mut ferret = Entity::new();
+            ferret.set("id", id.as_str());
+            ferret.set("name", format!("Ferret {}", i));
+            let key = EntityKey {
+                subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+                entity_type: "Ferret".to_owned(),
+                entity_id: id,
+            };
+            (key, ferret)
+        });
+
+        let inserted = layout
+            .insert_from_iter(conn, "Ferret", ferrets, BlockHeight::from(0), CHUNK_SIZE)
+            .expect("Failed to insert ferrets");
+        assert_eq!(COUNT, inserted);
+        assert_eq!(COUNT, count_ferrets());
+
+        let ferret = layout
+            .find(conn, "Ferret", "ferret4999", BlockHeight::MAX)
+            .expect("Failed to read ferret")
+            .expect("The last ferret is there");
+        assert_eq!(Some(&Value::from("Ferret 4999")), ferret.get("name"));
+        Ok(())
+    })
+}
+
+#[test]
+fn delete() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_entity(&conn, &layout, "Scalar", SCALAR_ENTITY.clone());
+        let mut two = SCALAR_ENTITY.clone();
+        two.set("id", "two");
+        insert_entity(&conn, &layout, "Scalar", two);
+
+        // Delete where nothing is getting deleted
+        let mut key = EntityKey {
+            subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+            entity_type: "Scalar".to_owned(),
+            entity_id: "no such entity".to_owned(),
+        };
+        let count = layout
+            .delete(&conn, &key, BlockHeight::from(1))
+            .expect("Failed to delete");
+        assert_eq!(0, count);
+        assert_eq!(2, count_scalar_entities(conn, layout));
+
+        // Delete entity two
+        key.entity_id = "two".to_owned();
+        let count = layout
+            .delete(&conn, &key, BlockHeight::from(1))
+            .expect("Failed to delete");
+        assert_eq!(1, count);
+        assert_eq!(1, count_scalar_entities(conn, layout));
+        Ok(())
+    });
+}
+
+#[test]
+fn prune() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_entity(&conn, &layout, "Scalar", SCALAR_ENTITY.clone());
+
+        let key = EntityKey {
+            subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+            entity_type: "Scalar".to_owned(),
+            entity_id: "one".to_owned(),
+        };
+        for block in 1..4 {
+            let mut entity = SCALAR_ENTITY.clone();
+            entity.set("int", block);
+            layout
+                .update(&conn, &key, entity, BlockHeight::from(block))
+                .expect("Failed to update");
+        }
+
+        let ranges = || -> Vec<_> {
+            layout
+                .history(&conn, "Scalar", "one")
+                .expect("Failed to read history of Scalar[one]")
+                .iter()
+                .map(|(_, lower, upper)| (*lower, *upper))
+                .collect()
+        };
+
+        // Removes the versions for [0, 1) and [1, 2)
+        let count = layout
+            .prune(&conn, "Scalar", BlockHeight::from(2))
+            .expect("Failed to prune");
+        assert_eq!(2, count);
+        assert_eq!(vec![(2, Some(3)), (3, None)], ranges());
+
+        // The current version is never removed
+        let count = layout
+            .prune(&conn, "Scalar", BlockHeight::MAX)
+            .expect("Failed to prune");
+        assert_eq!(1, count);
+        assert_eq!(vec![(3, None)], ranges());
+        let entity = layout
+            .find(conn, "Scalar", "one", BlockHeight::MAX)
+            .expect("Failed to read Scalar[one]")
             .unwrap();
-        assert_eq!(None, conflict);
+        assert_eq!(Some(&Value::Int(3)), entity.get("int"));
+        Ok(())
+    });
+}
 
-        // Chairs are not pets
-        let chair = "Chair".to_owned();
-        let result = layout.conflicting_entity(&conn, &id.to_owned(), vec![&dog, &ferret, &chair]);
-        assert!(result.is_err());
-        assert_eq!("unknown table 'Chair'", result.err().unwrap().to_string());
+#[test]
+fn apply_modifications() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+
+        let key = |id: &str| EntityKey {
+            subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+            entity_type: "User".to_owned(),
+            entity_id: id.to_owned(),
+        };
+        let user = |id: &str, name: &str| {
+            let mut user = layout
+                .find(conn, "User", "3", BlockHeight::MAX)
+                .expect("Failed to read User[3]")
+                .unwrap();
+            user.set("id", id);
+            user.set("name", name);
+            user
+        };
+
+        let mods = vec![
+            EntityModification::Insert {
+                key: key("4"),
+                data: user("4", "Tobin"),
+            },
+            EntityModification::Overwrite {
+                key: key("1"),
+                data: user("1", "Jonathan"),
+            },
+            EntityModification::Remove { key: key("2") },
+        ];
+        layout
+            .apply_modifications(conn, mods, BlockHeight::from(1))
+            .expect("Failed to apply modifications");
+
+        let names = |block: BlockNumber| -> Vec<(String, Value)> {
+            layout
+                .dump(conn, "User", BlockHeight::from(block))
+                .expect("Failed to dump users")
+                .iter()
+                .map(|user| (user.id().unwrap(), user.get("name").unwrap().clone()))
+                .collect()
+        };
+        assert_eq!(
+            vec![
+                ("1".to_owned(), Value::from("Jonathan")),
+                ("3".to_owned(), Value::from("Shaqueeena")),
+                ("4".to_owned(), Value::from("Tobin")),
+            ],
+            names(1)
+        );
+        // The modifications do not change earlier blocks
+        assert_eq!(
+            vec![
+                ("1".to_owned(), Value::from("Johnton")),
+                ("2".to_owned(), Value::from("Cindini")),
+                ("3".to_owned(), Value::from("Shaqueeena")),
+            ],
+            names(0)
+        );
         Ok(())
     })
 }
 
-fn test_find(expected_entity_ids: Vec<&str>, query: EntityQuery) {
-    let expected_entity_ids: Vec<String> =
-        expected_entity_ids.into_iter().map(str::to_owned).collect();
+#[test]
+fn delete_many() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        for id in &["one", "two", "three"] {
+            let mut scalar = SCALAR_ENTITY.clone();
+            scalar.set("id", *id);
+            insert_entity(&conn, &layout, "Scalar", scalar);
+        }
+
+        // Ids that do not exist are skipped
+        let ids = vec!["one".to_owned(), "three".to_owned(), "four".to_owned()];
+        let count = layout
+            .delete_many(&conn, "Scalar", &ids, BlockHeight::from(1))
+            .expect("Failed to delete");
+        assert_eq!(2, count);
+        assert_eq!(1, count_scalar_entities(conn, layout));
+        assert!(layout
+            .find(conn, "Scalar", "two", BlockHeight::MAX)
+            .expect("Failed to read Scalar[two]")
+            .is_some());
+
+        // Deleting the same entities again does nothing
+        let count = layout
+            .delete_many(&conn, "Scalar", &ids, BlockHeight::from(2))
+            .expect("Failed to delete");
+        assert_eq!(0, count);
+        Ok(())
+    });
+}
+
+#[test]
+fn swap_ids() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+
+        layout
+            .swap_ids(conn, "User", "1", "2", BlockHeight::from(1))
+            .expect("Failed to swap ids");
+
+        let name = |id: &str, block: BlockNumber| {
+            layout
+                .find(conn, "User", id, BlockHeight::from(block))
+                .expect("Failed to read User")
+                .expect("User exists")
+                .get("name")
+                .cloned()
+                .unwrap()
+        };
+        assert_eq!(Value::from("Cindini"), name("1", 1));
+        assert_eq!(Value::from("Johnton"), name("2", 1));
+        assert_eq!(Value::from("Shaqueeena"), name("3", 1));
+        // Earlier blocks still see the entities before the swap
+        assert_eq!(Value::from("Johnton"), name("1", 0));
+        assert_eq!(Value::from("Cindini"), name("2", 0));
+
+        // Both entities must exist
+        assert!(layout
+            .swap_ids(conn, "User", "1", "4", BlockHeight::from(2))
+            .is_err());
+        assert_eq!(Value::from("Cindini"), name("1", 2));
+        Ok(())
+    })
+}
+
+#[test]
+fn reindex() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+
+        let users = |filter: EntityFilter| -> Vec<String> {
+            layout
+                .query(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["User".to_owned()]),
+                    Some(filter),
+                    Some(("name".to_owned(), ValueType::String, EntityOrder::Ascending)),
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                )
+                .expect("Failed to query users")
+                .iter()
+                .map(|user| user.id().unwrap())
+                .collect()
+        };
+        let filter = || EntityFilter::GreaterThan("age".to_owned(), Value::Int(50));
+        let expected = users(filter());
+
+        // Jobs have to be started before they can make progress
+        assert!(layout.reindex(conn, "Cat", 1).is_err());
+
+        let status = layout
+            .start_reindex(conn, "User")
+            .expect("Failed to start reindexing");
+        assert_eq!(0, status.done);
+        assert!(status.total > 2);
+        assert!(!status.finished);
+
+        // Rebuild only some of the indexes, as if the job was interrupted
+        let status = layout
+            .reindex(conn, "User", 2)
+            .expect("Failed to reindex users");
+        assert_eq!(2, status.done);
+        assert!(!status.finished);
+        assert_eq!(Some(status), layout.reindex_status(conn, "User").unwrap());
+
+        // Resuming the job rebuilds the remaining indexes
+        let status = layout
+            .reindex(conn, "User", usize::MAX)
+            .expect("Failed to resume reindexing users");
+        assert_eq!(status.total, status.done);
+        assert!(status.finished);
+        assert_eq!(expected, users(filter()));
+
+        // Finished jobs do not do anything
+        let again = layout
+            .reindex(conn, "User", usize::MAX)
+            .expect("Failed to reindex users");
+        assert_eq!(status, again);
+        Ok(())
+    })
+}
+
+#[test]
+fn copy_type_to() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        const TARGET_SCHEMA: &str = "layout_copy";
 
-    run_test(move |conn, layout| -> Result<(), ()> {
         insert_users(conn, layout);
+        // Give user 1 a second version
         update_user_entity(
             conn,
             layout,
@@ -579,53 +1484,2876 @@ fn test_find(expected_entity_ids: Vec<&str>, query: EntityQuery) {
             Some("yellow"),
             None,
         );
-        insert_pets(conn, layout);
 
-        let order = match query.order_by {
-            Some((attribute, value_type)) => {
-                let direction = query.order_direction.unwrap_or(EntityOrder::Ascending);
-                Some((attribute, value_type, direction))
-            }
-            None => None,
+        let schema = Schema::parse(THINGS_GQL, THINGS_SUBGRAPH_ID.clone()).unwrap();
+        conn.batch_execute(&format!(
+            "drop schema if exists {0} cascade; create schema {0}",
+            TARGET_SCHEMA
+        ))
+        .unwrap();
+        let target = Layout::create_relational_schema(
+            &conn,
+            TARGET_SCHEMA,
+            THINGS_SUBGRAPH_ID.clone(),
+            &schema.document,
+        )
+        .expect("Failed to create target schema");
+
+        let count = layout
+            .copy_type_to(conn, "User", TARGET_SCHEMA)
+            .expect("Failed to copy users");
+        assert_eq!(4, count);
+
+        for (id, block) in vec![("1", 0), ("1", 1), ("2", 1), ("3", 1)] {
+            let expected = layout
+                .find(conn, "User", id, BlockHeight::from(block))
+                .unwrap();
+            let actual = target
+                .find(conn, "User", id, BlockHeight::from(block))
+                .unwrap();
+            assert_eq!(expected, actual);
+        }
+        assert_eq!(
+            layout.history(conn, "User", "1").unwrap().len(),
+            target.history(conn, "User", "1").unwrap().len()
+        );
+
+        conn.batch_execute(&format!("drop schema {} cascade", TARGET_SCHEMA))
+            .unwrap();
+        Ok(())
+    })
+}
+
+#[test]
+fn layout_for_deployment() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        const TENANTS: [&str; 2] = ["tenant_a", "tenant_b"];
+
+        let schema = Schema::parse(THINGS_GQL, THINGS_SUBGRAPH_ID.clone()).unwrap();
+        let tenants: Vec<_> = TENANTS
+            .iter()
+            .map(|tenant| {
+                conn.batch_execute(&format!(
+                    "drop schema if exists {0} cascade; create schema {0}",
+                    tenant
+                ))
+                .unwrap();
+                Layout::create_relational_schema(
+                    &conn,
+                    tenant,
+                    THINGS_SUBGRAPH_ID.clone(),
+                    &schema.document,
+                )
+                .expect("Failed to create tenant schema");
+                let subgraph = SubgraphDeploymentId::new(tenant.replace("_", "")).unwrap();
+                layout
+                    .for_deployment(subgraph, tenant)
+                    .expect("Failed to route layout to tenant")
+            })
+            .collect();
+
+        for (tenant, name) in tenants.iter().zip(&["Alice", "Bob"]) {
+            insert_user_entity(
+                conn,
+                tenant,
+                &name.to_lowercase(),
+                "User",
+                name,
+                "tenant@email.com",
+                30,
+                170.0,
+                false,
+                None,
+                None,
+            );
+        }
+
+        for (tenant, expected) in tenants.iter().zip(&["alice", "bob"]) {
+            let ids: Vec<_> = tenant
+                .query(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["User".to_owned()]),
+                    None,
+                    None,
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                )
+                .expect("Failed to query tenant")
+                .iter()
+                .map(|user| user.id().unwrap())
+                .collect();
+            assert_eq!(vec![expected.to_string()], ids);
+        }
+        // The original schema is untouched
+        assert_eq!(
+            None,
+            layout
+                .find(conn, "User", "alice", BlockHeight::MAX)
+                .unwrap()
+        );
+        assert!(layout
+            .for_deployment(THINGS_SUBGRAPH_ID.clone(), "bad;schema")
+            .is_err());
+
+        for tenant in TENANTS.iter() {
+            conn.batch_execute(&format!("drop schema {} cascade", tenant))
+                .unwrap();
+        }
+        Ok(())
+    })
+}
+
+#[test]
+fn query_union() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        const SCHEMAS: [&str; 2] = ["union_a", "union_b"];
+
+        let schema = Schema::parse(THINGS_GQL, THINGS_SUBGRAPH_ID.clone()).unwrap();
+        let layouts: Vec<_> = SCHEMAS
+            .iter()
+            .map(|name| {
+                conn.batch_execute(&format!(
+                    "drop schema if exists {0} cascade; create schema {0}",
+                    name
+                ))
+                .unwrap();
+                Layout::create_relational_schema(
+                    &conn,
+                    name,
+                    THINGS_SUBGRAPH_ID.clone(),
+                    &schema.document,
+                )
+                .expect("Failed to create schema");
+                let subgraph = SubgraphDeploymentId::new(name.replace("_", "")).unwrap();
+                layout.for_deployment(subgraph, name).unwrap()
+            })
+            .collect();
+
+        // Both subgraphs use the same ids for different users
+        let users = [
+            (&layouts[0], "1", "Alice", 30),
+            (&layouts[0], "2", "Carol", 50),
+            (&layouts[1], "1", "Bob", 40),
+            (&layouts[1], "2", "Dave", 20),
+        ];
+        for (layout, id, name, age) in &users {
+            insert_user_entity(
+                conn,
+                layout,
+                id,
+                "User",
+                name,
+                "someone@email.com",
+                *age,
+                160.0,
+                false,
+                None,
+                None,
+            );
+        }
+
+        let query = |filter: Option<EntityFilter>, order: &str, value_type, direction, range| {
+            let layouts: Vec<_> = layouts.iter().collect();
+            Layout::query_union(
+                &layouts,
+                conn,
+                "User",
+                filter,
+                Some((order.to_owned(), value_type, direction)),
+                range,
+                BlockHeight::MAX,
+            )
+            .expect("Failed to query users across subgraphs")
+            .into_iter()
+            .map(|(subgraph, user)| {
+                let name = user.get("name").unwrap().clone().as_string().unwrap();
+                (subgraph.to_string(), name)
+            })
+            .collect::<Vec<_>>()
+        };
+        let tagged = |expected: Vec<(&str, &str)>| {
+            expected
+                .into_iter()
+                .map(|(subgraph, name)| (subgraph.to_owned(), name.to_owned()))
+                .collect::<Vec<_>>()
+        };
+
+        // The order interleaves users from both subgraphs
+        assert_eq!(
+            tagged(vec![
+                ("uniona", "Alice"),
+                ("unionb", "Bob"),
+                ("uniona", "Carol"),
+                ("unionb", "Dave")
+            ]),
+            query(
+                None,
+                "name",
+                ValueType::String,
+                EntityOrder::Ascending,
+                EntityRange::first(10)
+            )
+        );
+        // The range applies to the combined users
+        assert_eq!(
+            tagged(vec![("unionb", "Bob"), ("uniona", "Carol")]),
+            query(
+                None,
+                "name",
+                ValueType::String,
+                EntityOrder::Ascending,
+                EntityRange {
+                    first: Some(2),
+                    skip: 1
+                }
+            )
+        );
+        // The filter applies to each subgraph
+        assert_eq!(
+            tagged(vec![
+                ("uniona", "Carol"),
+                ("unionb", "Bob"),
+                ("uniona", "Alice")
+            ]),
+            query(
+                Some(EntityFilter::GreaterThan("age".to_owned(), Value::Int(25))),
+                "age",
+                ValueType::Int,
+                EntityOrder::Descending,
+                EntityRange::first(10)
+            )
+        );
+
+        for name in SCHEMAS.iter() {
+            conn.batch_execute(&format!("drop schema {} cascade", name))
+                .unwrap();
+        }
+        Ok(())
+    })
+}
+
+#[test]
+fn conflicting_entity() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        let id = "fred";
+        let cat = "Cat".to_owned();
+        let dog = "Dog".to_owned();
+        let ferret = "Ferret".to_owned();
+
+        let mut fred = Entity::new();
+        fred.set("id", id);
+        fred.set("name", id);
+        insert_entity(&conn, &layout, "Cat", fred);
+
+        // If we wanted to create Fred the dog, which is forbidden, we'd run this:
+        let conflict = layout
+            .conflicting_entity(&conn, &id.to_owned(), vec![&cat, &ferret])
+            .unwrap();
+        assert_eq!(Some("Cat".to_owned()), conflict);
+
+        // If we wanted to manipulate Fred the cat, which is ok, we'd run:
+        let conflict = layout
+            .conflicting_entity(&conn, &id.to_owned(), vec![&dog, &ferret])
+            .unwrap();
+        assert_eq!(None, conflict);
+
+        // Chairs are not pets
+        let chair = "Chair".to_owned();
+        let result = layout.conflicting_entity(&conn, &id.to_owned(), vec![&dog, &ferret, &chair]);
+        assert!(result.is_err());
+        assert_eq!("unknown table 'Chair'", result.err().unwrap().to_string());
+        Ok(())
+    })
+}
+
+#[test]
+fn conflicting_entity_at() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        let id = "fred".to_owned();
+        let cat = "Cat".to_owned();
+        let ferret = "Ferret".to_owned();
+
+        // Fred the cat lives from block 0 until it is deleted at block 5
+        let mut fred = Entity::new();
+        fred.set("id", id.as_str());
+        fred.set("name", id.as_str());
+        insert_entity(&conn, &layout, "Cat", fred);
+        let key = EntityKey {
+            subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+            entity_type: cat.clone(),
+            entity_id: id.clone(),
+        };
+        layout
+            .delete(&conn, &key, BlockHeight::from(5))
+            .expect("Failed to delete Fred");
+
+        let conflict = |block: BlockNumber| {
+            layout
+                .conflicting_entity_at(&conn, &id, vec![&cat, &ferret], BlockHeight::from(block))
+                .expect("Failed to check for conflicts")
+        };
+        assert_eq!(Some("Cat".to_owned()), conflict(3));
+        assert_eq!(None, conflict(BLOCK_NUMBER_MAX));
+
+        // Without a block, all versions of Fred are considered
+        let conflict = layout
+            .conflicting_entity(&conn, &id, vec![&cat, &ferret])
+            .unwrap();
+        assert_eq!(Some("Cat".to_owned()), conflict);
+        Ok(())
+    })
+}
+
+fn insert_transaction(conn: &PgConnection, layout: &Layout, id: &str, from: &Bytes, nonce: i32) {
+    let mut tx = Entity::new();
+    tx.set("id", id);
+    tx.set("from", Value::Bytes(from.clone()));
+    tx.set("nonce", BigInt::from(nonce));
+    insert_entity(conn, layout, "Transaction", tx);
+}
+
+#[test]
+fn nonce_gaps() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        let sender = Bytes::from_str("0x0102").unwrap();
+        let other = Bytes::from_str("0x0304").unwrap();
+
+        insert_transaction(conn, layout, "tx0", &sender, 0);
+        insert_transaction(conn, layout, "tx1", &sender, 1);
+        insert_transaction(conn, layout, "tx3", &sender, 3);
+        // Transactions from other senders do not fill the gap
+        insert_transaction(conn, layout, "other2", &other, 2);
+
+        let gaps = layout
+            .nonce_gaps(conn, "Transaction", &Value::Bytes(sender), BlockHeight::MAX)
+            .expect("Failed to find nonce gaps");
+        assert_eq!(vec![BigInt::from(2)], gaps);
+
+        let gaps = layout
+            .nonce_gaps(conn, "Transaction", &Value::Bytes(other), BlockHeight::MAX)
+            .expect("Failed to find nonce gaps");
+        assert!(gaps.is_empty());
+        Ok(())
+    })
+}
+
+#[test]
+fn latest_per_group() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        let sender = Bytes::from_str("0x0102").unwrap();
+        let other = Bytes::from_str("0x0304").unwrap();
+
+        insert_transaction(conn, layout, "tx0", &sender, 0);
+        insert_transaction(conn, layout, "tx2", &sender, 2);
+        insert_transaction(conn, layout, "tx1", &sender, 1);
+        insert_transaction(conn, layout, "other5", &other, 5);
+        insert_transaction(conn, layout, "other3", &other, 3);
+
+        // A later transaction from `sender` at block 1
+        let mut tx = Entity::new();
+        tx.set("id", "tx3");
+        tx.set("from", Value::Bytes(sender.clone()));
+        tx.set("nonce", BigInt::from(3));
+        let key = EntityKey {
+            subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+            entity_type: "Transaction".to_owned(),
+            entity_id: "tx3".to_owned(),
+        };
+        layout
+            .insert(conn, &key, tx, BlockHeight::from(1))
+            .expect("Failed to insert transaction");
+
+        let latest = |block: BlockNumber| -> Vec<String> {
+            layout
+                .latest_per_group(
+                    conn,
+                    "Transaction",
+                    "from",
+                    "nonce",
+                    BlockHeight::from(block),
+                )
+                .expect("Failed to find the latest transactions")
+                .iter()
+                .map(|tx| tx.id().unwrap())
+                .collect()
         };
 
-        let entities = layout
-            .query(
-                &*LOGGER,
+        assert_eq!(vec!["tx2", "other5"], latest(0));
+        assert_eq!(vec!["tx3", "other5"], latest(BLOCK_NUMBER_MAX));
+
+        assert!(layout
+            .latest_per_group(conn, "Transaction", "sender", "nonce", BlockHeight::MAX)
+            .is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn query_stream() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        const COUNT: usize = 2100;
+        for i in 0..COUNT {
+            insert_pet(conn, layout, "Ferret", &format!("ferret{:04}", i), "Ferret");
+        }
+
+        let mut progress = Vec::new();
+        let entities = layout
+            .query_stream(
+                conn,
+                EntityCollection::All(vec!["Ferret".to_owned()]),
+                None,
+                None,
+                BlockHeight::MAX,
+                250,
+                Some(Box::new(|count: usize| progress.push(count))),
+            )
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Failed to stream ferrets");
+        assert_eq!(COUNT, entities.len());
+
+        // Eight full batches of 250 and one with the remaining 100
+        let expected: Vec<usize> = (1..=8).map(|i| i * 250).chain(vec![COUNT]).collect();
+        assert_eq!(expected, progress);
+        Ok(())
+    })
+}
+
+#[test]
+fn query_stream_prefetch() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        const COUNT: usize = 1100;
+        for i in 0..COUNT {
+            insert_pet(conn, layout, "Ferret", &format!("ferret{:04}", i), "Ferret");
+        }
+
+        let stream = || {
+            layout.query_stream(
+                conn,
+                EntityCollection::All(vec!["Ferret".to_owned()]),
+                None,
+                None,
+                BlockHeight::MAX,
+                250,
+                None,
+            )
+        };
+        fn ids(stream: EntityStream) -> Vec<String> {
+            stream
+                .map(|entity| entity.map(|entity| entity.id().unwrap()))
+                .collect::<Result<Vec<_>, _>>()
+                .expect("Failed to stream ferrets")
+        }
+
+        let prefetch_conn =
+            PgConnection::establish(postgres_test_url().as_str()).expect("Failed to connect");
+        let expected = ids(stream());
+        let actual = ids(stream().with_prefetch(Box::new(prefetch_conn)));
+        assert_eq!(COUNT, actual.len());
+        assert_eq!(expected, actual);
+        Ok(())
+    })
+}
+
+fn test_find(expected_entity_ids: Vec<&str>, query: EntityQuery) {
+    let expected_entity_ids: Vec<String> =
+        expected_entity_ids.into_iter().map(str::to_owned).collect();
+
+    run_test(move |conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+        update_user_entity(
+            conn,
+            layout,
+            "1",
+            "User",
+            "Jono",
+            "achangedemail@email.com",
+            67 as i32,
+            184.4,
+            false,
+            Some("yellow"),
+            None,
+        );
+        insert_pets(conn, layout);
+
+        let order = match query.order_by {
+            Some((attribute, value_type)) => {
+                let direction = query.order_direction.unwrap_or(EntityOrder::Ascending);
+                Some((attribute, value_type, direction))
+            }
+            None => None,
+        };
+
+        let entities = layout
+            .query(
+                &*LOGGER,
+                conn,
+                query.collection,
+                query.filter,
+                order,
+                query.range,
+                BlockHeight::MAX,
+            )
+            .expect("layout.query failed to execute query");
+
+        let entity_ids: Vec<_> = entities
+            .into_iter()
+            .map(|entity| match entity.get("id") {
+                Some(Value::String(id)) => id.to_owned(),
+                Some(_) => panic!("layout.query returned entity with non-string ID attribute"),
+                None => panic!("layout.query returned entity with no ID attribute"),
+            })
+            .collect();
+
+        assert_eq!(entity_ids, expected_entity_ids);
+
+        Ok(())
+    })
+}
+
+fn query(entity_types: Vec<&str>) -> EntityQuery {
+    EntityQuery::new(
+        THINGS_SUBGRAPH_ID.clone(),
+        BLOCK_NUMBER_MAX,
+        EntityCollection::All(entity_types.into_iter().map(|s| s.to_owned()).collect()),
+    )
+}
+
+fn user_query() -> EntityQuery {
+    query(vec!["User"])
+}
+
+#[test]
+fn find_any_field_contains() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+
+        let query = |filter: EntityFilter| {
+            layout.query(
+                &*LOGGER,
+                conn,
+                EntityCollection::All(vec!["User".to_owned()]),
+                Some(filter),
+                None,
+                EntityRange::first(100),
+                BlockHeight::MAX,
+            )
+        };
+
+        // 'john' is not in the name 'Johnton', but in the email 'tonofjohn'
+        let filter = EntityFilter::AnyFieldContains(
+            vec!["name".to_owned(), "email".to_owned()],
+            "john".into(),
+        );
+        let ids: Vec<_> = query(filter)
+            .expect("Failed to query users")
+            .iter()
+            .map(|user| user.id().unwrap())
+            .collect();
+        assert_eq!(vec!["1".to_owned()], ids);
+
+        // Only text attributes can be searched
+        let filter =
+            EntityFilter::AnyFieldContains(vec!["name".to_owned(), "age".to_owned()], "4".into());
+        assert!(query(filter).is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn query_columnar() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+
+        let result = layout
+            .query_columnar(
+                &*LOGGER,
+                conn,
+                EntityCollection::All(vec!["User".to_owned()]),
+                None,
+                Some(("age".to_owned(), ValueType::Int, EntityOrder::Ascending)),
+                EntityRange::first(100),
+                BlockHeight::MAX,
+            )
+            .expect("Failed to query users");
+
+        assert_eq!(3, result.rows);
+        assert!(result.columns.values().all(|column| column.len() == 3));
+        assert_eq!(
+            &vec![Value::from("3"), Value::from("2"), Value::from("1")],
+            result.columns.get("id").unwrap()
+        );
+        assert_eq!(
+            &vec![Value::Int(28), Value::Int(43), Value::Int(67)],
+            result.columns.get("age").unwrap()
+        );
+        // User 3 has no favorite color
+        assert_eq!(
+            &vec![Value::Null, Value::from("red"), Value::from("yellow")],
+            result.columns.get("favorite_color").unwrap()
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn query_cache() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+
+        let query_range = |filter: EntityFilter, range: EntityRange| {
+            layout
+                .query(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["User".to_owned()]),
+                    Some(filter),
+                    None,
+                    range,
+                    BlockHeight::MAX,
+                )
+                .expect("Failed to query users")
+                .iter()
+                .map(|user| user.id().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let query = |filter: EntityFilter| query_range(filter, EntityRange::first(100));
+
+        assert_eq!(0, layout.query_cache_hits());
+        let ids = query(EntityFilter::Equal("name".into(), "Johnton".into()));
+        assert_eq!(vec!["1".to_owned()], ids);
+        assert_eq!(0, layout.query_cache_hits());
+
+        // Same shape, different value
+        let ids = query(EntityFilter::Equal("name".into(), "Cindini".into()));
+        assert_eq!(vec!["2".to_owned()], ids);
+        assert_eq!(1, layout.query_cache_hits());
+
+        // Comparing with null or with a long string changes the SQL text
+        let ids = query(EntityFilter::Equal("favorite_color".into(), Value::Null));
+        assert_eq!(vec!["3".to_owned()], ids);
+        let long_name = "x".repeat(STRING_PREFIX_SIZE + 1);
+        let ids = query(EntityFilter::Equal("name".into(), long_name.into()));
+        assert!(ids.is_empty());
+        assert_eq!(1, layout.query_cache_hits());
+
+        // The limit and offset are bind parameters; only whether a query
+        // has them changes the SQL text
+        let ids_filter = || EntityFilter::new_in("id", vec!["1", "2", "3"]);
+        let page = |skip: u32| EntityRange {
+            first: Some(1),
+            skip,
+        };
+        assert_eq!(vec!["1".to_owned()], query_range(ids_filter(), page(0)));
+        assert_eq!(1, layout.query_cache_hits());
+        assert_eq!(vec!["2".to_owned()], query_range(ids_filter(), page(1)));
+        assert_eq!(1, layout.query_cache_hits());
+        assert_eq!(vec!["3".to_owned()], query_range(ids_filter(), page(2)));
+        assert_eq!(2, layout.query_cache_hits());
+        let ids = query_range(ids_filter(), EntityRange::first(2));
+        assert_eq!(vec!["1".to_owned(), "2".to_owned()], ids);
+        assert_eq!(3, layout.query_cache_hits());
+        Ok(())
+    })
+}
+
+#[test]
+fn query_tiebreak_seed() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        // Users 1 and 3 do not drink coffee, and neither do users 4 to 6
+        insert_users(conn, layout);
+        for id in &["4", "5", "6"] {
+            insert_user_entity(
+                conn,
+                layout,
+                id,
+                "User",
+                "Tobin",
+                "tobin@email.com",
+                37 as i32,
+                171.2,
+                false,
+                None,
+                None,
+            );
+        }
+
+        let query = |collection: Vec<&str>, seed: u32| {
+            let options = QueryOptions {
+                tiebreak_seed: Some(seed),
+                ..QueryOptions::default()
+            };
+            layout
+                .query_with_options(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(collection.into_iter().map(str::to_owned).collect()),
+                    None,
+                    Some((
+                        "coffee".to_owned(),
+                        ValueType::Boolean,
+                        EntityOrder::Ascending,
+                    )),
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                    options,
+                )
+                .map(|users| {
+                    users
+                        .iter()
+                        .map(|user| user.id().unwrap())
+                        .collect::<Vec<_>>()
+                })
+        };
+
+        let first = query(vec!["User"], 1).expect("Failed to query users");
+        let second = query(vec!["User"], 1).expect("Failed to query users");
+        let other = query(vec!["User"], 2).expect("Failed to query users");
+
+        // The seed only shuffles users with the same value for `coffee`
+        assert_eq!(6, first.len());
+        assert_eq!(Some(&"2".to_owned()), first.last());
+        assert_eq!(Some(&"2".to_owned()), other.last());
+
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+
+        // The tiebreak can not be used across entity types
+        assert!(query(vec!["Cat", "Dog"], 1).is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn find_list_empty() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        // User 1 has no drinks, users 2 and 3 have some, and user 4 has
+        // an empty list of drinks
+        insert_users(conn, layout);
+        insert_user_entity(
+            conn,
+            layout,
+            "4",
+            "User",
+            "Tobin",
+            "tobin@email.com",
+            37 as i32,
+            171.2,
+            true,
+            None,
+            Some(vec![]),
+        );
+
+        let query = |filter: EntityFilter| -> Result<Vec<String>, QueryExecutionError> {
+            Ok(layout
+                .query(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["User".to_owned()]),
+                    Some(filter),
+                    Some(("id".to_owned(), ValueType::String, EntityOrder::Ascending)),
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                )?
+                .iter()
+                .map(|user| user.id().unwrap())
+                .collect())
+        };
+
+        assert_eq!(
+            vec!["4".to_owned()],
+            query(EntityFilter::ListEmpty("drinks".into())).unwrap()
+        );
+        assert_eq!(
+            vec!["2".to_owned(), "3".to_owned()],
+            query(EntityFilter::ListNotEmpty("drinks".into())).unwrap()
+        );
+        assert_eq!(
+            vec!["1".to_owned()],
+            query(EntityFilter::Equal("drinks".into(), Value::Null)).unwrap()
+        );
+
+        // Only list attributes can be used
+        assert!(query(EntityFilter::ListEmpty("name".into())).is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn query_scored() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+
+        let query = |min_score: Option<i32>| -> Vec<(String, Value)> {
+            layout
+                .query_scored(
+                    conn,
+                    "User",
+                    None,
+                    vec![
+                        (EntityFilter::Contains("name".to_owned(), "o".into()), 2),
+                        (EntityFilter::Equal("coffee".to_owned(), true.into()), 3),
+                    ],
+                    min_score,
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                )
+                .expect("Failed to score users")
+                .into_iter()
+                .map(|user| (user.id().unwrap(), user.get("__score").unwrap().clone()))
+                .collect()
+        };
+
+        // Cindini drinks coffee, Johnton has an `o` in their name, and
+        // Shaqueeena has neither
+        assert_eq!(
+            vec![
+                ("2".to_owned(), Value::Int(3)),
+                ("1".to_owned(), Value::Int(2)),
+                ("3".to_owned(), Value::Int(0)),
+            ],
+            query(None)
+        );
+        assert_eq!(
+            vec![
+                ("2".to_owned(), Value::Int(3)),
+                ("1".to_owned(), Value::Int(2)),
+            ],
+            query(Some(1))
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn query_by_recency() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        let key = |id: &str| EntityKey {
+            subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+            entity_type: "Ferret".to_owned(),
+            entity_id: id.to_owned(),
+        };
+        let ferret = |id: &str, name: &str| {
+            let mut ferret = Entity::new();
+            ferret.set("id", id);
+            ferret.set("name", name);
+            ferret
+        };
+        for (id, block) in &[("a", 0), ("b", 3), ("c", 6), ("d", 1)] {
+            layout
+                .insert(
+                    conn,
+                    &key(id),
+                    ferret(id, "Ferret"),
+                    BlockHeight::from(*block),
+                )
+                .expect("Failed to insert ferret");
+        }
+        // Updating an entity does not make it more recent
+        layout
+            .update(
+                conn,
+                &key("a"),
+                ferret("a", "Renamed"),
+                BlockHeight::from(7),
+            )
+            .expect("Failed to update ferret");
+
+        let query = |direction: EntityOrder| -> Vec<(String, f64)> {
+            layout
+                .query_by_recency(
+                    conn,
+                    "Ferret",
+                    None,
+                    4,
+                    direction,
+                    EntityRange::first(100),
+                    BlockHeight::from(10),
+                )
+                .expect("Failed to query ferrets by recency")
+                .into_iter()
+                .map(|ferret| {
+                    let recency = match ferret.get("__recency") {
+                        Some(Value::BigDecimal(recency)) => recency.to_string().parse().unwrap(),
+                        other => panic!("unexpected recency {:?}", other),
+                    };
+                    (ferret.id().unwrap(), recency)
+                })
+                .collect()
+        };
+
+        let newest_first = query(EntityOrder::Descending);
+        let ids: Vec<_> = newest_first.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(vec!["c", "b", "d", "a"], ids);
+        for ((id, recency), block) in newest_first.iter().zip(&[6, 3, 1, 0]) {
+            let expected = (-(10 - block) as f64 / 4.0).exp();
+            assert!(
+                (recency - expected).abs() < 1e-9,
+                "recency of {} is {} but should be {}",
+                id,
+                recency,
+                expected
+            );
+        }
+
+        let oldest_first = query(EntityOrder::Ascending);
+        let ids: Vec<_> = oldest_first.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(vec!["a", "d", "b", "c"], ids);
+
+        // Entities created after the query's block are not returned
+        let ids: Vec<_> = layout
+            .query_by_recency(
+                conn,
+                "Ferret",
+                None,
+                4,
+                EntityOrder::Descending,
+                EntityRange::first(100),
+                BlockHeight::from(2),
+            )
+            .expect("Failed to query ferrets by recency")
+            .into_iter()
+            .map(|ferret| ferret.id().unwrap())
+            .collect();
+        assert_eq!(vec!["d", "a"], ids);
+
+        let err = layout
+            .query_by_recency(
+                conn,
+                "Ferret",
+                None,
+                0,
+                EntityOrder::Descending,
+                EntityRange::first(100),
+                BlockHeight::from(10),
+            )
+            .expect_err("a halflife of 0 is not allowed");
+        assert!(err.to_string().contains("halflife"));
+        Ok(())
+    })
+}
+
+#[test]
+fn find_one() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+
+        let heaviest = layout
+            .find_one(
+                conn,
+                EntityCollection::All(vec!["User".to_owned()]),
+                None,
+                Some((
+                    "weight".to_owned(),
+                    ValueType::BigDecimal,
+                    EntityOrder::Descending,
+                )),
+                BlockHeight::MAX,
+            )
+            .expect("Failed to find the heaviest user")
+            .expect("There is a heaviest user");
+        assert_eq!("1", heaviest.id().unwrap());
+
+        let nobody = layout
+            .find_one(
+                conn,
+                EntityCollection::All(vec!["User".to_owned()]),
+                Some(EntityFilter::Equal("name".to_owned(), "Nobody".into())),
+                None,
+                BlockHeight::MAX,
+            )
+            .expect("Failed to look for a user");
+        assert_eq!(None, nobody);
+        Ok(())
+    })
+}
+
+#[test]
+fn find_is_whole() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        // The weights of these users all have a fractional part
+        insert_users(conn, layout);
+        for (id, name, weight) in &[("4", "Wholey", 80.0), ("5", "Roundy", 100.0)] {
+            insert_user_entity(
+                conn,
+                layout,
+                id,
+                "User",
+                name,
+                "whole@email.com",
+                30 as i32,
+                *weight,
+                false,
+                None,
+                None,
+            );
+        }
+
+        let query = |filter: EntityFilter| -> Result<Vec<String>, QueryExecutionError> {
+            Ok(layout
+                .query(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["User".to_owned()]),
+                    Some(filter),
+                    Some(("id".to_owned(), ValueType::String, EntityOrder::Ascending)),
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                )?
+                .iter()
+                .map(|user| user.id().unwrap())
+                .collect())
+        };
+
+        assert_eq!(
+            vec!["4", "5"],
+            query(EntityFilter::IsWhole("weight".into())).unwrap()
+        );
+
+        // Only BigDecimal attributes can be used
+        assert!(query(EntityFilter::IsWhole("age".into())).is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn find_in_subnet() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        for (id, address) in &[
+            ("p1", "10.1.2.3"),
+            ("p2", "192.168.0.1"),
+            ("p3", "10.255.0.1"),
+            ("p4", "2001:db8::1"),
+        ] {
+            let mut peer = Entity::new();
+            peer.set("id", *id);
+            peer.set("address", *address);
+            insert_entity(conn, layout, "Peer", peer);
+        }
+
+        let query = |filter: EntityFilter| -> Result<Vec<String>, QueryExecutionError> {
+            Ok(layout
+                .query(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["Peer".to_owned()]),
+                    Some(filter),
+                    Some(("id".to_owned(), ValueType::String, EntityOrder::Ascending)),
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                )?
+                .iter()
+                .map(|peer| peer.id().unwrap())
+                .collect())
+        };
+
+        assert_eq!(
+            vec!["p1", "p3"],
+            query(EntityFilter::InSubnet(
+                "address".into(),
+                "10.0.0.0/8".into()
+            ))
+            .unwrap()
+        );
+        assert_eq!(
+            vec!["p4"],
+            query(EntityFilter::InSubnet(
+                "address".into(),
+                "2001:db8::/32".into()
+            ))
+            .unwrap()
+        );
+        // Addresses are returned as they were stored
+        assert_eq!(
+            Some(&Value::from("10.1.2.3")),
+            layout
+                .find(conn, "Peer", "p1", BlockHeight::MAX)
+                .unwrap()
+                .unwrap()
+                .get("address")
+        );
+
+        // Only @inet attributes can be used
+        assert!(query(EntityFilter::InSubnet("id".into(), "10.0.0.0/8".into())).is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn encrypted_attribute() {
+    use diesel::QueryableByName;
+
+    #[derive(QueryableByName)]
+    struct Raw {
+        #[sql_type = "diesel::sql_types::Binary"]
+        email: Vec<u8>,
+    }
+
+    run_test(|conn, layout| -> Result<(), ()> {
+        const EMAIL: &str = "patient@example.com";
+
+        let mut patient = Entity::new();
+        patient.set("id", "p1");
+        patient.set("email", EMAIL);
+
+        // Without a key, encrypted attributes can not be written
+        let key = EntityKey {
+            subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+            entity_type: "Patient".to_owned(),
+            entity_id: "p1".to_owned(),
+        };
+        assert!(layout
+            .insert(conn, &key, patient.clone(), BlockHeight::from(0))
+            .is_err());
+
+        let mut layout = layout.clone();
+        layout.encryption_key = Some(EncryptionKey::new(&[7u8; 64]).unwrap());
+        let layout = &layout;
+        insert_entity(conn, layout, "Patient", patient);
+
+        // The database only has the ciphertext
+        let raw = diesel::sql_query(format!(
+            "select email from {}.patient where id = 'p1'",
+            layout.schema
+        ))
+        .get_result::<Raw>(conn)
+        .expect("Failed to read the raw email");
+        assert!(!raw
+            .email
+            .windows(EMAIL.len())
+            .any(|window| window == EMAIL.as_bytes()));
+
+        // Reading the entity returns the plaintext
+        let patient = layout
+            .find(conn, "Patient", "p1", BlockHeight::MAX)
+            .expect("Failed to read Patient[p1]")
+            .unwrap();
+        assert_eq!(Some(&Value::from(EMAIL)), patient.get("email"));
+
+        // Equality filters work since the encryption is deterministic
+        let query = |filter: EntityFilter| -> Result<Vec<String>, QueryExecutionError> {
+            Ok(layout
+                .query(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["Patient".to_owned()]),
+                    Some(filter),
+                    None,
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                )?
+                .iter()
+                .map(|patient| patient.id().unwrap())
+                .collect())
+        };
+        assert_eq!(
+            vec!["p1"],
+            query(EntityFilter::Equal("email".into(), EMAIL.into())).unwrap()
+        );
+        assert!(query(EntityFilter::Equal(
+            "email".into(),
+            "other@example.com".into()
+        ))
+        .unwrap()
+        .is_empty());
+        // Other comparisons can not be done on the ciphertext
+        assert!(query(EntityFilter::StartsWith("email".into(), "patient".into())).is_err());
+
+        // Groups are reported with their plaintext, and distinct values
+        // are counted correctly
+        let mut other = Entity::new();
+        other.set("id", "p2");
+        other.set("email", EMAIL);
+        insert_entity(conn, layout, "Patient", other);
+        assert_eq!(
+            vec![(Value::from(EMAIL), 2)],
+            layout
+                .group_count(conn, "Patient", "email", None, BlockHeight::MAX)
+                .expect("Failed to group patients by email")
+        );
+        assert_eq!(
+            1,
+            layout
+                .count_distinct(conn, "Patient", "email", None, BlockHeight::MAX)
+                .expect("Failed to count distinct emails")
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn find_list_length() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        // User 1 has no drinks, users 2 and 3 have two, and user 4 has
+        // an empty list of drinks
+        insert_users(conn, layout);
+        insert_user_entity(
+            conn,
+            layout,
+            "4",
+            "User",
+            "Tobin",
+            "tobin@email.com",
+            37 as i32,
+            171.2,
+            true,
+            None,
+            Some(vec![]),
+        );
+
+        let query = |filter: EntityFilter| -> Result<Vec<String>, QueryExecutionError> {
+            Ok(layout
+                .query(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["User".to_owned()]),
+                    Some(filter),
+                    Some(("id".to_owned(), ValueType::String, EntityOrder::Ascending)),
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                )?
+                .iter()
+                .map(|user| user.id().unwrap())
+                .collect())
+        };
+
+        assert_eq!(
+            vec!["2".to_owned(), "3".to_owned()],
+            query(EntityFilter::LengthEqual("drinks".into(), 2)).unwrap()
+        );
+        // A null list has no length, just like it is not empty
+        assert_eq!(
+            vec!["4".to_owned()],
+            query(EntityFilter::LengthEqual("drinks".into(), 0)).unwrap()
+        );
+        assert_eq!(
+            vec!["2".to_owned(), "3".to_owned()],
+            query(EntityFilter::LengthGreaterThan("drinks".into(), 1)).unwrap()
+        );
+        assert_eq!(
+            vec!["4".to_owned()],
+            query(EntityFilter::LengthLessThan("drinks".into(), 2)).unwrap()
+        );
+        assert_eq!(
+            vec!["4".to_owned()],
+            query(EntityFilter::ListEmpty("drinks".into())).unwrap()
+        );
+
+        // Only list attributes can be used
+        assert!(query(EntityFilter::LengthEqual("name".into(), 2)).is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn find_reference_exists() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        let sender = Bytes::from_str("0x0102").unwrap();
+
+        let mut block = Entity::new();
+        block.set("id", "b1");
+        block.set("number", 1);
+        insert_entity(conn, layout, "Block", block);
+        // `tx2` refers to a block that does not exist
+        for (id, block) in &[("tx1", "b1"), ("tx2", "b2")] {
+            let mut tx = Entity::new();
+            tx.set("id", *id);
+            tx.set("from", Value::Bytes(sender.clone()));
+            tx.set("nonce", BigInt::from(0));
+            tx.set("block", *block);
+            insert_entity(conn, layout, "Transaction", tx);
+        }
+
+        let query = |attribute: &str| {
+            layout
+                .query(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["Transaction".to_owned()]),
+                    Some(EntityFilter::ReferenceExists(attribute.to_owned())),
+                    None,
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                )
+                .map(|entities| {
+                    entities
+                        .iter()
+                        .map(|entity| entity.id().unwrap())
+                        .collect::<Vec<_>>()
+                })
+        };
+
+        assert_eq!(vec!["tx1"], query("block").unwrap());
+
+        // Only references can be used
+        assert!(query("nonce").is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn find_child_not_exists() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        let sender = Bytes::from_str("0x0102").unwrap();
+        let other = Bytes::from_str("0x0304").unwrap();
+
+        for (id, number) in &[("b1", 1), ("b2", 2), ("b3", 3)] {
+            let mut block = Entity::new();
+            block.set("id", *id);
+            block.set("number", *number);
+            insert_entity(conn, layout, "Block", block);
+        }
+        for (id, from, block) in &[("tx1", &sender, "b1"), ("tx2", &other, "b2")] {
+            let mut tx = Entity::new();
+            tx.set("id", *id);
+            tx.set("from", Value::Bytes((*from).clone()));
+            tx.set("nonce", BigInt::from(0));
+            tx.set("block", *block);
+            insert_entity(conn, layout, "Transaction", tx);
+        }
+
+        let query = |entity_type: &str, filter: EntityFilter| -> Vec<String> {
+            layout
+                .query(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec![entity_type.to_owned()]),
+                    Some(filter),
+                    None,
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                )
+                .expect("Failed to query")
+                .iter()
+                .map(|entity| entity.id().unwrap())
+                .collect()
+        };
+
+        // Blocks without a transaction from `sender`, following the
+        // derived field `transactions`
+        let filter = EntityFilter::ChildNotExists(
+            "transactions".to_owned(),
+            Box::new(EntityFilter::Equal(
+                "from".to_owned(),
+                Value::Bytes(sender.clone()),
+            )),
+        );
+        assert_eq!(vec!["b2", "b3"], query("Block", filter));
+
+        // Transactions whose block does not have number 1, following the
+        // stored reference `block`
+        let filter = EntityFilter::ChildNotExists(
+            "block".to_owned(),
+            Box::new(EntityFilter::Equal("number".to_owned(), 1.into())),
+        );
+        assert_eq!(vec!["tx2"], query("Transaction", filter));
+
+        // The child filter is checked against the child's attributes
+        let filter = EntityFilter::ChildNotExists(
+            "transactions".to_owned(),
+            Box::new(EntityFilter::Equal("number".to_owned(), 1.into())),
+        );
+        assert!(layout
+            .query(
+                &*LOGGER,
+                conn,
+                EntityCollection::All(vec!["Block".to_owned()]),
+                Some(filter),
+                None,
+                EntityRange::first(100),
+                BlockHeight::MAX,
+            )
+            .is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn block_height() {
+    // Turning an integer into a block height, and back, has to be spelled
+    // out; passing a bare integer where `Layout` expects a block height
+    // does not compile
+    let height = BlockHeight::from(17);
+    assert_eq!(17, height.number());
+    assert_eq!(17, BlockNumber::from(height));
+    assert!(height < BlockHeight::MAX);
+    assert_eq!(BLOCK_NUMBER_MAX, BlockHeight::MAX.number());
+}
+
+#[test]
+fn large_int_round_trip() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_entity(&conn, &layout, "Scalar", SCALAR_ENTITY.clone());
+
+        // LARGE_INT does not fit into an i64 and must not be truncated on
+        // the way into or out of the database
+        assert_eq!(None, LARGE_INT.to_i64_checked());
+        let entity = layout
+            .find(conn, "Scalar", "one", BlockHeight::MAX)
+            .expect("Failed to read Scalar[one]")
+            .unwrap();
+        assert_eq!(
+            Some(&Value::BigInt(LARGE_INT.clone())),
+            entity.get("bigInt")
+        );
+
+        // Filtering on the exact value finds the entity
+        let entities = layout
+            .query(
+                &*LOGGER,
+                conn,
+                EntityCollection::All(vec!["Scalar".to_owned()]),
+                Some(EntityFilter::Equal(
+                    "bigInt".to_owned(),
+                    Value::BigInt(LARGE_INT.clone()),
+                )),
+                None,
+                EntityRange::first(100),
+                BlockHeight::MAX,
+            )
+            .expect("Failed to query Scalar");
+        assert_eq!(1, entities.len());
+
+        // A value that differs only in the last digit does not
+        let entities = layout
+            .query(
+                &*LOGGER,
+                conn,
+                EntityCollection::All(vec!["Scalar".to_owned()]),
+                Some(EntityFilter::Equal(
+                    "bigInt".to_owned(),
+                    Value::BigInt(LARGE_INT.clone() + BigInt::from(1)),
+                )),
+                None,
+                EntityRange::first(100),
+                BlockHeight::MAX,
+            )
+            .expect("Failed to query Scalar");
+        assert!(entities.is_empty());
+        Ok(())
+    });
+}
+
+#[test]
+fn find_interface_interleaved() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        for (id, name) in &[("c1", "Alpha"), ("c2", "Bravo"), ("c3", "Charlie")] {
+            insert_pet(conn, layout, "Cat", id, name);
+        }
+        for (id, name) in &[("d1", "Delta"), ("d2", "Echo")] {
+            insert_pet(conn, layout, "Dog", id, name);
+        }
+
+        let query = |range: EntityRange, interleave_types: bool| -> Vec<String> {
+            let options = QueryOptions {
+                interleave_types,
+                ..QueryOptions::default()
+            };
+            layout
+                .query_with_options(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["Cat".to_owned(), "Dog".to_owned()]),
+                    None,
+                    Some(("name".to_owned(), ValueType::String, EntityOrder::Ascending)),
+                    range,
+                    BlockHeight::MAX,
+                    options,
+                )
+                .expect("Failed to query pets")
+                .iter()
+                .map(|entity| entity.id().unwrap())
+                .collect()
+        };
+
+        assert_eq!(
+            vec!["c1", "c2", "c3", "d1", "d2"],
+            query(EntityRange::first(100), false)
+        );
+        // Types alternate until the dogs run out
+        assert_eq!(
+            vec!["c1", "d1", "c2", "d2", "c3"],
+            query(EntityRange::first(100), true)
+        );
+        // The range applies to the interleaved entities
+        let range = EntityRange {
+            first: Some(2),
+            skip: 2,
+        };
+        assert_eq!(vec!["c2", "d2"], query(range, true));
+        Ok(())
+    })
+}
+
+#[test]
+fn find_interface_order_by_enum() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        for (entity_type, id, color) in &[
+            ("Cat", "c1", Some("BLUE")),
+            ("Cat", "c2", None),
+            ("Dog", "d1", Some("yellow")),
+            ("Dog", "d2", Some("red")),
+            ("Ferret", "f1", Some("red")),
+        ] {
+            let mut pet = Entity::new();
+            pet.set("id", *id);
+            pet.set("name", *id);
+            if let Some(color) = color {
+                pet.set("color", *color);
+            }
+            insert_entity(conn, layout, entity_type, pet);
+        }
+
+        let query = |direction: EntityOrder, interleave_types: bool| -> Vec<String> {
+            let options = QueryOptions {
+                interleave_types,
+                ..QueryOptions::default()
+            };
+            layout
+                .query_with_options(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec![
+                        "Cat".to_owned(),
+                        "Dog".to_owned(),
+                        "Ferret".to_owned(),
+                    ]),
+                    None,
+                    Some(("color".to_owned(), ValueType::String, direction)),
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                    options,
+                )
+                .expect("Failed to query pets by color")
+                .iter()
+                .map(|entity| entity.id().unwrap())
+                .collect()
+        };
+
+        // Colors sort in declaration order (yellow, red, BLUE) across all
+        // types, and pets without a color come last
+        assert_eq!(
+            vec!["d1", "d2", "f1", "c1", "c2"],
+            query(EntityOrder::Ascending, false)
+        );
+        assert_eq!(
+            vec!["c1", "f1", "d2", "d1", "c2"],
+            query(EntityOrder::Descending, false)
+        );
+        assert_eq!(
+            vec!["c1", "d1", "f1", "c2", "d2"],
+            query(EntityOrder::Ascending, true)
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn find_interface_with_type_filters() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        for (id, name) in &[("c1", "Alpha"), ("c2", "Bravo"), ("c3", "Charlie")] {
+            insert_pet(conn, layout, "Cat", id, name);
+        }
+        for (id, name) in &[("d1", "Delta"), ("d2", "Echo")] {
+            insert_pet(conn, layout, "Dog", id, name);
+        }
+
+        let query = |type_filters: Vec<(&str, Option<EntityFilter>)>| {
+            let type_filters = type_filters
+                .into_iter()
+                .map(|(entity_type, filter)| (entity_type.to_owned(), filter))
+                .collect();
+            layout
+                .query_with_type_filters(
+                    conn,
+                    type_filters,
+                    Some(("name".to_owned(), ValueType::String, EntityOrder::Ascending)),
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                )
+                .map(|entities| {
+                    entities
+                        .iter()
+                        .map(|entity| entity.id().unwrap())
+                        .collect::<Vec<_>>()
+                })
+        };
+
+        // Cats named Bravo or dogs whose name starts with an 'E'
+        let ids = query(vec![
+            (
+                "Cat",
+                Some(EntityFilter::Equal("name".to_owned(), "Bravo".into())),
+            ),
+            (
+                "Dog",
+                Some(EntityFilter::StartsWith("name".to_owned(), "E".into())),
+            ),
+        ])
+        .expect("Failed to query pets");
+        assert_eq!(vec!["c2", "d2"], ids);
+
+        // A type without a filter contributes all its entities
+        let ids = query(vec![
+            (
+                "Cat",
+                Some(EntityFilter::Equal("name".to_owned(), "Charlie".into())),
+            ),
+            ("Dog", None),
+        ])
+        .expect("Failed to query pets");
+        assert_eq!(vec!["c3", "d1", "d2"], ids);
+
+        // Each type can only have one filter
+        let res = query(vec![
+            (
+                "Cat",
+                Some(EntityFilter::Equal("name".to_owned(), "Alpha".into())),
+            ),
+            (
+                "Cat",
+                Some(EntityFilter::Equal("name".to_owned(), "Bravo".into())),
+            ),
+        ]);
+        assert!(res.is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn find_interface_distinct_on_id() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_pet(conn, layout, "Cat", "felix", "Felix");
+        insert_pet(conn, layout, "Dog", "felix", "Felix");
+        insert_pet(conn, layout, "Cat", "garfield", "Garfield");
+
+        let query = |first: u32, distinct_on_id: bool| -> Vec<String> {
+            let collection = EntityCollection::All(vec!["Cat".to_owned(), "Dog".to_owned()]);
+            let range = EntityRange::first(first);
+            let entities = if distinct_on_id {
+                layout.query_distinct_on_id(
+                    &*LOGGER,
+                    conn,
+                    collection,
+                    None,
+                    None,
+                    range,
+                    BlockHeight::MAX,
+                )
+            } else {
+                layout.query(
+                    &*LOGGER,
+                    conn,
+                    collection,
+                    None,
+                    None,
+                    range,
+                    BlockHeight::MAX,
+                )
+            };
+            entities
+                .expect("Failed to query pets")
+                .iter()
+                .map(|entity| entity.id().unwrap())
+                .collect()
+        };
+
+        assert_eq!(vec!["felix"], query(1, true));
+        assert_eq!(vec!["felix", "garfield"], query(2, true));
+        // Without deduplication, the limit applies to the union of all rows
+        assert_eq!(vec!["felix", "felix"], query(2, false));
+        Ok(())
+    })
+}
+
+#[test]
+fn list_index_is_used() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+
+        // The test data is tiny; make sure Postgres does not prefer a
+        // sequential scan because of that
+        conn.batch_execute("set enable_seqscan = off")
+            .expect("Failed to change planner settings");
+
+        // List attributes are indexed with GIN, which supports `@>`
+        let filter = EntityFilter::Contains("drinks".to_owned(), vec!["beer"].into());
+        let plan = layout
+            .explain_query(
+                conn,
+                EntityCollection::All(vec!["User".to_owned()]),
+                Some(filter.clone()),
+                None,
+                EntityRange::first(100),
+                BlockHeight::MAX,
+            )
+            .expect("Failed to explain query")
+            .join("\n");
+        assert!(
+            plan.contains("Bitmap Index Scan") && plan.contains("_user_drinks"),
+            "the index on `drinks` is not used:\n{}",
+            plan
+        );
+        assert!(
+            !plan.contains("Seq Scan"),
+            "the query scans `user` sequentially:\n{}",
+            plan
+        );
+
+        let ids: Vec<_> = layout
+            .query(
+                &*LOGGER,
+                conn,
+                EntityCollection::All(vec!["User".to_owned()]),
+                Some(filter),
+                None,
+                EntityRange::first(100),
+                BlockHeight::MAX,
+            )
+            .expect("Failed to query users")
+            .iter()
+            .map(|user| user.id().unwrap())
+            .collect();
+        assert_eq!(vec!["2"], ids);
+        Ok(())
+    })
+}
+
+#[test]
+fn order_index_is_used() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+
+        // The test data is tiny; make sure Postgres does not prefer a
+        // sequential scan or an explicit sort because of that
+        conn.batch_execute("set enable_seqscan = off; set enable_sort = off")
+            .expect("Failed to change planner settings");
+
+        for direction in vec![EntityOrder::Ascending, EntityOrder::Descending] {
+            let plan = layout
+                .explain_query(
+                    conn,
+                    EntityCollection::All(vec!["User".to_owned()]),
+                    None,
+                    Some(("name".to_owned(), ValueType::String, direction)),
+                    EntityRange {
+                        first: Some(2),
+                        skip: 1,
+                    },
+                    BlockHeight::MAX,
+                )
+                .expect("Failed to explain query")
+                .join("\n");
+            assert!(
+                plan.contains("Index Scan") && plan.contains("_user on "),
+                "the order index is not used for {:?}:\n{}",
+                direction,
+                plan
+            );
+            assert!(
+                !plan.contains("Sort"),
+                "the query for {:?} sorts explicitly:\n{}",
+                direction,
+                plan
+            );
+        }
+
+        // The results are still in the right order
+        let names: Vec<_> = layout
+            .query(
+                &*LOGGER,
+                conn,
+                EntityCollection::All(vec!["User".to_owned()]),
+                None,
+                Some((
+                    "name".to_owned(),
+                    ValueType::String,
+                    EntityOrder::Descending,
+                )),
+                EntityRange::first(100),
+                BlockHeight::MAX,
+            )
+            .expect("Failed to query users")
+            .iter()
+            .map(|user| user.get("name").unwrap().to_string())
+            .collect();
+        let mut sorted = names.clone();
+        sorted.sort();
+        sorted.reverse();
+        assert_eq!(sorted, names);
+        Ok(())
+    })
+}
+
+#[test]
+fn dump() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        // Insert users out of order so that the order in which they are
+        // stored differs from the order of their ids
+        for (id, name) in &[("3", "Shaqueeena"), ("1", "Johnton"), ("2", "Cindini")] {
+            insert_user_entity(
+                conn,
+                layout,
+                id,
+                "User",
+                name,
+                "someone@email.com",
+                30,
+                160.0,
+                false,
+                None,
+                None,
+            );
+        }
+
+        let ids: Vec<_> = layout
+            .dump(conn, "User", BlockHeight::MAX)
+            .expect("Failed to dump users")
+            .iter()
+            .map(|user| user.id().unwrap())
+            .collect();
+        assert_eq!(vec!["1", "2", "3"], ids);
+
+        // Users that were deleted are not part of the dump
+        let key = EntityKey {
+            subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+            entity_type: "User".to_owned(),
+            entity_id: "2".to_owned(),
+        };
+        layout
+            .delete(conn, &key, BlockHeight::from(1))
+            .expect("Failed to delete user");
+        let ids: Vec<_> = layout
+            .dump(conn, "User", BlockHeight::MAX)
+            .expect("Failed to dump users")
+            .iter()
+            .map(|user| user.id().unwrap())
+            .collect();
+        assert_eq!(vec!["1", "3"], ids);
+        Ok(())
+    })
+}
+
+#[test]
+fn changed_since() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        for (id, block) in &[("one", 1), ("two", 2), ("three", 3)] {
+            let mut scalar = SCALAR_ENTITY.clone();
+            scalar.set("id", *id);
+            let key = EntityKey {
+                subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+                entity_type: "Scalar".to_owned(),
+                entity_id: id.to_string(),
+            };
+            layout
+                .insert(conn, &key, scalar, BlockHeight::from(*block))
+                .expect("Failed to insert Scalar");
+        }
+
+        let changed = |since: BlockNumber, block: BlockNumber| -> Vec<String> {
+            layout
+                .changed_since(
+                    conn,
+                    "Scalar",
+                    BlockHeight::from(since),
+                    BlockHeight::from(block),
+                )
+                .expect("Failed to find changed scalars")
+                .iter()
+                .map(|scalar| scalar.id().unwrap())
+                .collect()
+        };
+        assert_eq!(vec!["three", "two"], changed(1, BLOCK_NUMBER_MAX));
+        assert_eq!(vec!["two"], changed(1, 2));
+        assert!(changed(3, BLOCK_NUMBER_MAX).is_empty());
+        Ok(())
+    })
+}
+
+#[test]
+fn find_dangling_references() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        let sender = Bytes::from_str("0x0102").unwrap();
+
+        let mut block = Entity::new();
+        block.set("id", "b1");
+        block.set("number", 1);
+        insert_entity(conn, layout, "Block", block);
+        for (id, block) in &[("tx1", Some("b1")), ("tx2", Some("b2")), ("tx3", None)] {
+            let mut tx = Entity::new();
+            tx.set("id", *id);
+            tx.set("from", Value::Bytes(sender.clone()));
+            tx.set("nonce", BigInt::from(0));
+            tx.set("block", block.map(str::to_owned));
+            insert_entity(conn, layout, "Transaction", tx);
+        }
+
+        let dangling = |block: BlockNumber| {
+            layout
+                .find_dangling_references(conn, "Transaction", "block", BlockHeight::from(block))
+                .expect("Failed to find dangling references")
+        };
+
+        assert_eq!(vec!["tx2"], dangling(BLOCK_NUMBER_MAX));
+
+        // Once its block is deleted, `tx1` is dangling, too
+        let key = EntityKey {
+            subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+            entity_type: "Block".to_owned(),
+            entity_id: "b1".to_owned(),
+        };
+        layout
+            .delete(conn, &key, BlockHeight::from(1))
+            .expect("Failed to delete block");
+        assert_eq!(vec!["tx1", "tx2"], dangling(BLOCK_NUMBER_MAX));
+        assert_eq!(vec!["tx2"], dangling(0));
+
+        // Only references can dangle
+        assert!(layout
+            .find_dangling_references(conn, "Transaction", "nonce", BlockHeight::MAX)
+            .is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn find_duplicate_live() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_pet(conn, layout, "Ferret", "f1", "Fred");
+        insert_pet(conn, layout, "Ferret", "f2", "Frida");
+
+        let duplicates = || {
+            layout
+                .find_duplicate_live(conn, "Ferret")
+                .expect("Failed to check for duplicate live versions")
+        };
+        assert!(duplicates().is_empty());
+
+        // The exclusion constraint prevents duplicates; get rid of it so
+        // that we can insert a second live version for `f2`
+        conn.batch_execute(&format!(
+            "do $$
+             declare
+               excl text;
+             begin
+               select conname into excl
+                 from pg_constraint
+                where conrelid = '{schema}.ferret'::regclass and contype = 'x';
+               execute format('alter table {schema}.ferret drop constraint %I', excl);
+             end $$;
+             insert into {schema}.ferret(id, name, block_range)
+             values ('f2', 'Frida', int4range(1, null))",
+            schema = layout.schema
+        ))
+        .expect("Failed to insert a duplicate live version");
+        assert_eq!(vec!["f2"], duplicates());
+
+        // Versions that are not live do not count
+        conn.batch_execute(&format!(
+            "update {}.ferret set block_range = int4range(0, 1)
+              where id = 'f2' and lower(block_range) = 0",
+            layout.schema
+        ))
+        .expect("Failed to clamp the first version of f2");
+        assert!(duplicates().is_empty());
+        Ok(())
+    })
+}
+
+#[test]
+fn estimate_selectivity() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        // 30% of the ferrets are named 'Fred'
+        conn.batch_execute(&format!(
+            "insert into {}.ferret(id, name, block_range)
+             select 'f' || i, case when i % 10 < 3 then 'Fred' else 'Frida' end,
+                    int4range(0, null)
+               from generate_series(1, 5000) i",
+            layout.schema
+        ))
+        .expect("Failed to insert ferrets");
+
+        let estimate = |sample_pct: f64| {
+            layout.estimate_selectivity(
+                conn,
+                "Ferret",
+                EntityFilter::Equal("name".to_owned(), "Fred".into()),
+                sample_pct,
+                BlockHeight::MAX,
+            )
+        };
+        let selectivity = estimate(20.0).expect("Failed to estimate selectivity");
+        assert!(
+            (selectivity - 0.3).abs() < 0.05,
+            "estimated selectivity {} is too far off",
+            selectivity
+        );
+
+        // Sampling everything gives the exact selectivity
+        let selectivity = estimate(100.0).expect("Failed to estimate selectivity");
+        assert!((selectivity - 0.3).abs() < 1e-9);
+
+        assert!(estimate(0.0).is_err());
+        assert!(estimate(101.0).is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn verify_columns() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        layout
+            .verify_columns(conn)
+            .expect("The freshly created tables match the layout");
+
+        conn.batch_execute(&format!(
+            "alter table {}.ferret alter column name type varchar",
+            layout.schema
+        ))
+        .expect("Failed to change the type of ferret.name");
+        let err = layout
+            .verify_columns(conn)
+            .expect_err("The type of ferret.name does not match the layout");
+        let msg = err.to_string();
+        assert!(msg.contains("ferret.name should have type text but has type varchar"));
+        assert!(!msg.contains("ferret.id"));
+        Ok(())
+    })
+}
+
+#[test]
+fn sample() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        for i in 0..50 {
+            let id = format!("ferret{:02}", i);
+            insert_pet(conn, layout, "Ferret", &id, &id);
+        }
+
+        let sample = |seed: f64| -> Vec<String> {
+            layout
+                .sample(conn, "Ferret", 5, seed, BlockHeight::MAX)
+                .expect("Failed to sample ferrets")
+                .iter()
+                .map(|ferret| ferret.id().unwrap())
+                .collect()
+        };
+
+        let first = sample(0.5);
+        assert_eq!(5, first.len());
+        assert_eq!(first, sample(0.5));
+        assert_ne!(first, sample(-0.25));
+
+        // Seeds must be between -1 and 1
+        assert!(layout
+            .sample(conn, "Ferret", 5, 2.0, BlockHeight::MAX)
+            .is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn query_list_heads() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+
+        let options = QueryOptions {
+            list_heads: true,
+            ..QueryOptions::default()
+        };
+        let users = layout
+            .query_with_options(
+                &*LOGGER,
+                conn,
+                EntityCollection::All(vec!["User".to_owned()]),
+                None,
+                None,
+                EntityRange::first(100),
+                BlockHeight::MAX,
+                options,
+            )
+            .expect("Failed to query users");
+
+        let mut with_drinks = 0;
+        let mut without_drinks = 0;
+        for user in users {
+            let first = user.get("drinks_first");
+            match user.get("drinks") {
+                Some(Value::List(drinks)) if !drinks.is_empty() => {
+                    with_drinks += 1;
+                    assert_eq!(Some(&drinks[0]), first);
+                }
+                _ => {
+                    without_drinks += 1;
+                    assert_eq!(Some(&Value::Null), first);
+                }
+            }
+        }
+        assert!(with_drinks > 0);
+        assert!(without_drinks > 0);
+
+        // Without the option, there are no additional attributes
+        let users = layout
+            .query(
+                &*LOGGER,
+                conn,
+                EntityCollection::All(vec!["User".to_owned()]),
+                None,
+                None,
+                EntityRange::first(100),
+                BlockHeight::MAX,
+            )
+            .expect("Failed to query users");
+        assert!(users.iter().all(|user| user.get("drinks_first").is_none()));
+        Ok(())
+    })
+}
+
+#[test]
+fn query_child_count() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        let sender = Bytes::from_str("0x0102").unwrap();
+
+        // The header of `b1` claims a different number of transactions
+        // than actually point to it
+        for (id, number, count) in &[("b1", 1, 5), ("b2", 2, 0)] {
+            let mut block = Entity::new();
+            block.set("id", *id);
+            block.set("number", *number);
+            block.set("transactionCount", *count);
+            insert_entity(conn, layout, "Block", block);
+        }
+        for id in &["tx1", "tx2", "tx3"] {
+            let mut tx = Entity::new();
+            tx.set("id", *id);
+            tx.set("from", Value::Bytes(sender.clone()));
+            tx.set("nonce", BigInt::from(0));
+            tx.set("block", "b1");
+            insert_entity(conn, layout, "Transaction", tx);
+        }
+
+        let query = |entity_type: &str, field: &str| {
+            let options = QueryOptions {
+                child_count: Some(field.to_owned()),
+                ..QueryOptions::default()
+            };
+            layout.query_with_options(
+                &*LOGGER,
+                conn,
+                EntityCollection::All(vec![entity_type.to_owned()]),
+                None,
+                Some(("number".to_owned(), ValueType::Int, EntityOrder::Ascending)),
+                EntityRange::first(100),
+                BlockHeight::MAX,
+                options,
+            )
+        };
+
+        let blocks = query("Block", "transactions").expect("Failed to query blocks");
+        let counts: Vec<_> = blocks
+            .iter()
+            .map(|block| {
+                (
+                    block.id().unwrap(),
+                    block.get("__childCount").cloned(),
+                    block.get("transactionCount").cloned(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                ("b1".to_owned(), Some(Value::Int(3)), Some(Value::Int(5))),
+                ("b2".to_owned(), Some(Value::Int(0)), Some(Value::Int(0))),
+            ],
+            counts
+        );
+
+        // Only derived fields have children that can be counted
+        assert!(query("Block", "number").is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn find_transfers_value() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        let sender = Bytes::from_str("0x0102").unwrap();
+        for (id, transfers_value) in &[("call", false), ("transfer", true)] {
+            let mut tx = Entity::new();
+            tx.set("id", *id);
+            tx.set("from", Value::Bytes(sender.clone()));
+            tx.set("nonce", BigInt::from(0));
+            tx.set("transfersValue", *transfers_value);
+            insert_entity(conn, layout, "Transaction", tx);
+        }
+
+        let query = |transfers_value: bool| -> Vec<String> {
+            layout
+                .query(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["Transaction".to_owned()]),
+                    Some(EntityFilter::new_equal("transfersValue", transfers_value)),
+                    None,
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                )
+                .expect("Failed to query transactions")
+                .iter()
+                .map(|tx| tx.id().unwrap())
+                .collect()
+        };
+        assert_eq!(vec!["transfer"], query(true));
+        assert_eq!(vec!["call"], query(false));
+        Ok(())
+    })
+}
+
+#[test]
+fn query_blocks_old() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        const HEAD: BlockNumber = 20;
+        let sender = Bytes::from_str("0x0102").unwrap();
+
+        for (id, number) in &[("b1", 10), ("b2", 15)] {
+            let mut block = Entity::new();
+            block.set("id", *id);
+            block.set("number", *number);
+            insert_entity(conn, layout, "Block", block);
+        }
+        let txs = vec![
+            ("tx1", Some("b1")),
+            ("tx2", Some("b2")),
+            ("tx3", Some("b9")),
+            ("tx4", None),
+        ];
+        for (id, block) in &txs {
+            let mut tx = Entity::new();
+            tx.set("id", *id);
+            tx.set("from", Value::Bytes(sender.clone()));
+            tx.set("nonce", BigInt::from(0));
+            tx.set("block", block.map(str::to_owned));
+            insert_entity(conn, layout, "Transaction", tx);
+        }
+
+        let query = |entity_type: &str| {
+            let options = QueryOptions {
+                blocks_old: true,
+                ..QueryOptions::default()
+            };
+            layout.query_with_options(
+                &*LOGGER,
+                conn,
+                EntityCollection::All(vec![entity_type.to_owned()]),
+                None,
+                Some(("id".to_owned(), ValueType::String, EntityOrder::Ascending)),
+                EntityRange::first(100),
+                BlockHeight::from(HEAD),
+                options,
+            )
+        };
+
+        let ages: Vec<_> = query("Transaction")
+            .expect("Failed to query transactions")
+            .iter()
+            .map(|tx| (tx.id().unwrap(), tx.get("blocksOld").cloned()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("tx1".to_owned(), Some(Value::Int(10))),
+                ("tx2".to_owned(), Some(Value::Int(5))),
+                // The block `b9` does not exist
+                ("tx3".to_owned(), Some(Value::Null)),
+                ("tx4".to_owned(), Some(Value::Null)),
+            ],
+            ages
+        );
+
+        // Blocks do not reference a block
+        assert!(query("Block").is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn query_decimal_scale() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+        insert_user_entity(
+            conn,
+            layout,
+            "4",
+            "User",
+            "Tobin",
+            "tobin@email.com",
+            37 as i32,
+            72.125,
+            true,
+            None,
+            None,
+        );
+
+        let options = QueryOptions {
+            decimal_scale: Some(2),
+            ..QueryOptions::default()
+        };
+        let weights: Vec<_> = layout
+            .query_with_options(
+                &*LOGGER,
+                conn,
+                EntityCollection::All(vec!["User".to_owned()]),
+                None,
+                Some(("id".to_owned(), ValueType::String, EntityOrder::Ascending)),
+                EntityRange::first(100),
+                BlockHeight::MAX,
+                options,
+            )
+            .expect("Failed to query users")
+            .iter()
+            .map(|user| user.get("weight").unwrap().to_string())
+            .collect();
+        // 72.125 is rounded half to even
+        assert_eq!(vec!["184.40", "159.10", "111.70", "72.12"], weights);
+
+        // The stored value is unchanged
+        let user = layout
+            .find(conn, "User", "4", BlockHeight::MAX)
+            .expect("Failed to read User[4]")
+            .unwrap();
+        assert_eq!(
+            Some(&Value::BigDecimal(BigDecimal::from_str("72.125").unwrap())),
+            user.get("weight")
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn query_unclamped() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+        let mut user = layout
+            .find(conn, "User", "1", BlockHeight::MAX)
+            .expect("Failed to read User[1]")
+            .unwrap();
+        user.set("name", "Johnny");
+        update_entity(conn, layout, "User", user);
+
+        let query = |unclamped: bool| -> Vec<String> {
+            let options = QueryOptions {
+                unclamped,
+                ..QueryOptions::default()
+            };
+            layout
+                .query_with_options(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["User".to_owned()]),
+                    Some(EntityFilter::Equal("id".to_owned(), "1".into())),
+                    Some(("name".to_owned(), ValueType::String, EntityOrder::Ascending)),
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                    options,
+                )
+                .expect("Failed to query users")
+                .iter()
+                .map(|user| user.get("name").unwrap().to_string())
+                .collect()
+        };
+
+        // Normally, we only see the current version
+        assert_eq!(vec!["Johnny"], query(false));
+        // Unclamped queries return the old version, too
+        assert_eq!(vec!["Johnny", "Johnton"], query(true));
+        Ok(())
+    })
+}
+
+#[test]
+fn query_default_order() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+        // Updating User[1] puts its current version after the other users
+        // in the table
+        let mut user = layout
+            .find(conn, "User", "1", BlockHeight::MAX)
+            .expect("Failed to read User[1]")
+            .unwrap();
+        user.set("name", "Johnny");
+        update_entity(conn, layout, "User", user);
+
+        let query = |unordered: bool,
+                     order: Option<(String, ValueType, EntityOrder)>|
+         -> Result<Vec<String>, QueryExecutionError> {
+            let options = QueryOptions {
+                unordered,
+                ..QueryOptions::default()
+            };
+            layout
+                .query_with_options(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["User".to_owned()]),
+                    None,
+                    order,
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                    options,
+                )
+                .map(|users| {
+                    users
+                        .iter()
+                        .map(|user| user.id().expect("users have an id"))
+                        .collect()
+                })
+        };
+
+        // Without an order, users are ordered by id
+        assert_eq!(vec!["1", "2", "3"], query(false, None).unwrap());
+
+        // Unordered queries return the same users, in any order
+        let mut ids = query(true, None).expect("Failed to query unordered users");
+        ids.sort();
+        assert_eq!(vec!["1", "2", "3"], ids);
+
+        // A query with an order can not be unordered
+        let order = Some(("name".to_owned(), ValueType::String, EntityOrder::Ascending));
+        assert!(query(true, order).is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn query_collation() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        for (id, name) in &[("1", "Af"), ("2", "Ärger"), ("3", "Adam")] {
+            insert_user_entity(
+                conn,
+                layout,
+                id,
+                "User",
+                name,
+                "someone@email.com",
+                30,
+                170.0,
+                false,
+                None,
+                None,
+            );
+        }
+
+        let query = |attribute: &str, collation: &str| {
+            let options = QueryOptions {
+                collation: Some(collation.to_owned()),
+                ..QueryOptions::default()
+            };
+            layout
+                .query_with_options(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["User".to_owned()]),
+                    None,
+                    Some((
+                        attribute.to_owned(),
+                        ValueType::String,
+                        EntityOrder::Ascending,
+                    )),
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                    options,
+                )
+                .map(|users| {
+                    users
+                        .iter()
+                        .map(|user| user.get("name").unwrap().clone().as_string().unwrap())
+                        .collect::<Vec<_>>()
+                })
+        };
+
+        // German dictionary order sorts `Ä` like `A`, but phonebook order
+        // sorts it like `Ae`
+        assert_eq!(
+            vec!["Adam", "Af", "Ärger"],
+            query("name", "de-x-icu").expect("Failed to query users")
+        );
+        assert_eq!(
+            vec!["Adam", "Ärger", "Af"],
+            query("name", "de-u-co-phonebk-x-icu").expect("Failed to query users")
+        );
+
+        // The collation must exist
+        assert!(query("name", "no-such-collation").is_err());
+        // Only String attributes can be sorted with a collation
+        assert!(query("age", "de-x-icu").is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn query_ids() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+        insert_pets(conn, layout);
+
+        let check = |entity_types: Vec<&str>,
+                     filter: Option<EntityFilter>,
+                     order: Option<(String, ValueType, EntityOrder)>,
+                     range: EntityRange| {
+            let collection =
+                EntityCollection::All(entity_types.into_iter().map(str::to_owned).collect());
+            let expected: Vec<_> = layout
+                .query(
+                    &*LOGGER,
+                    conn,
+                    collection.clone(),
+                    filter.clone(),
+                    order.clone(),
+                    range.clone(),
+                    BlockHeight::MAX,
+                )
+                .expect("Failed to query entities")
+                .iter()
+                .map(|entity| entity.id().unwrap())
+                .collect();
+            let ids = layout
+                .query_ids(
+                    &*LOGGER,
+                    conn,
+                    collection,
+                    filter,
+                    order,
+                    range,
+                    BlockHeight::MAX,
+                )
+                .expect("Failed to query ids");
+            assert!(!ids.is_empty());
+            assert_eq!(expected, ids);
+        };
+
+        check(
+            vec!["User"],
+            Some(EntityFilter::GreaterThan("age".to_owned(), Value::Int(40))),
+            Some((
+                "name".to_owned(),
+                ValueType::String,
+                EntityOrder::Descending,
+            )),
+            EntityRange::first(2),
+        );
+        check(
+            vec!["User"],
+            None,
+            Some((
+                "weight".to_owned(),
+                ValueType::BigDecimal,
+                EntityOrder::Ascending,
+            )),
+            EntityRange {
+                first: Some(2),
+                skip: 1,
+            },
+        );
+        check(
+            vec!["Cat", "Dog"],
+            None,
+            Some((
+                "name".to_owned(),
+                ValueType::String,
+                EntityOrder::Descending,
+            )),
+            EntityRange::first(10),
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn cumulative_gas_used() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        let sender = Bytes::from_str("0x0102").unwrap();
+        let other = Bytes::from_str("0x0304").unwrap();
+
+        for (id, number) in &[("b1", 1), ("b2", 2)] {
+            let mut block = Entity::new();
+            block.set("id", *id);
+            block.set("number", *number);
+            insert_entity(conn, layout, "Block", block);
+        }
+        // Insert in an order that differs from the chain order
+        for (id, from, block, index, gas) in &[
+            ("txa", &sender, "b2", 0, 30),
+            ("txb", &sender, "b1", 1, 20),
+            ("txc", &sender, "b1", 0, 10),
+            ("other", &other, "b1", 2, 1000),
+        ] {
+            let mut tx = Entity::new();
+            tx.set("id", *id);
+            tx.set("from", Value::Bytes((*from).clone()));
+            tx.set("nonce", BigInt::from(0));
+            tx.set("block", *block);
+            tx.set("index", *index);
+            tx.set("gasUsed", BigInt::from(*gas));
+            insert_entity(conn, layout, "Transaction", tx);
+        }
+
+        let totals = layout
+            .cumulative_gas_used(conn, "Transaction", &Value::Bytes(sender), BlockHeight::MAX)
+            .expect("Failed to compute cumulative gas");
+        assert_eq!(
+            vec![
+                ("txc".to_owned(), BigInt::from(10)),
+                ("txb".to_owned(), BigInt::from(30)),
+                ("txa".to_owned(), BigInt::from(60)),
+            ],
+            totals
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn group_count() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        // Colors are an enum, and groups of the same size are sorted in
+        // the order in which the enum declares its values
+        let groups = layout
+            .group_count(conn, "User", "favorite_color", None, BlockHeight::MAX)
+            .expect("Failed to count groups");
+        assert_eq!(
+            vec![
+                (Value::from("yellow"), 1),
+                (Value::from("red"), 1),
+                (Value::Null, 1)
+            ],
+            groups
+        );
+
+        // No color is used by more than one user
+        let groups = layout
+            .group_count(conn, "User", "favorite_color", Some(2), BlockHeight::MAX)
+            .expect("Failed to count groups");
+        assert!(groups.is_empty());
+
+        insert_user_entity(
+            conn,
+            layout,
+            "4",
+            "User",
+            "Tobin",
+            "tobin@email.com",
+            37 as i32,
+            171.2,
+            true,
+            Some("red"),
+            None,
+        );
+        let groups = layout
+            .group_count(conn, "User", "favorite_color", Some(2), BlockHeight::MAX)
+            .expect("Failed to count groups");
+        assert_eq!(vec![(Value::from("red"), 2)], groups);
+        Ok(())
+    })
+}
+
+#[test]
+fn count_distinct() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+
+        // The users' favorite colors are yellow, red, and not set; users
+        // without a favorite color are not counted
+        let count = layout
+            .count_distinct(conn, "User", "favorite_color", None, BlockHeight::MAX)
+            .expect("Failed to count favorite colors");
+        assert_eq!(2, count);
+
+        let count = layout
+            .count_distinct(
                 conn,
-                query.collection,
-                query.filter,
-                order,
-                query.range,
-                BLOCK_NUMBER_MAX,
+                "User",
+                "favorite_color",
+                Some(EntityFilter::Equal("coffee".to_owned(), Value::Bool(false))),
+                BlockHeight::MAX,
             )
-            .expect("layout.query failed to execute query");
+            .expect("Failed to count favorite colors");
+        assert_eq!(1, count);
+        Ok(())
+    })
+}
 
-        let entity_ids: Vec<_> = entities
-            .into_iter()
-            .map(|entity| match entity.get("id") {
-                Some(Value::String(id)) => id.to_owned(),
-                Some(_) => panic!("layout.query returned entity with non-string ID attribute"),
-                None => panic!("layout.query returned entity with no ID attribute"),
-            })
+#[test]
+fn materialize() {
+    use diesel::QueryableByName;
+
+    #[derive(QueryableByName)]
+    struct Snapshot {
+        #[sql_type = "diesel::sql_types::Text"]
+        id: String,
+        #[sql_type = "diesel::sql_types::Text"]
+        name: String,
+    }
+
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+
+        let count = layout
+            .materialize(
+                conn,
+                EntityCollection::All(vec!["User".to_owned()]),
+                Some(EntityFilter::Equal("coffee".to_owned(), Value::Bool(false))),
+                BlockHeight::MAX,
+                "no_coffee",
+            )
+            .expect("Failed to materialize users");
+        assert_eq!(2, count);
+
+        let rows = diesel::sql_query(format!(
+            "select id, name from {}.no_coffee order by id",
+            layout.schema
+        ))
+        .load::<Snapshot>(conn)
+        .expect("Failed to read snapshot");
+        let rows: Vec<_> = rows
+            .iter()
+            .map(|row| (row.id.as_str(), row.name.as_str()))
             .collect();
+        assert_eq!(vec![("1", "Johnton"), ("3", "Shaqueeena")], rows);
 
-        assert_eq!(entity_ids, expected_entity_ids);
+        // The target table must not exist yet
+        assert!(layout
+            .materialize(
+                conn,
+                EntityCollection::All(vec!["User".to_owned()]),
+                None,
+                BlockHeight::MAX,
+                "no_coffee",
+            )
+            .is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn insert_by_typename() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        let insert = |id: &str, typename: &str| {
+            let mut pet = Entity::new();
+            pet.set("id", id);
+            pet.set("name", "Tom");
+            pet.set("__typename", typename);
+            let key = EntityKey {
+                subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+                entity_type: "Pet".to_owned(),
+                entity_id: id.to_owned(),
+            };
+            layout.insert_by_typename(conn, &key, pet, BlockHeight::from(0))
+        };
 
+        insert("tom", "Cat").expect("Failed to insert Pet as a Cat");
+        let cat = layout
+            .find(conn, "Cat", "tom", BlockHeight::MAX)
+            .expect("Failed to read Cat[tom]");
+        assert!(cat.is_some());
+        let dog = layout
+            .find(conn, "Dog", "tom", BlockHeight::MAX)
+            .expect("Failed to read Dog[tom]");
+        assert!(dog.is_none());
+
+        // User does not implement Pet
+        assert!(insert("jerry", "User").is_err());
+        // There is no type Parrot
+        assert!(insert("polly", "Parrot").is_err());
         Ok(())
     })
 }
 
-fn query(entity_types: Vec<&str>) -> EntityQuery {
-    EntityQuery::new(
-        THINGS_SUBGRAPH_ID.clone(),
-        BLOCK_NUMBER_MAX,
-        EntityCollection::All(entity_types.into_iter().map(|s| s.to_owned()).collect()),
-    )
+#[test]
+fn insert_unknown_attribute() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        let mut pet = Entity::new();
+        pet.set("id", "garfield");
+        pet.set("name", "Garfield");
+        pet.set("lasagna", true);
+        let key = EntityKey {
+            subgraph_id: THINGS_SUBGRAPH_ID.clone(),
+            entity_type: "Cat".to_owned(),
+            entity_id: "garfield".to_owned(),
+        };
+
+        let err = layout
+            .insert(conn, &key, pet.clone(), BlockHeight::from(0))
+            .expect_err("attributes that are not in the schema are rejected");
+        assert_eq!(
+            "entity type 'Cat' has no attribute 'lasagna'",
+            err.to_string()
+        );
+        let err = layout
+            .update(conn, &key, pet.clone(), BlockHeight::from(1))
+            .expect_err("attributes that are not in the schema are rejected");
+        assert_eq!(
+            "entity type 'Cat' has no attribute 'lasagna'",
+            err.to_string()
+        );
+
+        // With the opt-out, the unknown attribute is ignored
+        let lenient = layout.clone().with_unknown_attributes_allowed();
+        lenient
+            .insert(conn, &key, pet, BlockHeight::from(0))
+            .expect("unknown attributes are ignored");
+        let cat = layout
+            .find(conn, "Cat", "garfield", BlockHeight::MAX)
+            .expect("Failed to read Cat[garfield]")
+            .unwrap();
+        assert_eq!(None, cat.get("lasagna"));
+        Ok(())
+    })
 }
 
-fn user_query() -> EntityQuery {
-    query(vec!["User"])
+#[test]
+fn find_near() {
+    test_find(
+        vec!["2"],
+        user_query().filter(EntityFilter::Near("age".into(), 45.into(), 5.into())),
+    );
+
+    // Works with BigInt attributes and values, and includes the bounds
+    test_find(
+        vec!["2", "3"],
+        user_query()
+            .filter(EntityFilter::Near(
+                "seconds_age".into(),
+                Value::BigInt(BigInt::from(35) * 31557600.into()),
+                Value::BigInt(BigInt::from(8) * 31557600.into()),
+            ))
+            .order_by("id", ValueType::String, EntityOrder::Ascending),
+    );
+
+    // The bounds for Int attributes do not overflow
+    test_find(
+        vec!["1"],
+        user_query().filter(EntityFilter::Near(
+            "age".into(),
+            std::i32::MAX.into(),
+            (std::i32::MAX - 50).into(),
+        )),
+    );
+
+    // Only numeric attributes that are not encrypted can be used
+    run_test(|conn, layout| -> Result<(), ()> {
+        let near = |entity_type: &str, attr: &str| {
+            layout.query(
+                &*LOGGER,
+                conn,
+                EntityCollection::All(vec![entity_type.to_owned()]),
+                Some(EntityFilter::Near(attr.into(), 45.into(), 5.into())),
+                None,
+                EntityRange::first(100),
+                BlockHeight::MAX,
+            )
+        };
+        assert!(near("User", "name").is_err());
+        assert!(near("Patient", "email").is_err());
+        Ok(())
+    });
 }
 
 #[test]
@@ -683,6 +4411,41 @@ fn find_fulltext_and() {
     )
 }
 
+#[test]
+fn find_fulltext_weighted() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        // Without weights, both users would rank the same and the tie
+        // would be broken by id in favor of `z`
+        for (id, name, email) in &[("a", "Sparrow", "finch"), ("z", "Finch", "sparrow")] {
+            insert_user_entity(
+                conn, layout, id, "User", name, email, 30, 160.0, false, None, None,
+            );
+        }
+
+        let ids: Vec<_> = layout
+            .query(
+                &*LOGGER,
+                conn,
+                EntityCollection::All(vec!["User".to_owned()]),
+                Some(EntityFilter::Equal("userSearch".into(), "sparrow".into())),
+                Some((
+                    "userSearch".to_owned(),
+                    ValueType::String,
+                    EntityOrder::Descending,
+                )),
+                EntityRange::first(100),
+                BlockHeight::MAX,
+            )
+            .expect("Failed to search users")
+            .iter()
+            .map(|user| user.id().unwrap())
+            .collect();
+        // The name has weight A and the email the default weight D
+        assert_eq!(vec!["a", "z"], ids);
+        Ok(())
+    })
+}
+
 #[test]
 fn find_list_contains() {
     fn query(v: Vec<&str>) -> EntityQuery {
@@ -810,6 +4573,260 @@ fn find_string_in() {
     )
 }
 
+#[test]
+fn find_like_escapes_wildcards() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+        for (id, name) in &[("4", "a_b"), ("5", "50%"), ("6", "back\\slash")] {
+            insert_user_entity(
+                conn,
+                layout,
+                id,
+                "User",
+                name,
+                "someone@email.com",
+                30,
+                160.0,
+                false,
+                None,
+                None,
+            );
+        }
+
+        let query = |filter: EntityFilter| -> Vec<String> {
+            layout
+                .query(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["User".to_owned()]),
+                    Some(filter),
+                    None,
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                )
+                .expect("Failed to query users")
+                .iter()
+                .map(|user| user.id().unwrap())
+                .collect()
+        };
+        let name = |s: &str| -> (String, Value) { ("name".to_owned(), s.into()) };
+
+        // `_`, `%`, and `\` only match themselves
+        let (attr, value) = name("_");
+        assert_eq!(vec!["4"], query(EntityFilter::Contains(attr, value)));
+        let (attr, value) = name("%");
+        assert_eq!(vec!["5"], query(EntityFilter::Contains(attr, value)));
+        let (attr, value) = name("\\");
+        assert_eq!(vec!["6"], query(EntityFilter::Contains(attr, value)));
+        let (attr, value) = name("a_");
+        assert_eq!(vec!["4"], query(EntityFilter::StartsWith(attr, value)));
+        let (attr, value) = name("0%");
+        assert_eq!(vec!["5"], query(EntityFilter::EndsWith(attr, value)));
+        let (attr, value) = name("_");
+        assert_eq!(5, query(EntityFilter::NotContains(attr, value)).len());
+        Ok(())
+    })
+}
+
+#[test]
+fn find_word_match() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+        insert_user_entity(
+            conn,
+            layout,
+            "4",
+            "User",
+            "Johnny John",
+            "jj@email.com",
+            35,
+            170.0,
+            true,
+            None,
+            None,
+        );
+
+        let query = |word: &str| -> Vec<String> {
+            layout
+                .query(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["User".to_owned()]),
+                    Some(EntityFilter::WordMatch("name".to_owned(), word.into())),
+                    None,
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                )
+                .expect("Failed to query users")
+                .iter()
+                .map(|user| user.id().unwrap())
+                .collect()
+        };
+
+        // `john` is a word in `Johnny John`, but only part of a word in
+        // `Johnton` and `Johnny`
+        assert_eq!(vec!["4"], query("john"));
+        assert!(query("johnn").is_empty());
+        // Regular expression metacharacters are matched literally
+        assert!(query("j.hn").is_empty());
+        assert!(query("john|cindini").is_empty());
+        Ok(())
+    })
+}
+
+#[test]
+fn find_bits_set() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        for (id, flags) in &[("s1", 0b0011), ("s2", 0b0101), ("s3", 0b1000)] {
+            let mut scalar = Entity::new();
+            scalar.set("id", *id);
+            scalar.set("int", *flags);
+            scalar.set(
+                "bigInt",
+                BigInt::from(*flags as i64) * BigInt::from(1u64 << 32),
+            );
+            insert_entity(conn, layout, "Scalar", scalar);
+        }
+        let mut unset = Entity::new();
+        unset.set("id", "s4");
+        insert_entity(conn, layout, "Scalar", unset);
+        // A BigInt that does not fit into 64 bits never matches, but must
+        // not make queries on the attribute fail
+        let mut huge = Entity::new();
+        huge.set("id", "s5");
+        huge.set("bigInt", BigInt::from(u64::MAX) * BigInt::from(1u64 << 32));
+        insert_entity(conn, layout, "Scalar", huge);
+
+        let query = |filter: EntityFilter| -> Vec<String> {
+            layout
+                .query(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["Scalar".to_owned()]),
+                    Some(filter),
+                    Some(("id".to_owned(), ValueType::String, EntityOrder::Ascending)),
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                )
+                .expect("Failed to query scalars")
+                .iter()
+                .map(|scalar| scalar.id().unwrap())
+                .collect()
+        };
+        let big_mask = |mask: i64| Value::BigInt(BigInt::from(mask) * BigInt::from(1u64 << 32));
+
+        // All the bits in the mask must be set
+        let ids = query(EntityFilter::BitsSet("int".to_owned(), Value::Int(0b0001)));
+        assert_eq!(vec!["s1", "s2"], ids);
+        let ids = query(EntityFilter::BitsSet("int".to_owned(), Value::Int(0b0011)));
+        assert_eq!(vec!["s1"], ids);
+        let ids = query(EntityFilter::BitsSet("bigInt".to_owned(), big_mask(0b0101)));
+        assert_eq!(vec!["s2"], ids);
+
+        // Any of the bits in the mask must be set
+        let ids = query(EntityFilter::BitsAnySet(
+            "int".to_owned(),
+            Value::Int(0b1010),
+        ));
+        assert_eq!(vec!["s1", "s3"], ids);
+        let ids = query(EntityFilter::BitsAnySet(
+            "bigInt".to_owned(),
+            big_mask(0b1100),
+        ));
+        assert_eq!(vec!["s2", "s3"], ids);
+        let ids = query(EntityFilter::BitsAnySet(
+            "int".to_owned(),
+            Value::Int(0b10000),
+        ));
+        assert!(ids.is_empty());
+
+        // Only integer attributes and masks are supported
+        let err = layout.query(
+            &*LOGGER,
+            conn,
+            EntityCollection::All(vec!["Scalar".to_owned()]),
+            Some(EntityFilter::BitsSet("string".to_owned(), Value::Int(1))),
+            None,
+            EntityRange::first(100),
+            BlockHeight::MAX,
+        );
+        assert!(err.is_err());
+        let err = layout.query(
+            &*LOGGER,
+            conn,
+            EntityCollection::All(vec!["Scalar".to_owned()]),
+            Some(EntityFilter::BitsSet("int".to_owned(), Value::from("1"))),
+            None,
+            EntityRange::first(100),
+            BlockHeight::MAX,
+        );
+        assert!(err.is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn find_int_mod_equals() {
+    test_find(
+        vec!["3"],
+        user_query().filter(EntityFilter::ModEquals("age".to_owned(), 2, 0)),
+    )
+}
+
+#[test]
+fn find_int_mod_equals_odd() {
+    test_find(
+        vec!["1", "2"],
+        user_query()
+            .filter(EntityFilter::ModEquals("age".to_owned(), 2, 1))
+            .order_by("name", ValueType::String, EntityOrder::Descending),
+    )
+}
+
+#[test]
+fn find_int_mod_equals_zero_modulus() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+        let res = layout.query(
+            &*LOGGER,
+            conn,
+            EntityCollection::All(vec!["User".to_owned()]),
+            Some(EntityFilter::ModEquals("age".to_owned(), 0, 0)),
+            None,
+            EntityRange::first(100),
+            BlockHeight::MAX,
+        );
+        assert!(res.is_err());
+        Ok(())
+    })
+}
+
+#[test]
+fn find_string_in_case_insensitive() {
+    test_find(
+        vec!["1", "2"],
+        user_query()
+            .filter(EntityFilter::InCaseInsensitive(
+                "name".to_owned(),
+                vec!["JONO".into(), "cindini".into()],
+            ))
+            .order_by("name", ValueType::String, EntityOrder::Descending),
+    )
+}
+
+#[test]
+fn find_enum_in_case_insensitive() {
+    test_find(
+        vec!["2"],
+        user_query()
+            .filter(EntityFilter::InCaseInsensitive(
+                "favorite_color".to_owned(),
+                vec!["RED".into(), "Purple".into()],
+            ))
+            .order_by("name", ValueType::String, EntityOrder::Descending),
+    )
+}
+
 #[test]
 fn find_empty_in() {
     test_find(
@@ -1087,6 +5104,70 @@ fn find_bool_not_equal() {
     )
 }
 
+#[test]
+fn find_bool_unknown() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+        // We do not know whether user 4 drinks coffee
+        let mut user = Entity::new();
+        user.set("id", "4");
+        user.set("name", "Unknowne");
+        let bin_name = Bytes::from_str(&hex::encode("Unknowne")).unwrap();
+        user.set("bin_name", Value::Bytes(bin_name));
+        user.set("email", "unknown@email.com");
+        user.set("age", 31);
+        user.set("seconds_age", BigInt::from(31) * 31557600.into());
+        user.set("weight", BigDecimal::from(170.0));
+        insert_entity(conn, layout, "User", user);
+
+        let query = |filter: EntityFilter| -> Vec<String> {
+            let mut ids: Vec<_> = layout
+                .query(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["User".to_owned()]),
+                    Some(filter),
+                    None,
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                )
+                .expect("Failed to query users")
+                .iter()
+                .map(|user| user.id().unwrap())
+                .collect();
+            ids.sort();
+            ids
+        };
+
+        // `true`, `false`, and unknown divide the users between them
+        let coffee = |value| query(EntityFilter::new_bool_equal("coffee", value));
+        assert_eq!(vec!["2"], coffee(Some(true)));
+        assert_eq!(vec!["1", "3"], coffee(Some(false)));
+        assert_eq!(vec!["4"], coffee(None));
+
+        // Null is neither `true` nor `false`
+        let coffee_in = |values: Vec<Value>| query(EntityFilter::In("coffee".to_owned(), values));
+        let coffee_not_in =
+            |values: Vec<Value>| query(EntityFilter::NotIn("coffee".to_owned(), values));
+        assert_eq!(
+            vec!["1", "2", "3"],
+            coffee_in(vec![Value::Bool(true), Value::Bool(false)])
+        );
+        assert_eq!(
+            vec!["2", "4"],
+            coffee_in(vec![Value::Bool(true), Value::Null])
+        );
+        assert_eq!(vec!["4"], coffee_in(vec![Value::Null]));
+        assert_eq!(vec!["1", "3"], coffee_not_in(vec![Value::Bool(true)]));
+        assert_eq!(
+            vec!["1", "3"],
+            coffee_not_in(vec![Value::Bool(true), Value::Null])
+        );
+        assert_eq!(vec!["1", "2", "3"], coffee_not_in(vec![Value::Null]));
+        Ok(())
+    })
+}
+
 #[test]
 fn find_bool_in() {
     test_find(
@@ -1128,6 +5209,58 @@ fn find_bytes_equal() {
     )
 }
 
+#[test]
+fn find_bytes_equal_hex() {
+    // 0x4a6f6e6f is the hex form of "Jono"
+    test_find(
+        vec!["1"],
+        user_query()
+            .filter(EntityFilter::Equal(
+                "bin_name".to_owned(),
+                Value::from("0x4a6f6e6f"),
+            ))
+            .order_by("name", ValueType::String, EntityOrder::Descending),
+    )
+}
+
+#[test]
+fn find_bytes_in_hex() {
+    test_find(
+        vec!["1"],
+        user_query()
+            .filter(EntityFilter::In(
+                "bin_name".to_owned(),
+                vec![Value::from("0x4a6f6e6f"), Value::from("0xdeadbeef")],
+            ))
+            .order_by("name", ValueType::String, EntityOrder::Descending),
+    )
+}
+
+#[test]
+fn find_bytes_invalid_hex() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+        for hex in &["0xnothex", "4a6f6e6f"] {
+            let err = layout
+                .query(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["User".to_owned()]),
+                    Some(EntityFilter::Equal(
+                        "bin_name".to_owned(),
+                        Value::from(*hex),
+                    )),
+                    None,
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                )
+                .expect_err("invalid hex strings are rejected");
+            assert!(err.to_string().contains("0x-hex"));
+        }
+        Ok(())
+    })
+}
+
 #[test]
 fn find_null_equal() {
     test_find(
@@ -1222,6 +5355,67 @@ fn find_order_by_string() {
     );
 }
 
+#[test]
+fn find_order_by_enum() {
+    // Enums sort in the order in which their values are declared
+    // (yellow, red, BLUE), not alphabetically; users without a color
+    // always come last
+    test_find(
+        vec!["1", "2", "3"],
+        user_query().order_by("favorite_color", ValueType::String, EntityOrder::Ascending),
+    );
+    test_find(
+        vec!["2", "1", "3"],
+        user_query().order_by("favorite_color", ValueType::String, EntityOrder::Descending),
+    );
+}
+
+#[test]
+fn find_order_by_int_with_ties() {
+    run_test(|conn, layout| -> Result<(), ()> {
+        insert_users(conn, layout);
+        // Same age as user 2
+        insert_user_entity(
+            conn,
+            layout,
+            "4",
+            "User",
+            "Quattro",
+            "quattro@email.com",
+            43 as i32,
+            172.5,
+            true,
+            None,
+            None,
+        );
+
+        let ids = |direction: EntityOrder| -> Vec<String> {
+            layout
+                .query(
+                    &*LOGGER,
+                    conn,
+                    EntityCollection::All(vec!["User".to_owned()]),
+                    None,
+                    Some(("age".to_owned(), ValueType::Int, direction)),
+                    EntityRange::first(100),
+                    BlockHeight::MAX,
+                )
+                .expect("Failed to query users")
+                .iter()
+                .map(|user| user.id().unwrap())
+                .collect()
+        };
+
+        // Ties are broken by id, in the same direction as the sort on age,
+        // and the order is the same every time we run the query
+        for _ in 0..3 {
+            assert_eq!(vec!["3", "2", "4", "1"], ids(EntityOrder::Ascending));
+            assert_eq!(vec!["1", "4", "2", "3"], ids(EntityOrder::Descending));
+        }
+        Ok(())
+    })
+}
+
 #[test]
 fn find_where_nested_and_or() {
     test_find(
@@ -1335,7 +5529,7 @@ fn text_find(expected_entity_ids: Vec<&str>, filter: EntityFilter) {
                 query.filter,
                 order,
                 query.range,
-                BLOCK_NUMBER_MAX,
+                BlockHeight::MAX,
             )
             .expect("layout.query failed to execute query");
 
@@ -34,7 +34,8 @@ const USER_GQL: &str = "
         seconds_age: BigInt,
         weight: BigDecimal,
         coffee: Boolean,
-        favorite_color: String
+        favorite_color: String,
+        drinks: [String!]
     }
 
     type Person implements ColorAndAge @entity {
@@ -588,6 +589,91 @@ fn find_string_contains() {
     )
 }
 
+#[test]
+fn find_list_not_empty() {
+    run_test(|store| -> Result<(), ()> {
+        // Users with a null list, with an empty list, and with a list that
+        // has an element; users 4 and up do not have `drinks` at all
+        let drinks = vec![
+            ("1", Value::Null),
+            ("2", Value::List(vec![])),
+            ("3", Value::List(vec![Value::from("beer")])),
+        ];
+        let ops = drinks
+            .into_iter()
+            .map(|(id, drinks)| EntityOperation::Set {
+                key: EntityKey {
+                    subgraph_id: TEST_SUBGRAPH_ID.clone(),
+                    entity_type: USER.to_owned(),
+                    entity_id: id.to_owned(),
+                },
+                data: Entity::from(vec![("id", Value::from(id)), ("drinks", drinks)]),
+            })
+            .collect();
+        transact_entity_operations(&store, TEST_SUBGRAPH_ID.clone(), *TEST_BLOCK_3_PTR, ops)
+            .unwrap();
+
+        let ids: Vec<_> = store
+            .find(user_query().filter(EntityFilter::ListNotEmpty("drinks".to_owned())))
+            .expect("store.find failed to execute query")
+            .into_iter()
+            .map(|entity| entity.id().unwrap())
+            .collect();
+        assert_eq!(vec!["3".to_owned()], ids);
+        Ok(())
+    })
+}
+
+#[test]
+fn find_list_length_skips_null_lists() {
+    run_test(|store| -> Result<(), ()> {
+        // Users with a null list, with an empty list, and with a list that
+        // has an element; users 4 and up do not have `drinks` at all
+        let drinks = vec![
+            ("1", Value::Null),
+            ("2", Value::List(vec![])),
+            ("3", Value::List(vec![Value::from("beer")])),
+        ];
+        let ops = drinks
+            .into_iter()
+            .map(|(id, drinks)| EntityOperation::Set {
+                key: EntityKey {
+                    subgraph_id: TEST_SUBGRAPH_ID.clone(),
+                    entity_type: USER.to_owned(),
+                    entity_id: id.to_owned(),
+                },
+                data: Entity::from(vec![("id", Value::from(id)), ("drinks", drinks)]),
+            })
+            .collect();
+        transact_entity_operations(&store, TEST_SUBGRAPH_ID.clone(), *TEST_BLOCK_3_PTR, ops)
+            .unwrap();
+
+        let find = |filter: EntityFilter| -> Vec<String> {
+            store
+                .find(user_query().filter(filter))
+                .expect("store.find failed to execute query")
+                .into_iter()
+                .map(|entity| entity.id().unwrap())
+                .collect()
+        };
+
+        // A null list matches neither `ListEmpty` nor any length filter
+        assert_eq!(
+            vec!["2".to_owned()],
+            find(EntityFilter::ListEmpty("drinks".to_owned()))
+        );
+        assert_eq!(
+            vec!["2".to_owned()],
+            find(EntityFilter::LengthEqual("drinks".to_owned(), 0))
+        );
+        assert_eq!(
+            vec!["2".to_owned()],
+            find(EntityFilter::LengthLessThan("drinks".to_owned(), 1))
+        );
+        Ok(())
+    })
+}
+
 #[test]
 fn find_string_equal() {
     test_find(